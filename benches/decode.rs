@@ -0,0 +1,74 @@
+// Decode benchmark
+// ----------------
+// Compares instructions per second of the plain interpreter, which decodes
+// every opcode as it runs, against the decode cache with and without basic
+// blocks and the JIT. Run it with:
+//
+//   cargo bench --bench decode
+//
+// Each rom runs for a fixed number of instructions with no input, the best
+// of a few rounds is reported.
+extern crate chip8_emulator;
+
+use std::fs;
+use std::time::{ Duration, Instant };
+
+use chip8_emulator::chip8::{ DecodeCache, Jit, CHIP8 };
+
+const ROMS: &[&str] = &[
+    "chip8/Sierpinski [Sergey Naydenov, 2010].ch8",
+    "chip8/Particle Demo [zeroZshadow, 2008].ch8",
+    "chip8/Brix [Andreas Gustafsson, 1990].ch8",
+];
+
+const CYCLES: u64 = 5_000_000;
+const ROUNDS: usize = 10;
+
+fn load(rom: &str) -> CHIP8 {
+    let path = format!("{}/docs/chip8/roms/{}", env!("CARGO_MANIFEST_DIR"), rom);
+    let data = fs::read(&path).unwrap_or_else(|err| panic!("{}: {}", path, err));
+
+    let mut emu = CHIP8::new();
+    emu.load_rom(Some(data.into_boxed_slice()));
+    emu
+}
+
+// Best time of `ROUNDS` runs of `run` on a fresh copy of `emu`.
+fn time<F>(emu: &CHIP8, mut run: F) -> Duration
+    where F: FnMut(&mut CHIP8)
+{
+    (0..ROUNDS).map(|_| {
+        let mut emu = emu.clone();
+        let start = Instant::now();
+        run(&mut emu);
+        start.elapsed()
+    }).min().unwrap()
+}
+
+fn main() {
+    println!("{:<48} {:>12} {:>12} {:>12} {:>12}", "rom (instructions/s)", "tick", "cache", "blocks", "jit");
+    for rom in ROMS {
+        let emu = load(rom);
+
+        let tick = time(&emu, |emu| {
+            for _ in 0..CYCLES {
+                emu.tick().unwrap();
+            }
+        });
+        let cache = time(&emu, |emu| {
+            let mut cache = DecodeCache::new();
+            cache.set_max_block_len(1);
+            cache.run(emu, CYCLES).unwrap();
+        });
+        let blocks = time(&emu, |emu| {
+            DecodeCache::new().run(emu, CYCLES).unwrap();
+        });
+        let jit = time(&emu, |emu| {
+            Jit::new().run(emu, CYCLES).unwrap();
+        });
+
+        let ips = |elapsed: Duration| format!("{:.1}M", CYCLES as f64 / elapsed.as_secs_f64() / 1e6);
+        let name = rom.trim_start_matches("chip8/");
+        println!("{:<48} {:>12} {:>12} {:>12} {:>12}", name, ips(tick), ips(cache), ips(blocks), ips(jit));
+    }
+}
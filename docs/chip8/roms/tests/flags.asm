@@ -0,0 +1,256 @@
+; Flags test
+; ----------
+; Checks the results & VF flags of the arithmetic instructions, including
+; with VF as one of the operands, in the spirit of the flags rom from
+; Timendus' chip8-test-suite. Doesn't depend on any quirks: shifts use the
+; same register for vx & vy.
+;
+; Every check draws a tick when it passes and a cross when it fails, eight
+; to a row, left to right:
+;
+;   row 1  ADD result, ADD flag, ADD result & flag with carry,
+;          SUB result & flag, SUB result & flag with borrow
+;   row 2  SUB result & flag of equal values, SUBN result & flag,
+;          SUBN result & flag with borrow, SUBN result & flag of equal values
+;   row 3  SHR result & flag, SHR result & flag without carry,
+;          SHL result & flag, SHL result & flag without carry
+;   row 4  the flag wins when VF is vx for: ADD, ADD with carry, SUB,
+;          SUB with borrow, SUBN, SUBN with borrow, SHR, SHR without carry
+;   row 5  SHL, SHL without carry, ADD result & flag with VF as vy
+;
+; The checked value goes in V0 and the expected one in V1, VD & VE hold
+; where the next mark goes.
+
+        CLS
+        LD VD, 0
+        LD VE, 0
+
+        ; ADD
+        LD V2, 0x10
+        LD V3, 0x20
+        ADD V2, V3
+        LD V4, VF
+        LD V0, V2
+        LD V1, 0x30
+        CALL check
+        LD V0, V4
+        LD V1, 0
+        CALL check
+
+        LD V2, 0xF0
+        LD V3, 0x20
+        ADD V2, V3
+        LD V4, VF
+        LD V0, V2
+        LD V1, 0x10
+        CALL check
+        LD V0, V4
+        LD V1, 1
+        CALL check
+
+        ; SUB
+        LD V2, 0x30
+        LD V3, 0x10
+        SUB V2, V3
+        LD V4, VF
+        LD V0, V2
+        LD V1, 0x20
+        CALL check
+        LD V0, V4
+        LD V1, 1
+        CALL check
+
+        LD V2, 0x10
+        LD V3, 0x30
+        SUB V2, V3
+        LD V4, VF
+        LD V0, V2
+        LD V1, 0xE0
+        CALL check
+        LD V0, V4
+        LD V1, 0
+        CALL check
+
+        LD V2, 0x10
+        LD V3, 0x10
+        SUB V2, V3
+        LD V4, VF
+        LD V0, V2
+        LD V1, 0
+        CALL check
+        LD V0, V4
+        LD V1, 1
+        CALL check
+
+        ; SUBN
+        LD V2, 0x10
+        LD V3, 0x30
+        SUBN V2, V3
+        LD V4, VF
+        LD V0, V2
+        LD V1, 0x20
+        CALL check
+        LD V0, V4
+        LD V1, 1
+        CALL check
+
+        LD V2, 0x30
+        LD V3, 0x10
+        SUBN V2, V3
+        LD V4, VF
+        LD V0, V2
+        LD V1, 0xE0
+        CALL check
+        LD V0, V4
+        LD V1, 0
+        CALL check
+
+        LD V2, 0x10
+        LD V3, 0x10
+        SUBN V2, V3
+        LD V4, VF
+        LD V0, V2
+        LD V1, 0
+        CALL check
+        LD V0, V4
+        LD V1, 1
+        CALL check
+
+        ; SHR
+        LD V2, 0x05
+        SHR V2
+        LD V4, VF
+        LD V0, V2
+        LD V1, 0x02
+        CALL check
+        LD V0, V4
+        LD V1, 1
+        CALL check
+
+        LD V2, 0x04
+        SHR V2
+        LD V4, VF
+        LD V0, V2
+        LD V1, 0x02
+        CALL check
+        LD V0, V4
+        LD V1, 0
+        CALL check
+
+        ; SHL
+        LD V2, 0x81
+        SHL V2
+        LD V4, VF
+        LD V0, V2
+        LD V1, 0x02
+        CALL check
+        LD V0, V4
+        LD V1, 1
+        CALL check
+
+        LD V2, 0x41
+        SHL V2
+        LD V4, VF
+        LD V0, V2
+        LD V1, 0x82
+        CALL check
+        LD V0, V4
+        LD V1, 0
+        CALL check
+
+        ; VF as vx, only the flag is left.
+        LD VF, 0x10
+        LD V3, 0x20
+        ADD VF, V3
+        LD V0, VF
+        LD V1, 0
+        CALL check
+
+        LD VF, 0xF0
+        LD V3, 0x20
+        ADD VF, V3
+        LD V0, VF
+        LD V1, 1
+        CALL check
+
+        LD VF, 0x30
+        LD V3, 0x10
+        SUB VF, V3
+        LD V0, VF
+        LD V1, 1
+        CALL check
+
+        LD VF, 0x10
+        LD V3, 0x30
+        SUB VF, V3
+        LD V0, VF
+        LD V1, 0
+        CALL check
+
+        LD VF, 0x10
+        LD V3, 0x30
+        SUBN VF, V3
+        LD V0, VF
+        LD V1, 1
+        CALL check
+
+        LD VF, 0x30
+        LD V3, 0x10
+        SUBN VF, V3
+        LD V0, VF
+        LD V1, 0
+        CALL check
+
+        LD VF, 0x05
+        SHR VF
+        LD V0, VF
+        LD V1, 1
+        CALL check
+
+        LD VF, 0x04
+        SHR VF
+        LD V0, VF
+        LD V1, 0
+        CALL check
+
+        LD VF, 0x81
+        SHL VF
+        LD V0, VF
+        LD V1, 1
+        CALL check
+
+        LD VF, 0x41
+        SHL VF
+        LD V0, VF
+        LD V1, 0
+        CALL check
+
+        ; VF as vy.
+        LD V2, 0xF0
+        LD VF, 0x20
+        ADD V2, VF
+        LD V4, VF
+        LD V0, V2
+        LD V1, 0x10
+        CALL check
+        LD V0, V4
+        LD V1, 1
+        CALL check
+
+done:   JP done
+
+; Draws a tick at VE, VD if V0 matches V1 and a cross if it doesn't, then
+; moves on to the next spot.
+check:  LD I, pass
+        SE V0, V1
+        LD I, fail
+        DRW VE, VD, 5
+        ADD VE, 8
+        SE VE, 64
+        RET
+        LD VE, 0
+        ADD VD, 6
+        RET
+
+pass:   db 0x04, 0x04, 0x08, 0x88, 0x50
+fail:   db 0x88, 0x50, 0x20, 0x50, 0x88
@@ -0,0 +1,260 @@
+; Opcode test
+; -----------
+; Checks the CHIP-8 instructions that every interpreter agrees on, in the
+; spirit of the corax+ rom from Timendus' chip8-test-suite. Anything quirky
+; is left to quirks.asm and the flags to flags.asm.
+;
+; Every check draws a tick when it passes and a cross when it fails, eight
+; to a row, left to right:
+;
+;   row 1  3XNN skips & doesn't, 4XNN skips & doesn't, 5XY0 skips &
+;          doesn't, 9XY0 skips & doesn't
+;   row 2  7XNN wraps around, 7XNN leaves VF alone, 8XY0, 8XY1, 8XY2,
+;          8XY3, 1NNN, 2NNN & 00EE
+;   row 3  nested 2NNN & 00EE, ANNN & FX1E, FX55 & FX65 first and last
+;          register, FX33 hundreds, tens & ones, FX29
+;   row 4  CXNN with an empty mask, FX15 & FX07, FX18 leaves the
+;          registers alone, DXYN without & with a collision
+;
+; The checked value goes in V0 and the expected one in V1, VD & VE hold
+; where the next mark goes.
+
+        CLS
+        LD VD, 0
+        LD VE, 0
+
+        ; 3XNN
+        LD V2, 0x42
+        LD V0, 1
+        SE V2, 0x42
+        LD V0, 0
+        LD V1, 1
+        CALL check
+
+        LD V0, 1
+        SE V2, 0x43
+        JP se_no_skip
+        LD V0, 0
+se_no_skip:
+        LD V1, 1
+        CALL check
+
+        ; 4XNN
+        LD V0, 1
+        SNE V2, 0x43
+        LD V0, 0
+        LD V1, 1
+        CALL check
+
+        LD V0, 1
+        SNE V2, 0x42
+        JP sne_no_skip
+        LD V0, 0
+sne_no_skip:
+        LD V1, 1
+        CALL check
+
+        ; 5XY0
+        LD V3, 0x42
+        LD V0, 1
+        SE V2, V3
+        LD V0, 0
+        LD V1, 1
+        CALL check
+
+        LD V3, 0x43
+        LD V0, 1
+        SE V2, V3
+        JP se_reg_no_skip
+        LD V0, 0
+se_reg_no_skip:
+        LD V1, 1
+        CALL check
+
+        ; 9XY0
+        LD V0, 1
+        SNE V2, V3
+        LD V0, 0
+        LD V1, 1
+        CALL check
+
+        LD V3, 0x42
+        LD V0, 1
+        SNE V2, V3
+        JP sne_reg_no_skip
+        LD V0, 0
+sne_reg_no_skip:
+        LD V1, 1
+        CALL check
+
+        ; 7XNN
+        LD VF, 7
+        LD V2, 0xFE
+        ADD V2, 3
+        LD V4, VF
+        LD V0, V2
+        LD V1, 0x01
+        CALL check
+        LD V0, V4
+        LD V1, 7
+        CALL check
+
+        ; 8XY0 to 8XY3
+        LD V2, 0x0F
+        LD V3, 0x3C
+        LD V0, V3
+        LD V1, 0x3C
+        CALL check
+
+        LD V0, V2
+        OR V0, V3
+        LD V1, 0x3F
+        CALL check
+
+        LD V0, V2
+        AND V0, V3
+        LD V1, 0x0C
+        CALL check
+
+        LD V0, V2
+        XOR V0, V3
+        LD V1, 0x33
+        CALL check
+
+        ; 1NNN
+        LD V0, 1
+        JP jumped
+        LD V0, 0
+jumped: LD V1, 1
+        CALL check
+
+        ; 2NNN & 00EE
+        LD V2, 0
+        CALL set_v2
+        LD V0, V2
+        LD V1, 0x42
+        CALL check
+
+        LD V2, 0
+        CALL nested
+        LD V0, V2
+        LD V1, 0x43
+        CALL check
+
+        ; ANNN & FX1E
+        LD I, bytes
+        LD V2, 2
+        ADD I, V2
+        LD V0, [I]
+        LD V1, 0x33
+        CALL check
+
+        ; FX55 & FX65
+        LD V2, 0xA1
+        LD V3, 0xB2
+        LD V4, 0xC3
+        LD I, scratch
+        LD [I], V4
+        LD V2, 0
+        LD V4, 0
+        LD I, scratch
+        LD V4, [I]
+        LD V5, V4
+        LD V0, V2
+        LD V1, 0xA1
+        CALL check
+        LD V0, V5
+        LD V1, 0xC3
+        CALL check
+
+        ; FX33
+        LD V2, 234
+        LD I, scratch
+        LD B, V2
+        LD I, scratch
+        LD V2, [I]
+        LD V3, V1
+        LD V4, V2
+        LD V1, 2
+        CALL check
+        LD V0, V3
+        LD V1, 3
+        CALL check
+        LD V0, V4
+        LD V1, 4
+        CALL check
+
+        ; FX29
+        LD V2, 0xA
+        LD F, V2
+        LD V0, [I]
+        LD V1, 0xF0
+        CALL check
+
+        ; CXNN
+        LD V2, 0xFF
+        RND V2, 0
+        LD V0, V2
+        LD V1, 0
+        CALL check
+
+        ; FX15 & FX07, the timer may have ticked once since.
+        LD V2, 10
+        LD DT, V2
+        LD V2, DT
+        LD V0, 0
+        SE V2, 0
+        LD V0, 1
+        LD V1, 1
+        CALL check
+
+        ; FX18 doesn't touch the registers.
+        LD V2, 0
+        LD ST, V2
+        LD V0, V2
+        LD V1, 0
+        CALL check
+
+        ; DXYN sets VF on collisions only. Draws past the last row of
+        ; marks and erases it again.
+        LD I, bytes
+        LD V2, 56
+        LD V3, 27
+        DRW V2, V3, 1
+        LD V4, VF
+        DRW V2, V3, 1
+        LD V5, VF
+        LD V0, V4
+        LD V1, 0
+        CALL check
+        LD V0, V5
+        LD V1, 1
+        CALL check
+
+done:   JP done
+
+set_v2: LD V2, 0x42
+        RET
+
+nested: CALL set_v2
+        ADD V2, 1
+        RET
+
+; Draws a tick at VE, VD if V0 matches V1 and a cross if it doesn't, then
+; moves on to the next spot.
+check:  LD I, pass
+        SE V0, V1
+        LD I, fail
+        DRW VE, VD, 5
+        ADD VE, 8
+        SE VE, 64
+        RET
+        LD VE, 0
+        ADD VD, 6
+        RET
+
+pass:   db 0x04, 0x04, 0x08, 0x88, 0x50
+fail:   db 0x88, 0x50, 0x20, 0x50, 0x88
+bytes:  db 0x11, 0x22, 0x33, 0x44
+scratch:
+        db 0, 0, 0
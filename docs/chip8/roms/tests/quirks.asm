@@ -0,0 +1,98 @@
+; Quirks test
+; -----------
+; Works out how the interpreter handles the instructions CHIP-8 flavors
+; disagree on, in the spirit of the quirks rom from Timendus'
+; chip8-test-suite. Each quirk is drawn as its number followed by a filled
+; box if the interpreter has it and an empty one if it doesn't:
+;
+;   1  VF reset: OR, AND & XOR reset VF to 0
+;   2  Memory: FX55 & FX65 move I past the registers
+;   3  Shifting: 8XY6 & 8XYE shift vx in place, ignoring vy
+;   4  Jumping: BNNN jumps to XNN + vx rather than NNN + V0
+;   5  Clipping: sprites are cut off at the bottom of the screen rather
+;      than wrapping around to the top
+;
+; The answer goes in V0, 1 when the quirk is there. VC is the number of the
+; quirk, VD & VE where it goes.
+
+        ; Clipping goes first, it needs an empty screen.
+        CLS
+        LD I, on
+        LD V2, 0
+        LD V3, 30
+        DRW V2, V3, 4
+        ; Anything that wrapped around collides with this.
+        LD V3, 0
+        DRW V2, V3, 4
+        LD V5, VF
+        CLS
+
+        LD VC, 1
+        LD VD, 0
+        LD VE, 0
+
+        ; VF reset
+        LD VF, 5
+        LD V2, 1
+        OR V2, V2
+        LD V0, 0
+        SNE VF, 0
+        LD V0, 1
+        CALL show
+
+        ; Memory
+        LD I, bytes
+        LD V1, [I]
+        LD V0, [I]
+        LD V1, 0
+        SNE V0, 0x33
+        LD V1, 1
+        LD V0, V1
+        CALL show
+
+        ; Shifting
+        LD V2, 0x10
+        LD V3, 0x02
+        SHR V2, V3
+        LD V0, 0
+        SNE V2, 0x08
+        LD V0, 1
+        CALL show
+
+        ; Jumping, BNNN with X = 2 since the table is below 0x300.
+        LD V0, 0
+        LD V2, 2
+        JP V0, table
+jumped: CALL show
+
+        ; Clipping
+        LD V0, 1
+        SE V5, 0
+        LD V0, 0
+        CALL show
+
+done:   JP done
+
+table:  JP no_jump
+        LD V0, 1
+        JP jumped
+no_jump:
+        LD V0, 0
+        JP jumped
+
+; Draws the quirk number in VC and a box for the answer in V0 at VE, VD,
+; then moves on to the next quirk.
+show:   LD F, VC
+        DRW VE, VD, 5
+        ADD VE, 5
+        LD I, off
+        SE V0, 0
+        LD I, on
+        DRW VE, VD, 5
+        ADD VE, 7
+        ADD VC, 1
+        RET
+
+on:     db 0xF8, 0xF8, 0xF8, 0xF8, 0xF8
+off:    db 0xF8, 0x88, 0x88, 0x88, 0xF8
+bytes:  db 0x11, 0x22, 0x33, 0x44
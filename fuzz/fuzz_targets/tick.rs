@@ -0,0 +1,10 @@
+// Feeds arbitrary machines & roms through the interpreter, see
+// `src/chip8/fuzz.rs` for the input layout.
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate chip8_emulator;
+
+fuzz_target!(|data: &[u8]| {
+    chip8_emulator::chip8::fuzz(data);
+});
@@ -0,0 +1,395 @@
+// Terminal CHIP-8 frontend
+// ------------------------
+// Plays roms right in the terminal, handy over SSH. The display is drawn
+// with half-block characters so every character cell covers two pixel
+// rows, registers are shown in a panel next to it and the sound timer rings
+// the terminal bell.
+//
+//   chip8-tui --mode schip --clock 1000 roms/game.ch8
+//
+// The keypad uses the same layout as the web frontend:
+//
+//   1 2 3 4        1 2 3 C
+//   Q W E R   ->   4 5 6 D
+//   A S D F        7 8 9 E
+//   Z X C V        A 0 B F
+//
+// Esc quits, space pauses and tab steps a single instruction while paused.
+// With `--record <FILE>` the session is saved as a movie on exit, which the
+// headless runner can replay with `chip8 --replay <FILE>`.
+#[macro_use]
+extern crate crossterm;
+extern crate chip8_emulator;
+
+use std::env;
+use std::fs;
+use std::io::{ self, Write };
+use std::process;
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+
+use crossterm::cursor::{ Hide, MoveTo, Show };
+use crossterm::event::{
+    self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
+    PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+};
+use crossterm::style::Print;
+use crossterm::terminal::{
+    self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen,
+};
+
+use chip8_emulator::chip8::{ Key, Mode, Movie, Quirks, Register, CHIP8, DEFAULT_CLOCK_RATE };
+
+fn usage() -> String {
+    format!("\
+USAGE:
+    chip8-tui [OPTIONS] <ROM>
+
+OPTIONS:
+    --clock <HZ>         Instructions per second (default: {clock})
+    --mode <MODE>        chip8, schip or xochip (default: chip8)
+    --quirks <QUIRKS>    vip, chip48, schip, xochip or modern (default: modern)
+    --record <FILE>      Save the session as a movie when quitting
+    -h, --help           Print this message", clock = DEFAULT_CLOCK_RATE)
+}
+
+// Same layout as `KEY_MAP` in www/src/lib/engine.ts.
+const KEY_MAP: [(char, u8); 16] = [
+    ('1', 0x1), ('2', 0x2), ('3', 0x3), ('4', 0xC),
+    ('q', 0x4), ('w', 0x5), ('e', 0x6), ('r', 0xD),
+    ('a', 0x7), ('s', 0x8), ('d', 0x9), ('f', 0xE),
+    ('z', 0xA), ('x', 0x0), ('c', 0xB), ('v', 0xF),
+];
+
+const FRAME: Duration = Duration::from_micros(1_000_000 / 60);
+// Most terminals only report key presses, so a key counts as released once
+// it hasn't been pressed (or auto-repeated) for this long.
+const KEY_HOLD: Duration = Duration::from_millis(200);
+// Columns between the display and the side panel.
+const PANEL_GAP: u16 = 2;
+
+struct Options {
+    rom: String,
+    clock: u32,
+    mode: Mode,
+    quirks: Quirks,
+    record: Option<String>,
+}
+
+fn parse_args(mut args: env::Args) -> Result<Options, String> {
+    let mut options = Options {
+        rom: String::new(),
+        clock: DEFAULT_CLOCK_RATE,
+        mode: Mode::Chip8,
+        quirks: Quirks::modern(),
+        record: None,
+    };
+
+    // Skip the program name.
+    args.next();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next().ok_or_else(|| format!("{} needs a value", name))
+        };
+
+        match arg.as_str() {
+            "--clock" => {
+                let clock = value(&arg)?;
+                options.clock = clock
+                    .parse()
+                    .map_err(|_| format!("--clock expects a number, got \"{}\"", clock))?;
+            },
+            "--mode" => options.mode = value(&arg)?.parse()?,
+            "--quirks" => options.quirks = value(&arg)?.parse()?,
+            "--record" => options.record = Some(value(&arg)?),
+            "-h" | "--help" => {
+                println!("{}", usage());
+                process::exit(0);
+            },
+            _ if arg.starts_with('-') => return Err(format!("unknown option \"{}\"", arg)),
+            _ if options.rom.is_empty() => options.rom = arg,
+            _ => return Err(format!("unexpected argument \"{}\"", arg)),
+        }
+    }
+
+    if options.rom.is_empty() {
+        return Err("no rom given".to_string());
+    }
+
+    Ok(options)
+}
+
+// Puts the terminal into raw mode on the alternate screen and restores it
+// when dropped, even when we panic.
+struct Terminal {
+    // Whether the terminal reports key releases.
+    key_release: bool,
+}
+
+impl Terminal {
+    fn enter() -> io::Result<Terminal> {
+        terminal::enable_raw_mode()?;
+        let key_release = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        let mut stdout = io::stdout();
+        execute!(stdout, EnterAlternateScreen, Hide, Clear(ClearType::All))?;
+        if key_release {
+            execute!(stdout, PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES))?;
+        }
+
+        Ok(Terminal { key_release })
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let mut stdout = io::stdout();
+        if self.key_release {
+            let _ = execute!(stdout, PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(stdout, Show, LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+struct App {
+    emu: CHIP8,
+    paused: bool,
+    quit: bool,
+    // When each keypad key was last pressed, None if it isn't held.
+    pressed: [Option<Instant>; 16],
+    key_release: bool,
+    // Whether the sound timer was active last frame, the bell only rings
+    // when it starts.
+    beeping: bool,
+    // Session being recorded, if any.
+    movie: Option<Movie>,
+}
+
+impl App {
+    fn handle_key(&mut self, key: KeyEvent) {
+        if key.kind == KeyEventKind::Release {
+            if let KeyCode::Char(c) = key.code {
+                self.release(c);
+            }
+            return;
+        }
+
+        match key.code {
+            KeyCode::Esc => self.quit = true,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => self.quit = true,
+            KeyCode::Char(' ') if key.kind == KeyEventKind::Press => self.paused = !self.paused,
+            KeyCode::Tab if self.paused => {
+                let _ = self.emu.tick();
+            },
+            KeyCode::Char(c) => {
+                let c = c.to_ascii_lowercase();
+                if let Some(&(_, idx)) = KEY_MAP.iter().find(|&&(key, _)| key == c) {
+                    self.pressed[idx as usize] = Some(Instant::now());
+                    if let Some(key) = Key::from_index(idx) {
+                        self.key_press(key);
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+
+    // Key presses go through the movie when recording.
+    fn key_press(&mut self, key: Key) {
+        match self.movie {
+            Some(ref mut movie) => movie.key_press(&mut self.emu, key),
+            None => self.emu.key_press(key),
+        }
+    }
+
+    fn key_up(&mut self, key: Key) {
+        match self.movie {
+            Some(ref mut movie) => movie.key_up(&mut self.emu, key),
+            None => self.emu.key_up(key),
+        }
+    }
+
+    fn release(&mut self, c: char) {
+        let c = c.to_ascii_lowercase();
+        if let Some(&(_, idx)) = KEY_MAP.iter().find(|&&(key, _)| key == c) {
+            self.pressed[idx as usize] = None;
+            if let Some(key) = Key::from_index(idx) {
+                self.key_up(key);
+            }
+        }
+    }
+
+    // Releases keys the terminal stopped repeating.
+    fn expire_keys(&mut self) {
+        if self.key_release {
+            return;
+        }
+
+        for idx in 0..self.pressed.len() {
+            let expired = self.pressed[idx].is_some_and(|at| at.elapsed() >= KEY_HOLD);
+            if expired {
+                self.pressed[idx] = None;
+                if let Some(key) = Key::from_index(idx as u8) {
+                    self.key_up(key);
+                }
+            }
+        }
+    }
+
+    fn draw(&mut self, out: &mut impl Write) -> io::Result<()> {
+        let width = self.emu.display_width();
+        let height = self.emu.display_height();
+        let pixels = self.emu.pixels();
+        let lit = |x: usize, y: usize| pixels[y * width + x] != 0;
+
+        // Two pixel rows per line of text.
+        for row in 0..height / 2 {
+            let line: String = (0..width)
+                .map(|col| match (lit(col, row * 2), lit(col, row * 2 + 1)) {
+                    (true, true) => '█',
+                    (true, false) => '▀',
+                    (false, true) => '▄',
+                    (false, false) => ' ',
+                })
+                .collect();
+            queue!(out, MoveTo(0, row as u16), Print(line))?;
+        }
+
+        let registers = self.emu.register_bank();
+        let mut panel = vec![
+            format!("PC {:04X}  I {:04X}  SP {:02X}", self.emu.pc(), self.emu.i_reg(), self.emu.sp()),
+            format!(
+                "DT {:02X}    ST {:02X}",
+                registers[Register::DT as usize],
+                registers[Register::ST as usize],
+            ),
+            String::new(),
+        ];
+        for row in 0..4 {
+            let regs: Vec<String> = (0..4)
+                .map(|col| row * 4 + col)
+                .map(|idx| format!("V{:X} {:02X}", idx, registers[idx]))
+                .collect();
+            panel.push(regs.join("  "));
+        }
+        panel.push(String::new());
+        panel.extend(self.emu.disassemble(self.emu.pc(), 4).lines().map(|line| line.to_string()));
+        panel.push(String::new());
+        panel.push(match self.emu.fault() {
+            Some(fault) => fault,
+            None if self.emu.is_halted() => "Exited".to_string(),
+            None if self.paused => "Paused, tab to step".to_string(),
+            None => "Running".to_string(),
+        });
+        panel.push("Esc quit, space pause".to_string());
+
+        let col = width as u16 + PANEL_GAP;
+        for (row, line) in panel.iter().enumerate() {
+            queue!(out, MoveTo(col, row as u16), Clear(ClearType::UntilNewLine), Print(line))?;
+        }
+
+        let beeping = self.emu.has_beep();
+        if beeping && !self.beeping {
+            queue!(out, Print('\x07'))?;
+        }
+        self.beeping = beeping;
+
+        out.flush()
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        let mut out = io::stdout();
+        let mut last_frame = Instant::now();
+        let mut width = self.emu.display_width();
+        while !self.quit {
+            // Handle input until it's time for the next frame.
+            let deadline = last_frame + FRAME;
+            loop {
+                let now = Instant::now();
+                if now >= deadline || !event::poll(deadline - now)? {
+                    break;
+                }
+                if let Event::Key(key) = event::read()? {
+                    self.handle_key(key);
+                }
+            }
+            self.expire_keys();
+
+            let now = Instant::now();
+            let elapsed = now - last_frame;
+            last_frame = now;
+            if !self.paused && self.emu.run_for(elapsed.as_secs_f64() * 1000.0).is_err() {
+                // Leave the faulting state on screen.
+                self.paused = true;
+            }
+
+            // Switching resolutions moves the side panel.
+            if self.emu.display_width() != width {
+                width = self.emu.display_width();
+                queue!(out, Clear(ClearType::All))?;
+            }
+            self.draw(&mut out)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn main() {
+    let options = match parse_args(env::args()) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("error: {}\n\n{}", err, usage());
+            process::exit(2);
+        },
+    };
+
+    let rom = match fs::read(&options.rom) {
+        Ok(rom) => rom,
+        Err(err) => {
+            eprintln!("error: couldn't read {}: {}", options.rom, err);
+            process::exit(2);
+        },
+    };
+
+    let mut emu = CHIP8::new();
+    emu.set_mode(options.mode);
+    emu.set_quirks(options.quirks);
+    emu.set_clock_rate(options.clock);
+    // A different random sequence every time the game is played.
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    emu.set_seed(now.as_nanos() as u64);
+    let movie = match options.record {
+        Some(_) => Some(Movie::record(&mut emu, &rom)),
+        None => {
+            emu.load_rom(Some(rom.into_boxed_slice()));
+            None
+        },
+    };
+
+    let result = Terminal::enter().and_then(|terminal| {
+        let mut app = App {
+            emu,
+            paused: false,
+            quit: false,
+            pressed: [None; 16],
+            key_release: terminal.key_release,
+            beeping: false,
+            movie,
+        };
+        app.run()?;
+
+        if let Some(ref mut movie) = app.movie {
+            movie.stop(&app.emu);
+        }
+        Ok(app.movie)
+    });
+
+    let result = result.and_then(|movie| match (movie, options.record) {
+        (Some(movie), Some(path)) => fs::write(path, movie.to_bytes()),
+        _ => Ok(()),
+    });
+    if let Err(err) = result {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
+}
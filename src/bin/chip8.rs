@@ -0,0 +1,519 @@
+// Headless CHIP-8 runner
+// ----------------------
+// Runs a rom for a fixed number of instructions or frames without a
+// browser and prints the display (and optionally the registers & memory)
+// when it's done, so rom checks can be scripted on CI machines.
+//
+//   chip8 --frames 120 --mode schip --dump-registers roms/test.ch8
+//
+// A movie recorded with `chip8-tui --record` can be replayed to check a
+// full game session, it brings its own mode, quirks, clock rate & seed:
+//
+//   chip8 --replay session.c8m --dump-memory roms/game.ch8
+//
+// The display can also be saved as a PNG, or every frame of the run as an
+// animated GIF:
+//
+//   chip8 --frames 300 --gif run.gif --png last.png roms/game.ch8
+//
+// `--trace` logs every instruction with what it changed, see
+// `src/chip8/trace.rs` for the format. `--profile` prints the hottest
+// addresses, opcode classes and subroutines when done, `--coverage` which
+// bytes of the rom ran as code or were used as data.
+//
+// `--diff` runs the rom on the interpreter and another backend side by
+// side and stops at the first instruction they disagree on, `--diff-trace`
+// compares the run against a trace written by `--trace` earlier (or any
+// other interpreter producing the same format):
+//
+//   chip8 --diff jit --frames 600 roms/game.ch8
+//   chip8 --diff-trace reference.log --replay session.c8m roms/game.ch8
+//
+// With `--gdb` the rom waits for a debugger to attach instead, which then
+// decides what runs, see `src/chip8/gdb.rs`:
+//
+//   chip8 --gdb 1234 roms/game.ch8
+//   gdb -ex "target remote localhost:1234"
+//
+// Exits with 1 if the rom faults or a diff finds a divergence and 2 on bad
+// arguments.
+extern crate chip8_emulator;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::net::TcpListener;
+use std::process;
+
+use chip8_emulator::chip8::{
+    compare_trace, lockstep, Backend, Coverage, Error, GdbStub, GifRecorder, Mode, Movie, Observer, Palette,
+    Profiler, Quirks, Tracer, CHIP8, DEFAULT_CLOCK_RATE,
+};
+
+fn usage() -> String {
+    format!("\
+USAGE:
+    chip8 [OPTIONS] <ROM>
+
+OPTIONS:
+    --cycles <N>         Run for N instructions (default: 1000)
+    --frames <N>         Run for N frames, 60 frames per second
+    --clock <HZ>         Instructions per second (default: {clock})
+    --mode <MODE>        chip8, schip or xochip (default: chip8)
+    --quirks <QUIRKS>    vip, chip48, schip, xochip or modern (default: modern)
+    --seed <N>           Seed for the random number generator
+    --backend <NAME>     interpreter, cached or jit (default: interpreter)
+    --replay <MOVIE>     Replay a recorded movie until it ends
+    --trace <FILE>       Log every instruction to FILE, - for stdout
+    --diff <BACKEND>     Run on the interpreter & BACKEND in lockstep and stop
+                         where they disagree
+    --diff-trace <FILE>  Compare every instruction against a trace in FILE
+    --profile            Print a report of where time was spent when done
+    --coverage           Print which parts of the rom were code or data
+    --gdb <PORT>         Wait for gdb on localhost:PORT and let it run the rom
+    --no-display         Don't print the display when done
+    --png <FILE>         Save the display as a PNG when done
+    --gif <FILE>         Save every frame as an animated GIF
+    --scale <N>          Size of a pixel in saved images (default: 8)
+    --foreground <RGB>   Color of lit pixels, e.g. 33FF66 (default: 000000)
+    --background <RGB>   Color of unlit pixels (default: FFFFFF)
+    --dump-registers     Print the registers when done
+    --dump-memory        Print a hex dump of memory when done
+    -h, --help           Print this message", clock = DEFAULT_CLOCK_RATE)
+}
+
+const FRAME_MS: f64 = 1000.0 / 60.0;
+// Where roms are loaded.
+const ROM_START: u16 = 0x200;
+// Entries per section of the profile report.
+const PROFILE_ENTRIES: usize = 20;
+const BYTES_PER_ROW: usize = 16;
+
+enum Duration {
+    Cycles(u64),
+    Frames(u64),
+}
+
+struct Options {
+    rom: String,
+    duration: Duration,
+    clock: u32,
+    mode: Mode,
+    quirks: Quirks,
+    seed: Option<u64>,
+    backend: Backend,
+    replay: Option<String>,
+    trace: Option<String>,
+    diff: Option<Backend>,
+    diff_trace: Option<String>,
+    profile: bool,
+    coverage: bool,
+    gdb: Option<u16>,
+    display: bool,
+    png: Option<String>,
+    gif: Option<String>,
+    scale: u32,
+    palette: Palette,
+    dump_registers: bool,
+    dump_memory: bool,
+}
+
+fn parse_args(mut args: env::Args) -> Result<Options, String> {
+    let mut options = Options {
+        rom: String::new(),
+        duration: Duration::Cycles(1000),
+        clock: DEFAULT_CLOCK_RATE,
+        mode: Mode::Chip8,
+        quirks: Quirks::modern(),
+        seed: None,
+        backend: Backend::Interpreter,
+        replay: None,
+        trace: None,
+        diff: None,
+        diff_trace: None,
+        profile: false,
+        coverage: false,
+        gdb: None,
+        display: true,
+        png: None,
+        gif: None,
+        scale: 8,
+        palette: Palette::default(),
+        dump_registers: false,
+        dump_memory: false,
+    };
+
+    // Skip the program name.
+    args.next();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next().ok_or_else(|| format!("{} needs a value", name))
+        };
+        let number = |name: &str, value: String| {
+            value.parse::<u64>().map_err(|_| format!("{} expects a number, got \"{}\"", name, value))
+        };
+        let color = |name: &str, value: String| {
+            let hex = value.trim_start_matches('#');
+            match u32::from_str_radix(hex, 16) {
+                Ok(rgb) if hex.len() == 6 => Ok(rgb),
+                _ => Err(format!("{} expects a color like FF8800, got \"{}\"", name, value)),
+            }
+        };
+
+        match arg.as_str() {
+            "--cycles" => options.duration = Duration::Cycles(number(&arg, value(&arg)?)?),
+            "--frames" => options.duration = Duration::Frames(number(&arg, value(&arg)?)?),
+            "--clock" => options.clock = number(&arg, value(&arg)?)?.min(u64::from(u32::MAX)) as u32,
+            "--mode" => options.mode = value(&arg)?.parse()?,
+            "--quirks" => options.quirks = value(&arg)?.parse()?,
+            "--seed" => options.seed = Some(number(&arg, value(&arg)?)?),
+            "--backend" => options.backend = value(&arg)?.parse()?,
+            "--replay" => options.replay = Some(value(&arg)?),
+            "--trace" => options.trace = Some(value(&arg)?),
+            "--diff" => options.diff = Some(value(&arg)?.parse()?),
+            "--diff-trace" => options.diff_trace = Some(value(&arg)?),
+            "--profile" => options.profile = true,
+            "--coverage" => options.coverage = true,
+            "--gdb" => {
+                let port = value(&arg)?;
+                options.gdb = Some(port.parse().map_err(|_| format!("--gdb expects a port, got \"{}\"", port))?);
+            },
+            "--no-display" => options.display = false,
+            "--png" => options.png = Some(value(&arg)?),
+            "--gif" => options.gif = Some(value(&arg)?),
+            "--scale" => options.scale = number(&arg, value(&arg)?)?.min(u64::from(u32::MAX)) as u32,
+            "--foreground" => options.palette.set_color(1, color(&arg, value(&arg)?)?),
+            "--background" => options.palette.set_color(0, color(&arg, value(&arg)?)?),
+            "--dump-registers" => options.dump_registers = true,
+            "--dump-memory" => options.dump_memory = true,
+            "-h" | "--help" => {
+                println!("{}", usage());
+                process::exit(0);
+            },
+            _ if arg.starts_with('-') => return Err(format!("unknown option \"{}\"", arg)),
+            _ if options.rom.is_empty() => options.rom = arg,
+            _ => return Err(format!("unexpected argument \"{}\"", arg)),
+        }
+    }
+
+    if options.rom.is_empty() {
+        return Err("no rom given".to_string());
+    }
+
+    Ok(options)
+}
+
+fn dump_registers(emu: &CHIP8) {
+    let registers = emu.register_bank();
+    for (idx, value) in registers.iter().take(16).enumerate() {
+        print!("V{:X}: {:02X}", idx, value);
+        print!("{}", if idx % 8 == 7 { "\n" } else { "  " });
+    }
+    println!("DT: {:02X}  ST: {:02X}", registers[16], registers[17]);
+    println!("I: {:04X}  PC: {:04X}  SP: {:02X}", emu.i_reg(), emu.pc(), emu.sp());
+
+    let stack: Vec<String> = emu.call_stack()[1..=emu.sp() as usize]
+        .iter()
+        .map(|addr| format!("{:04X}", addr))
+        .collect();
+    println!("Stack: [{}]", stack.join(", "));
+}
+
+fn dump_memory(emu: &CHIP8) {
+    for (row, bytes) in emu.ram().chunks(BYTES_PER_ROW).enumerate() {
+        let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        println!("{:04X}: {}", row * BYTES_PER_ROW, hex.join(" "));
+    }
+}
+
+fn read_file(path: &str) -> Vec<u8> {
+    match fs::read(path) {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!("error: couldn't read {}: {}", path, err);
+            process::exit(2);
+        },
+    }
+}
+
+fn write_file(path: &str, data: &[u8]) {
+    if let Err(err) = fs::write(path, data) {
+        eprintln!("error: couldn't write {}: {}", path, err);
+        process::exit(2);
+    }
+}
+
+// Captures a GIF frame whenever the emulated time reaches a new 60Hz frame.
+struct Recording {
+    gif: GifRecorder,
+    frame: Option<u64>,
+}
+
+impl Recording {
+    fn capture(&mut self, emu: &CHIP8) {
+        let frame = emu.cycles() * 60 / u64::from(emu.clock_rate());
+        if self.frame != Some(frame) {
+            self.frame = Some(frame);
+            self.gif.capture(emu);
+        }
+    }
+}
+
+fn open_trace(path: &str) -> Tracer {
+    if path == "-" {
+        return Tracer::with_sink(Box::new(io::BufWriter::new(io::stdout())));
+    }
+
+    match fs::File::create(path) {
+        Ok(file) => Tracer::with_sink(Box::new(io::BufWriter::new(file))),
+        Err(err) => {
+            eprintln!("error: couldn't create {}: {}", path, err);
+            process::exit(2);
+        },
+    }
+}
+
+// Tools every instruction is run through.
+struct Tools {
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+}
+
+impl Tools {
+    fn is_empty(&self) -> bool {
+        self.tracer.is_none() && self.profiler.is_none() && self.coverage.is_none()
+    }
+}
+
+// Runs a single instruction through the tools, letting the movie apply its
+// inputs first when replaying.
+fn step(emu: &mut CHIP8, tools: &mut Tools, mut movie: Option<&mut Movie>) -> Result<(), Error> {
+    let Tools { ref mut tracer, ref mut profiler, ref mut coverage } = *tools;
+    let mut run = |emu: &mut CHIP8| match movie {
+        Some(ref mut movie) => movie.tick(emu),
+        None => emu.tick(),
+    };
+    let mut covered = |emu: &mut CHIP8| match *coverage {
+        Some(ref mut coverage) => coverage.record(emu, &mut run),
+        None => run(emu),
+    };
+    let mut profiled = |emu: &mut CHIP8| match *profiler {
+        Some(ref mut profiler) => profiler.record(emu, &mut covered),
+        None => covered(emu),
+    };
+
+    match *tracer {
+        Some(ref mut tracer) => tracer.record(emu, &mut profiled),
+        None => profiled(emu),
+    }
+}
+
+// Hands the machine to the first debugger that connects until it detaches.
+fn debug(emu: &mut CHIP8, port: u16) -> Result<(), Error> {
+    let served = TcpListener::bind(("127.0.0.1", port)).and_then(|listener| {
+        eprintln!("Waiting for gdb on localhost:{}", port);
+        let (stream, _) = listener.accept()?;
+        GdbStub::new(stream).serve(emu)
+    });
+    if let Err(err) = served {
+        eprintln!("error: gdb connection failed: {}", err);
+        process::exit(2);
+    }
+
+    emu.faulted().map_or(Ok(()), Err)
+}
+
+fn load_movie(path: &str, emu: &mut CHIP8, rom: &[u8]) -> Movie {
+    let result = Movie::from_bytes(&read_file(path)).and_then(|mut movie| {
+        movie.play(emu, rom)?;
+        Ok(movie)
+    });
+    match result {
+        Ok(movie) => movie,
+        Err(err) => {
+            eprintln!("error: {}: {}", path, err);
+            process::exit(2);
+        },
+    }
+}
+
+// Runs the differential checks that were asked for, replaying the movie's
+// input on both sides if there is one, and exits.
+fn diff(options: &Options, emu: &mut CHIP8, movie: Option<Movie>) {
+    let cycles = match (movie.as_ref(), &options.duration) {
+        (Some(movie), _) => movie.end_cycle().saturating_sub(emu.cycles()),
+        (None, &Duration::Cycles(cycles)) => cycles,
+        (None, &Duration::Frames(frames)) => frames * u64::from(emu.clock_rate()) / 60,
+    };
+    let playing = |mut movie: Option<Movie>| move |emu: &mut CHIP8| {
+        if let Some(ref mut movie) = movie {
+            movie.apply_inputs(emu);
+        }
+        emu.tick()
+    };
+
+    let mut diverged = false;
+    if let Some(backend) = options.diff {
+        let mut interpreter = emu.clone();
+        interpreter.set_backend(Backend::Interpreter);
+        let mut other = emu.clone();
+        other.set_backend(backend);
+        match lockstep(&interpreter, &other, cycles, playing(movie.clone()), playing(movie.clone())) {
+            Ok(()) => println!("No divergence from {:?} in {} instructions", backend, cycles),
+            Err(divergence) => {
+                print!("{}", divergence.report("Interpreter", &format!("{:?}", backend)));
+                diverged = true;
+            },
+        }
+    }
+    if let Some(ref path) = options.diff_trace {
+        // Only as much of the trace as we're running for.
+        let reference: String = String::from_utf8_lossy(&read_file(path))
+            .lines()
+            .take(cycles as usize)
+            .map(|line| format!("{}\n", line))
+            .collect();
+        match compare_trace(emu, &reference, playing(movie)) {
+            Ok(()) => println!("No divergence from {} in {} instructions", path, reference.lines().count()),
+            Err(divergence) => {
+                print!("{}", divergence);
+                diverged = true;
+            },
+        }
+    }
+
+    process::exit(if diverged { 1 } else { 0 });
+}
+
+// Prints & saves whatever was asked for and exits with the right status.
+// `rom_len` is the number of bytes of the rom that were loaded.
+fn finish(
+    options: &Options,
+    emu: &CHIP8,
+    rom_len: usize,
+    recording: Option<Recording>,
+    tools: Tools,
+    result: Result<(), Error>,
+) {
+    if let Some(mut tracer) = tools.tracer {
+        if let Err(err) = tracer.flush() {
+            eprintln!("error: couldn't write the trace: {}", err);
+            process::exit(2);
+        }
+    }
+    if options.display {
+        print!("{}", emu);
+    }
+    if let Some(ref path) = options.png {
+        write_file(path, &emu.to_png(options.scale, &options.palette));
+    }
+    if let (Some(path), Some(mut recording)) = (options.gif.as_ref(), recording) {
+        recording.gif.capture(emu);
+        write_file(path, &recording.gif.to_bytes());
+    }
+    if options.dump_registers {
+        dump_registers(emu);
+    }
+    if options.dump_memory {
+        dump_memory(emu);
+    }
+    if let Some(ref profiler) = tools.profiler {
+        print!("{}", profiler.report(emu, PROFILE_ENTRIES));
+    }
+    if let Some(ref coverage) = tools.coverage {
+        print!("{}\n{}", coverage.report(ROM_START, rom_len), coverage.listing(emu, ROM_START, rom_len));
+    }
+
+    if let Err(fault) = result {
+        eprintln!("error: {}", fault);
+        process::exit(1);
+    }
+}
+
+fn main() {
+    let options = match parse_args(env::args()) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("error: {}\n\n{}", err, usage());
+            process::exit(2);
+        },
+    };
+
+    let rom = read_file(&options.rom);
+
+    let mut emu = CHIP8::new();
+    emu.set_backend(options.backend);
+    emu.set_mode(options.mode);
+    emu.set_quirks(options.quirks);
+    emu.set_clock_rate(options.clock);
+    if let Some(seed) = options.seed {
+        emu.set_seed(seed);
+    }
+    let rom_len = emu.load_rom(Some(rom.clone().into_boxed_slice()));
+
+    let mut recording = options.gif.as_ref().map(|_| Recording {
+        gif: GifRecorder::new(options.scale, &options.palette),
+        frame: None,
+    });
+    let mut tools = Tools {
+        tracer: options.trace.as_ref().map(|path| open_trace(path)),
+        profiler: if options.profile { Some(Profiler::new()) } else { None },
+        coverage: if options.coverage { Some(Coverage::new()) } else { None },
+    };
+
+    if let Some(port) = options.gdb {
+        let result = debug(&mut emu, port);
+        finish(&options, &emu, rom_len, recording, tools, result);
+        return;
+    }
+
+    let movie = options.replay.as_ref().map(|path| load_movie(path, &mut emu, &rom));
+    if options.diff.is_some() || options.diff_trace.is_some() {
+        diff(&options, &mut emu, movie);
+        return;
+    }
+
+    if let Some(mut movie) = movie {
+        let mut result = Ok(());
+        while result.is_ok() && !movie.is_finished(&emu) {
+            if let Some(ref mut recording) = recording {
+                recording.capture(&emu);
+            }
+            result = step(&mut emu, &mut tools, Some(&mut movie));
+        }
+        finish(&options, &emu, rom_len, recording, tools, result);
+        return;
+    }
+
+    // Without tools watching every instruction the backend can run them
+    // in one go.
+    if let Duration::Cycles(cycles) = options.duration {
+        if tools.is_empty() && recording.is_none() {
+            let result = emu.run(cycles);
+            finish(&options, &emu, rom_len, recording, tools, result);
+            return;
+        }
+    }
+
+    let steps = match options.duration {
+        Duration::Cycles(cycles) | Duration::Frames(cycles) => cycles,
+    };
+
+    let mut result = Ok(());
+    for _ in 0..steps {
+        if let Some(ref mut recording) = recording {
+            recording.capture(&emu);
+        }
+        result = match options.duration {
+            Duration::Cycles(_) => step(&mut emu, &mut tools, None),
+            Duration::Frames(_) if tools.is_empty() => emu.run_for(FRAME_MS),
+            Duration::Frames(_) => emu.run_for_with(FRAME_MS, |emu| step(emu, &mut tools, None)),
+        };
+        if result.is_err() || emu.is_halted() {
+            break;
+        }
+    }
+
+    finish(&options, &emu, rom_len, recording, tools, result);
+}
@@ -0,0 +1,558 @@
+// Assembler
+// ---------
+// Turns assembly source into rom bytes that can be handed straight to
+// `CHIP8::load_rom`. The syntax is the one the disassembler produces, so a
+// listing can be edited and assembled again:
+//
+//   ; Comments start with a semicolon.
+//   SPEED EQU 2            ; Constants.
+//   include "sprites.asm"  ; Pulls in a file registered with `add_file`.
+//   start:                 ; Labels, on their own line or before code.
+//       LD V0, SPEED
+//       LD I, sprite + 5   ; Expressions can add & subtract.
+//       DRW V0, V1, 5
+//       JP start
+//   sprite:
+//       db 0xF0, 0x90, 0b11110000
+//       dw 0x1234
+//
+// Mnemonics, registers & directives are case insensitive, labels and
+// constants aren't.
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use wasm_bindgen::prelude::*;
+
+// Where roms get loaded, so where labels start counting from.
+const ORIGIN: u16 = 0x200;
+// Includes nested deeper than this are assumed to be a cycle.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+const MNEMONICS: [&str; 35] = [
+    "CLS", "RET", "SYS", "SCD", "SCU", "SCR", "SCL", "EXIT", "LOW", "HIGH", "JP", "CALL", "SE",
+    "SNE", "SAVE", "LOAD", "LD", "ADD", "OR", "AND", "XOR", "SUB", "SHR", "SUBN", "SHL", "RND",
+    "DRW", "SKP", "SKNP", "PLANE", "AUDIO", "PITCH", "DB", "DW", "INCLUDE",
+];
+// Operand names that can't be used as labels or constants.
+const RESERVED: [&str; 10] = ["I", "DT", "ST", "K", "F", "HF", "B", "R", "LONG", "EQU"];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AsmError {
+    // Include the error happened in, None for the main source.
+    file: Option<String>,
+    // 1-based line number.
+    line: usize,
+    message: String,
+}
+
+impl AsmError {
+    pub fn file(&self) -> Option<&str> { self.file.as_deref() }
+    pub fn line(&self) -> usize { self.line }
+    pub fn message(&self) -> &str { &self.message }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.file {
+            Some(ref file) => write!(f, "{}:{}: {}", file, self.line, self.message),
+            None => write!(f, "line {}: {}", self.line, self.message),
+        }
+    }
+}
+
+impl error::Error for AsmError {}
+
+impl From<AsmError> for JsValue {
+    fn from(err: AsmError) -> JsValue {
+        JsValue::from_str(&err.to_string())
+    }
+}
+
+#[derive(Debug)]
+enum Operand {
+    V(u8),
+    I,
+    // [I]
+    IndirectI,
+    Dt,
+    St,
+    K,
+    F,
+    Hf,
+    B,
+    R,
+    // LONG <expr>, the XO-CHIP 16-bit address for `LD I`.
+    Long(String),
+    Expr(String),
+}
+
+fn parse_operand(text: &str) -> Operand {
+    let upper = text.to_uppercase();
+    match upper.as_str() {
+        "I" => return Operand::I,
+        "[I]" => return Operand::IndirectI,
+        "DT" => return Operand::Dt,
+        "ST" => return Operand::St,
+        "K" => return Operand::K,
+        "F" => return Operand::F,
+        "HF" => return Operand::Hf,
+        "B" => return Operand::B,
+        "R" => return Operand::R,
+        _ => {},
+    }
+
+    if text.get(..5).is_some_and(|prefix| prefix.eq_ignore_ascii_case("long ")) {
+        return Operand::Long(text[5..].trim().to_string());
+    }
+    if upper.len() == 2 && upper.starts_with('V') {
+        if let Ok(reg) = u8::from_str_radix(&upper[1..], 16) {
+            return Operand::V(reg);
+        }
+    }
+
+    Operand::Expr(text.to_string())
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let lower = text.to_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = lower.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    let valid = match chars.next() {
+        Some(c) => c.is_ascii_alphabetic() || c == '_',
+        None => false,
+    };
+    let upper = name.to_uppercase();
+    let register = upper.len() == 2 && upper.starts_with('V')
+        && upper[1..].chars().all(|c| c.is_ascii_hexdigit());
+
+    valid
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !register
+        && !RESERVED.contains(&upper.as_str())
+        && !MNEMONICS.contains(&upper.as_str())
+}
+
+// A line that produces bytes, waiting for all labels to be known.
+struct Statement {
+    file: Option<String>,
+    line: usize,
+    mnemonic: String,
+    operands: Vec<String>,
+}
+
+struct Context<'a> {
+    files: &'a HashMap<String, String>,
+    symbols: HashMap<String, i64>,
+    statements: Vec<Statement>,
+    addr: i64,
+}
+
+impl<'a> Context<'a> {
+    fn eval(&self, expr: &str) -> Result<i64, String> {
+        let expr = expr.trim();
+        if expr.is_empty() {
+            return Err("missing value".to_string());
+        }
+
+        // Split into terms, each keeping the sign in front of it.
+        let mut total = 0i64;
+        let mut start = 0;
+        let mut sign = 1;
+        let bytes = expr.as_bytes();
+        for idx in 0..=bytes.len() {
+            let at_end = idx == bytes.len();
+            if !at_end && bytes[idx] != b'+' && bytes[idx] != b'-' {
+                continue;
+            }
+
+            let term = expr[start..idx].trim();
+            if term.is_empty() {
+                // Only a leading sign may go without a term in front.
+                if idx != 0 || at_end {
+                    return Err(format!("invalid expression \"{}\"", expr));
+                }
+            } else {
+                let value = match parse_number(term) {
+                    Some(value) => value,
+                    None => match self.symbols.get(term) {
+                        Some(&value) => value,
+                        None if is_identifier(term) => return Err(format!("unknown symbol \"{}\"", term)),
+                        None => return Err(format!("invalid value \"{}\"", term)),
+                    },
+                };
+                total = match value.checked_mul(sign).and_then(|value| total.checked_add(value)) {
+                    Some(total) => total,
+                    None => return Err(format!("value out of range in \"{}\"", expr)),
+                };
+            }
+
+            if !at_end {
+                sign = if bytes[idx] == b'-' { -1 } else { 1 };
+                start = idx + 1;
+            }
+        }
+
+        Ok(total)
+    }
+
+    fn define(&mut self, name: &str, value: i64) -> Result<(), String> {
+        if !is_identifier(name) {
+            return Err(format!("invalid name \"{}\"", name));
+        }
+        if self.symbols.contains_key(name) {
+            return Err(format!("\"{}\" is already defined", name));
+        }
+
+        self.symbols.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    // First pass, records statements & the address of every label.
+    fn parse(&mut self, source: &str, file: Option<&str>, depth: usize) -> Result<(), AsmError> {
+        for (idx, line) in source.lines().enumerate() {
+            let err = |message: String| AsmError {
+                file: file.map(|file| file.to_string()),
+                line: idx + 1,
+                message,
+            };
+            self.parse_line(line, file, idx + 1, depth).map_err(|message| match message {
+                // Errors from inside an include already know where they are.
+                Ok(inner) => inner,
+                Err(message) => err(message),
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn parse_line(&mut self, line: &str, file: Option<&str>, number: usize, depth: usize)
+        -> Result<(), Result<AsmError, String>>
+    {
+        let mut line = match line.find(';') {
+            Some(idx) => &line[..idx],
+            None => line,
+        }.trim();
+
+        // Any number of labels can come before the code on a line.
+        loop {
+            let head = line.split_whitespace().next().unwrap_or("");
+            if !head.ends_with(':') {
+                break;
+            }
+            let addr = self.addr;
+            self.define(&head[..head.len() - 1], addr).map_err(Err)?;
+            line = line[head.len()..].trim();
+        }
+        if line.is_empty() {
+            return Ok(());
+        }
+
+        let (head, rest) = match line.find(char::is_whitespace) {
+            Some(idx) => (&line[..idx], line[idx..].trim()),
+            None => (line, ""),
+        };
+
+        // NAME EQU value
+        let upper_rest = rest.to_uppercase();
+        if upper_rest == "EQU" || upper_rest.starts_with("EQU ") {
+            let value = self.eval(&rest[3..]).map_err(Err)?;
+            return self.define(head, value).map_err(Err);
+        }
+
+        let mnemonic = head.to_uppercase();
+        if mnemonic == "INCLUDE" {
+            let name = rest.trim_matches('"');
+            if rest.len() < 2 || !rest.starts_with('"') || !rest.ends_with('"') {
+                return Err(Err("expected a quoted file name".to_string()));
+            }
+            if depth >= MAX_INCLUDE_DEPTH {
+                return Err(Err(format!("includes nested too deep including \"{}\"", name)));
+            }
+            let files = self.files;
+            let source = files.get(name).ok_or_else(|| Err(format!("unknown include \"{}\"", name)))?;
+            return self.parse(source, Some(name), depth + 1).map_err(Ok);
+        }
+
+        if !MNEMONICS.contains(&mnemonic.as_str()) {
+            return Err(Err(format!("unknown instruction \"{}\"", head)));
+        }
+
+        let operands: Vec<String> = if rest.is_empty() {
+            Vec::new()
+        } else {
+            rest.split(',').map(|operand| operand.trim().to_string()).collect()
+        };
+        self.addr += match mnemonic.as_str() {
+            "DB" => operands.len() as i64,
+            "DW" => operands.len() as i64 * 2,
+            "LD" if operands.len() == 2 && operands[1].to_uppercase().starts_with("LONG ") => 4,
+            _ => 2,
+        };
+        self.statements.push(Statement {
+            file: file.map(|file| file.to_string()),
+            line: number,
+            mnemonic,
+            operands,
+        });
+
+        Ok(())
+    }
+
+    fn value(&self, expr: &str, max: i64) -> Result<u16, String> {
+        let value = self.eval(expr)?;
+        if !(0..=max).contains(&value) {
+            return Err(format!("value {} out of range (0 to {:#X})", value, max));
+        }
+
+        Ok(value as u16)
+    }
+
+    // Second pass, turns a statement into bytes.
+    fn encode(&self, statement: &Statement, out: &mut Vec<u8>) -> Result<(), String> {
+        if statement.mnemonic == "DB" || statement.mnemonic == "DW" {
+            if statement.operands.is_empty() {
+                return Err(format!("{} needs at least one value", statement.mnemonic));
+            }
+            for operand in statement.operands.iter() {
+                if statement.mnemonic == "DB" {
+                    out.push(self.value(operand, 0xFF)? as u8);
+                } else {
+                    out.extend_from_slice(&self.value(operand, 0xFFFF)?.to_be_bytes());
+                }
+            }
+            return Ok(());
+        }
+
+        let ops: Vec<Operand> = statement.operands.iter().map(|operand| parse_operand(operand)).collect();
+        let addr = |expr: &str| self.value(expr, 0xFFF);
+        let byte = |expr: &str| self.value(expr, 0xFF);
+        let nibble = |expr: &str| self.value(expr, 0xF);
+        let xy = |x: u8, y: u8| u16::from(x) << 8 | u16::from(y) << 4;
+        let vx = |x: u8| u16::from(x) << 8;
+
+        let opcode = match (statement.mnemonic.as_str(), &ops[..]) {
+            ("CLS", []) => 0x00E0,
+            ("RET", []) => 0x00EE,
+            ("SYS", [Operand::Expr(a)]) => addr(a)?,
+            ("SCD", [Operand::Expr(n)]) => 0x00C0 | nibble(n)?,
+            ("SCU", [Operand::Expr(n)]) => 0x00D0 | nibble(n)?,
+            ("SCR", []) => 0x00FB,
+            ("SCL", []) => 0x00FC,
+            ("EXIT", []) => 0x00FD,
+            ("LOW", []) => 0x00FE,
+            ("HIGH", []) => 0x00FF,
+            ("JP", [Operand::Expr(a)]) => 0x1000 | addr(a)?,
+            ("JP", [Operand::V(0), Operand::Expr(a)]) => 0xB000 | addr(a)?,
+            ("CALL", [Operand::Expr(a)]) => 0x2000 | addr(a)?,
+            ("SE", [Operand::V(x), Operand::Expr(b)]) => 0x3000 | vx(*x) | byte(b)?,
+            ("SNE", [Operand::V(x), Operand::Expr(b)]) => 0x4000 | vx(*x) | byte(b)?,
+            ("SE", [Operand::V(x), Operand::V(y)]) => 0x5000 | xy(*x, *y),
+            ("SAVE", [Operand::V(x), Operand::V(y)]) => 0x5002 | xy(*x, *y),
+            ("LOAD", [Operand::V(x), Operand::V(y)]) => 0x5003 | xy(*x, *y),
+            ("LD", [Operand::V(x), Operand::Expr(b)]) => 0x6000 | vx(*x) | byte(b)?,
+            ("ADD", [Operand::V(x), Operand::Expr(b)]) => 0x7000 | vx(*x) | byte(b)?,
+            ("LD", [Operand::V(x), Operand::V(y)]) => 0x8000 | xy(*x, *y),
+            ("OR", [Operand::V(x), Operand::V(y)]) => 0x8001 | xy(*x, *y),
+            ("AND", [Operand::V(x), Operand::V(y)]) => 0x8002 | xy(*x, *y),
+            ("XOR", [Operand::V(x), Operand::V(y)]) => 0x8003 | xy(*x, *y),
+            ("ADD", [Operand::V(x), Operand::V(y)]) => 0x8004 | xy(*x, *y),
+            ("SUB", [Operand::V(x), Operand::V(y)]) => 0x8005 | xy(*x, *y),
+            ("SHR", [Operand::V(x)]) => 0x8006 | xy(*x, *x),
+            ("SHR", [Operand::V(x), Operand::V(y)]) => 0x8006 | xy(*x, *y),
+            ("SUBN", [Operand::V(x), Operand::V(y)]) => 0x8007 | xy(*x, *y),
+            ("SHL", [Operand::V(x)]) => 0x800E | xy(*x, *x),
+            ("SHL", [Operand::V(x), Operand::V(y)]) => 0x800E | xy(*x, *y),
+            ("SNE", [Operand::V(x), Operand::V(y)]) => 0x9000 | xy(*x, *y),
+            ("LD", [Operand::I, Operand::Expr(a)]) => 0xA000 | addr(a)?,
+            ("LD", [Operand::I, Operand::Long(a)]) => {
+                out.extend_from_slice(&[0xF0, 0x00]);
+                out.extend_from_slice(&self.value(a, 0xFFFF)?.to_be_bytes());
+                return Ok(());
+            },
+            ("RND", [Operand::V(x), Operand::Expr(b)]) => 0xC000 | vx(*x) | byte(b)?,
+            ("DRW", [Operand::V(x), Operand::V(y), Operand::Expr(n)]) => 0xD000 | xy(*x, *y) | nibble(n)?,
+            ("SKP", [Operand::V(x)]) => 0xE09E | vx(*x),
+            ("SKNP", [Operand::V(x)]) => 0xE0A1 | vx(*x),
+            ("PLANE", [Operand::Expr(n)]) => 0xF001 | nibble(n)? << 8,
+            ("AUDIO", []) => 0xF002,
+            ("LD", [Operand::V(x), Operand::Dt]) => 0xF007 | vx(*x),
+            ("LD", [Operand::V(x), Operand::K]) => 0xF00A | vx(*x),
+            ("LD", [Operand::Dt, Operand::V(x)]) => 0xF015 | vx(*x),
+            ("LD", [Operand::St, Operand::V(x)]) => 0xF018 | vx(*x),
+            ("ADD", [Operand::I, Operand::V(x)]) => 0xF01E | vx(*x),
+            ("LD", [Operand::F, Operand::V(x)]) => 0xF029 | vx(*x),
+            ("LD", [Operand::Hf, Operand::V(x)]) => 0xF030 | vx(*x),
+            ("LD", [Operand::B, Operand::V(x)]) => 0xF033 | vx(*x),
+            ("PITCH", [Operand::V(x)]) => 0xF03A | vx(*x),
+            ("LD", [Operand::IndirectI, Operand::V(x)]) => 0xF055 | vx(*x),
+            ("LD", [Operand::V(x), Operand::IndirectI]) => 0xF065 | vx(*x),
+            ("LD", [Operand::R, Operand::V(x)]) => 0xF075 | vx(*x),
+            ("LD", [Operand::V(x), Operand::R]) => 0xF085 | vx(*x),
+            (mnemonic, _) => {
+                return Err(format!("invalid operands for {}: \"{}\"", mnemonic, statement.operands.join(", ")));
+            },
+        };
+
+        out.extend_from_slice(&u16::to_be_bytes(opcode));
+        Ok(())
+    }
+}
+
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct Assembler {
+    // Sources that can be pulled in with `include "name"`.
+    files: HashMap<String, String>,
+}
+
+#[wasm_bindgen]
+impl Assembler {
+    pub fn new() -> Assembler {
+        Assembler::default()
+    }
+
+    // Makes `source` available to `include "name"`.
+    pub fn add_file(&mut self, name: &str, source: &str) {
+        self.files.insert(name.to_string(), source.to_string());
+    }
+
+    pub fn assemble(&self, source: &str) -> Result<Vec<u8>, AsmError> {
+        let mut ctx = Context {
+            files: &self.files,
+            symbols: HashMap::new(),
+            statements: Vec::new(),
+            addr: i64::from(ORIGIN),
+        };
+        ctx.parse(source, None, 0)?;
+
+        let mut out = Vec::new();
+        for statement in ctx.statements.iter() {
+            ctx.encode(statement, &mut out).map_err(|message| AsmError {
+                file: statement.file.clone(),
+                line: statement.line,
+                message,
+            })?;
+        }
+
+        Ok(out)
+    }
+}
+
+// Assembles source that doesn't use any includes.
+#[wasm_bindgen]
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    Assembler::new().assemble(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{ listing, CHIP8 };
+
+    fn error(source: &str) -> (usize, String) {
+        let err = assemble(source).unwrap_err();
+        (err.line(), err.message().to_string())
+    }
+
+    #[test]
+    fn test_assemble() {
+        let source = "
+            ; Draw a sprite forever.
+            X EQU 4
+            Y EQU X + 0x10
+            start:  LD V0, X
+                    LD v1, Y
+                    ld i, sprite - 1 + 1
+            loop:
+                    DRW V0, V1, 2
+                    CALL wait
+                    JP loop
+            wait:   LD I, LONG 0xBEEF
+                    SHR V3
+                    RET
+            sprite: db 0b11110000, 0x90
+                    DW 0x1234, start
+        ";
+        assert_eq!(assemble(source).unwrap(), vec![
+            0x60, 0x04,
+            0x61, 0x14,
+            0xA2, 0x14,
+            0xD0, 0x12,
+            0x22, 0x0C,
+            0x12, 0x06,
+            0xF0, 0x00, 0xBE, 0xEF,
+            0x83, 0x36,
+            0x00, 0xEE,
+            0xF0, 0x90,
+            0x12, 0x34, 0x02, 0x00,
+        ]);
+
+        let mut emu = CHIP8::new();
+        emu.load_rom(Some(assemble(source).unwrap().into_boxed_slice()));
+        emu.tick().unwrap();
+        assert_eq!(emu.registers[0], 4);
+    }
+
+    #[test]
+    fn test_includes() {
+        let mut asm = Assembler::new();
+        asm.add_file("sprites.asm", "sprite: db 0xFF\nbad: db 0x100");
+        asm.add_file("loop.asm", "include \"loop.asm\"");
+        asm.add_file("ok.asm", "ONE EQU 1\nlabel: db ONE");
+
+        assert_eq!(asm.assemble("LD I, label\ninclude \"ok.asm\"").unwrap(), vec![0xA2, 0x02, 0x01]);
+
+        let err = asm.assemble("CLS\ninclude \"sprites.asm\"").unwrap_err();
+        assert_eq!(err.file(), Some("sprites.asm"));
+        assert_eq!(err.line(), 2);
+        assert_eq!(err.to_string(), "sprites.asm:2: value 256 out of range (0 to 0xFF)");
+
+        let err = asm.assemble("include \"loop.asm\"").unwrap_err();
+        assert!(err.message().contains("nested too deep"));
+
+        let err = asm.assemble("\ninclude \"missing.asm\"").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: unknown include \"missing.asm\"");
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(error("CLS\nFOO V0"), (2, "unknown instruction \"FOO\"".to_string()));
+        assert_eq!(error("JP nowhere"), (1, "unknown symbol \"nowhere\"".to_string()));
+        assert_eq!(error("a:\na:"), (2, "\"a\" is already defined".to_string()));
+        assert_eq!(error("V1: CLS"), (1, "invalid name \"V1\"".to_string()));
+        assert_eq!(error("\n\nLD V0, 0x100"), (3, "value 256 out of range (0 to 0xFF)".to_string()));
+        assert_eq!(error("DRW V0, 5"), (1, "invalid operands for DRW: \"V0, 5\"".to_string()));
+        assert_eq!(error("JP 0x1000"), (1, "value 4096 out of range (0 to 0xFFF)".to_string()));
+        assert_eq!(error("LD V0, 1 +"), (1, "invalid expression \"1 +\"".to_string()));
+        assert_eq!(error("X EQU later\nlater:"), (1, "unknown symbol \"later\"".to_string()));
+        assert_eq!(error("LD V0, 0x7FFFFFFFFFFFFFFF + 1"), (1, "value out of range in \"0x7FFFFFFFFFFFFFFF + 1\"".to_string()));
+        assert_eq!(error("x EQU 0x7FFFFFFFFFFFFFFF\nLD V0, x + x"), (2, "value out of range in \"x + x\"".to_string()));
+        // Uppercasing changes the length of some characters.
+        assert_eq!(error("LD I, long ııı"), (1, "invalid value \"ııı\"".to_string()));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // Every possible opcode goes through the disassembler and back.
+        for opcode in 0..=0xFFFFu16 {
+            let rom = opcode.to_be_bytes();
+            let source = listing(&rom, 0x200);
+            assert_eq!(assemble(&source).unwrap(), rom.to_vec(), "{}", source);
+        }
+
+        let rom = [
+            0x22, 0x08, 0xF0, 0x00, 0x12, 0x34, 0x12, 0x02, 0x6A, 0x0F, 0xB2, 0x00,
+            0xF3, 0x01, 0x00, 0x00, 0x00, 0xEE, 0x42,
+        ];
+        assert_eq!(assemble(&listing(&rom, 0x200)).unwrap(), rom.to_vec());
+    }
+}
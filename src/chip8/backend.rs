@@ -0,0 +1,158 @@
+// Backends
+// --------
+// The emulator can run instructions three ways, all with exactly the same
+// results: the plain interpreter (`CHIP8::tick`), the decode cache and the
+// JIT. Every machine has its own backend, `CHIP8::tick` & `run_for` go
+// through it, so the frontends and tools built on those run on whichever
+// one was picked without caring which it is.
+use std::str::FromStr;
+use wasm_bindgen::prelude::*;
+
+use super::{ DecodeCache, Error, Jit, CHIP8 };
+
+#[wasm_bindgen]
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    // Fetches & decodes every instruction as it runs.
+    Interpreter,
+    // Runs basic blocks of instructions decoded ahead of time.
+    Cached,
+    // Compiles hot basic blocks into closures.
+    Jit,
+}
+
+impl Backend {
+    pub const ALL: [Backend; 3] = [Backend::Interpreter, Backend::Cached, Backend::Jit];
+}
+
+// Names used on the command line.
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Backend, String> {
+        match name {
+            "interpreter" => Ok(Backend::Interpreter),
+            "cached" => Ok(Backend::Cached),
+            "jit" => Ok(Backend::Jit),
+            _ => Err(format!("unknown backend \"{}\"", name)),
+        }
+    }
+}
+
+// Runs a machine's instructions on its backend and keeps whatever code the
+// backend built for them. Copies of a machine keep the backend but start
+// without code, it belongs to the machine it was built from.
+pub(super) struct Engine {
+    backend: Backend,
+    code: Option<Code>,
+}
+
+enum Code {
+    Cached(DecodeCache),
+    Jit(Jit),
+}
+
+impl Engine {
+    pub(super) fn new(backend: Backend) -> Engine {
+        Engine { backend, code: None }
+    }
+}
+
+impl Clone for Engine {
+    fn clone(&self) -> Engine {
+        Engine::new(self.backend)
+    }
+}
+
+#[wasm_bindgen]
+impl CHIP8 {
+    // Which backend `tick` & `run_for` run instructions on.
+    pub fn backend(&self) -> Backend {
+        self.engine.backend
+    }
+
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.engine = Engine::new(backend);
+    }
+}
+
+impl CHIP8 {
+    // Runs `cycles` instructions on the machine's backend, stopping early if
+    // one faults.
+    pub fn run(&mut self, cycles: u64) -> Result<(), Error> {
+        // The code is built lazily, copies are often thrown away unused.
+        // It's taken out of the machine while it runs so both can be
+        // borrowed.
+        let mut code = match self.engine.backend {
+            Backend::Interpreter => {
+                for _ in 0..cycles {
+                    self.step()?;
+                }
+                return Ok(());
+            },
+            Backend::Cached => self.engine.code.take().unwrap_or_else(|| Code::Cached(DecodeCache::new())),
+            Backend::Jit => self.engine.code.take().unwrap_or_else(|| Code::Jit(Jit::new())),
+        };
+
+        let result = match code {
+            Code::Cached(ref mut cache) => cache.run(self, cycles),
+            Code::Jit(ref mut jit) => jit.run(self, cycles),
+        };
+        self.engine.code = Some(code);
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{ assemble };
+
+    #[test]
+    fn test_backends() {
+        let rom = assemble("
+                LD V0, 0
+            loop:
+                ADD V0, 1
+                LD F, V0
+                DRW V0, V0, 5
+                SE V0, 20
+                JP loop
+                EXIT
+        ").unwrap();
+        let mut emu = CHIP8::new();
+        emu.set_mode("schip".parse().unwrap());
+        emu.load_rom(Some(rom.into_boxed_slice()));
+
+        let mut expected = emu.clone();
+        while !expected.is_halted() {
+            expected.tick().unwrap();
+        }
+
+        for &backend in Backend::ALL.iter() {
+            let mut actual = emu.clone();
+            actual.set_backend(backend);
+            assert_eq!(actual.backend(), backend);
+            actual.run(1000).unwrap();
+            assert!(actual.save_state() == expected.save_state(), "{:?}", backend);
+            assert_eq!(actual.engine.code.is_some(), backend != Backend::Interpreter);
+
+            // Copies keep the backend but build their own code.
+            let copy = actual.clone();
+            assert_eq!(copy.backend(), backend);
+            assert!(copy.engine.code.is_none());
+
+            // Tools tick one instruction at a time, on the same backend.
+            let mut ticked = emu.clone();
+            ticked.set_backend(backend);
+            while !ticked.is_halted() {
+                ticked.tick().unwrap();
+            }
+            assert!(ticked.save_state() == expected.save_state(), "{:?}", backend);
+        }
+        assert_eq!("jit".parse(), Ok(Backend::Jit));
+        assert!("fast".parse::<Backend>().is_err());
+    }
+}
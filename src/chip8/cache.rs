@@ -0,0 +1,290 @@
+// Decode cache
+// ------------
+// Runs the emulator from a cache of decoded instructions instead of
+// fetching and decoding every opcode again. Straight-line code is grouped
+// into basic blocks that run back to back after a single lookup, which
+// keeps high clock rates cheap.
+//
+// Blocks end after anything that can jump, skip or wait, and after
+// instructions that write memory through I. If such a write lands on code
+// in the cache, the whole cache starts over, so self-modifying code works.
+// Anything else that changes memory (loading a rom or a save state,
+// switching modes) bumps the machine's memory epoch, which does the same.
+//
+// Timers, cycle counts & faults behave exactly like the interpreter, the
+// cache only changes how fast instructions run. A cache belongs to a single
+// machine, which keeps its own when running on this backend.
+use super::{ decode, Error, Instruction, CHIP8, MEM_SIZE };
+
+// Longest run of instructions in a block.
+const MAX_BLOCK_LEN: usize = 32;
+
+pub struct DecodeCache {
+    // Index + 1 of the block starting at each address, 0 if none.
+    starts: Vec<u32>,
+    blocks: Vec<Vec<(Instruction, u16)>>,
+    // Whether a cached block covers each byte of memory.
+    code: Vec<bool>,
+    max_block_len: usize,
+    // Memory epoch of the machine the blocks were decoded from.
+    epoch: u32,
+}
+
+impl Default for DecodeCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DecodeCache {
+    pub fn new() -> DecodeCache {
+        DecodeCache {
+            starts: vec![0; MEM_SIZE],
+            blocks: Vec::new(),
+            code: vec![false; MEM_SIZE],
+            max_block_len: MAX_BLOCK_LEN,
+            epoch: 0,
+        }
+    }
+
+    // Limits how many instructions a block holds, 1 caches single
+    // instructions without grouping them.
+    pub fn set_max_block_len(&mut self, len: usize) {
+        self.max_block_len = len.max(1);
+        self.clear();
+    }
+
+    // Number of blocks decoded so far.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        if self.blocks.is_empty() {
+            return;
+        }
+
+        self.blocks.clear();
+        for start in self.starts.iter_mut() {
+            *start = 0;
+        }
+        for code in self.code.iter_mut() {
+            *code = false;
+        }
+    }
+
+    // Runs `cycles` instructions, stopping early if one faults.
+    pub fn run(&mut self, emu: &mut CHIP8, cycles: u64) -> Result<(), Error> {
+        let mut left = cycles;
+        while left > 0 {
+            if self.epoch != emu.memory_epoch {
+                self.clear();
+                self.epoch = emu.memory_epoch;
+            }
+
+            // Let `step` deal with halted machines and reporting faults.
+            let pc = emu.pc as usize;
+            if emu.is_halted() || pc + 1 >= emu.mem_size() {
+                return emu.step();
+            }
+
+            let idx = match self.starts[pc] {
+                0 => self.decode(emu, pc),
+                start => start as usize - 1,
+            };
+            let block = &self.blocks[idx];
+            let len = (block.len() as u64).min(left);
+            for &(instr, opcode) in block.iter().take(len as usize) {
+                emu.run_instruction(|emu| {
+                    emu.pc = emu.pc.wrapping_add(2);
+                    emu.execute_instruction(instr, opcode)
+                })?;
+            }
+            left -= len;
+
+            // Only the last instruction of a block can write.
+            if let Some(access) = emu.last_write {
+                let start = access.addr as usize;
+                if self.code[start..start + access.len as usize].contains(&true) {
+                    self.clear();
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Decodes the block starting at `start` and returns its index.
+    fn decode(&mut self, emu: &CHIP8, start: usize) -> usize {
+        let mut block = Vec::new();
+        let mut addr = start;
+        while block.len() < self.max_block_len && addr + 1 < emu.mem_size() {
+            let opcode = u16::from(emu.memory[addr]) << 8 | u16::from(emu.memory[addr + 1]);
+            let instr = decode(opcode);
+            block.push((instr, opcode));
+            for code in self.code.iter_mut().skip(addr).take(instr.size()) {
+                *code = true;
+            }
+            addr += instr.size();
+
+            if ends_block(instr) {
+                break;
+            }
+        }
+
+        self.blocks.push(block);
+        self.starts[start] = self.blocks.len() as u32;
+        self.blocks.len() - 1
+    }
+}
+
+// Whether execution might not continue with the next instruction, or the
+// instruction could overwrite code.
+pub(super) fn ends_block(instr: Instruction) -> bool {
+    matches!(instr,
+        Instruction::Jp(_)
+        | Instruction::Call(_)
+        | Instruction::Ret
+        | Instruction::JpV0(_)
+        | Instruction::SeByte(..)
+        | Instruction::SneByte(..)
+        | Instruction::SeReg(..)
+        | Instruction::SneReg(..)
+        | Instruction::Skp(_)
+        | Instruction::Sknp(_)
+        | Instruction::LdVxK(_)
+        | Instruction::Exit
+        | Instruction::Sys(_)
+        | Instruction::Unknown(_)
+        | Instruction::LdB(_)
+        | Instruction::LdIVx(_)
+        | Instruction::Save(..))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{ assemble, Mode };
+
+    fn program(source: &str) -> CHIP8 {
+        let mut emu = CHIP8::new();
+        emu.load_rom(Some(assemble(source).unwrap().into_boxed_slice()));
+        emu
+    }
+
+    // Runs a copy of `emu` through `tick` and another through the cache,
+    // both should end up in exactly the same state.
+    fn compare(emu: &CHIP8, cycles: u64, max_block_len: usize) {
+        let mut expected = emu.clone();
+        let expected_result = (0..cycles).map(|_| expected.tick()).find(Result::is_err).unwrap_or(Ok(()));
+
+        let mut actual = emu.clone();
+        let mut cache = DecodeCache::new();
+        cache.set_max_block_len(max_block_len);
+        // Odd slices so blocks get cut short.
+        let mut result = Ok(());
+        let mut left = cycles;
+        while left > 0 && result.is_ok() {
+            let slice = left.min(7);
+            result = cache.run(&mut actual, slice);
+            left -= slice;
+        }
+
+        assert_eq!(result, expected_result);
+        assert_eq!(actual.save_state(), expected.save_state());
+        assert_eq!(actual.cycles(), expected.cycles());
+    }
+
+    #[test]
+    fn test_matches_tick() {
+        let emu = program("
+                LD V0, 0
+                LD V1, 10
+            loop:
+                ADD V0, 3
+                CALL draw
+                LD I, 0x300
+                LD B, V0
+                LD V2, [I]
+                SNE V0, 60
+                JP done
+                SE V1, V1
+                CLS
+                JP loop
+            draw:
+                LD F, V2
+                DRW V0, V1, 5
+                RET
+            done:
+                LD VF, 1
+                ADD VF, 0xFF
+                JP 0xFFF
+        ");
+        for &len in [1, 2, MAX_BLOCK_LEN].iter() {
+            compare(&emu, 500, len);
+        }
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        // Patches the JP at `patch` into LD V5, 0x42 before running it.
+        let mut emu = program("
+                LD V0, 0x65
+                LD V1, 0x42
+                LD I, patch
+            loop:
+                ADD V3, 1
+                SE V3, 2
+                JP patch
+                LD [I], V1
+            patch:
+                JP loop
+            halt:
+                JP halt
+        ");
+        compare(&emu, 40, MAX_BLOCK_LEN);
+
+        let mut cache = DecodeCache::new();
+        cache.run(&mut emu, 40).unwrap();
+        assert_eq!(emu.registers[5], 0x42);
+        assert_eq!(emu.pc, 0x210);
+    }
+
+    #[test]
+    fn test_invalidation() {
+        let mut emu = program("LD V0, 1\nhalt: JP halt");
+        let mut cache = DecodeCache::new();
+        cache.run(&mut emu, 4).unwrap();
+        assert_eq!(emu.registers[0], 1);
+        assert_eq!(cache.len(), 2);
+
+        // A new rom at the same address replaces the old code.
+        emu.load_rom(Some(assemble("LD V0, 2\nhalt: JP halt").unwrap().into_boxed_slice()));
+        cache.run(&mut emu, 4).unwrap();
+        assert_eq!(emu.registers[0], 2);
+
+        // So does restoring a save state.
+        let state = emu.save_state();
+        emu.load_rom(Some(assemble("LD V0, 3\nhalt: JP halt").unwrap().into_boxed_slice()));
+        cache.run(&mut emu, 1).unwrap();
+        emu.load_state(&state).unwrap();
+        emu.pc = 0x200;
+        cache.run(&mut emu, 1).unwrap();
+        assert_eq!(emu.registers[0], 2);
+
+        // Faults & halts are reported like `tick` does.
+        let mut emu = program("JP 0xFFF");
+        assert!(DecodeCache::new().run(&mut emu, 10).is_err());
+        assert_eq!(emu.cycles(), 2);
+
+        let mut emu = program("EXIT");
+        emu.set_mode(Mode::SuperChip);
+        assert_eq!(DecodeCache::new().run(&mut emu, 10), Ok(()));
+        assert!(emu.is_halted());
+        assert_eq!(emu.cycles(), 1);
+    }
+}
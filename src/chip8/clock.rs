@@ -0,0 +1,147 @@
+// Clock
+// -----
+// The CPU runs at a configurable number of instructions per second while
+// the delay & sound timers always count down at 60Hz. Timer decrements are
+// scheduled in instructions rather than wall clock time, so a program sees
+// exactly `clock_rate / 60` instructions between decrements (on average,
+// when the rate isn't a multiple of 60) no matter how the frontend slices
+// up time.
+use wasm_bindgen::prelude::*;
+
+use super::{ Error, Register, CHIP8 };
+
+// Rate the delay & sound timers count down at.
+pub(super) const TIMER_RATE: u32 = 60;
+// Longest stretch of time `run_for` catches up on in one go. Anything
+// longer (e.g. a backgrounded browser tab) is dropped rather than running
+// thousands of instructions in a single frame.
+const MAX_RUN_MS: f64 = 250.0;
+
+impl CHIP8 {
+    // Accounts for one instruction's worth of time on the timers.
+    pub(super) fn advance_timers(&mut self) {
+        self.timer_cycles += TIMER_RATE;
+        while self.timer_cycles >= self.clock_rate {
+            self.timer_cycles -= self.clock_rate;
+
+            let dt = &mut self.registers[Register::DT as usize];
+            *dt = dt.saturating_sub(1);
+            let st = &mut self.registers[Register::ST as usize];
+            *st = st.saturating_sub(1);
+        }
+    }
+
+    // Adds `elapsed_ms` to the time owed to the CPU and takes out the
+    // whole instructions that fit in it.
+    pub(super) fn owed_cycles(&mut self, elapsed_ms: f64) -> u64 {
+        if elapsed_ms.is_nan() {
+            return 0;
+        }
+        let elapsed_ms = elapsed_ms.clamp(0.0, MAX_RUN_MS);
+        self.pending_cycles += elapsed_ms * f64::from(self.clock_rate) / 1000.0;
+
+        let cycles = self.pending_cycles.floor();
+        self.pending_cycles -= cycles;
+        cycles as u64
+    }
+}
+
+impl CHIP8 {
+    // `run_for`, but every instruction is run through `step`, which lets
+    // tools like the tracer or profiler see each one.
+    pub fn run_for_with<F>(&mut self, elapsed_ms: f64, mut step: F) -> Result<(), Error>
+        where F: FnMut(&mut CHIP8) -> Result<(), Error>
+    {
+        for _ in 0..self.owed_cycles(elapsed_ms) {
+            step(self)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[wasm_bindgen]
+impl CHIP8 {
+    // Instructions executed per second.
+    pub fn clock_rate(&self) -> u32 {
+        self.clock_rate
+    }
+
+    pub fn set_clock_rate(&mut self, rate: u32) {
+        self.clock_rate = rate.max(1);
+        self.timer_cycles %= self.clock_rate;
+    }
+
+    // Runs as many instructions as fit in `elapsed_ms` at the current clock
+    // rate. Leftover time is carried over to the next call so nothing is
+    // lost to rounding. Stops early if an instruction faults.
+    pub fn run_for(&mut self, elapsed_ms: f64) -> Result<(), Error> {
+        let cycles = self.owed_cycles(elapsed_ms);
+        self.run(cycles)
+    }
+
+    // Instructions executed since the rom was loaded.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Loops forever without touching the timers.
+    fn idle_rom() -> Option<Box<[u8]>> {
+        Some(Box::new([0x12, 0x00]))
+    }
+
+    #[test]
+    fn test_timers() {
+        let mut emu = CHIP8::new();
+        emu.load_rom(idle_rom());
+        emu.set_clock_rate(600);
+        emu.registers[Register::DT as usize] = 100;
+        emu.registers[Register::ST as usize] = 1;
+
+        // 10 instructions per timer decrement.
+        for _ in 0..9 {
+            emu.tick().unwrap();
+        }
+        assert_eq!(emu.registers[Register::DT as usize], 100);
+        emu.tick().unwrap();
+        assert_eq!(emu.registers[Register::DT as usize], 99);
+        assert_eq!(emu.registers[Register::ST as usize], 0);
+
+        // Rates that aren't a multiple of 60 still average out.
+        emu.set_clock_rate(700);
+        emu.registers[Register::DT as usize] = 100;
+        for _ in 0..700 {
+            emu.tick().unwrap();
+        }
+        assert_eq!(emu.registers[Register::DT as usize], 40);
+        assert_eq!(emu.registers[Register::ST as usize], 0);
+    }
+
+    #[test]
+    fn test_run_for() {
+        let mut emu = CHIP8::new();
+        // Counts instructions in v0.
+        emu.load_rom(Some(Box::new([0x70, 0x01, 0x12, 0x00])));
+        emu.set_clock_rate(1000);
+        emu.registers[Register::DT as usize] = 60;
+
+        emu.run_for(100.0).unwrap();
+        assert_eq!(emu.registers[0], 50);
+        assert_eq!(emu.registers[Register::DT as usize], 54);
+
+        // Partial instructions carry over between calls.
+        for _ in 0..4 {
+            emu.run_for(0.5).unwrap();
+        }
+        assert_eq!(emu.registers[0], 51);
+
+        // Long pauses are capped.
+        emu.run_for(10_000.0).unwrap();
+        assert_eq!(emu.registers[0], 51 + 125);
+    }
+}
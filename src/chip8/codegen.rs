@@ -0,0 +1,745 @@
+// WebAssembly code generation
+// ---------------------------
+// Encodes the JIT's blocks as WebAssembly modules for the wasm build. A
+// module imports the emulator's own linear memory and works on the machine
+// in place, at field offsets taken from a real machine, so compiled code
+// and the rest of the emulator never need to copy state back and forth.
+//
+// Each module exports `run(emu, budget)`, which runs up to `budget` of the
+// block's instructions on the machine at address `emu` and returns how many
+// it ran. Instructions the JIT has closures for are inlined, everything
+// else calls the imported `fallback(emu, opcode)`, which runs the opcode on
+// the interpreter and returns non-zero if it faulted, ending the block.
+// Before every instruction the timers & cycle count advance just like in
+// `run_instruction`.
+//
+// On XO-CHIP a skip has to look at the instruction it skips, which might be
+// a long one, so skips go through the fallback there.
+//
+// Native tests run the modules on wasmi against the interpreter, `make
+// check-wasm` keeps the browser side compiling.
+use super::clock::TIMER_RATE;
+use super::{ decode, Error, Instruction, Mode, Register, CHIP8 };
+
+// Browsers only compile modules this small on the main thread.
+pub(super) const MAX_MODULE_SIZE: usize = 4096;
+
+// Value & block types.
+const I32: u8 = 0x7F;
+const FUNC: u8 = 0x60;
+const EMPTY: u8 = 0x40;
+
+// Sections.
+const TYPE_SECTION: u8 = 1;
+const IMPORT_SECTION: u8 = 2;
+const FUNCTION_SECTION: u8 = 3;
+const EXPORT_SECTION: u8 = 7;
+const CODE_SECTION: u8 = 10;
+
+// Instructions.
+const BLOCK: u8 = 0x02;
+const LOOP: u8 = 0x03;
+const IF: u8 = 0x04;
+const END: u8 = 0x0B;
+const BR: u8 = 0x0C;
+const BR_IF: u8 = 0x0D;
+const RETURN: u8 = 0x0F;
+const CALL: u8 = 0x10;
+const SELECT: u8 = 0x1B;
+const LOCAL_GET: u8 = 0x20;
+const LOCAL_SET: u8 = 0x21;
+const I32_LOAD: u8 = 0x28;
+const I64_LOAD: u8 = 0x29;
+const I32_LOAD8_U: u8 = 0x2D;
+const I32_STORE: u8 = 0x36;
+const I64_STORE: u8 = 0x37;
+const I32_STORE8: u8 = 0x3A;
+const I32_STORE16: u8 = 0x3B;
+const I32_CONST: u8 = 0x41;
+const I64_CONST: u8 = 0x42;
+const I32_EQ: u8 = 0x46;
+const I32_NE: u8 = 0x47;
+const I32_LT_U: u8 = 0x49;
+const I32_GE_U: u8 = 0x4F;
+const I32_ADD: u8 = 0x6A;
+const I32_SUB: u8 = 0x6B;
+const I32_SHR_U: u8 = 0x76;
+const I64_ADD: u8 = 0x7C;
+
+// Function indices, imports come first.
+const FALLBACK: u32 = 0;
+const BEGIN: u32 = 1;
+const RUN: u32 = 2;
+
+// Locals of `run`: the machine's address, the budget & a scratch value.
+const EMU: u32 = 0;
+const BUDGET: u32 = 1;
+const SCRATCH: u32 = 2;
+
+// Where the fields compiled code touches live, relative to the machine.
+pub(super) struct Layout {
+    pc: u32,
+    i_reg: u32,
+    registers: u32,
+    timer_cycles: u32,
+    clock_rate: u32,
+    cycles: u32,
+}
+
+impl Layout {
+    pub(super) fn of(emu: &CHIP8) -> Layout {
+        let base = emu as *const CHIP8 as usize;
+        let offset = |field: usize| (field - base) as u32;
+
+        Layout {
+            pc: offset(&emu.pc as *const u16 as usize),
+            i_reg: offset(&emu.i_reg as *const u16 as usize),
+            registers: offset(emu.registers.as_ptr() as usize),
+            timer_cycles: offset(&emu.timer_cycles as *const u32 as usize),
+            clock_rate: offset(&emu.clock_rate as *const u32 as usize),
+            cycles: offset(&emu.cycles as *const u64 as usize),
+        }
+    }
+
+    fn register(&self, reg: u8) -> u32 {
+        self.registers + u32::from(reg)
+    }
+}
+
+// Whether `compile` inlines the instruction instead of calling the
+// fallback.
+pub(super) fn inlined(instr: Instruction, mode: Mode) -> bool {
+    match instr {
+        Instruction::Jp(_)
+        | Instruction::LdByte(..)
+        | Instruction::AddByte(..)
+        | Instruction::LdReg(..)
+        | Instruction::AddReg(..)
+        | Instruction::Sub(..)
+        | Instruction::LdI(_) => true,
+        Instruction::SeByte(..)
+        | Instruction::SneByte(..)
+        | Instruction::SeReg(..)
+        | Instruction::SneReg(..) => mode != Mode::XoChip,
+        _ => false,
+    }
+}
+
+// Encodes a module for `block`, each instruction with its address and
+// opcode.
+pub(super) fn compile(layout: &Layout, mode: Mode, block: &[(u16, Instruction, u16)]) -> Vec<u8> {
+    let mut module = vec![0x00, 0x61, 0x73, 0x6D, 0x01, 0x00, 0x00, 0x00];
+
+    // (i32, i32) -> i32 for `fallback` & `run`, (i32) -> () for `begin`.
+    section(&mut module, TYPE_SECTION, &[2, FUNC, 2, I32, I32, 1, I32, FUNC, 1, I32, 0]);
+
+    let mut imports = vec![2];
+    name(&mut imports, "env");
+    name(&mut imports, "memory");
+    // A memory of at least 0 pages, with no maximum.
+    imports.extend_from_slice(&[0x02, 0x00, 0x00]);
+    name(&mut imports, "env");
+    name(&mut imports, "fallback");
+    imports.extend_from_slice(&[0x00, 0]);
+    section(&mut module, IMPORT_SECTION, &imports);
+
+    section(&mut module, FUNCTION_SECTION, &[2, 1, 0]);
+
+    let mut exports = vec![1];
+    name(&mut exports, "run");
+    exports.extend_from_slice(&[0x00, RUN as u8]);
+    section(&mut module, EXPORT_SECTION, &exports);
+
+    let mut code = vec![2];
+    function(&mut code, &[], &begin(layout));
+    function(&mut code, &[(1, I32)], &run(layout, mode, block));
+    section(&mut module, CODE_SECTION, &code);
+
+    module
+}
+
+// Runs the first `len` instructions of a block through `run`, which calls
+// the module's `run` on the machine with a budget & returns its result.
+// The module does what `run_instruction` does around every instruction,
+// except for clearing the memory accesses, left to `fallback` & done here
+// for the rest. `fallbacks` has which instructions call the fallback.
+pub(super) fn run_block<F>(emu: &mut CHIP8, fallbacks: &[bool], len: usize, run: F) -> Result<(), Error>
+    where F: FnOnce(&mut CHIP8, u32) -> u32
+{
+    emu.last_read = None;
+    emu.last_write = None;
+
+    let ran = run(emu, len as u32) as usize;
+    if ran > 0 && !fallbacks[ran - 1] {
+        emu.last_read = None;
+        emu.last_write = None;
+    }
+
+    match emu.fault {
+        Some(fault) => Err(fault),
+        None => Ok(()),
+    }
+}
+
+// What the `fallback` import does, runs an instruction compiled code
+// doesn't handle itself on the interpreter. The program counter has already
+// been moved past it. Returns non-zero if it faulted, which halts the
+// machine like `run_instruction` does.
+pub(super) fn fallback(emu: &mut CHIP8, opcode: u16) -> u32 {
+    emu.last_read = None;
+    emu.last_write = None;
+
+    match emu.execute_instruction(decode(opcode), opcode) {
+        Ok(()) => 0,
+        Err(fault) => {
+            emu.fault = Some(fault);
+            1
+        },
+    }
+}
+
+// `begin(emu)`: advances the timers & the cycle count, see
+// `advance_timers`.
+fn begin(layout: &Layout) -> Code {
+    let mut code = Code::default();
+
+    code.local(LOCAL_GET, EMU)
+        .local(LOCAL_GET, EMU).load(I32_LOAD, layout.timer_cycles)
+        .i32(TIMER_RATE as i32).op(I32_ADD)
+        .store(I32_STORE, layout.timer_cycles);
+
+    code.op(LOOP).op(EMPTY).op(BLOCK).op(EMPTY);
+    code.local(LOCAL_GET, EMU).load(I32_LOAD, layout.timer_cycles)
+        .local(LOCAL_GET, EMU).load(I32_LOAD, layout.clock_rate)
+        .op(I32_LT_U).br(BR_IF, 0);
+    code.local(LOCAL_GET, EMU)
+        .local(LOCAL_GET, EMU).load(I32_LOAD, layout.timer_cycles)
+        .local(LOCAL_GET, EMU).load(I32_LOAD, layout.clock_rate)
+        .op(I32_SUB)
+        .store(I32_STORE, layout.timer_cycles);
+    for &timer in [Register::DT, Register::ST].iter() {
+        // Saturating, the timer minus whether it's above 0.
+        let timer = layout.register(timer as u8);
+        code.local(LOCAL_GET, EMU)
+            .local(LOCAL_GET, EMU).load(I32_LOAD8_U, timer)
+            .local(LOCAL_GET, EMU).load(I32_LOAD8_U, timer)
+            .i32(0).op(I32_NE)
+            .op(I32_SUB)
+            .store(I32_STORE8, timer);
+    }
+    code.br(BR, 1).op(END).op(END);
+
+    code.local(LOCAL_GET, EMU)
+        .local(LOCAL_GET, EMU).load(I64_LOAD, layout.cycles)
+        .op(I64_CONST).sleb(1).op(I64_ADD)
+        .store(I64_STORE, layout.cycles);
+
+    code
+}
+
+// `run(emu, budget)`: the block itself.
+fn run(layout: &Layout, mode: Mode, block: &[(u16, Instruction, u16)]) -> Code {
+    let mut code = Code::default();
+    let vf = Register::VF as u8;
+
+    for (idx, &(addr, instr, opcode)) in block.iter().enumerate() {
+        let idx = idx as i32;
+        let next = addr.wrapping_add(2);
+        if idx > 0 {
+            code.i32(idx).local(LOCAL_GET, BUDGET).op(I32_GE_U)
+                .op(IF).op(EMPTY).i32(idx).op(RETURN).op(END);
+        }
+        code.local(LOCAL_GET, EMU).call(BEGIN);
+
+        if !inlined(instr, mode) {
+            code.set_pc(layout, next)
+                .local(LOCAL_GET, EMU).i32(i32::from(opcode)).call(FALLBACK)
+                .op(IF).op(EMPTY).i32(idx + 1).op(RETURN).op(END);
+            continue;
+        }
+
+        match instr {
+            Instruction::Jp(target) => {
+                code.set_pc(layout, target);
+            },
+            Instruction::SeByte(vx, byte) | Instruction::SneByte(vx, byte) => {
+                let compare = if let Instruction::SeByte(..) = instr { I32_EQ } else { I32_NE };
+                code.local(LOCAL_GET, EMU)
+                    .i32(i32::from(addr.wrapping_add(4))).i32(i32::from(next))
+                    .reg(layout, vx).i32(i32::from(byte)).op(compare)
+                    .op(SELECT)
+                    .store(I32_STORE16, layout.pc);
+            },
+            Instruction::SeReg(vx, vy) | Instruction::SneReg(vx, vy) => {
+                let compare = if let Instruction::SeReg(..) = instr { I32_EQ } else { I32_NE };
+                code.local(LOCAL_GET, EMU)
+                    .i32(i32::from(addr.wrapping_add(4))).i32(i32::from(next))
+                    .reg(layout, vx).reg(layout, vy).op(compare)
+                    .op(SELECT)
+                    .store(I32_STORE16, layout.pc);
+            },
+            Instruction::LdByte(vx, byte) => {
+                code.set_pc(layout, next)
+                    .local(LOCAL_GET, EMU).i32(i32::from(byte))
+                    .store(I32_STORE8, layout.register(vx));
+            },
+            Instruction::AddByte(vx, byte) => {
+                code.set_pc(layout, next)
+                    .local(LOCAL_GET, EMU).reg(layout, vx).i32(i32::from(byte)).op(I32_ADD)
+                    .store(I32_STORE8, layout.register(vx));
+            },
+            Instruction::LdReg(vx, vy) => {
+                code.set_pc(layout, next)
+                    .local(LOCAL_GET, EMU).reg(layout, vy)
+                    .store(I32_STORE8, layout.register(vx));
+            },
+            Instruction::AddReg(vx, vy) => {
+                // The flag is the 9th bit of the sum, written last.
+                code.set_pc(layout, next)
+                    .reg(layout, vx).reg(layout, vy).op(I32_ADD).local(LOCAL_SET, SCRATCH)
+                    .local(LOCAL_GET, EMU).local(LOCAL_GET, SCRATCH)
+                    .store(I32_STORE8, layout.register(vx))
+                    .local(LOCAL_GET, EMU).local(LOCAL_GET, SCRATCH).i32(8).op(I32_SHR_U)
+                    .store(I32_STORE8, layout.register(vf));
+            },
+            Instruction::Sub(vx, vy) => {
+                // No borrow, worked out before Vx changes & written last.
+                code.set_pc(layout, next)
+                    .reg(layout, vx).reg(layout, vy).op(I32_GE_U).local(LOCAL_SET, SCRATCH)
+                    .local(LOCAL_GET, EMU).reg(layout, vx).reg(layout, vy).op(I32_SUB)
+                    .store(I32_STORE8, layout.register(vx))
+                    .local(LOCAL_GET, EMU).local(LOCAL_GET, SCRATCH)
+                    .store(I32_STORE8, layout.register(vf));
+            },
+            Instruction::LdI(target) => {
+                code.set_pc(layout, next)
+                    .local(LOCAL_GET, EMU).i32(i32::from(target))
+                    .store(I32_STORE16, layout.i_reg);
+            },
+            _ => unreachable!("{:?} isn't inlined", instr),
+        }
+    }
+    code.i32(block.len() as i32);
+
+    code
+}
+
+// Instructions of a function body.
+#[derive(Default)]
+struct Code(Vec<u8>);
+
+impl Code {
+    fn op(&mut self, op: u8) -> &mut Code {
+        self.0.push(op);
+        self
+    }
+
+    fn uleb(&mut self, value: u32) -> &mut Code {
+        uleb(&mut self.0, value);
+        self
+    }
+
+    fn sleb(&mut self, value: i64) -> &mut Code {
+        let mut value = value;
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+            self.0.push(if done { byte } else { byte | 0x80 });
+            if done {
+                return self;
+            }
+        }
+    }
+
+    fn i32(&mut self, value: i32) -> &mut Code {
+        self.op(I32_CONST).sleb(i64::from(value))
+    }
+
+    fn local(&mut self, op: u8, idx: u32) -> &mut Code {
+        self.op(op).uleb(idx)
+    }
+
+    fn br(&mut self, op: u8, depth: u32) -> &mut Code {
+        self.op(op).uleb(depth)
+    }
+
+    fn call(&mut self, func: u32) -> &mut Code {
+        self.op(CALL).uleb(func)
+    }
+
+    // Loads & stores take the alignment, which is only a hint, and the
+    // offset from the address on the stack.
+    fn load(&mut self, op: u8, offset: u32) -> &mut Code {
+        self.op(op).uleb(alignment(op)).uleb(offset)
+    }
+
+    fn store(&mut self, op: u8, offset: u32) -> &mut Code {
+        self.load(op, offset)
+    }
+
+    fn reg(&mut self, layout: &Layout, reg: u8) -> &mut Code {
+        self.local(LOCAL_GET, EMU).load(I32_LOAD8_U, layout.register(reg))
+    }
+
+    fn set_pc(&mut self, layout: &Layout, pc: u16) -> &mut Code {
+        self.local(LOCAL_GET, EMU).i32(i32::from(pc)).store(I32_STORE16, layout.pc)
+    }
+}
+
+// Natural alignment of a load or store, as a power of two.
+fn alignment(op: u8) -> u32 {
+    match op {
+        I32_LOAD8_U | I32_STORE8 => 0,
+        I32_STORE16 => 1,
+        I32_LOAD | I32_STORE => 2,
+        _ => 3,
+    }
+}
+
+fn uleb(out: &mut Vec<u8>, value: u32) {
+    let mut value = value;
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn name(out: &mut Vec<u8>, name: &str) {
+    uleb(out, name.len() as u32);
+    out.extend_from_slice(name.as_bytes());
+}
+
+fn section(module: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    module.push(id);
+    uleb(module, contents.len() as u32);
+    module.extend_from_slice(contents);
+}
+
+// A function body, `locals` being groups of a count & a type.
+fn function(out: &mut Vec<u8>, locals: &[(u32, u8)], code: &Code) {
+    let mut body = Vec::new();
+    uleb(&mut body, locals.len() as u32);
+    for &(count, kind) in locals {
+        uleb(&mut body, count);
+        body.push(kind);
+    }
+    body.extend_from_slice(&code.0);
+    body.push(END);
+
+    uleb(out, body.len() as u32);
+    out.extend_from_slice(&body);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(not(target_arch = "wasm32"))]
+    use super::super::{ Quirks, MemAccess };
+    #[cfg(not(target_arch = "wasm32"))]
+    use proptest::prelude::*;
+    #[cfg(not(target_arch = "wasm32"))]
+    use std::convert::TryInto;
+    #[cfg(not(target_arch = "wasm32"))]
+    use std::mem;
+    #[cfg(not(target_arch = "wasm32"))]
+    use wasmi::{ Caller, Engine, Linker, Memory, MemoryType, Module, Store };
+
+    fn block(start: u16, opcodes: &[u16]) -> Vec<(u16, Instruction, u16)> {
+        opcodes.iter().enumerate()
+            .map(|(k, &opcode)| (start + 2 * k as u16, decode(opcode), opcode))
+            .collect()
+    }
+
+    #[test]
+    fn test_layout() {
+        let emu = CHIP8::new();
+        let layout = Layout::of(&emu);
+        let size = ::std::mem::size_of::<CHIP8>() as u32;
+
+        assert!(layout.pc + 2 <= size);
+        assert!(layout.i_reg + 2 <= size);
+        assert!(layout.register(Register::VF as u8) < size);
+        assert!(layout.timer_cycles + 4 <= size);
+        assert!(layout.clock_rate + 4 <= size);
+        assert!(layout.cycles + 8 <= size);
+        assert_eq!(layout.cycles % 8, 0);
+    }
+
+    #[test]
+    fn test_inlined() {
+        assert!(inlined(decode(0x6A12), Mode::Chip8));
+        assert!(inlined(decode(0x8AB5), Mode::XoChip));
+        assert!(inlined(decode(0x3A12), Mode::Chip8));
+        assert!(inlined(decode(0x9AB0), Mode::SuperChip));
+        // A skip might have to step over a long instruction.
+        assert!(!inlined(decode(0x3A12), Mode::XoChip));
+        assert!(!inlined(decode(0x9AB0), Mode::XoChip));
+        assert!(!inlined(decode(0x8AB1), Mode::Chip8));
+        assert!(!inlined(decode(0xDAB5), Mode::Chip8));
+    }
+
+    #[test]
+    fn test_module() {
+        let layout = Layout::of(&CHIP8::new());
+        let module = compile(&layout, Mode::Chip8, &block(0x200, &[0x6A12, 0x8AB1, 0x1200]));
+
+        assert_eq!(&module[..8], &[0x00, 0x61, 0x73, 0x6D, 0x01, 0x00, 0x00, 0x00]);
+        // Sections in order, each as long as it says.
+        let mut offset = 8;
+        let mut ids = Vec::new();
+        while offset < module.len() {
+            ids.push(module[offset]);
+            let mut len = 0;
+            let mut shift = 0;
+            offset += 1;
+            loop {
+                let byte = module[offset];
+                offset += 1;
+                len |= usize::from(byte & 0x7F) << shift;
+                shift += 7;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+            offset += len;
+        }
+        assert_eq!(offset, module.len());
+        assert_eq!(ids, vec![TYPE_SECTION, IMPORT_SECTION, FUNCTION_SECTION, EXPORT_SECTION, CODE_SECTION]);
+    }
+
+    #[test]
+    fn test_module_size() {
+        // The longest block the JIT compiles, out of the biggest
+        // instructions, on the top of memory.
+        let layout = Layout::of(&CHIP8::new());
+        for &mode in [Mode::Chip8, Mode::SuperChip, Mode::XoChip].iter() {
+            for &opcode in [0x6A12, 0x7A12, 0x8AB0, 0x8AB4, 0x8AB5, 0xA123, 0x3A12, 0x5AB0, 0x8AB1].iter() {
+                let module = compile(&layout, mode, &block(0xFF80, &[opcode; 32]));
+                assert!(module.len() <= MAX_MODULE_SIZE, "{:04X} in {:?}: {} bytes", opcode, mode, module.len());
+            }
+        }
+    }
+
+    // Where the machine goes in a module's memory.
+    #[cfg(not(target_arch = "wasm32"))]
+    const BASE: u32 = 64;
+
+    // What the modules see when run on wasmi instead of in the browser.
+    // Their memory isn't the machine's, so the fields compiled code touches
+    // are copied in & out around every call, and the machine is handed to
+    // the fallback while a block runs.
+    #[cfg(not(target_arch = "wasm32"))]
+    struct Host {
+        emu: CHIP8,
+        layout: Layout,
+        memory: Option<Memory>,
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn copy_in(layout: &Layout, emu: &CHIP8, memory: &mut [u8]) {
+        let mut put = |offset: u32, bytes: &[u8]| {
+            let at = (BASE + offset) as usize;
+            memory[at..at + bytes.len()].copy_from_slice(bytes);
+        };
+        put(layout.pc, &emu.pc.to_le_bytes());
+        put(layout.i_reg, &emu.i_reg.to_le_bytes());
+        put(layout.registers, &emu.registers);
+        put(layout.timer_cycles, &emu.timer_cycles.to_le_bytes());
+        put(layout.clock_rate, &emu.clock_rate.to_le_bytes());
+        put(layout.cycles, &emu.cycles.to_le_bytes());
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn copy_out(layout: &Layout, emu: &mut CHIP8, memory: &[u8]) {
+        let get = |offset: u32, len: usize| &memory[(BASE + offset) as usize..][..len];
+        emu.pc = u16::from_le_bytes(get(layout.pc, 2).try_into().unwrap());
+        emu.i_reg = u16::from_le_bytes(get(layout.i_reg, 2).try_into().unwrap());
+        let len = emu.registers.len();
+        emu.registers.copy_from_slice(get(layout.registers, len));
+        emu.timer_cycles = u32::from_le_bytes(get(layout.timer_cycles, 4).try_into().unwrap());
+        emu.clock_rate = u32::from_le_bytes(get(layout.clock_rate, 4).try_into().unwrap());
+        emu.cycles = u64::from_le_bytes(get(layout.cycles, 8).try_into().unwrap());
+    }
+
+    // Compiles `block` and runs its first `len` instructions on `emu`, the
+    // way the JIT does in the browser.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn run_compiled(engine: &Engine, emu: &mut CHIP8, block: &[(u16, Instruction, u16)], len: usize) -> Result<(), Error> {
+        let layout = Layout::of(emu);
+        let module = Module::new(engine, &compile(&layout, emu.mode, block)[..]).expect("a valid module");
+        let fallbacks: Vec<bool> = block.iter().map(|&(_, instr, _)| !inlined(instr, emu.mode)).collect();
+
+        let mut store = Store::new(engine, Host { emu: CHIP8::new(), layout, memory: None });
+        let memory = Memory::new(&mut store, MemoryType::new(2, None).unwrap()).unwrap();
+        store.data_mut().memory = Some(memory);
+        let mut linker = Linker::new(engine);
+        linker.define("env", "memory", memory).unwrap();
+        linker.func_wrap("env", "fallback", |mut caller: Caller<Host>, addr: u32, opcode: u32| -> u32 {
+            assert_eq!(addr, BASE);
+            let memory = caller.data().memory.unwrap();
+            let (bytes, host) = memory.data_and_store_mut(&mut caller);
+            copy_out(&host.layout, &mut host.emu, bytes);
+            let faulted = fallback(&mut host.emu, opcode as u16);
+            copy_in(&host.layout, &host.emu, bytes);
+            faulted
+        }).unwrap();
+        let instance = linker.instantiate(&mut store, &module).unwrap().start(&mut store).unwrap();
+        let run = instance.get_typed_func::<(u32, u32), u32>(&store, "run").unwrap();
+
+        run_block(emu, &fallbacks, len, |emu, budget| {
+            mem::swap(emu, &mut store.data_mut().emu);
+            let (bytes, host) = memory.data_and_store_mut(&mut store);
+            copy_in(&host.layout, &host.emu, bytes);
+            let ran = run.call(&mut store, (BASE, budget)).expect("compiled blocks don't trap");
+            let (bytes, host) = memory.data_and_store_mut(&mut store);
+            copy_out(&host.layout, &mut host.emu, bytes);
+            mem::swap(emu, &mut store.data_mut().emu);
+            assert!(ran <= budget);
+            ran
+        })
+    }
+
+    // What inlined instructions can change.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn cpu(emu: &CHIP8) -> (u16, u16, [u8; 18], u32, u64) {
+        (emu.pc, emu.i_reg, emu.registers, emu.timer_cycles, emu.cycles)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn rest(emu: &CHIP8) -> (Option<Error>, Option<MemAccess>, Option<MemAccess>) {
+        (emu.fault, emu.last_read, emu.last_write)
+    }
+
+    // Everything about the machine, saved state doesn't keep the accesses.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn state(emu: &CHIP8) -> (Vec<u8>, Option<MemAccess>, Option<MemAccess>) {
+        (emu.save_state(), emu.last_read, emu.last_write)
+    }
+
+    // Every inlined opcode does the same thing compiled as interpreted, in
+    // every mode, with the timers about to tick & the cycle count about to
+    // carry into its upper half.
+    #[test]
+    #[cfg(not(target_arch = "wasm32"))]
+    fn test_matches_execute() {
+        let engine = Engine::default();
+        let mut base = CHIP8::new();
+        for (idx, reg) in base.registers.iter_mut().take(16).enumerate() {
+            *reg = (idx as u8).wrapping_mul(37);
+        }
+        base.registers[3] = base.registers[7];
+        base.registers[Register::DT as usize] = 1;
+        base.i_reg = 0x300;
+        base.pc = 0x400;
+        // A long instruction to skip over in XO-CHIP mode.
+        base.memory[0x402] = 0xF0;
+        base.timer_cycles = base.clock_rate - TIMER_RATE;
+        base.cycles = u64::from(u32::MAX);
+
+        for &mode in [Mode::Chip8, Mode::SuperChip, Mode::XoChip].iter() {
+            for &quirks in [Quirks::cosmac_vip(), Quirks::modern()].iter() {
+                let mut start = base.clone();
+                start.set_mode(mode);
+                start.set_quirks(quirks);
+
+                for opcode in 0x0000..=0xFFFF {
+                    let instr = decode(opcode);
+                    if !inlined(instr, mode) {
+                        continue;
+                    }
+
+                    let mut expected = start.clone();
+                    let expected_result = expected.run_instruction(|emu| {
+                        emu.pc += 2;
+                        emu.execute(opcode)
+                    });
+                    let mut actual = start.clone();
+                    let actual_result = run_compiled(&engine, &mut actual, &[(start.pc, instr, opcode)], 1);
+
+                    assert_eq!(actual_result, expected_result, "{:04X} in {:?}", opcode, mode);
+                    assert_eq!(cpu(&actual), cpu(&expected), "{:04X} in {:?}", opcode, mode);
+                    assert_eq!(rest(&actual), rest(&expected), "{:04X} in {:?}", opcode, mode);
+                    assert!(actual.memory[..] == expected.memory[..], "{:04X} in {:?}", opcode, mode);
+                }
+            }
+        }
+    }
+
+    // Opcodes blocks are made of, as a fixed part & a mask of the part
+    // that's random.
+    #[cfg(not(target_arch = "wasm32"))]
+    const OPCODES: [(u16, u16); 26] = [
+        (0x6000, 0x0FFF), (0x7000, 0x0FFF), (0x8000, 0x0FF0), (0x8004, 0x0FF0),
+        (0x8005, 0x0FF0), (0xA000, 0x0FFF), (0x1000, 0x0FFF), (0x3000, 0x0FFF),
+        (0x4000, 0x0FFF), (0x5000, 0x0FF0), (0x9000, 0x0FF0),
+        (0x8001, 0x0FF0), (0x8006, 0x0FF0), (0x8007, 0x0FF0), (0x800E, 0x0FF0),
+        (0xF007, 0x0F00), (0xF015, 0x0F00), (0xF01E, 0x0F00), (0xF029, 0x0F00),
+        (0xF033, 0x0F00), (0xF065, 0x0F00), (0xD000, 0x0FFF), (0xC000, 0x0FFF),
+        (0x2000, 0x0FFF), (0x00EE, 0x0000), (0x0000, 0xFFFF),
+    ];
+
+    #[cfg(not(target_arch = "wasm32"))]
+    proptest! {
+        // Blocks of inlined instructions & fallbacks, laid out like the JIT
+        // finds them and cut short by the budget or a fault, run the same
+        // as on the interpreter.
+        #[test]
+        fn prop_matches_step(
+            mode in 0..3usize,
+            quirks in any::<bool>(),
+            registers in any::<[u8; 16]>(),
+            timers in any::<[u8; 2]>(),
+            clock_rate in 1..2000u32,
+            timer_cycles in any::<u32>(),
+            cycles in prop::sample::select(vec![0, u64::from(u32::MAX) - 4]),
+            opcodes in prop::collection::vec((0..OPCODES.len(), any::<u16>()), 1..40),
+            budget in 1..40usize,
+        ) {
+            let mut start = CHIP8::new();
+            start.set_mode([Mode::Chip8, Mode::SuperChip, Mode::XoChip][mode]);
+            start.set_quirks(if quirks { Quirks::cosmac_vip() } else { Quirks::modern() });
+            start.registers[..16].copy_from_slice(&registers);
+            start.registers[16..].copy_from_slice(&timers);
+            start.clock_rate = clock_rate;
+            start.timer_cycles = timer_cycles % clock_rate;
+            start.cycles = cycles;
+            start.i_reg = 0x300;
+            start.pc = 0x400;
+            for (idx, &(kind, random)) in opcodes.iter().enumerate() {
+                let (fixed, mask) = OPCODES[kind];
+                let opcode = fixed | (random & mask);
+                start.memory[0x400 + 2 * idx] = (opcode >> 8) as u8;
+                start.memory[0x400 + 2 * idx + 1] = opcode as u8;
+            }
+
+            // What `Jit::compile` would make of it.
+            let mut block = Vec::new();
+            let mut addr = 0x400;
+            while block.len() < 32 {
+                let opcode = u16::from(start.memory[addr]) << 8 | u16::from(start.memory[addr + 1]);
+                let instr = decode(opcode);
+                block.push((addr as u16, instr, opcode));
+                addr += instr.size();
+                if super::super::cache::ends_block(instr) {
+                    break;
+                }
+            }
+            let len = budget.min(block.len());
+
+            let engine = Engine::default();
+            let mut expected = start.clone();
+            let expected_result = (0..len).map(|_| expected.step()).find(Result::is_err).unwrap_or(Ok(()));
+            let mut actual = start.clone();
+            let actual_result = run_compiled(&engine, &mut actual, &block, len);
+
+            prop_assert_eq!(actual_result, expected_result);
+            prop_assert!(state(&actual) == state(&expected));
+        }
+    }
+}
@@ -0,0 +1,263 @@
+// Coverage
+// --------
+// Marks every byte of memory as executed, read as data or written while a
+// rom runs. CHIP-8 roms mix code and sprites freely, so this is the only
+// reliable way to tell them apart: bytes that ran are code, bytes read
+// through I (sprites drawn by DXYN, tables loaded by FX65) are data, and
+// bytes written through I (FX33, FX55) are variables.
+//
+// The map is one byte of flags per address. A byte can be more than one
+// thing, e.g. self-modifying code is both executed and written. Only what
+// runs through it is covered, as with the other tools (see `Observer`).
+use std::fmt::Write;
+use wasm_bindgen::prelude::*;
+
+use super::{ disassemble, Error, Instruction, Observer, CHIP8, MEM_SIZE };
+
+// Number of bytes in a range with each kind of access.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CoverageSummary {
+    pub len: usize,
+    pub executed: usize,
+    pub read: usize,
+    pub written: usize,
+    pub untouched: usize,
+}
+
+impl CoverageSummary {
+    // Fraction of the bytes that were used at all, from 0 to 1.
+    pub fn exercised(&self) -> f64 {
+        if self.len == 0 {
+            return 0.0;
+        }
+
+        (self.len - self.untouched) as f64 / self.len as f64
+    }
+}
+
+#[wasm_bindgen]
+pub struct Coverage {
+    flags: Vec<u8>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[wasm_bindgen]
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage { flags: vec![0; MEM_SIZE] }
+    }
+
+    pub fn clear(&mut self) {
+        for flags in self.flags.iter_mut() {
+            *flags = 0;
+        }
+    }
+
+    // The flags of a single byte.
+    pub fn flags(&self, addr: u16) -> u8 {
+        self.flags[addr as usize]
+    }
+
+    // The flags of the first `len` bytes of memory, a combination of
+    // EXECUTED (1), READ (2) and WRITTEN (4) per byte.
+    pub fn map(&self, len: usize) -> Vec<u8> {
+        self.flags[..len.min(MEM_SIZE)].to_vec()
+    }
+
+    // How much of the `len` bytes at `start` were used, usually the rom.
+    pub fn report(&self, start: u16, len: usize) -> String {
+        let summary = self.summary(start, len);
+        let percent = |count: usize| 100.0 * count as f64 / summary.len.max(1) as f64;
+
+        let mut out = String::new();
+        let end = start as usize + summary.len;
+        let _ = writeln!(out, "Coverage of {:03X}-{:03X} ({} bytes)", start, end.max(1) - 1, summary.len);
+        for &(name, count) in [
+            ("executed", summary.executed),
+            ("read", summary.read),
+            ("written", summary.written),
+            ("untouched", summary.untouched),
+        ].iter() {
+            let _ = writeln!(out, "  {:<10} {:>6} {:>6.2}%", name, count, percent(count));
+        }
+
+        out
+    }
+
+    // Disassembles the `len` bytes at `start`, one line per instruction
+    // prefixed with the address like `CHIP8::disassemble`. Only bytes that
+    // executed are shown as code, data is shown a byte at a time along with
+    // its pixels and runs of bytes that weren't used are collapsed.
+    pub fn listing(&self, emu: &CHIP8, start: u16, len: usize) -> String {
+        let start = (start as usize).min(emu.mem_size());
+        let end = (start + len).min(emu.mem_size());
+        let word = |addr: usize| {
+            let hi = emu.memory[addr];
+            let lo = if addr + 1 < emu.mem_size() { emu.memory[addr + 1] } else { 0 };
+            u16::from(hi) << 8 | u16::from(lo)
+        };
+
+        let mut out = String::new();
+        let mut addr = start;
+        while addr < end {
+            let flags = self.flags[addr];
+            if flags & Coverage::EXECUTED != 0 {
+                let instr = disassemble(word(addr));
+                match instr {
+                    Instruction::LdILong => {
+                        let _ = writeln!(out, "{:03X}: LD I, LONG {:#06X}", addr, word(addr + 2));
+                    },
+                    _ => { let _ = writeln!(out, "{:03X}: {}", addr, instr); },
+                }
+                addr += instr.size();
+            } else if flags != 0 {
+                let byte = emu.memory[addr];
+                let pixels: String = (0..8).rev()
+                    .map(|bit| if byte >> bit & 1 == 1 { '#' } else { '.' })
+                    .collect();
+                let _ = writeln!(out, "{:03X}: {:<22} {}", addr, format!("DB {:#04X}", byte), pixels);
+                addr += 1;
+            } else {
+                let unused = self.flags[addr..end].iter().take_while(|&&flags| flags == 0).count();
+                let _ = writeln!(out, "{:03X}: ; {} unused byte{}", addr, unused, if unused == 1 { "" } else { "s" });
+                addr += unused;
+            }
+        }
+
+        out
+    }
+}
+
+impl Coverage {
+    // Flags in the coverage map.
+    pub const EXECUTED: u8 = 1;
+    pub const READ: u8 = 1 << 1;
+    pub const WRITTEN: u8 = 1 << 2;
+
+    // Counts the bytes in `len` bytes at `start`.
+    pub fn summary(&self, start: u16, len: usize) -> CoverageSummary {
+        let start = (start as usize).min(MEM_SIZE);
+        let flags = &self.flags[start..(start + len).min(MEM_SIZE)];
+        let count = |flag: u8| flags.iter().filter(|&&flags| flags & flag != 0).count();
+
+        CoverageSummary {
+            len: flags.len(),
+            executed: count(Coverage::EXECUTED),
+            read: count(Coverage::READ),
+            written: count(Coverage::WRITTEN),
+            untouched: flags.iter().filter(|&&flags| flags == 0).count(),
+        }
+    }
+
+    fn mark(&mut self, start: usize, len: usize, flag: u8) {
+        for flags in self.flags.iter_mut().skip(start).take(len) {
+            *flags |= flag;
+        }
+    }
+}
+
+export_observer!(Coverage);
+
+impl Observer for Coverage {
+    // Where the instruction is & how long, if it's in memory at all.
+    type Before = Option<(usize, usize)>;
+
+    fn before(&mut self, emu: &CHIP8) -> Option<(usize, usize)> {
+        // Taken up front, the instruction could overwrite itself.
+        let pc = emu.pc as usize;
+        emu.memory.get(pc..pc + 2)
+            .map(|word| (pc, disassemble(u16::from(word[0]) << 8 | u16::from(word[1])).size()))
+    }
+
+    fn after(&mut self, emu: &CHIP8, before: Option<(usize, usize)>, result: &Result<(), Error>) {
+        let (pc, size) = match before {
+            Some(before) if result.is_ok() => before,
+            _ => return,
+        };
+        self.mark(pc, size, Coverage::EXECUTED);
+        if let Some(access) = emu.last_read {
+            self.mark(access.addr as usize, access.len as usize, Coverage::READ);
+        }
+        if let Some(access) = emu.last_write {
+            self.mark(access.addr as usize, access.len as usize, Coverage::WRITTEN);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{ assemble };
+
+    #[test]
+    fn test_coverage() {
+        let rom = assemble("
+                LD I, sprite
+                DRW V0, V0, 2
+                LD I, score
+                LD B, V0
+                SE V0, 0
+                CLS
+            done:
+                JP done
+            unused:
+                DB 0xAA, 0xBB
+            sprite:
+                DB 0b11110000, 0x90
+            score:
+                DB 0, 0, 0
+        ").unwrap();
+        let mut emu = CHIP8::new();
+        emu.load_rom(Some(rom.clone().into_boxed_slice()));
+        let mut coverage = Coverage::new();
+        for _ in 0..8 {
+            coverage.tick(&mut emu).unwrap();
+        }
+
+        // The skipped CLS never ran.
+        assert_eq!(coverage.flags(0x208), Coverage::EXECUTED);
+        assert_eq!(coverage.flags(0x20A), 0);
+        assert_eq!(coverage.flags(0x20E), 0);
+        assert_eq!(coverage.flags(0x210), Coverage::READ);
+        assert_eq!(coverage.flags(0x212), Coverage::WRITTEN);
+        assert_eq!(coverage.map(0x216)[0x200..0x204], [Coverage::EXECUTED; 4]);
+
+        assert_eq!(coverage.summary(0x200, rom.len()), CoverageSummary {
+            len: 21,
+            executed: 12,
+            read: 2,
+            written: 3,
+            untouched: 4,
+        });
+        assert_eq!(coverage.report(0x200, rom.len()), "\
+Coverage of 200-214 (21 bytes)
+  executed       12  57.14%
+  read            2   9.52%
+  written         3  14.29%
+  untouched       4  19.05%
+");
+        assert_eq!(coverage.listing(&emu, 0x200, rom.len()), "\
+200: LD I, 0x210
+202: DRW V0, V0, 2
+204: LD I, 0x212
+206: LD B, V0
+208: SE V0, 0x00
+20A: ; 2 unused bytes
+20C: JP 0x20C
+20E: ; 2 unused bytes
+210: DB 0xF0                ####....
+211: DB 0x90                #..#....
+212: DB 0x00                ........
+213: DB 0x00                ........
+214: DB 0x00                ........
+");
+
+        coverage.clear();
+        assert_eq!(coverage.summary(0x200, rom.len()).exercised(), 0.0);
+    }
+}
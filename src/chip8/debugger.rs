@@ -0,0 +1,373 @@
+// Debugger
+// --------
+// Breakpoints, watchpoints and stepping on top of a CHIP8. The debugger
+// doesn't own the machine, every call is handed the CHIP8 to work on so
+// the frontend can keep rendering it like it normally would.
+use wasm_bindgen::prelude::*;
+
+use super::{ CHIP8, Register, Rewind };
+
+// Default amount of instructions we can step back through.
+const HISTORY_DEPTH: usize = 1024;
+const HISTORY_BUDGET: usize = 4 * 1024 * 1024;
+
+// Why the debugger handed control back.
+#[wasm_bindgen]
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    // Finished the requested step.
+    Step,
+    // The next instruction has a breakpoint on it.
+    Breakpoint,
+    // The last instruction accessed a watched memory range.
+    Watchpoint,
+    // The last instruction faulted.
+    Fault,
+    // The program exited or the emulator is halted.
+    Halted,
+    // Ran the maximum number of instructions without stopping.
+    Limit,
+}
+
+#[wasm_bindgen]
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+// Which kind of memory accesses a watchpoint triggers on.
+#[wasm_bindgen]
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Condition {
+    register: Register,
+    cmp: Comparison,
+    value: u8,
+}
+
+impl Condition {
+    fn holds(&self, emu: &CHIP8) -> bool {
+        let value = emu.registers[self.register as usize];
+        match self.cmp {
+            Comparison::Equal => value == self.value,
+            Comparison::NotEqual => value != self.value,
+            Comparison::Less => value < self.value,
+            Comparison::LessOrEqual => value <= self.value,
+            Comparison::Greater => value > self.value,
+            Comparison::GreaterOrEqual => value >= self.value,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Breakpoint {
+    addr: u16,
+    // Only break if the condition holds.
+    condition: Option<Condition>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Watchpoint {
+    addr: u16,
+    len: u16,
+    kind: WatchKind,
+}
+
+#[wasm_bindgen]
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    // Address of the watchpoint that stopped execution last.
+    watch_hit: Option<u16>,
+    // Machine state after every instruction, for stepping backwards.
+    history: Rewind,
+    // Whether the state before the first instruction has been recorded.
+    recorded: bool,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[wasm_bindgen]
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            history: Rewind::new(HISTORY_DEPTH, HISTORY_BUDGET),
+            recorded: false,
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.push(Breakpoint { addr, condition: None });
+    }
+
+    // Breaks at `addr` only if `register <cmp> value` holds.
+    pub fn add_conditional_breakpoint(&mut self, addr: u16, register: Register, cmp: Comparison, value: u8) {
+        let condition = Some(Condition { register, cmp, value });
+        self.breakpoints.push(Breakpoint { addr, condition });
+    }
+
+    // Removes every breakpoint at `addr`, conditional or not.
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.retain(|bp| bp.addr != addr);
+    }
+
+    pub fn has_breakpoint(&self, addr: u16) -> bool {
+        self.breakpoints.iter().any(|bp| bp.addr == addr)
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    // Watches the `len` bytes starting at `addr`. Only accesses through the
+    // I register count (DXYN, FX33, FX55, FX65, ...), not instruction fetches.
+    pub fn add_watchpoint(&mut self, addr: u16, len: u16, kind: WatchKind) {
+        self.watchpoints.push(Watchpoint { addr, len, kind });
+    }
+
+    // Removes every watchpoint starting at `addr`.
+    pub fn remove_watchpoint(&mut self, addr: u16) {
+        self.watchpoints.retain(|wp| wp.addr != addr);
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints.clear();
+    }
+
+    // Start address of the watchpoint that caused the last
+    // `StopReason::Watchpoint`.
+    pub fn watch_hit(&self) -> Option<u16> {
+        self.watch_hit
+    }
+
+    // How many instructions we can step back through, 0 disables recording.
+    pub fn set_history_depth(&mut self, depth: usize) {
+        self.history.set_depth(depth);
+    }
+
+    // Needs to be called whenever the machine is changed outside of the
+    // debugger, e.g. loading a new rom.
+    pub fn clear_history(&mut self) {
+        self.history.clear();
+        self.recorded = false;
+    }
+
+    // Undoes the last instruction. Returns false if there's no history left.
+    pub fn step_back(&mut self, emu: &mut CHIP8) -> bool {
+        self.history.rewind(emu, 1) == 1
+    }
+
+    // Executes a single instruction.
+    pub fn step_into(&mut self, emu: &mut CHIP8) -> StopReason {
+        self.run_until(emu, 1, |_| true)
+    }
+
+    // Executes a single instruction, running through subroutine calls until
+    // they return.
+    pub fn step_over(&mut self, emu: &mut CHIP8, max_instructions: usize) -> StopReason {
+        let pc = emu.pc as usize;
+        let is_call = pc < emu.mem_size() && emu.memory[pc] & 0xF0 == 0x20;
+        if !is_call {
+            return self.step_into(emu);
+        }
+
+        let sp = emu.sp;
+        self.run_until(emu, max_instructions, |emu| emu.sp <= sp)
+    }
+
+    // Runs until the current subroutine returns.
+    pub fn step_out(&mut self, emu: &mut CHIP8, max_instructions: usize) -> StopReason {
+        let sp = emu.sp;
+        self.run_until(emu, max_instructions, |emu| emu.sp < sp)
+    }
+
+    // Runs until a breakpoint or watchpoint is hit, or `max_instructions`
+    // have been executed.
+    pub fn run(&mut self, emu: &mut CHIP8, max_instructions: usize) -> StopReason {
+        self.run_until(emu, max_instructions, |_| false)
+    }
+}
+
+impl Debugger {
+    // Steps until `done` returns true for the machine. A breakpoint on the
+    // instruction we start at is ignored, otherwise we could never continue
+    // after hitting one.
+    fn run_until<F>(&mut self, emu: &mut CHIP8, max_instructions: usize, done: F) -> StopReason
+        where F: Fn(&CHIP8) -> bool
+    {
+        for _ in 0..max_instructions {
+            if let Some(reason) = self.step_one(emu) {
+                return reason;
+            }
+
+            if done(emu) {
+                return StopReason::Step;
+            }
+
+            if self.breakpoint_hit(emu) {
+                return StopReason::Breakpoint;
+            }
+        }
+
+        StopReason::Limit
+    }
+
+    fn step_one(&mut self, emu: &mut CHIP8) -> Option<StopReason> {
+        if emu.fault.is_some() {
+            return Some(StopReason::Fault);
+        }
+        if emu.is_halted() {
+            return Some(StopReason::Halted);
+        }
+
+        let recording = self.history.depth() > 0;
+        if recording && !self.recorded {
+            self.history.record(emu);
+            self.recorded = true;
+        }
+
+        let result = emu.tick();
+        if recording {
+            self.history.record(emu);
+        }
+
+        if result.is_err() {
+            return Some(StopReason::Fault);
+        }
+
+        self.watch_hit = self.watchpoint_hit(emu);
+        if self.watch_hit.is_some() {
+            return Some(StopReason::Watchpoint);
+        }
+
+        None
+    }
+
+    fn breakpoint_hit(&self, emu: &CHIP8) -> bool {
+        self.breakpoints.iter().any(|bp| {
+            bp.addr == emu.pc && bp.condition.map_or(true, |cond| cond.holds(emu))
+        })
+    }
+
+    fn watchpoint_hit(&self, emu: &CHIP8) -> Option<u16> {
+        self.watchpoints.iter().find(|wp| {
+            let read = match wp.kind {
+                WatchKind::Read | WatchKind::ReadWrite => emu.last_read,
+                WatchKind::Write => None,
+            };
+            let write = match wp.kind {
+                WatchKind::Write | WatchKind::ReadWrite => emu.last_write,
+                WatchKind::Read => None,
+            };
+
+            read.into_iter().chain(write).any(|access| access.overlaps(wp.addr, wp.len))
+        }).map(|wp| wp.addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(rom: &[u8]) -> CHIP8 {
+        let mut emu = CHIP8::new();
+        emu.load_rom(Some(rom.to_vec().into_boxed_slice()));
+        emu
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        // 0x200: v0 += 1, jump back to 0x200
+        let mut emu = load(&[0x70, 0x01, 0x12, 0x00]);
+        let mut dbg = Debugger::new();
+        dbg.add_breakpoint(0x202);
+        assert_eq!(dbg.run(&mut emu, 100), StopReason::Breakpoint);
+        assert_eq!(emu.pc, 0x202);
+        // Continuing doesn't get stuck on the breakpoint we're sitting on.
+        assert_eq!(dbg.run(&mut emu, 100), StopReason::Breakpoint);
+        assert_eq!(emu.registers[0], 2);
+
+        dbg.remove_breakpoint(0x202);
+        dbg.add_conditional_breakpoint(0x200, Register::V0, Comparison::GreaterOrEqual, 10);
+        assert_eq!(dbg.run(&mut emu, 100), StopReason::Breakpoint);
+        assert_eq!(emu.registers[0], 10);
+
+        dbg.clear_breakpoints();
+        assert_eq!(dbg.run(&mut emu, 100), StopReason::Limit);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        // 0x200: I = 0x300, store v0 - v1, draw 1 byte sprite from 0x301
+        let mut emu = load(&[0xA3, 0x00, 0xF1, 0x55, 0xA3, 0x01, 0xD0, 0x01]);
+        let mut dbg = Debugger::new();
+        dbg.add_watchpoint(0x301, 1, WatchKind::Read);
+        dbg.add_watchpoint(0x301, 1, WatchKind::Write);
+        dbg.remove_watchpoint(0x301);
+        dbg.add_watchpoint(0x301, 1, WatchKind::Read);
+        dbg.add_watchpoint(0x300, 1, WatchKind::Write);
+
+        assert_eq!(dbg.run(&mut emu, 100), StopReason::Watchpoint);
+        assert_eq!(dbg.watch_hit(), Some(0x300));
+        assert_eq!(emu.pc, 0x204);
+        assert_eq!(dbg.run(&mut emu, 100), StopReason::Watchpoint);
+        assert_eq!(dbg.watch_hit(), Some(0x301));
+        assert_eq!(emu.pc, 0x208);
+    }
+
+    #[test]
+    fn test_stepping() {
+        // 0x200: call 0x206, v0 = 1, (halt)
+        // 0x206: call 0x20A, ret
+        // 0x20A: v1 = 1, ret
+        let mut emu = load(&[
+            0x22, 0x06, 0x60, 0x01, 0x00, 0x00,
+            0x22, 0x0A, 0x00, 0xEE,
+            0x61, 0x01, 0x00, 0xEE,
+        ]);
+        let mut dbg = Debugger::new();
+        assert_eq!(dbg.step_into(&mut emu), StopReason::Step);
+        assert_eq!(emu.pc, 0x206);
+        assert_eq!(dbg.step_over(&mut emu, 100), StopReason::Step);
+        assert_eq!(emu.pc, 0x208);
+        assert_eq!(emu.registers[1], 1);
+        assert_eq!(dbg.step_out(&mut emu, 100), StopReason::Step);
+        assert_eq!(emu.pc, 0x202);
+        assert_eq!(dbg.step_over(&mut emu, 100), StopReason::Step);
+        assert_eq!(emu.registers[0], 1);
+        assert_eq!(dbg.step_into(&mut emu), StopReason::Fault);
+        assert_eq!(dbg.step_into(&mut emu), StopReason::Fault);
+
+        // Step all the way back to the start.
+        let mut steps = 0;
+        while dbg.step_back(&mut emu) {
+            steps += 1;
+        }
+        assert_eq!(steps, 7);
+        assert_eq!(emu.pc, 0x200);
+        assert_eq!(emu.registers[0], 0);
+        assert_eq!(emu.registers[1], 0);
+        assert!(!emu.is_halted());
+    }
+}
@@ -0,0 +1,325 @@
+// Differential testing
+// --------------------
+// Runs the same machine two ways and stops at the first instruction where
+// they disagree. `lockstep` runs two copies of a machine side by side, say
+// one on the interpreter and one on the JIT, `compare_trace` compares a
+// run against a trace recorded earlier, by another build or a reference
+// interpreter that writes the format in `trace.rs`.
+//
+// Both sides have to see the same input. Start from the same machine (same
+// rom, seed, mode & quirks) and apply inputs by cycle, e.g. with
+// `Movie::apply_inputs`, rather than by wall clock time.
+use std::fmt;
+use std::fmt::Write;
+
+use super::{ disassemble, Error, Observer, Tracer, CHIP8, STACK_SIZE };
+use super::trace::{ REGISTER_NAMES };
+
+// Bytes per row of the memory comparison and most rows shown.
+const BYTES_PER_ROW: usize = 8;
+const MEMORY_ROWS: usize = 8;
+// Matching trace lines shown before the one that differs.
+const TRACE_CONTEXT: usize = 3;
+
+// Where two runs of a machine went their separate ways.
+pub struct Divergence {
+    // Instructions both ran before the one that differed.
+    pub cycle: u64,
+    // The state both sides started that instruction from.
+    pub before: CHIP8,
+    pub left: CHIP8,
+    pub right: CHIP8,
+    pub left_result: Result<(), Error>,
+    pub right_result: Result<(), Error>,
+}
+
+impl Divergence {
+    // Names of what differs: registers, "memory", "display" or "result".
+    pub fn differences(&self) -> Vec<&'static str> {
+        let (left, right) = (&self.left, &self.right);
+        let mut names: Vec<&'static str> = REGISTER_NAMES.iter()
+            .zip(left.registers.iter().zip(right.registers.iter()))
+            .filter(|&(_, (a, b))| a != b)
+            .map(|(&name, _)| name)
+            .collect();
+        for &(name, differs) in [
+            ("I", left.i_reg != right.i_reg),
+            ("PC", left.pc != right.pc),
+            ("SP", left.sp != right.sp),
+            ("stack", left.stack[..] != right.stack[..]),
+            ("memory", left.memory[..] != right.memory[..]),
+            ("display", left.display[..] != right.display[..] || left.hires != right.hires),
+            ("result", self.left_result != self.right_result || left.exited != right.exited),
+        ].iter() {
+            if differs {
+                names.push(name);
+            }
+        }
+
+        names
+    }
+
+    // Both states side by side under the given names, differing lines are
+    // marked with a `<`.
+    pub fn report(&self, left_name: &str, right_name: &str) -> String {
+        let (left, right) = (&self.left, &self.right);
+        let pc = self.before.pc as usize;
+        let mut out = String::new();
+        match self.before.memory.get(pc..pc + 2) {
+            Some(word) => {
+                let opcode = u16::from(word[0]) << 8 | u16::from(word[1]);
+                let _ = writeln!(out, "Diverged after {} instructions, at {:04X}: {:04X}  {}", self.cycle, pc, opcode, disassemble(opcode));
+            },
+            None => { let _ = writeln!(out, "Diverged after {} instructions, at {:04X}", self.cycle, pc); },
+        }
+
+        let _ = writeln!(out, "  {:<12} {:<24} {}", "", left_name, right_name);
+        let mut row = |name: &str, a: String, b: String| {
+            if a == b {
+                let _ = writeln!(out, "  {:<12} {:<24} {}", name, a, b);
+            } else {
+                let _ = writeln!(out, "  {:<12} {:<24} {:<24} <", name, a, b);
+            }
+        };
+        for (idx, name) in REGISTER_NAMES.iter().enumerate() {
+            row(name, format!("{:02X}", left.registers[idx]), format!("{:02X}", right.registers[idx]));
+        }
+        row("I", format!("{:04X}", left.i_reg), format!("{:04X}", right.i_reg));
+        row("PC", format!("{:04X}", left.pc), format!("{:04X}", right.pc));
+        row("SP", format!("{:02X}", left.sp), format!("{:02X}", right.sp));
+        let stack = |emu: &CHIP8| {
+            let top = (emu.sp as usize).min(STACK_SIZE - 1);
+            emu.stack[1..=top].iter().map(|addr| format!("{:04X}", addr)).collect::<Vec<_>>().join(" ")
+        };
+        row("stack", stack(left), stack(right));
+        let result = |result: &Result<(), Error>, emu: &CHIP8| match *result {
+            Err(ref fault) => format!("{}", fault),
+            Ok(()) if emu.exited => "exited".to_string(),
+            Ok(()) => "ok".to_string(),
+        };
+        row("result", result(&self.left_result, left), result(&self.right_result, right));
+
+        let rows = left.memory.chunks(BYTES_PER_ROW).zip(right.memory.chunks(BYTES_PER_ROW))
+            .enumerate()
+            .filter(|&(_, (a, b))| a != b);
+        let hex = |bytes: &[u8]| bytes.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(" ");
+        for (idx, (a, b)) in rows.take(MEMORY_ROWS) {
+            row(&format!("[{:04X}]", idx * BYTES_PER_ROW), hex(a), hex(b));
+        }
+
+        if left.display[..] != right.display[..] || left.hires != right.hires {
+            let (a, b) = (left.to_string(), right.to_string());
+            let width = a.lines().map(|line| line.chars().count()).max().unwrap_or(0);
+            let _ = writeln!(out, "  display");
+            let mut lines = (a.lines(), b.lines());
+            loop {
+                match (lines.0.next(), lines.1.next()) {
+                    (None, None) => break,
+                    (a, b) => {
+                        let (a, b) = (a.unwrap_or(""), b.unwrap_or(""));
+                        let mark = if a != b { " <" } else { "" };
+                        let _ = writeln!(out, "  {:<width$}  {}{}", a, b, mark, width = width);
+                    },
+                }
+            }
+        }
+
+        out
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.report("left", "right"))
+    }
+}
+
+impl fmt::Debug for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Divergence {{ cycle: {}, differences: {:?} }}", self.cycle, self.differences())
+    }
+}
+
+// Runs copies of `left` & `right` through `step_left` & `step_right` until
+// `cycles` instructions ran, comparing the machines after every step. Both
+// should start out as the same machine, possibly on different backends.
+// Steps should run a single instruction to pinpoint the first one that
+// differs. Stops early, without an error, once both sides halted the same
+// way.
+pub fn lockstep<L, R>(left: &CHIP8, right: &CHIP8, cycles: u64, mut step_left: L, mut step_right: R) -> Result<(), Box<Divergence>>
+    where L: FnMut(&mut CHIP8) -> Result<(), Error>,
+          R: FnMut(&mut CHIP8) -> Result<(), Error>
+{
+    let mut divergence = Box::new(Divergence {
+        cycle: left.cycles,
+        before: left.clone(),
+        left: left.clone(),
+        right: right.clone(),
+        left_result: Ok(()),
+        right_result: Ok(()),
+    });
+
+    while divergence.left.cycles < left.cycles + cycles {
+        divergence.cycle = divergence.left.cycles;
+        divergence.before = divergence.left.clone();
+        divergence.left_result = step_left(&mut divergence.left);
+        divergence.right_result = step_right(&mut divergence.right);
+        if !divergence.differences().is_empty() || divergence.left.cycles != divergence.right.cycles {
+            return Err(divergence);
+        }
+        if divergence.left.is_halted() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+// A run that didn't match the reference trace.
+#[derive(Debug)]
+pub struct TraceDivergence {
+    // Line of the reference trace that differed, starting at 1.
+    pub line: usize,
+    // The lines up to and including the one that differed, from the
+    // reference & this run. The last reference line is empty if the
+    // reference ended first.
+    pub reference: Vec<String>,
+    pub actual: Vec<String>,
+}
+
+impl fmt::Display for TraceDivergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Diverged from the reference trace at line {}", self.line)?;
+        let width = self.reference.iter().map(|line| line.len()).max().unwrap_or(0).max("reference".len());
+        writeln!(f, "  {:<width$}  ours", "reference", width = width)?;
+        for (idx, (a, b)) in self.reference.iter().zip(self.actual.iter()).enumerate() {
+            let mark = if idx + 1 == self.reference.len() { " <" } else { "" };
+            writeln!(f, "  {:<width$}  {}{}", a, b, mark, width = width)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Runs `emu` through `step` for every line of `reference` and compares the
+// trace of each instruction with it. Faults end the run like any other
+// line, the reference should end there too.
+pub fn compare_trace<F>(emu: &mut CHIP8, reference: &str, mut step: F) -> Result<(), TraceDivergence>
+    where F: FnMut(&mut CHIP8) -> Result<(), Error>
+{
+    let reference: Vec<&str> = reference.lines().map(str::trim_end).filter(|line| !line.is_empty()).collect();
+    let mut tracer = Tracer::new(1);
+    let mut actual: Vec<String> = Vec::new();
+    let diverged = |idx: usize, actual: &[String]| {
+        let start = idx.saturating_sub(TRACE_CONTEXT);
+        let mut expected: Vec<String> = reference[start..idx].iter().map(|line| line.to_string()).collect();
+        expected.push(reference.get(idx).map_or(String::new(), |line| line.to_string()));
+        TraceDivergence { line: idx + 1, reference: expected, actual: actual[start..].to_vec() }
+    };
+
+    for (idx, &expected) in reference.iter().enumerate() {
+        if emu.is_halted() {
+            actual.push("(halted)".to_string());
+            return Err(diverged(idx, &actual));
+        }
+
+        let result = tracer.record(emu, &mut step);
+        actual.push(tracer.lines().trim_end().to_string());
+        if actual[idx] != expected {
+            return Err(diverged(idx, &actual));
+        }
+        if result.is_err() && idx + 1 < reference.len() {
+            actual.push("(halted)".to_string());
+            return Err(diverged(idx + 1, &actual));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{ assemble, Backend };
+
+    fn program(source: &str) -> CHIP8 {
+        let mut emu = CHIP8::new();
+        emu.load_rom(Some(assemble(source).unwrap().into_boxed_slice()));
+        emu
+    }
+
+    const COUNTER: &str = "
+            LD I, 0x300
+        loop:
+            ADD V0, 1
+            LD B, V0
+            SE V0, 5
+            JP loop
+            LD F, V0
+            DRW V1, V1, 5
+        halt:
+            JP halt
+    ";
+
+    #[test]
+    fn test_lockstep() {
+        let emu = program(COUNTER);
+        for &backend in Backend::ALL.iter() {
+            let mut other = emu.clone();
+            other.set_backend(backend);
+            assert!(lockstep(&emu, &other, 100, CHIP8::tick, CHIP8::tick).is_ok());
+        }
+
+        // An engine that gets ADD wrong on the third time around.
+        let broken = |emu: &mut CHIP8| {
+            emu.tick()?;
+            if emu.cycles == 10 {
+                emu.registers[0] += 1;
+            }
+            Ok(())
+        };
+        let divergence = lockstep(&emu, &emu, 100, CHIP8::tick, broken).unwrap_err();
+        assert_eq!(divergence.cycle, 9);
+        assert_eq!(divergence.before.pc, 0x202);
+        assert_eq!(divergence.differences(), ["V0"]);
+
+        let report = divergence.report("interpreter", "broken");
+        assert!(report.starts_with("Diverged after 9 instructions, at 0202: 7001  ADD V0, 0x01\n"));
+        assert!(report.contains("  V0           03                       04                       <\n"));
+        assert!(report.contains("  V1           00                       00\n"));
+
+        // Display differences show both screens.
+        let blank = |emu: &mut CHIP8| {
+            emu.tick()?;
+            emu.display[0] = 0;
+            Ok(())
+        };
+        let divergence = lockstep(&emu, &emu, 100, CHIP8::tick, blank).unwrap_err();
+        assert_eq!(divergence.differences(), ["display"]);
+        assert!(divergence.report("a", "b").contains("  display\n  ◼◼◼◼"));
+    }
+
+    #[test]
+    fn test_compare_trace() {
+        let mut emu = program(COUNTER);
+        let mut tracer = Tracer::new(100);
+        for _ in 0..12 {
+            tracer.tick(&mut emu).unwrap();
+        }
+        let reference = tracer.lines();
+
+        let mut emu = program(COUNTER);
+        assert!(compare_trace(&mut emu, &reference, CHIP8::tick).is_ok());
+        assert_eq!(emu.cycles(), 12);
+
+        // A reference where the fifth instruction loaded a different value.
+        let changed = reference.replacen("[0300]=000001", "[0300]=000002", 1);
+        let mut emu = program(COUNTER);
+        let divergence = compare_trace(&mut emu, &changed, CHIP8::tick).unwrap_err();
+        assert_eq!(divergence.line, 3);
+        assert_eq!(divergence.reference.len(), 3);
+        assert!(divergence.reference[2].ends_with("[0300]=000002"));
+        assert!(divergence.actual[2].ends_with("[0300]=000001"));
+        assert!(divergence.to_string().starts_with("Diverged from the reference trace at line 3\n  reference"));
+    }
+}
@@ -0,0 +1,226 @@
+// Dirty regions
+// -------------
+// Keeps track of which parts of the display changed since the frontend last
+// drew it, so it only has to repaint those (or nothing at all). Sprites mark
+// the rectangles they were drawn to, everything that moves or clears the
+// whole display marks all of it.
+//
+// Rectangles are in pixels of the active resolution. Touching or overlapping
+// rectangles are merged, and once there are too many to keep around the new
+// one is merged with whichever grows the least.
+use wasm_bindgen::prelude::*;
+
+use super::{ CHIP8 };
+
+// Enough for a handful of sprites per frame, most roms draw far fewer.
+const MAX_REGIONS: usize = 8;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u8,
+    pub y: u8,
+    pub width: u8,
+    pub height: u8,
+}
+
+impl Rect {
+    fn right(&self) -> usize { self.x as usize + self.width as usize }
+    fn bottom(&self) -> usize { self.y as usize + self.height as usize }
+    fn area(&self) -> usize { self.width as usize * self.height as usize }
+
+    fn touches(&self, other: &Rect) -> bool {
+        self.x as usize <= other.right() && other.x as usize <= self.right()
+            && self.y as usize <= other.bottom() && other.y as usize <= self.bottom()
+    }
+
+    fn union(&self, other: &Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Rect {
+            x,
+            y,
+            width: (self.right().max(other.right()) - x as usize) as u8,
+            height: (self.bottom().max(other.bottom()) - y as usize) as u8,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) struct Dirty {
+    regions: [Rect; MAX_REGIONS],
+    len: usize,
+}
+
+impl Dirty {
+    pub(super) fn new() -> Dirty {
+        Dirty { regions: [Rect::default(); MAX_REGIONS], len: 0 }
+    }
+
+    pub(super) fn regions(&self) -> &[Rect] {
+        &self.regions[..self.len]
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub(super) fn add(&mut self, mut rect: Rect) {
+        if rect.area() == 0 {
+            return;
+        }
+
+        // Merging can make the rectangle touch others it didn't before.
+        while let Some(idx) = self.regions().iter().position(|region| region.touches(&rect)) {
+            rect = rect.union(&self.regions[idx]);
+            self.len -= 1;
+            self.regions[idx] = self.regions[self.len];
+        }
+
+        if self.len < MAX_REGIONS {
+            self.regions[self.len] = rect;
+            self.len += 1;
+            return;
+        }
+
+        let growth = |region: &Rect| region.union(&rect).area() - region.area();
+        let idx = (0..self.len).min_by_key(|&idx| growth(&self.regions[idx])).unwrap();
+        self.regions[idx] = self.regions[idx].union(&rect);
+    }
+}
+
+impl CHIP8 {
+    // Marks the whole display as changed.
+    pub(super) fn mark_display(&mut self) {
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: self.display_width() as u8,
+            height: self.display_height() as u8,
+        };
+        self.dirty.clear();
+        self.dirty.add(rect);
+    }
+
+    // Marks the pixels a sprite drawn at (x, y) could have touched, split
+    // up the same way `draw_plane` wraps or clips it.
+    pub(super) fn mark_sprite(&mut self, x: u8, y: u8, width: usize, rows: usize) {
+        let clipping = self.quirks.clipping;
+        let spans = |start: usize, len: usize, size: usize| {
+            let start = start % size;
+            let first = len.min(size - start);
+            let rest = if clipping { 0 } else { (len - first).min(size) };
+            [(start, first), (0, rest)]
+        };
+
+        for &(y, height) in spans(y as usize, rows, self.display_height()).iter() {
+            for &(x, width) in spans(x as usize, width, self.display_width()).iter() {
+                self.dirty.add(Rect { x: x as u8, y: y as u8, width: width as u8, height: height as u8 });
+            }
+        }
+    }
+
+    // Rectangles of the display that changed since `clear_dirty`.
+    pub fn dirty_regions(&self) -> &[Rect] {
+        self.dirty.regions()
+    }
+}
+
+#[wasm_bindgen]
+impl CHIP8 {
+    // Whether anything was drawn since `clear_dirty`.
+    pub fn is_dirty(&self) -> bool {
+        !self.dirty.regions().is_empty()
+    }
+
+    // The changed rectangles as a flat list of x, y, width, height.
+    pub fn dirty_rects(&self) -> Vec<u8> {
+        self.dirty.regions()
+            .iter()
+            .flat_map(|rect| vec![rect.x, rect.y, rect.width, rect.height])
+            .collect()
+    }
+
+    // Call once the display has been drawn.
+    pub fn clear_dirty(&mut self) {
+        self.dirty.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{ Mode };
+
+    fn rect(x: u8, y: u8, width: u8, height: u8) -> Rect {
+        Rect { x, y, width, height }
+    }
+
+    fn run(emu: &mut CHIP8, rom: &[u8]) {
+        emu.load_rom(Some(rom.to_vec().into_boxed_slice()));
+        emu.clear_dirty();
+        for _ in 0..rom.len() / 2 {
+            emu.tick().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_merging() {
+        let mut dirty = Dirty::new();
+        dirty.add(rect(0, 0, 8, 5));
+        dirty.add(rect(20, 20, 8, 5));
+        // Touches the first one.
+        dirty.add(rect(8, 2, 8, 5));
+        assert_eq!(dirty.regions(), &[rect(20, 20, 8, 5), rect(0, 0, 16, 7)]);
+        // Bridges both.
+        dirty.add(rect(10, 6, 12, 16));
+        assert_eq!(dirty.regions(), &[rect(0, 0, 28, 25)]);
+        dirty.add(rect(4, 4, 0, 8));
+        assert_eq!(dirty.regions().len(), 1);
+
+        let mut dirty = Dirty::new();
+        for idx in 0..MAX_REGIONS as u8 {
+            dirty.add(rect(idx * 8, 0, 1, 1));
+        }
+        dirty.add(rect(2, 2, 1, 1));
+        assert_eq!(dirty.regions().len(), MAX_REGIONS);
+        assert_eq!(dirty.regions()[0], rect(0, 0, 3, 3));
+    }
+
+    #[test]
+    fn test_drawing() {
+        let mut emu = CHIP8::new();
+        assert_eq!(emu.dirty_regions(), &[rect(0, 0, 64, 32)]);
+
+        // Clipped sprites only mark what's on screen.
+        let mut quirks = emu.quirks();
+        quirks.clipping = true;
+        emu.set_quirks(quirks);
+        // V0 = 60, V1 = 30, draw 5 rows at (V0, V1) and at (V1, V1).
+        run(&mut emu, &[0x60, 0x3C, 0x61, 0x1E, 0xD0, 0x15, 0xD1, 0x15]);
+        assert!(emu.is_dirty());
+        assert_eq!(emu.dirty_regions(), &[rect(60, 30, 4, 2), rect(30, 30, 8, 2)]);
+        assert_eq!(emu.dirty_rects(), vec![60, 30, 4, 2, 30, 30, 8, 2]);
+        emu.clear_dirty();
+        assert!(!emu.is_dirty());
+
+        // Without clipping the sprite wraps around to all four corners.
+        let mut emu = CHIP8::new();
+        run(&mut emu, &[0x60, 0x3C, 0x61, 0x1E, 0xD0, 0x15]);
+        assert_eq!(emu.dirty_regions(), &[
+            rect(60, 30, 4, 2), rect(0, 30, 4, 2), rect(60, 0, 4, 3), rect(0, 0, 4, 3),
+        ]);
+
+        // Clearing and scrolling touch everything.
+        run(&mut emu, &[0x00, 0xE0]);
+        assert_eq!(emu.dirty_regions(), &[rect(0, 0, 64, 32)]);
+        emu.set_mode(Mode::SuperChip);
+        run(&mut emu, &[0x00, 0xFF, 0x00, 0xFB]);
+        assert_eq!(emu.dirty_regions(), &[rect(0, 0, 128, 64)]);
+
+        // As does restoring a save state.
+        let state = emu.save_state();
+        emu.clear_dirty();
+        emu.load_state(&state).unwrap();
+        assert_eq!(emu.dirty_regions(), &[rect(0, 0, 128, 64)]);
+    }
+}
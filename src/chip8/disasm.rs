@@ -0,0 +1,476 @@
+// Disassembler
+// ------------
+// Decodes opcodes into instructions using the mnemonics from Cowgod's
+// technical reference, extended with the SUPER-CHIP and XO-CHIP
+// instructions. Every instruction set is decoded regardless of the mode the
+// emulator runs in.
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fmt::Write;
+use wasm_bindgen::prelude::*;
+
+use super::{ CHIP8 };
+
+// Registers are stored as their index, 0x0 through 0xF.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    // 00E0
+    Cls,
+    // 00EE
+    Ret,
+    // 0NNN, machine code routine on the COSMAC VIP.
+    Sys(u16),
+    // 00CN
+    Scd(u8),
+    // 00DN
+    Scu(u8),
+    // 00FB
+    Scr,
+    // 00FC
+    Scl,
+    // 00FD
+    Exit,
+    // 00FE
+    Low,
+    // 00FF
+    High,
+    // 1NNN
+    Jp(u16),
+    // 2NNN
+    Call(u16),
+    // 3XNN
+    SeByte(u8, u8),
+    // 4XNN
+    SneByte(u8, u8),
+    // 5XY0
+    SeReg(u8, u8),
+    // 5XY2
+    Save(u8, u8),
+    // 5XY3
+    Load(u8, u8),
+    // 6XNN
+    LdByte(u8, u8),
+    // 7XNN
+    AddByte(u8, u8),
+    // 8XY0
+    LdReg(u8, u8),
+    // 8XY1
+    Or(u8, u8),
+    // 8XY2
+    And(u8, u8),
+    // 8XY3
+    Xor(u8, u8),
+    // 8XY4
+    AddReg(u8, u8),
+    // 8XY5
+    Sub(u8, u8),
+    // 8XY6
+    Shr(u8, u8),
+    // 8XY7
+    Subn(u8, u8),
+    // 8XYE
+    Shl(u8, u8),
+    // 9XY0
+    SneReg(u8, u8),
+    // ANNN
+    LdI(u16),
+    // BNNN
+    JpV0(u16),
+    // CXNN
+    Rnd(u8, u8),
+    // DXYN
+    Drw(u8, u8, u8),
+    // EX9E
+    Skp(u8),
+    // EXA1
+    Sknp(u8),
+    // F000 NNNN, the address is stored in the two bytes that follow.
+    LdILong,
+    // FN01
+    Plane(u8),
+    // F002
+    Audio,
+    // FX07
+    LdVxDt(u8),
+    // FX0A
+    LdVxK(u8),
+    // FX15
+    LdDtVx(u8),
+    // FX18
+    LdStVx(u8),
+    // FX1E
+    AddI(u8),
+    // FX29
+    LdF(u8),
+    // FX30
+    LdHf(u8),
+    // FX33
+    LdB(u8),
+    // FX3A
+    Pitch(u8),
+    // FX55
+    LdIVx(u8),
+    // FX65
+    LdVxI(u8),
+    // FX75
+    LdRVx(u8),
+    // FX85
+    LdVxR(u8),
+    // Anything else, most likely data.
+    Unknown(u16),
+}
+
+pub fn disassemble(opcode: u16) -> Instruction {
+    let addr = opcode & 0x0FFF;
+    let lower = (opcode & 0x00FF) as u8;
+    let nibble = (opcode & 0x000F) as u8;
+    let x = ((opcode & 0x0F00) >> 8) as u8;
+    let y = ((opcode & 0x00F0) >> 4) as u8;
+
+    match opcode & 0xF000 {
+        0x0000 => match opcode {
+            0x00E0 => Instruction::Cls,
+            0x00EE => Instruction::Ret,
+            0x00C0..=0x00CF => Instruction::Scd(nibble),
+            0x00D0..=0x00DF => Instruction::Scu(nibble),
+            0x00FB => Instruction::Scr,
+            0x00FC => Instruction::Scl,
+            0x00FD => Instruction::Exit,
+            0x00FE => Instruction::Low,
+            0x00FF => Instruction::High,
+            // An all zero word is much more likely to be data.
+            0x0000 => Instruction::Unknown(opcode),
+            _ => Instruction::Sys(addr),
+        },
+        0x1000 => Instruction::Jp(addr),
+        0x2000 => Instruction::Call(addr),
+        0x3000 => Instruction::SeByte(x, lower),
+        0x4000 => Instruction::SneByte(x, lower),
+        0x5000 => match nibble {
+            0 => Instruction::SeReg(x, y),
+            2 => Instruction::Save(x, y),
+            3 => Instruction::Load(x, y),
+            _ => Instruction::Unknown(opcode),
+        },
+        0x6000 => Instruction::LdByte(x, lower),
+        0x7000 => Instruction::AddByte(x, lower),
+        0x8000 => match nibble {
+            0x0 => Instruction::LdReg(x, y),
+            0x1 => Instruction::Or(x, y),
+            0x2 => Instruction::And(x, y),
+            0x3 => Instruction::Xor(x, y),
+            0x4 => Instruction::AddReg(x, y),
+            0x5 => Instruction::Sub(x, y),
+            0x6 => Instruction::Shr(x, y),
+            0x7 => Instruction::Subn(x, y),
+            0xE => Instruction::Shl(x, y),
+            _ => Instruction::Unknown(opcode),
+        },
+        0x9000 if nibble == 0 => Instruction::SneReg(x, y),
+        0xA000 => Instruction::LdI(addr),
+        0xB000 => Instruction::JpV0(addr),
+        0xC000 => Instruction::Rnd(x, lower),
+        0xD000 => Instruction::Drw(x, y, nibble),
+        0xE000 => match lower {
+            0x9E => Instruction::Skp(x),
+            0xA1 => Instruction::Sknp(x),
+            _ => Instruction::Unknown(opcode),
+        },
+        0xF000 => match lower {
+            0x00 if x == 0 => Instruction::LdILong,
+            0x01 => Instruction::Plane(x),
+            0x02 if x == 0 => Instruction::Audio,
+            0x07 => Instruction::LdVxDt(x),
+            0x0A => Instruction::LdVxK(x),
+            0x15 => Instruction::LdDtVx(x),
+            0x18 => Instruction::LdStVx(x),
+            0x1E => Instruction::AddI(x),
+            0x29 => Instruction::LdF(x),
+            0x30 => Instruction::LdHf(x),
+            0x33 => Instruction::LdB(x),
+            0x3A => Instruction::Pitch(x),
+            0x55 => Instruction::LdIVx(x),
+            0x65 => Instruction::LdVxI(x),
+            0x75 => Instruction::LdRVx(x),
+            0x85 => Instruction::LdVxR(x),
+            _ => Instruction::Unknown(opcode),
+        },
+        _ => Instruction::Unknown(opcode),
+    }
+}
+
+impl Instruction {
+    // Size of the instruction in bytes, including any operands that follow.
+    pub fn size(&self) -> usize {
+        match *self {
+            Instruction::LdILong => 4,
+            _ => 2,
+        }
+    }
+
+    // Address the instruction jumps or calls to, if known ahead of time.
+    pub fn target(&self) -> Option<u16> {
+        match *self {
+            Instruction::Jp(addr) | Instruction::Call(addr) | Instruction::JpV0(addr) => Some(addr),
+            _ => None,
+        }
+    }
+
+    // Opcode pattern the instruction belongs to, e.g. "DXYN".
+    pub fn pattern(&self) -> &'static str {
+        match *self {
+            Instruction::Cls => "00E0",
+            Instruction::Ret => "00EE",
+            Instruction::Sys(_) => "0NNN",
+            Instruction::Scd(_) => "00CN",
+            Instruction::Scu(_) => "00DN",
+            Instruction::Scr => "00FB",
+            Instruction::Scl => "00FC",
+            Instruction::Exit => "00FD",
+            Instruction::Low => "00FE",
+            Instruction::High => "00FF",
+            Instruction::Jp(_) => "1NNN",
+            Instruction::Call(_) => "2NNN",
+            Instruction::SeByte(..) => "3XNN",
+            Instruction::SneByte(..) => "4XNN",
+            Instruction::SeReg(..) => "5XY0",
+            Instruction::Save(..) => "5XY2",
+            Instruction::Load(..) => "5XY3",
+            Instruction::LdByte(..) => "6XNN",
+            Instruction::AddByte(..) => "7XNN",
+            Instruction::LdReg(..) => "8XY0",
+            Instruction::Or(..) => "8XY1",
+            Instruction::And(..) => "8XY2",
+            Instruction::Xor(..) => "8XY3",
+            Instruction::AddReg(..) => "8XY4",
+            Instruction::Sub(..) => "8XY5",
+            Instruction::Shr(..) => "8XY6",
+            Instruction::Subn(..) => "8XY7",
+            Instruction::Shl(..) => "8XYE",
+            Instruction::SneReg(..) => "9XY0",
+            Instruction::LdI(_) => "ANNN",
+            Instruction::JpV0(_) => "BNNN",
+            Instruction::Rnd(..) => "CXNN",
+            Instruction::Drw(..) => "DXYN",
+            Instruction::Skp(_) => "EX9E",
+            Instruction::Sknp(_) => "EXA1",
+            Instruction::LdILong => "F000",
+            Instruction::Plane(_) => "FN01",
+            Instruction::Audio => "F002",
+            Instruction::LdVxDt(_) => "FX07",
+            Instruction::LdVxK(_) => "FX0A",
+            Instruction::LdDtVx(_) => "FX15",
+            Instruction::LdStVx(_) => "FX18",
+            Instruction::AddI(_) => "FX1E",
+            Instruction::LdF(_) => "FX29",
+            Instruction::LdHf(_) => "FX30",
+            Instruction::LdB(_) => "FX33",
+            Instruction::Pitch(_) => "FX3A",
+            Instruction::LdIVx(_) => "FX55",
+            Instruction::LdVxI(_) => "FX65",
+            Instruction::LdRVx(_) => "FX75",
+            Instruction::LdVxR(_) => "FX85",
+            Instruction::Unknown(_) => "????",
+        }
+    }
+
+    // Formats the instruction, using `label` to name addresses.
+    fn write<F>(&self, f: &mut dyn fmt::Write, label: F) -> fmt::Result
+        where F: Fn(u16) -> String
+    {
+        match *self {
+            Instruction::Cls => write!(f, "CLS"),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::Sys(addr) => write!(f, "SYS {}", label(addr)),
+            Instruction::Scd(n) => write!(f, "SCD {}", n),
+            Instruction::Scu(n) => write!(f, "SCU {}", n),
+            Instruction::Scr => write!(f, "SCR"),
+            Instruction::Scl => write!(f, "SCL"),
+            Instruction::Exit => write!(f, "EXIT"),
+            Instruction::Low => write!(f, "LOW"),
+            Instruction::High => write!(f, "HIGH"),
+            Instruction::Jp(addr) => write!(f, "JP {}", label(addr)),
+            Instruction::Call(addr) => write!(f, "CALL {}", label(addr)),
+            Instruction::SeByte(x, byte) => write!(f, "SE V{:X}, {:#04X}", x, byte),
+            Instruction::SneByte(x, byte) => write!(f, "SNE V{:X}, {:#04X}", x, byte),
+            Instruction::SeReg(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            Instruction::Save(x, y) => write!(f, "SAVE V{:X}, V{:X}", x, y),
+            Instruction::Load(x, y) => write!(f, "LOAD V{:X}, V{:X}", x, y),
+            Instruction::LdByte(x, byte) => write!(f, "LD V{:X}, {:#04X}", x, byte),
+            Instruction::AddByte(x, byte) => write!(f, "ADD V{:X}, {:#04X}", x, byte),
+            Instruction::LdReg(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            Instruction::Or(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            Instruction::And(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            Instruction::Xor(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Instruction::AddReg(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Instruction::Sub(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Instruction::Shr(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Instruction::Subn(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Instruction::Shl(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Instruction::SneReg(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Instruction::LdI(addr) => write!(f, "LD I, {:#05X}", addr),
+            Instruction::JpV0(addr) => write!(f, "JP V0, {}", label(addr)),
+            Instruction::Rnd(x, byte) => write!(f, "RND V{:X}, {:#04X}", x, byte),
+            Instruction::Drw(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::Skp(x) => write!(f, "SKP V{:X}", x),
+            Instruction::Sknp(x) => write!(f, "SKNP V{:X}", x),
+            Instruction::LdILong => write!(f, "LD I, LONG"),
+            Instruction::Plane(n) => write!(f, "PLANE {}", n),
+            Instruction::Audio => write!(f, "AUDIO"),
+            Instruction::LdVxDt(x) => write!(f, "LD V{:X}, DT", x),
+            Instruction::LdVxK(x) => write!(f, "LD V{:X}, K", x),
+            Instruction::LdDtVx(x) => write!(f, "LD DT, V{:X}", x),
+            Instruction::LdStVx(x) => write!(f, "LD ST, V{:X}", x),
+            Instruction::AddI(x) => write!(f, "ADD I, V{:X}", x),
+            Instruction::LdF(x) => write!(f, "LD F, V{:X}", x),
+            Instruction::LdHf(x) => write!(f, "LD HF, V{:X}", x),
+            Instruction::LdB(x) => write!(f, "LD B, V{:X}", x),
+            Instruction::Pitch(x) => write!(f, "PITCH V{:X}", x),
+            Instruction::LdIVx(x) => write!(f, "LD [I], V{:X}", x),
+            Instruction::LdVxI(x) => write!(f, "LD V{:X}, [I]", x),
+            Instruction::LdRVx(x) => write!(f, "LD R, V{:X}", x),
+            Instruction::LdVxR(x) => write!(f, "LD V{:X}, R", x),
+            Instruction::Unknown(word) => write!(f, "DW {:#06X}", word),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, |addr| format!("{:#05X}", addr))
+    }
+}
+
+fn label_name(addr: u16) -> String {
+    format!("L{:03X}", addr)
+}
+
+// Decodes `bytes` into (address, instruction) pairs with a linear sweep,
+// treating everything as code. A trailing odd byte is left out.
+fn decode_all(bytes: &[u8], origin: u16) -> Vec<(u16, Instruction, Option<u16>)> {
+    let mut decoded = Vec::new();
+    let mut idx = 0;
+    while idx + 1 < bytes.len() {
+        let opcode = u16::from(bytes[idx]) << 8 | u16::from(bytes[idx + 1]);
+        let instr = disassemble(opcode);
+        let addr = origin.wrapping_add(idx as u16);
+        if instr.size() == 4 {
+            if idx + 3 < bytes.len() {
+                let operand = u16::from(bytes[idx + 2]) << 8 | u16::from(bytes[idx + 3]);
+                decoded.push((addr, instr, Some(operand)));
+                idx += 4;
+                continue;
+            }
+            // The operand got cut off, it can't be an instruction.
+            decoded.push((addr, Instruction::Unknown(opcode), None));
+        } else {
+            decoded.push((addr, instr, None));
+        }
+        idx += 2;
+    }
+
+    decoded
+}
+
+// Turns a rom loaded at `origin` into an assembly listing. Jump & call
+// targets that land on an instruction in the listing get a label, each line
+// is followed by its address and raw bytes as a comment.
+#[wasm_bindgen]
+pub fn listing(rom: &[u8], origin: u16) -> String {
+    let decoded = decode_all(rom, origin);
+    let lines: BTreeSet<u16> = decoded.iter().map(|&(addr, _, _)| addr).collect();
+    let labels: BTreeSet<u16> = decoded
+        .iter()
+        .filter_map(|&(_, instr, _)| instr.target())
+        .filter(|addr| lines.contains(addr))
+        .collect();
+    let label = |addr: u16| {
+        if labels.contains(&addr) { label_name(addr) } else { format!("{:#05X}", addr) }
+    };
+
+    let mut out = String::new();
+    for &(addr, instr, operand) in decoded.iter() {
+        if labels.contains(&addr) {
+            out.push_str(&label_name(addr));
+            out.push_str(":\n");
+        }
+
+        let offset = addr.wrapping_sub(origin) as usize;
+        let raw = &rom[offset..offset + instr.size()];
+        let mut text = String::new();
+        match operand {
+            Some(long) => { let _ = write!(text, "LD I, LONG {:#06X}", long); },
+            None => { let _ = instr.write(&mut text, label); },
+        }
+        let raw: Vec<String> = raw.iter().map(|byte| format!("{:02X}", byte)).collect();
+        let _ = writeln!(out, "    {:<24}; {:03X}: {}", text, addr, raw.join(""));
+    }
+
+    if rom.len() % 2 == 1 && decoded.iter().map(|&(_, instr, _)| instr.size()).sum::<usize>() < rom.len() {
+        let addr = origin.wrapping_add(rom.len() as u16 - 1);
+        let byte = rom[rom.len() - 1];
+        let _ = writeln!(out, "    {:<24}; {:03X}: {:02X}", format!("DB {:#04X}", byte), addr, byte);
+    }
+
+    out
+}
+
+#[wasm_bindgen]
+impl CHIP8 {
+    // Disassembles `count` instructions starting at `addr`, one per line
+    // prefixed with the address. Used to show the code around the program
+    // counter.
+    pub fn disassemble(&self, addr: u16, count: usize) -> String {
+        let start = (addr as usize).min(self.mem_size());
+        let end = (start + count * 2 + 2).min(self.mem_size());
+        let mut out = String::new();
+        for (addr, instr, operand) in decode_all(&self.memory[start..end], start as u16).into_iter().take(count) {
+            match operand {
+                Some(long) => { let _ = writeln!(out, "{:03X}: LD I, LONG {:#06X}", addr, long); },
+                None => { let _ = writeln!(out, "{:03X}: {}", addr, instr); },
+            }
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble() {
+        assert_eq!(disassemble(0x00E0), Instruction::Cls);
+        assert_eq!(disassemble(0x1234), Instruction::Jp(0x234));
+        assert_eq!(disassemble(0x8AB6), Instruction::Shr(0xA, 0xB));
+        assert_eq!(disassemble(0xD015), Instruction::Drw(0, 1, 5));
+        assert_eq!(disassemble(0xF000), Instruction::LdILong);
+        assert_eq!(disassemble(0xFA65), Instruction::LdVxI(0xA));
+        assert_eq!(disassemble(0x5121), Instruction::Unknown(0x5121));
+
+        assert_eq!(disassemble(0xD015).to_string(), "DRW V0, V1, 5");
+        assert_eq!(disassemble(0x2300).to_string(), "CALL 0x300");
+        assert_eq!(disassemble(0x6A0F).to_string(), "LD VA, 0x0F");
+        assert_eq!(disassemble(0xFFFF).to_string(), "DW 0xFFFF");
+    }
+
+    #[test]
+    fn test_listing() {
+        let rom = [0x22, 0x06, 0x12, 0x02, 0xFF, 0xFF, 0xF0, 0x00, 0x12, 0x34, 0x00, 0xEE, 0xAB];
+        let expected = "    CALL L206               ; 200: 2206\n\
+                        L202:\n    JP L202                 ; 202: 1202\n\
+                        \x20   DW 0xFFFF               ; 204: FFFF\n\
+                        L206:\n    LD I, LONG 0x1234       ; 206: F0001234\n\
+                        \x20   RET                     ; 20A: 00EE\n\
+                        \x20   DB 0xAB                 ; 20C: AB\n";
+        assert_eq!(listing(&rom, 0x200), expected);
+    }
+
+    #[test]
+    fn test_disassemble_memory() {
+        let mut emu = CHIP8::new();
+        emu.load_rom(Some(Box::new([0x00, 0xE0, 0xA2, 0x0A])));
+        assert_eq!(emu.disassemble(0x200, 2), "200: CLS\n202: LD I, 0x20A\n");
+    }
+}
@@ -0,0 +1,79 @@
+// Faults that can be raised while fetching or executing an instruction.
+//
+// Every variant remembers the address of the instruction that caused the
+// fault and its raw opcode so the frontend can point at the offending
+// location in memory.
+use std::error;
+use std::fmt;
+use wasm_bindgen::prelude::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    // The opcode doesn't map to any instruction we know about.
+    UnknownOpcode { pc: u16, opcode: u16 },
+    // CALL with no room left on the stack.
+    StackOverflow { pc: u16, opcode: u16 },
+    // RET with nothing on the stack to return to.
+    StackUnderflow { pc: u16, opcode: u16 },
+    // The program counter points past the end of memory. Since the opcode
+    // could not be fetched it is always 0.
+    PcOutOfBounds { pc: u16, opcode: u16 },
+    // An instruction tried to read or write memory past the end of memory
+    // using the I register.
+    IOutOfBounds { pc: u16, opcode: u16, i_reg: u16 },
+}
+
+impl Error {
+    // Address of the instruction that caused the fault.
+    pub fn pc(&self) -> u16 {
+        match *self {
+            Error::UnknownOpcode { pc, .. }
+            | Error::StackOverflow { pc, .. }
+            | Error::StackUnderflow { pc, .. }
+            | Error::PcOutOfBounds { pc, .. }
+            | Error::IOutOfBounds { pc, .. } => pc,
+        }
+    }
+
+    // Raw opcode of the instruction that caused the fault.
+    pub fn opcode(&self) -> u16 {
+        match *self {
+            Error::UnknownOpcode { opcode, .. }
+            | Error::StackOverflow { opcode, .. }
+            | Error::StackUnderflow { opcode, .. }
+            | Error::PcOutOfBounds { opcode, .. }
+            | Error::IOutOfBounds { opcode, .. } => opcode,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::UnknownOpcode { pc, opcode } => {
+                write!(f, "Unknown opcode {:#06X} at {:#05X}", opcode, pc)
+            },
+            Error::StackOverflow { pc, opcode } => {
+                write!(f, "Stack overflow by {:#06X} at {:#05X}", opcode, pc)
+            },
+            Error::StackUnderflow { pc, opcode } => {
+                write!(f, "Stack underflow by {:#06X} at {:#05X}", opcode, pc)
+            },
+            Error::PcOutOfBounds { pc, .. } => {
+                write!(f, "Program counter out of bounds at {:#06X}", pc)
+            },
+            Error::IOutOfBounds { pc, opcode, i_reg } => {
+                write!(f, "I register out of bounds ({:#06X}) by {:#06X} at {:#05X}", i_reg, opcode, pc)
+            },
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+// Faults surface in JS as a thrown error with the formatted message.
+impl From<Error> for JsValue {
+    fn from(err: Error) -> JsValue {
+        JsValue::from_str(&err.to_string())
+    }
+}
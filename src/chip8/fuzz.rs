@@ -0,0 +1,210 @@
+// Fuzzing
+// -------
+// A harness that turns arbitrary bytes into a machine, runs it and checks
+// that nothing panics and the machine stays in a state it could legally be
+// in. The same harness backs the property tests below and the cargo-fuzz
+// target in `fuzz/`, see the Makefile for running it.
+//
+// The input is a fixed size header describing the machine followed by the
+// rom, loaded at 0x200 as usual. An all zero header is a freshly loaded
+// CHIP-8 rom, so roms with a zeroed header in front make a good corpus.
+//
+//   mode       1 byte   modulo the number of modes
+//   quirks     1 byte   one bit per quirk, in declaration order
+//   registers  18 bytes V0-VF, DT, ST
+//   I          2 bytes
+//   pc         2 bytes  xor'ed with 0x200
+//   sp         1 byte   modulo the stack size
+//   stack      32 bytes
+//   display    1 byte   bit 0: high resolution, bits 1-2: XO-CHIP planes
+//   keys       2 bytes  one bit per key held down
+//
+// All multi-byte values are big endian. Besides the invariants, the decode
+// cache & JIT have to end up exactly where the interpreter does.
+use super::{ Backend, Mode, Quirks, CHIP8, STACK_SIZE };
+
+const HEADER_LEN: usize = 60;
+// Most instructions a single input runs for.
+const MAX_CYCLES: u64 = 1000;
+
+// Builds a machine from the fuzzer's input, see the layout above.
+fn machine(data: &[u8]) -> CHIP8 {
+    let byte = |idx: usize| data.get(idx).cloned().unwrap_or(0);
+    let word = |idx: usize| u16::from(byte(idx)) << 8 | u16::from(byte(idx + 1));
+
+    let mut emu = CHIP8::new();
+    emu.set_mode([Mode::Chip8, Mode::SuperChip, Mode::XoChip][byte(0) as usize % 3]);
+    let quirks = byte(1);
+    emu.set_quirks(Quirks {
+        vf_reset: quirks & 1 != 0,
+        memory_increment: quirks & 1 << 1 != 0,
+        shift_in_place: quirks & 1 << 2 != 0,
+        jump_vx: quirks & 1 << 3 != 0,
+        clipping: quirks & 1 << 4 != 0,
+    });
+    if let Some(rom) = data.get(HEADER_LEN..) {
+        emu.load_rom(Some(rom.to_vec().into_boxed_slice()));
+    }
+
+    for (idx, reg) in emu.registers.iter_mut().enumerate() {
+        *reg = byte(2 + idx);
+    }
+    emu.i_reg = word(20);
+    emu.pc = word(22) ^ 0x200;
+    emu.sp = byte(24) % STACK_SIZE as u8;
+    for (idx, addr) in emu.stack.iter_mut().enumerate() {
+        *addr = word(25 + idx * 2);
+    }
+    let display = byte(57);
+    if display & 1 != 0 && emu.mode != Mode::Chip8 {
+        emu.set_hires(true);
+    }
+    if emu.mode == Mode::XoChip {
+        emu.planes = display >> 1 & 0b11;
+    }
+    let keys = word(58);
+    for (idx, key) in emu.keys.iter_mut().enumerate() {
+        *key = keys & 1 << idx != 0;
+    }
+
+    emu
+}
+
+// Why the machine isn't in a state it could legally be in, if it isn't.
+// The display is only checked if something was drawn since the last check.
+fn broken_invariant(emu: &mut CHIP8) -> Option<String> {
+    if emu.sp as usize >= STACK_SIZE {
+        return Some(format!("SP is {:02X}, the stack only has {} slots", emu.sp, STACK_SIZE));
+    }
+    if emu.is_dirty() {
+        emu.clear_dirty();
+        let colors = if emu.mode == Mode::XoChip { 0b11 } else { 0b01 };
+        if let Some(idx) = emu.display.iter().position(|&pixel| pixel & !colors != 0) {
+            return Some(format!("pixel {} is {}", idx, emu.display[idx]));
+        }
+    }
+    if emu.timer_cycles >= emu.clock_rate {
+        return Some(format!("{} timer cycles at {}Hz", emu.timer_cycles, emu.clock_rate));
+    }
+
+    None
+}
+
+// Runs a machine built from `data`, panicking if any of the invariants
+// break or the backends disagree. Faults are fine, they're how the machine
+// deals with bad programs.
+pub fn fuzz(data: &[u8]) {
+    let mut start = machine(data);
+    if let Some(broken) = broken_invariant(&mut start) {
+        panic!("bad machine from the input: {}", broken);
+    }
+
+    let mut emu = start.clone();
+    let mut result = Ok(());
+    while emu.cycles < MAX_CYCLES && result.is_ok() && !emu.is_halted() {
+        result = emu.tick();
+        if let Some(broken) = broken_invariant(&mut emu) {
+            panic!("after {} instructions: {}", emu.cycles, broken);
+        }
+    }
+
+    for &backend in [Backend::Cached, Backend::Jit].iter() {
+        let mut other = start.clone();
+        other.set_backend(backend);
+        let other_result = other.run(emu.cycles - start.cycles);
+        assert_eq!(other_result, result, "{:?} result", backend);
+        assert!(other.save_state() == emu.save_state(), "{:?} ended up somewhere else", backend);
+    }
+}
+
+// proptest doesn't build for wasm.
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use super::super::{ assemble };
+    use proptest::prelude::*;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::OnceLock;
+
+    fn header() -> Vec<u8> {
+        vec![0; HEADER_LEN]
+    }
+
+    fn with_rom(mut header: Vec<u8>, source: &str) -> Vec<u8> {
+        header.extend(assemble(source).unwrap());
+        header
+    }
+
+    // Every rom bundled in docs/chip8/roms, the corpus for mutations.
+    fn bundled_roms() -> &'static [Vec<u8>] {
+        static ROMS: OnceLock<Vec<Vec<u8>>> = OnceLock::new();
+        fn walk(dir: &Path, roms: &mut Vec<Vec<u8>>) {
+            let mut entries: Vec<_> = fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+            entries.sort();
+            for path in entries {
+                if path.is_dir() {
+                    walk(&path, roms);
+                } else if path.extension().and_then(|ext| ext.to_str()) == Some("ch8") {
+                    roms.push(fs::read(path).unwrap());
+                }
+            }
+        }
+
+        ROMS.get_or_init(|| {
+            let mut roms = Vec::new();
+            walk(&Path::new(env!("CARGO_MANIFEST_DIR")).join("docs/chip8/roms"), &mut roms);
+            roms
+        })
+    }
+
+    #[test]
+    fn test_known_edge_cases() {
+        // Overflowing ADD, RET on an empty stack, running off the end of
+        // memory and BCD past the end of memory.
+        fuzz(&with_rom(header(), "LD V0, 0xFF\nADD V0, 0xFF\nRET"));
+        fuzz(&with_rom(header(), "JP 0xFFF"));
+        fuzz(&with_rom(header(), "LD I, 0xFFF\nLD B, V0"));
+
+        let mut full_stack = header();
+        full_stack[24] = STACK_SIZE as u8 - 1;
+        fuzz(&with_rom(full_stack, "loop: CALL loop"));
+
+        // The last byte of XO-CHIP memory, in high resolution with both
+        // planes selected.
+        let mut xo_chip = header();
+        xo_chip[0] = 2;
+        xo_chip[22] = 0xFD;
+        xo_chip[23] = 0xFF;
+        xo_chip[57] = 0b111;
+        fuzz(&xo_chip);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_arbitrary_machines(data in proptest::collection::vec(any::<u8>(), 0..HEADER_LEN + 256)) {
+            fuzz(&data);
+        }
+
+        // Bundled roms with a few bytes changed, started like any other rom
+        // but in any mode and with any quirks.
+        #[test]
+        fn prop_mutated_roms(
+            rom in any::<usize>(),
+            mode in any::<u8>(),
+            quirks in any::<u8>(),
+            mutations in proptest::collection::vec((any::<usize>(), any::<u8>()), 0..16),
+        ) {
+            let roms = bundled_roms();
+            let mut data = header();
+            data[0] = mode;
+            data[1] = quirks;
+            data.extend(&roms[rom % roms.len()]);
+            for (idx, byte) in mutations {
+                let idx = HEADER_LEN + idx % (data.len() - HEADER_LEN);
+                data[idx] = byte;
+            }
+            fuzz(&data);
+        }
+    }
+}
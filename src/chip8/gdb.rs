@@ -0,0 +1,595 @@
+// GDB remote stub
+// ---------------
+// Lets a debugger front-end that speaks the GDB remote serial protocol
+// (gdb, lldb, IDEs, scripts) attach to a native CHIP8 over TCP:
+//
+//   chip8 --gdb 1234 rom.ch8
+//   (gdb) target remote localhost:1234
+//
+// Supported are reading & writing registers and memory, software
+// breakpoints, watchpoints, continue (interruptible with Ctrl-C) and single
+// stepping. The stub describes its registers through `target.xml`:
+//
+//   0-15  V0-VF  8 bits
+//   16    I      16 bits
+//   17    PC     16 bits
+//   18    SP     8 bits
+//   19    DT     8 bits
+//   20    ST     8 bits
+//
+// Values are sent big endian, CHIP-8's byte order. Stepping and breakpoints
+// go through a `Debugger`, so they behave exactly like they do in the web
+// frontend.
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{ self, Read, Write };
+use std::net::TcpStream;
+
+use super::{ Debugger, Error, Register, StopReason, WatchKind, CHIP8, STACK_SIZE };
+
+// Instructions to run between checks for an interrupt from the client.
+const CONTINUE_SLICE: usize = 10_000;
+// Sent by the client to interrupt a running target.
+const INTERRUPT: u8 = 0x03;
+// Largest packet we accept, advertised to the client in hex.
+const PACKET_SIZE: usize = 0x1000;
+// Registers in `g` packet order, with their size in bytes.
+const REGISTERS: [(&str, usize); 21] = [
+    ("v0", 1), ("v1", 1), ("v2", 1), ("v3", 1), ("v4", 1), ("v5", 1), ("v6", 1), ("v7", 1),
+    ("v8", 1), ("v9", 1), ("va", 1), ("vb", 1), ("vc", 1), ("vd", 1), ("ve", 1), ("vf", 1),
+    ("i", 2), ("pc", 2), ("sp", 1), ("dt", 1), ("st", 1),
+];
+const I_REG: usize = 16;
+const PC_REG: usize = 17;
+const SP_REG: usize = 18;
+const DT_REG: usize = 19;
+const ST_REG: usize = 20;
+
+// Unix signal numbers used in stop replies.
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+// What to do after handling a packet.
+enum Action {
+    Reply(String),
+    // Resume execution, stepping a single instruction if true.
+    Resume(bool),
+    Detach,
+    Kill,
+}
+
+pub struct GdbStub {
+    stream: TcpStream,
+    debugger: Debugger,
+    // Kinds of the watchpoints set by the client, by address.
+    watchpoints: BTreeMap<u16, WatchKind>,
+    // Whether packets are acknowledged, until the client turns it off.
+    ack: bool,
+    // Received but not yet parsed bytes.
+    pending: Vec<u8>,
+}
+
+impl GdbStub {
+    // Serves the client connected on `stream`.
+    pub fn new(stream: TcpStream) -> GdbStub {
+        let mut debugger = Debugger::new();
+        // Reverse execution isn't supported, don't pay for the history.
+        debugger.set_history_depth(0);
+
+        GdbStub {
+            stream,
+            debugger,
+            watchpoints: BTreeMap::new(),
+            ack: true,
+            pending: Vec::new(),
+        }
+    }
+
+    // Handles requests until the client detaches, kills the target or
+    // disconnects. The machine is left in whatever state it was in.
+    pub fn serve(&mut self, emu: &mut CHIP8) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            match self.handle(emu, &packet) {
+                Action::Reply(reply) => self.send(&reply)?,
+                Action::Resume(step) => {
+                    let reply = self.resume(emu, step)?;
+                    self.send(&reply)?;
+                },
+                Action::Detach => return self.send("OK"),
+                Action::Kill => return Ok(()),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle(&mut self, emu: &mut CHIP8, packet: &str) -> Action {
+        let reply = |reply: &str| Action::Reply(reply.to_string());
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+
+        match command {
+            "?" => reply(&format!("S{:02x}", SIGTRAP)),
+            "g" => Action::Reply((0..REGISTERS.len()).map(|reg| read_register(emu, reg)).collect()),
+            "G" => match decode_hex(args) {
+                Some(ref bytes) if bytes.len() == register_bytes() => {
+                    let mut offset = 0;
+                    for (reg, &(_, size)) in REGISTERS.iter().enumerate() {
+                        if !write_register(emu, reg, &bytes[offset..offset + size]) {
+                            return reply("E01");
+                        }
+                        offset += size;
+                    }
+                    reply("OK")
+                },
+                _ => reply("E01"),
+            },
+            "p" => match parse_hex(args) {
+                Some(reg) if (reg as usize) < REGISTERS.len() => Action::Reply(read_register(emu, reg as usize)),
+                _ => reply("E01"),
+            },
+            "P" => {
+                let mut parts = args.splitn(2, '=');
+                let reg = parts.next().and_then(parse_hex).map(|reg| reg as usize);
+                let value = parts.next().and_then(decode_hex);
+                match (reg, value) {
+                    (Some(reg), Some(ref value)) if reg < REGISTERS.len() && value.len() == REGISTERS[reg].1 => {
+                        if write_register(emu, reg, value) { reply("OK") } else { reply("E01") }
+                    },
+                    _ => reply("E01"),
+                }
+            },
+            "m" => match parse_range(args).and_then(|(addr, len)| memory_range(emu, addr, len)) {
+                Some((start, end)) => Action::Reply(encode_hex(&emu.memory[start..end])),
+                None => reply("E01"),
+            },
+            "M" => {
+                let mut parts = args.splitn(2, ':');
+                let range = parts.next().and_then(parse_range).and_then(|(addr, len)| memory_range(emu, addr, len));
+                match (range, parts.next().and_then(decode_hex)) {
+                    (Some((start, end)), Some(ref bytes)) if bytes.len() == end - start => {
+                        emu.memory[start..end].copy_from_slice(bytes);
+                        emu.memory_changed();
+                        reply("OK")
+                    },
+                    _ => reply("E01"),
+                }
+            },
+            "Z" | "z" => self.breakpoint(command == "Z", args),
+            "c" | "s" => {
+                // An optional address to resume at.
+                if !args.is_empty() {
+                    match parse_hex(args) {
+                        Some(addr) if addr <= 0xFFFF => emu.pc = addr as u16,
+                        _ => return reply("E01"),
+                    }
+                }
+                Action::Resume(command == "s")
+            },
+            "D" => Action::Detach,
+            "k" => Action::Kill,
+            "H" => reply("OK"),
+            _ => self.query(packet),
+        }
+    }
+
+    // Queries and the other multi letter packets.
+    fn query(&mut self, packet: &str) -> Action {
+        let reply = |reply: &str| Action::Reply(reply.to_string());
+
+        if packet.starts_with("qSupported") {
+            return reply(&format!("PacketSize={:x};qXfer:features:read+;swbreak+;QStartNoAckMode+", PACKET_SIZE));
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let xml = target_xml();
+            return match parse_range(range) {
+                Some((offset, len)) => {
+                    let start = (offset as usize).min(xml.len());
+                    let end = (start + len as usize).min(xml.len());
+                    let more = if end < xml.len() { "m" } else { "l" };
+                    reply(&format!("{}{}", more, &xml[start..end]))
+                },
+                None => reply("E01"),
+            };
+        }
+
+        match packet {
+            "QStartNoAckMode" => {
+                // The OK is still acknowledged.
+                self.ack = false;
+                reply("OK")
+            },
+            "qAttached" => reply("1"),
+            "qC" => reply("QC1"),
+            "qfThreadInfo" => reply("m1"),
+            "qsThreadInfo" => reply("l"),
+            "qSymbol::" => reply("OK"),
+            _ if packet.starts_with("vKill") => Action::Kill,
+            // Empty replies tell the client we don't support it.
+            _ => reply(""),
+        }
+    }
+
+    // Z/z type,addr,kind: sets or removes a breakpoint or watchpoint. For
+    // watchpoints `kind` is the number of bytes to watch.
+    fn breakpoint(&mut self, insert: bool, args: &str) -> Action {
+        let mut parts = args.split(',');
+        let kind = parts.next();
+        let addr = parts.next().and_then(parse_hex).filter(|&addr| addr <= 0xFFFF).map(|addr| addr as u16);
+        let len = parts.next().and_then(parse_hex).filter(|&len| len <= 0xFFFF).map(|len| len as u16);
+        let (addr, len) = match (addr, len) {
+            (Some(addr), Some(len)) => (addr, len),
+            _ => return Action::Reply("E01".to_string()),
+        };
+
+        let watch = match kind {
+            // Software & hardware breakpoints are the same thing to us.
+            Some("0") | Some("1") => None,
+            Some("2") => Some(WatchKind::Write),
+            Some("3") => Some(WatchKind::Read),
+            Some("4") => Some(WatchKind::ReadWrite),
+            _ => return Action::Reply(String::new()),
+        };
+
+        match (watch, insert) {
+            (None, true) => self.debugger.add_breakpoint(addr),
+            (None, false) => self.debugger.remove_breakpoint(addr),
+            (Some(watch), true) => {
+                self.debugger.add_watchpoint(addr, len, watch);
+                self.watchpoints.insert(addr, watch);
+            },
+            (Some(_), false) => {
+                self.debugger.remove_watchpoint(addr);
+                self.watchpoints.remove(&addr);
+            },
+        }
+
+        Action::Reply("OK".to_string())
+    }
+
+    // Runs the machine and returns the stop reply.
+    fn resume(&mut self, emu: &mut CHIP8, step: bool) -> io::Result<String> {
+        let reason = if step {
+            self.debugger.step_into(emu)
+        } else {
+            loop {
+                match self.debugger.run(emu, CONTINUE_SLICE) {
+                    StopReason::Limit => {},
+                    reason => break reason,
+                }
+                if self.interrupted()? {
+                    return Ok(format!("S{:02x}", SIGINT));
+                }
+            }
+        };
+
+        Ok(match reason {
+            StopReason::Breakpoint => format!("T{:02x}swbreak:;", SIGTRAP),
+            StopReason::Watchpoint => {
+                let addr = self.debugger.watch_hit().unwrap_or(0);
+                let name = match self.watchpoints.get(&addr) {
+                    Some(WatchKind::Read) => "rwatch",
+                    Some(WatchKind::ReadWrite) => "awatch",
+                    _ => "watch",
+                };
+                format!("T{:02x}{}:{:x};", SIGTRAP, name, addr)
+            },
+            StopReason::Fault => match emu.fault {
+                Some(Error::UnknownOpcode { .. }) => format!("S{:02x}", SIGILL),
+                _ => format!("S{:02x}", SIGSEGV),
+            },
+            // The program exited through 00FD.
+            StopReason::Halted => "W00".to_string(),
+            StopReason::Step | StopReason::Limit => format!("S{:02x}", SIGTRAP),
+        })
+    }
+
+    // Whether the client asked to stop a running target. Doesn't block.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        if !self.pending.contains(&INTERRUPT) {
+            self.stream.set_nonblocking(true)?;
+            let result = self.fill();
+            self.stream.set_nonblocking(false)?;
+            match result {
+                // The client went away, stop so `serve` notices.
+                Ok(0) => return Ok(true),
+                Ok(_) => {},
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {},
+                Err(err) => return Err(err),
+            }
+        }
+
+        match self.pending.iter().position(|&byte| byte == INTERRUPT) {
+            Some(idx) => {
+                self.pending.remove(idx);
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
+    // Reads whatever is available into `pending`.
+    fn fill(&mut self) -> io::Result<usize> {
+        let mut buf = [0; 1024];
+        let len = self.stream.read(&mut buf)?;
+        self.pending.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn next_byte(&mut self) -> io::Result<Option<u8>> {
+        if self.pending.is_empty() && self.fill()? == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(self.pending.remove(0)))
+    }
+
+    // Reads the next packet, acknowledging it if needed. Returns None once
+    // the client disconnects.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // Skip acks & stray interrupts in between packets.
+            match self.next_byte()? {
+                Some(b'$') => {},
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.next_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) if data.len() < PACKET_SIZE => data.push(byte),
+                    Some(_) => {},
+                    None => return Ok(None),
+                }
+            }
+            let mut checksum = [0; 2];
+            for digit in checksum.iter_mut() {
+                match self.next_byte()? {
+                    Some(byte) => *digit = byte,
+                    None => return Ok(None),
+                }
+            }
+
+            let expected = std::str::from_utf8(&checksum).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            let valid = expected == Some(checksum_of(&data));
+            if self.ack {
+                self.stream.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+        }
+    }
+
+    // Sends a packet, resending it until the client acknowledges it.
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+            if !self.ack {
+                return Ok(());
+            }
+
+            match self.next_byte()? {
+                Some(b'-') => {},
+                Some(b'+') | None => return Ok(()),
+                // Anything else is the start of the next packet, take it as
+                // an implicit ack.
+                Some(byte) => {
+                    self.pending.insert(0, byte);
+                    return Ok(());
+                },
+            }
+        }
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn register_bytes() -> usize {
+    REGISTERS.iter().map(|&(_, size)| size).sum()
+}
+
+fn read_register(emu: &CHIP8, reg: usize) -> String {
+    match reg {
+        I_REG => format!("{:04x}", emu.i_reg),
+        PC_REG => format!("{:04x}", emu.pc),
+        SP_REG => format!("{:02x}", emu.sp),
+        DT_REG => format!("{:02x}", emu.registers[Register::DT as usize]),
+        ST_REG => format!("{:02x}", emu.registers[Register::ST as usize]),
+        _ => format!("{:02x}", emu.registers[reg]),
+    }
+}
+
+// Sets a register from its big endian bytes. Returns false if the value
+// isn't valid for it.
+fn write_register(emu: &mut CHIP8, reg: usize, value: &[u8]) -> bool {
+    let word = || u16::from(value[0]) << 8 | u16::from(value[1]);
+    match reg {
+        I_REG => emu.i_reg = word(),
+        PC_REG => emu.pc = word(),
+        // The stack pointer has to stay inside the stack.
+        SP_REG if value[0] as usize >= STACK_SIZE => return false,
+        SP_REG => emu.sp = value[0],
+        DT_REG => emu.registers[Register::DT as usize] = value[0],
+        ST_REG => emu.registers[Register::ST as usize] = value[0],
+        _ => emu.registers[reg] = value[0],
+    }
+
+    true
+}
+
+// Describes the registers to the client.
+fn target_xml() -> String {
+    let mut xml = String::from("<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n  <feature name=\"org.chip8.core\">\n");
+    for (reg, &(name, size)) in REGISTERS.iter().enumerate() {
+        let kind = match reg {
+            I_REG => "data_ptr",
+            PC_REG => "code_ptr",
+            _ => "uint8",
+        };
+        let _ = writeln!(xml, "    <reg name=\"{}\" bitsize=\"{}\" type=\"{}\" regnum=\"{}\"/>", name, size * 8, kind, reg);
+    }
+    xml.push_str("  </feature>\n</target>\n");
+
+    xml
+}
+
+// The part of memory `len` bytes at `addr` cover, if it's all addressable.
+fn memory_range(emu: &CHIP8, addr: u32, len: u32) -> Option<(usize, usize)> {
+    let start = addr as usize;
+    let end = start.checked_add(len as usize)?;
+    if end > emu.mem_size() {
+        return None;
+    }
+
+    Some((start, end))
+}
+
+fn parse_hex(hex: &str) -> Option<u32> {
+    u32::from_str_radix(hex, 16).ok()
+}
+
+// addr,len
+fn parse_range(args: &str) -> Option<(u32, u32)> {
+    let mut parts = args.splitn(2, ',');
+    Some((parse_hex(parts.next()?)?, parse_hex(parts.next()?)?))
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+
+    (0..hex.len()).step_by(2).map(|idx| u8::from_str_radix(&hex[idx..idx + 2], 16).ok()).collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+    use super::super::{ assemble };
+
+    struct Client {
+        stream: TcpStream,
+        ack: bool,
+    }
+
+    impl Client {
+        fn read_byte(&mut self) -> u8 {
+            let mut byte = [0];
+            self.stream.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+
+        fn send(&mut self, data: &str) {
+            let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+            self.stream.write_all(packet.as_bytes()).unwrap();
+            if self.ack {
+                assert_eq!(self.read_byte(), b'+');
+            }
+        }
+
+        fn reply(&mut self) -> String {
+            assert_eq!(self.read_byte(), b'$');
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte() {
+                    b'#' => break,
+                    byte => data.push(byte),
+                }
+            }
+            let checksum = [self.read_byte(), self.read_byte()];
+            assert_eq!(u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16).unwrap(), checksum_of(&data));
+            if self.ack {
+                self.stream.write_all(b"+").unwrap();
+            }
+
+            String::from_utf8(data).unwrap()
+        }
+
+        fn request(&mut self, data: &str) -> String {
+            self.send(data);
+            self.reply()
+        }
+    }
+
+    #[test]
+    fn test_session() {
+        let rom = assemble("
+                LD V0, 5
+            loop:
+                ADD V0, 1
+                LD I, 0x300
+                LD [I], V0
+                JP loop
+        ").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut emu = CHIP8::new();
+            emu.load_rom(Some(rom.into_boxed_slice()));
+            let (stream, _) = listener.accept().unwrap();
+            GdbStub::new(stream).serve(&mut emu).unwrap();
+            emu
+        });
+
+        let mut client = Client { stream: TcpStream::connect(addr).unwrap(), ack: true };
+        assert!(client.request("qSupported:swbreak+").contains("qXfer:features:read+"));
+        assert!(client.request("qXfer:features:read:target.xml:0,1000").contains("<reg name=\"pc\" bitsize=\"16\""));
+        assert_eq!(client.request("?"), "S05");
+
+        // A corrupted packet is asked for again.
+        client.stream.write_all(b"$g#00").unwrap();
+        assert_eq!(client.read_byte(), b'-');
+
+        assert_eq!(client.request("QStartNoAckMode"), "OK");
+        client.ack = false;
+
+        // Registers & memory.
+        assert_eq!(client.request("g"), format!("{}00000200000000", "00".repeat(16)));
+        assert_eq!(client.request("P10=0abc"), "OK");
+        assert_eq!(client.request("p10"), "0abc");
+        assert_eq!(client.request("P12=10"), "E01");
+        assert_eq!(client.request("m200,4"), "60057001");
+        assert_eq!(client.request("M300,2:abcd"), "OK");
+        assert_eq!(client.request("m300,3"), "abcd00");
+        assert_eq!(client.request("m1000,1"), "E01");
+        assert_eq!(client.request("qUnknown"), "");
+
+        // Breakpoints & stepping.
+        assert_eq!(client.request("Z0,206,2"), "OK");
+        assert_eq!(client.request("c"), "T05swbreak:;");
+        assert_eq!(client.request("p11"), "0206");
+        assert_eq!(client.request("s"), "S05");
+        assert_eq!(client.request("p11"), "0208");
+        assert_eq!(client.request("m300,1"), "06");
+        assert_eq!(client.request("z0,206,2"), "OK");
+
+        // Watchpoints.
+        assert_eq!(client.request("Z2,300,1"), "OK");
+        assert_eq!(client.request("c"), "T05watch:300;");
+        assert_eq!(client.request("p0"), "07");
+        assert_eq!(client.request("z2,300,1"), "OK");
+
+        // Running forever until interrupted.
+        client.send("c");
+        client.stream.write_all(&[INTERRUPT]).unwrap();
+        assert_eq!(client.reply(), "S02");
+
+        assert_eq!(client.request("P11=0202"), "OK");
+        assert_eq!(client.request("D"), "OK");
+        let emu = server.join().unwrap();
+        assert_eq!(emu.pc, 0x202);
+    }
+}
@@ -0,0 +1,539 @@
+// Image export
+// ------------
+// Turns the display into a PNG screenshot or a sequence of frames into an
+// animated GIF, for bug reports and comparing runs. Both encoders are tiny
+// and only handle what we need, a palette of four colors, so nothing has
+// to be pulled in for the wasm build.
+//
+// Pixels are looked up in a `Palette` by their plane bits, which keeps
+// XO-CHIP's second plane distinguishable.
+use std::collections::HashMap;
+use std::iter;
+use wasm_bindgen::prelude::*;
+
+use super::state::{ crc32 };
+use super::{ CHIP8, HIRES_WIDTH };
+
+// Upper limit for the scale factor, a 128x64 display becomes 4096x2048.
+const MAX_SCALE: u32 = 32;
+// GIF delays are in hundredths of a second and most viewers play anything
+// shorter than two of them a lot slower, so frames are kept at least this
+// far apart.
+const MIN_DELAY: u64 = 2;
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Palette {
+    // 0xRRGGBB for pixels with no plane, plane 1, plane 2 and both planes.
+    colors: [u32; 4],
+}
+
+// Same colors as the web frontend.
+impl Default for Palette {
+    fn default() -> Self {
+        Palette { colors: [0xFF_FFFF, 0x00_0000, 0xAA_AAAA, 0x55_5555] }
+    }
+}
+
+#[wasm_bindgen]
+impl Palette {
+    // Colors are given as 0xRRGGBB.
+    pub fn new(background: u32, foreground: u32) -> Palette {
+        let mut palette = Palette::default();
+        palette.set_color(0, background);
+        palette.set_color(1, foreground);
+        palette
+    }
+
+    // Sets the color for pixels with the given plane bits.
+    pub fn set_color(&mut self, pixel: u8, rgb: u32) {
+        if let Some(color) = self.colors.get_mut(pixel as usize) {
+            *color = rgb & 0xFF_FFFF;
+        }
+    }
+
+    pub fn color(&self, pixel: u8) -> u32 {
+        self.colors[pixel as usize & 3]
+    }
+}
+
+impl Palette {
+    fn rgb(&self) -> Vec<u8> {
+        self.colors.iter()
+            .flat_map(|&color| vec![(color >> 16) as u8, (color >> 8) as u8, color as u8])
+            .collect()
+    }
+}
+
+#[wasm_bindgen]
+impl CHIP8 {
+    // Encodes the display as a PNG, each pixel becoming a `scale` by
+    // `scale` square.
+    pub fn to_png(&self, scale: u32, palette: &Palette) -> Vec<u8> {
+        let width = self.display_width();
+        let height = self.display_height();
+        encode_png(self.pixels(), width, height, scale.clamp(1, MAX_SCALE) as usize, palette)
+    }
+}
+
+struct Frame {
+    pixels: Vec<u8>,
+    width: usize,
+    height: usize,
+    // When it was captured, in hundredths of a second of emulated time.
+    time: u64,
+}
+
+// Captures frames into an animated GIF. Frame delays are taken from the
+// emulated time between captures, so a recording plays back at the speed
+// the rom ran at no matter how fast it was produced.
+#[wasm_bindgen]
+pub struct GifRecorder {
+    scale: usize,
+    palette: Palette,
+    frames: Vec<Frame>,
+    // Time of the last capture, the last frame is shown until then.
+    end: u64,
+}
+
+#[wasm_bindgen]
+impl GifRecorder {
+    pub fn new(scale: u32, palette: &Palette) -> GifRecorder {
+        GifRecorder {
+            scale: scale.clamp(1, MAX_SCALE) as usize,
+            palette: *palette,
+            frames: Vec::new(),
+            end: 0,
+        }
+    }
+
+    // Number of distinct frames captured so far.
+    pub fn len(&self) -> usize { self.frames.len() }
+    pub fn is_empty(&self) -> bool { self.frames.is_empty() }
+
+    // Adds the current display as a frame. Nothing is added when the display
+    // hasn't changed, the previous frame is just shown longer. Captures too
+    // close together replace the previous frame.
+    pub fn capture(&mut self, emu: &CHIP8) {
+        let time = emu.cycles * 100 / u64::from(emu.clock_rate);
+        let width = emu.display_width();
+        let height = emu.display_height();
+        let pixels = emu.pixels();
+        self.end = self.end.max(time);
+
+        if let Some(last) = self.frames.last_mut() {
+            if last.width == width && last.pixels == pixels {
+                return;
+            }
+            if time.saturating_sub(last.time) < MIN_DELAY {
+                last.pixels = pixels.to_vec();
+                last.width = width;
+                last.height = height;
+                return;
+            }
+        }
+
+        self.frames.push(Frame { pixels: pixels.to_vec(), width, height, time });
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // Low resolution frames are scaled up further when the rom switches
+        // to high resolution halfway through.
+        let hires = self.frames.iter().any(|frame| frame.width == HIRES_WIDTH);
+        let width = self.frames.iter().map(|frame| frame.width).max().unwrap_or(0);
+        let height = self.frames.iter().map(|frame| frame.height).max().unwrap_or(0);
+
+        let mut out = Vec::new();
+        out.extend_from_slice(b"GIF89a");
+        push_u16(&mut out, (width * self.scale) as u16);
+        push_u16(&mut out, (height * self.scale) as u16);
+        // Global color table with 4 entries, background color 0, square
+        // pixels.
+        out.extend_from_slice(&[0x81, 0, 0]);
+        out.extend_from_slice(&self.palette.rgb());
+        // Loop forever.
+        out.extend_from_slice(b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\x00\x00\x00");
+
+        for (idx, frame) in self.frames.iter().enumerate() {
+            let next = self.frames.get(idx + 1).map_or(self.end, |next| next.time);
+            let delay = next.saturating_sub(frame.time).clamp(MIN_DELAY, u64::from(u16::MAX));
+            out.extend_from_slice(&[0x21, 0xF9, 0x04, 0x00]);
+            push_u16(&mut out, delay as u16);
+            out.extend_from_slice(&[0x00, 0x00]);
+
+            let scale = if hires && frame.width != HIRES_WIDTH { self.scale * 2 } else { self.scale };
+            out.push(0x2C);
+            push_u16(&mut out, 0);
+            push_u16(&mut out, 0);
+            push_u16(&mut out, (frame.width * scale) as u16);
+            push_u16(&mut out, (frame.height * scale) as u16);
+            out.push(0x00);
+
+            let indices: Vec<u8> = scaled_rows(&frame.pixels, frame.width, scale)
+                .flatten()
+                .collect();
+            out.push(LZW_MIN_CODE_SIZE);
+            for block in lzw_encode(&indices).chunks(255) {
+                out.push(block.len() as u8);
+                out.extend_from_slice(block);
+            }
+            out.push(0x00);
+        }
+
+        out.push(0x3B);
+        out
+    }
+}
+
+fn push_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+// Rows of the image blown up by `scale`, holding the plane bits of each
+// pixel.
+fn scaled_rows<'a>(pixels: &'a [u8], width: usize, scale: usize) -> impl Iterator<Item = Vec<u8>> + 'a {
+    pixels.chunks(width).flat_map(move |row| {
+        let scaled: Vec<u8> = row.iter()
+            .flat_map(|&pixel| iter::repeat(pixel & 3).take(scale))
+            .collect();
+        iter::repeat(scaled).take(scale)
+    })
+}
+
+// Writes bits least significant first, like both DEFLATE & GIF want them.
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter { out: Vec::new(), bits: 0, count: 0 }
+    }
+
+    fn write(&mut self, value: u32, count: u32) {
+        self.bits |= value << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // Huffman codes are stored most significant bit first.
+    fn write_code(&mut self, code: u32, count: u32) {
+        self.write(code.reverse_bits() >> (32 - count), count);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+// PNG
+// ---
+// An 8-bit palette image. Every row uses the "up" filter, which turns the
+// rows repeated by scaling into zeros, and the DEFLATE stream only uses
+// runs of the previous byte with the fixed Huffman codes. That's plenty to
+// shrink the long runs of identical pixels a scaled display consists of.
+fn encode_png(pixels: &[u8], width: usize, height: usize, scale: usize, palette: &Palette) -> Vec<u8> {
+    let mut raw = Vec::with_capacity((width * scale + 1) * height * scale);
+    let mut previous = vec![0; width * scale];
+    for row in scaled_rows(pixels, width, scale) {
+        raw.push(2);
+        raw.extend(row.iter().zip(previous.iter()).map(|(&pixel, &above)| pixel.wrapping_sub(above)));
+        previous = row;
+    }
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&((width * scale) as u32).to_be_bytes());
+    header.extend_from_slice(&((height * scale) as u32).to_be_bytes());
+    // 8 bits per pixel, palette colors, no interlacing.
+    header.extend_from_slice(&[8, 3, 0, 0, 0]);
+
+    let mut out = b"\x89PNG\r\n\x1A\n".to_vec();
+    png_chunk(&mut out, b"IHDR", &header);
+    png_chunk(&mut out, b"PLTE", &palette.rgb());
+    png_chunk(&mut out, b"IDAT", &zlib(&raw));
+    png_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let checksum = crc32(&out[start..]);
+    out.extend_from_slice(&checksum.to_be_bytes());
+}
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u32; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const MAX_RUN: usize = 258;
+
+fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut bits = BitWriter::new();
+    // A single final block using the fixed Huffman codes.
+    bits.write(1, 1);
+    bits.write(1, 2);
+
+    let mut idx = 0;
+    while idx < data.len() {
+        let run = if idx == 0 {
+            0
+        } else {
+            data[idx..].iter().take(MAX_RUN).take_while(|&&byte| byte == data[idx - 1]).count()
+        };
+
+        if run < 3 {
+            fixed_literal(&mut bits, u32::from(data[idx]));
+            idx += 1;
+            continue;
+        }
+
+        // Copy `run` bytes from a distance of one byte back.
+        let code = LENGTH_BASE.iter().rposition(|&base| base as usize <= run).unwrap();
+        fixed_literal(&mut bits, 257 + code as u32);
+        bits.write((run - LENGTH_BASE[code] as usize) as u32, LENGTH_EXTRA[code]);
+        bits.write_code(0, 5);
+        idx += run;
+    }
+    fixed_literal(&mut bits, 256);
+
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + u32::from(byte)) % 65521;
+        b = (b + a) % 65521;
+    }
+
+    let mut out = vec![0x78, 0x01];
+    out.extend(bits.finish());
+    out.extend_from_slice(&((b << 16) | a).to_be_bytes());
+    out
+}
+
+fn fixed_literal(bits: &mut BitWriter, value: u32) {
+    match value {
+        0..=143 => bits.write_code(0x30 + value, 8),
+        144..=255 => bits.write_code(0x190 + value - 144, 9),
+        256..=279 => bits.write_code(value - 256, 7),
+        _ => bits.write_code(0xC0 + value - 280, 8),
+    }
+}
+
+// GIF
+// ---
+// Variable code length LZW as GIF uses it. Two bits are enough for the
+// palette, the smallest size GIF allows.
+const LZW_MIN_CODE_SIZE: u8 = 2;
+const LZW_MAX_CODE: u16 = 4096;
+
+fn lzw_encode(indices: &[u8]) -> Vec<u8> {
+    let clear = 1u16 << LZW_MIN_CODE_SIZE;
+    let end = clear + 1;
+    let mut table: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut next = end + 1;
+    let mut size = u32::from(LZW_MIN_CODE_SIZE) + 1;
+
+    let mut bits = BitWriter::new();
+    bits.write(u32::from(clear), size);
+
+    let mut prefix: Option<u16> = None;
+    for &index in indices {
+        let code = match prefix {
+            None => {
+                prefix = Some(u16::from(index));
+                continue;
+            },
+            Some(code) => code,
+        };
+
+        if let Some(&known) = table.get(&(code, index)) {
+            prefix = Some(known);
+            continue;
+        }
+
+        bits.write(u32::from(code), size);
+        if next < LZW_MAX_CODE {
+            table.insert((code, index), next);
+            next += 1;
+            // The decoder adds its entry one code later, so only switch
+            // once it needs the extra bit too.
+            if u32::from(next) > 1 << size {
+                size += 1;
+            }
+        } else {
+            bits.write(u32::from(clear), size);
+            table.clear();
+            next = end + 1;
+            size = u32::from(LZW_MIN_CODE_SIZE) + 1;
+        }
+        prefix = Some(u16::from(index));
+    }
+
+    if let Some(code) = prefix {
+        bits.write(u32::from(code), size);
+    }
+    bits.write(u32::from(end), size);
+    bits.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reverses `lzw_encode` to make sure a decoder reads the same pixels.
+    fn lzw_decode(data: &[u8]) -> Vec<u8> {
+        let clear = 1u16 << LZW_MIN_CODE_SIZE;
+        let min_size = u32::from(LZW_MIN_CODE_SIZE) + 1;
+        let mut table: Vec<Vec<u8>> = Vec::new();
+        let mut size = min_size;
+        let mut previous: Option<Vec<u8>> = None;
+        let mut out = Vec::new();
+
+        let mut pos = 0;
+        let mut read = |size: u32| {
+            let mut code = 0u16;
+            for bit in 0..size {
+                let byte = data[pos / 8];
+                code |= u16::from((byte >> (pos % 8)) & 1) << bit;
+                pos += 1;
+            }
+            code
+        };
+
+        loop {
+            let code = read(size);
+            if code == clear {
+                table = (0..clear).map(|idx| vec![idx as u8]).collect();
+                table.push(Vec::new());
+                table.push(Vec::new());
+                size = min_size;
+                previous = None;
+                continue;
+            }
+            if code == clear + 1 {
+                return out;
+            }
+
+            let entry = match table.get(code as usize) {
+                Some(entry) => entry.clone(),
+                None => {
+                    let mut entry = previous.clone().unwrap();
+                    entry.push(entry[0]);
+                    entry
+                },
+            };
+            if let Some(mut prev) = previous {
+                if table.len() < LZW_MAX_CODE as usize {
+                    prev.push(entry[0]);
+                    table.push(prev);
+                    if table.len() == 1 << size && size < 12 {
+                        size += 1;
+                    }
+                }
+            }
+            out.extend_from_slice(&entry);
+            previous = Some(entry);
+        }
+    }
+
+    fn program(rom: &[u8], steps: usize) -> CHIP8 {
+        let mut emu = CHIP8::new();
+        emu.load_rom(Some(rom.to_vec().into_boxed_slice()));
+        for _ in 0..steps {
+            emu.tick().unwrap();
+        }
+        emu
+    }
+
+    #[test]
+    fn test_lzw() {
+        let mut pixels = vec![0; 10_000];
+        // Noise fills up the code table and forces a reset.
+        let mut seed = 1u32;
+        for pixel in pixels.iter_mut().skip(2000) {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            *pixel = (seed >> 16) as u8 & 3;
+        }
+        assert_eq!(lzw_decode(&lzw_encode(&pixels)), pixels);
+        assert_eq!(lzw_decode(&lzw_encode(&[])), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_png() {
+        // Draw a 0 in the top left corner.
+        let emu = program(&[0xD0, 0x05], 1);
+        let png = emu.to_png(2, &Palette::new(0x10_2030, 0xFF_EEDD));
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1A\n");
+
+        // Walk the chunks, checking their checksums.
+        let mut chunks = Vec::new();
+        let mut rest = &png[8..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            let (body, tail) = rest[4..].split_at(len + 4);
+            let checksum = u32::from_be_bytes([tail[0], tail[1], tail[2], tail[3]]);
+            assert_eq!(crc32(body), checksum);
+            chunks.push((body[..4].to_vec(), body[4..].to_vec()));
+            rest = &tail[4..];
+        }
+
+        let kinds: Vec<&[u8]> = chunks.iter().map(|(kind, _)| &kind[..]).collect();
+        assert_eq!(kinds, vec![&b"IHDR"[..], b"PLTE", b"IDAT", b"IEND"]);
+        assert_eq!(&chunks[0].1[..8], &[0, 0, 0, 128, 0, 0, 0, 64]);
+        assert_eq!(&chunks[1].1[..6], &[0x10, 0x20, 0x30, 0xFF, 0xEE, 0xDD]);
+        // A mostly empty display compresses really well.
+        assert!(chunks[2].1.len() < 400);
+    }
+
+    #[test]
+    fn test_gif() {
+        // Draws a 0, then another one next to it and loops forever.
+        let rom = [0xD0, 0x15, 0x70, 0x08, 0xD0, 0x15, 0x12, 0x06];
+        let mut emu = program(&rom, 1);
+        let mut gif = GifRecorder::new(1, &Palette::default());
+        gif.capture(&emu);
+        // Unchanged displays don't make new frames.
+        gif.capture(&emu);
+        emu.run_for(50.0).unwrap();
+        gif.capture(&emu);
+        assert_eq!(gif.len(), 2);
+
+        let data = gif.to_bytes();
+        assert_eq!(&data[..6], b"GIF89a");
+        assert_eq!(&data[6..10], &[64, 0, 32, 0]);
+        assert_eq!(data.last(), Some(&0x3B));
+
+        // First frame: skip the header, color table & loop extension. It's
+        // shown until the display scrolled 50ms later.
+        let frame = &data[13 + 12 + 19..];
+        assert_eq!(&frame[..4], &[0x21, 0xF9, 0x04, 0x00]);
+        assert_eq!(u16::from_le_bytes([frame[4], frame[5]]), 5);
+        assert_eq!(frame[8], 0x2C);
+
+        let mut lzw = Vec::new();
+        let mut blocks = &frame[19..];
+        while blocks[0] != 0 {
+            let len = blocks[0] as usize;
+            lzw.extend_from_slice(&blocks[1..=len]);
+            blocks = &blocks[len + 1..];
+        }
+        let pixels = lzw_decode(&lzw);
+        assert_eq!(pixels.len(), 64 * 32);
+        // Top row of the 0 is 0xF0.
+        assert_eq!(&pixels[..5], &[1, 1, 1, 1, 0]);
+    }
+}
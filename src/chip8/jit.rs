@@ -0,0 +1,581 @@
+// JIT
+// ---
+// Compiles hot basic blocks so they run without fetching, decoding or
+// dispatching on every opcode.
+//
+// In the wasm build every block becomes a WebAssembly module of its own
+// (see `codegen.rs`), instantiated through the browser's `WebAssembly.Module`
+// & `WebAssembly.Instance` and sharing the emulator's linear memory, so the
+// browser turns blocks into machine code. Blocks that can't be compiled,
+// say because the page isn't allowed to compile wasm, are left to the
+// interpreter.
+//
+// Native builds can't generate code like that, they translate blocks into
+// closure-threaded code instead: every instruction becomes a closure with
+// its operands baked in, so running a block is a straight walk over
+// function pointers. Either way common instructions get their own code and
+// the rest call back into the interpreter's `execute_instruction`.
+//
+// Code is interpreted until a block start has run `HOT_RUNS` times, cold
+// code isn't worth compiling. When a write lands on compiled code all
+// blocks are thrown away and the written bytes are marked as modified,
+// from then on they're always interpreted so self-modifying code doesn't
+// keep recompiling. Loading a rom or a save state starts over completely.
+#[cfg(target_arch = "wasm32")]
+use js_sys::{ Function, Object, Reflect, Uint8Array, WebAssembly };
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::prelude::*;
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::JsCast;
+
+use super::cache::ends_block;
+#[cfg(target_arch = "wasm32")]
+use super::codegen::{ self, Layout };
+use super::{ decode, Error, Instruction, CHIP8, MEM_SIZE };
+#[cfg(not(target_arch = "wasm32"))]
+use super::{ Register };
+
+// Longest run of instructions in a block.
+const MAX_BLOCK_LEN: usize = 32;
+// Times a block start is interpreted before it gets compiled, by default.
+const HOT_RUNS: u8 = 8;
+
+// A compiled instruction, runs it and moves the program counter on.
+#[cfg(not(target_arch = "wasm32"))]
+type Op = Box<dyn Fn(&mut CHIP8) -> Result<(), Error> + Send>;
+
+#[cfg(not(target_arch = "wasm32"))]
+type Block = Vec<Op>;
+
+#[cfg(target_arch = "wasm32")]
+struct Block {
+    // The module's `run(emu, budget)`.
+    run: Function,
+    // Which instructions call the fallback.
+    fallbacks: Vec<bool>,
+}
+
+#[cfg(target_arch = "wasm32")]
+impl Block {
+    fn len(&self) -> usize {
+        self.fallbacks.len()
+    }
+}
+
+pub struct Jit {
+    // Index + 1 of the compiled block starting at each address, 0 if none.
+    starts: Vec<u32>,
+    blocks: Vec<Block>,
+    // Whether a compiled block covers each byte of memory.
+    code: Vec<bool>,
+    // Bytes written after being compiled, or that couldn't be compiled,
+    // never compiled again.
+    modified: Vec<bool>,
+    // How often each address was interpreted as a block start.
+    runs: Vec<u8>,
+    hot_runs: u8,
+    // Memory epoch of the machine the blocks were compiled from.
+    epoch: u32,
+    // What every module imports: the memory & the fallback.
+    #[cfg(target_arch = "wasm32")]
+    imports: Object,
+    #[cfg(target_arch = "wasm32")]
+    _fallback: Closure<dyn FnMut(u32, u32) -> u32>,
+}
+
+impl Default for Jit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Jit {
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn new() -> Jit {
+        Jit {
+            starts: vec![0; MEM_SIZE],
+            blocks: Vec::new(),
+            code: vec![false; MEM_SIZE],
+            modified: vec![false; MEM_SIZE],
+            runs: vec![0; MEM_SIZE],
+            hot_runs: HOT_RUNS,
+            epoch: 0,
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn new() -> Jit {
+        let fallback = Closure::wrap(Box::new(fallback) as Box<dyn FnMut(u32, u32) -> u32>);
+        let env = Object::new();
+        set(&env, "memory", &wasm_bindgen::memory());
+        set(&env, "fallback", fallback.as_ref());
+        let imports = Object::new();
+        set(&imports, "env", &env);
+
+        Jit {
+            starts: vec![0; MEM_SIZE],
+            blocks: Vec::new(),
+            code: vec![false; MEM_SIZE],
+            modified: vec![false; MEM_SIZE],
+            runs: vec![0; MEM_SIZE],
+            hot_runs: HOT_RUNS,
+            epoch: 0,
+            imports,
+            _fallback: fallback,
+        }
+    }
+
+    // How many times a block start is interpreted before it gets compiled,
+    // 1 compiles everything straight away.
+    pub fn set_hot_runs(&mut self, runs: u8) {
+        self.hot_runs = runs.max(1);
+        self.clear();
+    }
+
+    // Number of blocks compiled so far.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    // Forgets all compiled code, along with what's hot and what's modified.
+    pub fn clear(&mut self) {
+        self.flush();
+        for modified in self.modified.iter_mut() {
+            *modified = false;
+        }
+        for runs in self.runs.iter_mut() {
+            *runs = 0;
+        }
+    }
+
+    // Runs `cycles` instructions, stopping early if one faults.
+    pub fn run(&mut self, emu: &mut CHIP8, cycles: u64) -> Result<(), Error> {
+        let mut left = cycles;
+        while left > 0 {
+            if self.epoch != emu.memory_epoch {
+                self.clear();
+                self.epoch = emu.memory_epoch;
+            }
+
+            // Let `step` deal with halted machines and reporting faults.
+            let pc = emu.pc as usize;
+            if emu.is_halted() || pc + 1 >= emu.mem_size() {
+                return emu.step();
+            }
+
+            match self.starts[pc] {
+                0 => {
+                    if !self.modified[pc] && !self.modified[pc + 1] {
+                        self.runs[pc] = self.runs[pc].saturating_add(1);
+                        if self.runs[pc] >= self.hot_runs {
+                            self.compile(emu, pc);
+                            continue;
+                        }
+                    }
+
+                    emu.step()?;
+                    left -= 1;
+                },
+                start => {
+                    let block = &self.blocks[start as usize - 1];
+                    let len = (block.len() as u64).min(left);
+                    run_block(block, emu, len as usize)?;
+                    left -= len;
+                },
+            }
+
+            // Only the last instruction of a block can write.
+            if let Some(access) = emu.last_write {
+                let (start, end) = (access.addr as usize, access.addr as usize + access.len as usize);
+                if self.code[start..end].contains(&true) {
+                    self.flush();
+                    for modified in self.modified[start..end].iter_mut() {
+                        *modified = true;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Throws away the compiled blocks.
+    fn flush(&mut self) {
+        if self.blocks.is_empty() {
+            return;
+        }
+
+        self.blocks.clear();
+        for start in self.starts.iter_mut() {
+            *start = 0;
+        }
+        for code in self.code.iter_mut() {
+            *code = false;
+        }
+    }
+
+    // Compiles the block starting at `start`, up to the first modified
+    // byte.
+    fn compile(&mut self, emu: &CHIP8, start: usize) {
+        let mut instrs = Vec::new();
+        let mut addr = start;
+        while instrs.len() < MAX_BLOCK_LEN && addr + 1 < emu.mem_size() {
+            let opcode = u16::from(emu.memory[addr]) << 8 | u16::from(emu.memory[addr + 1]);
+            let instr = decode(opcode);
+            let end = (addr + instr.size()).min(MEM_SIZE);
+            if self.modified[addr..end].contains(&true) {
+                break;
+            }
+
+            instrs.push((addr as u16, instr, opcode));
+            addr = end;
+
+            if ends_block(instr) {
+                break;
+            }
+        }
+
+        let block = match self.build(emu, &instrs) {
+            Some(block) => block,
+            None => {
+                self.modified[start] = true;
+                return;
+            },
+        };
+        for &(addr, instr, _) in instrs.iter() {
+            let (start, end) = (addr as usize, (addr as usize + instr.size()).min(MEM_SIZE));
+            for code in self.code[start..end].iter_mut() {
+                *code = true;
+            }
+        }
+        self.blocks.push(block);
+        self.starts[start] = self.blocks.len() as u32;
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn build(&self, _emu: &CHIP8, instrs: &[(u16, Instruction, u16)]) -> Option<Block> {
+        Some(instrs.iter().map(|&(_, instr, opcode)| compile(instr, opcode)).collect())
+    }
+
+    // Encodes & instantiates a module for the block, `None` if the browser
+    // won't have it.
+    #[cfg(target_arch = "wasm32")]
+    fn build(&self, emu: &CHIP8, instrs: &[(u16, Instruction, u16)]) -> Option<Block> {
+        let bytes = codegen::compile(&Layout::of(emu), emu.mode, instrs);
+        if bytes.len() > codegen::MAX_MODULE_SIZE {
+            return None;
+        }
+
+        let module = WebAssembly::Module::new(&Uint8Array::from(&bytes[..])).ok()?;
+        let instance = WebAssembly::Instance::new(&module, &self.imports).ok()?;
+        let run = Reflect::get(&instance.exports(), &JsValue::from_str("run")).ok()?;
+        Some(Block {
+            run: run.dyn_into().ok()?,
+            fallbacks: instrs.iter().map(|&(_, instr, _)| !codegen::inlined(instr, emu.mode)).collect(),
+        })
+    }
+}
+
+// Runs the first `len` instructions of the block.
+#[cfg(not(target_arch = "wasm32"))]
+fn run_block(block: &Block, emu: &mut CHIP8, len: usize) -> Result<(), Error> {
+    for op in block.iter().take(len) {
+        emu.run_instruction(|emu| op(emu))?;
+    }
+
+    Ok(())
+}
+
+// Runs the first `len` instructions of the block through its module.
+#[cfg(target_arch = "wasm32")]
+fn run_block(block: &Block, emu: &mut CHIP8, len: usize) -> Result<(), Error> {
+    codegen::run_block(emu, &block.fallbacks, len, |emu, budget| {
+        let addr = JsValue::from(emu as *mut CHIP8 as u32);
+        block.run.call2(&JsValue::NULL, &addr, &JsValue::from(budget))
+            .expect("compiled blocks don't throw")
+            .as_f64()
+            .unwrap_or(0.0) as u32
+    })
+}
+
+#[cfg(target_arch = "wasm32")]
+fn set(object: &Object, name: &str, value: &JsValue) {
+    Reflect::set(object, &JsValue::from_str(name), value).expect("plain objects take any property");
+}
+
+// The modules' `fallback` import.
+#[cfg(target_arch = "wasm32")]
+fn fallback(emu: u32, opcode: u32) -> u32 {
+    // Only ever called from a block running on the machine at `emu`, which
+    // `run_block` holds on to for the duration.
+    let emu = unsafe { &mut *(emu as usize as *mut CHIP8) };
+    codegen::fallback(emu, opcode as u16)
+}
+
+// Turns an instruction into a closure, the same thing `execute_instruction`
+// does without looking at the opcode again.
+#[cfg(not(target_arch = "wasm32"))]
+fn compile(instr: Instruction, opcode: u16) -> Op {
+    match instr {
+        Instruction::Jp(addr) => Box::new(move |emu| {
+            emu.pc = addr;
+            Ok(())
+        }),
+        Instruction::SeByte(vx, byte) => {
+            let vx = vx as usize;
+            Box::new(move |emu| {
+                emu.pc = emu.pc.wrapping_add(2);
+                if emu.registers[vx] == byte {
+                    emu.skip();
+                }
+                Ok(())
+            })
+        },
+        Instruction::SneByte(vx, byte) => {
+            let vx = vx as usize;
+            Box::new(move |emu| {
+                emu.pc = emu.pc.wrapping_add(2);
+                if emu.registers[vx] != byte {
+                    emu.skip();
+                }
+                Ok(())
+            })
+        },
+        Instruction::SeReg(vx, vy) => {
+            let (vx, vy) = (vx as usize, vy as usize);
+            Box::new(move |emu| {
+                emu.pc = emu.pc.wrapping_add(2);
+                if emu.registers[vx] == emu.registers[vy] {
+                    emu.skip();
+                }
+                Ok(())
+            })
+        },
+        Instruction::SneReg(vx, vy) => {
+            let (vx, vy) = (vx as usize, vy as usize);
+            Box::new(move |emu| {
+                emu.pc = emu.pc.wrapping_add(2);
+                if emu.registers[vx] != emu.registers[vy] {
+                    emu.skip();
+                }
+                Ok(())
+            })
+        },
+        Instruction::LdByte(vx, byte) => {
+            let vx = vx as usize;
+            Box::new(move |emu| {
+                emu.pc = emu.pc.wrapping_add(2);
+                emu.registers[vx] = byte;
+                Ok(())
+            })
+        },
+        Instruction::AddByte(vx, byte) => {
+            let vx = vx as usize;
+            Box::new(move |emu| {
+                emu.pc = emu.pc.wrapping_add(2);
+                emu.registers[vx] = emu.registers[vx].wrapping_add(byte);
+                Ok(())
+            })
+        },
+        Instruction::LdReg(vx, vy) => {
+            let (vx, vy) = (vx as usize, vy as usize);
+            Box::new(move |emu| {
+                emu.pc = emu.pc.wrapping_add(2);
+                emu.registers[vx] = emu.registers[vy];
+                Ok(())
+            })
+        },
+        Instruction::AddReg(vx, vy) => {
+            let (vx, vy) = (vx as usize, vy as usize);
+            Box::new(move |emu| {
+                emu.pc = emu.pc.wrapping_add(2);
+                let (sum, carry) = emu.registers[vx].overflowing_add(emu.registers[vy]);
+                emu.registers[vx] = sum;
+                emu.registers[Register::VF as usize] = carry as u8;
+                Ok(())
+            })
+        },
+        Instruction::Sub(vx, vy) => {
+            let (vx, vy) = (vx as usize, vy as usize);
+            Box::new(move |emu| {
+                emu.pc = emu.pc.wrapping_add(2);
+                let (diff, borrow) = emu.registers[vx].overflowing_sub(emu.registers[vy]);
+                emu.registers[vx] = diff;
+                emu.registers[Register::VF as usize] = !borrow as u8;
+                Ok(())
+            })
+        },
+        Instruction::LdI(addr) => Box::new(move |emu| {
+            emu.pc = emu.pc.wrapping_add(2);
+            emu.i_reg = addr;
+            Ok(())
+        }),
+        _ => Box::new(move |emu| {
+            emu.pc = emu.pc.wrapping_add(2);
+            emu.execute_instruction(instr, opcode)
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{ assemble };
+    #[cfg(not(target_arch = "wasm32"))]
+    use super::super::{ MemAccess, Mode, Quirks };
+
+    fn program(source: &str) -> CHIP8 {
+        let mut emu = CHIP8::new();
+        emu.load_rom(Some(assemble(source).unwrap().into_boxed_slice()));
+        emu
+    }
+
+    // The parts of the machine most instructions touch.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn cpu(emu: &CHIP8) -> (u16, u16, u8, [u16; 16], [u8; 18]) {
+        (emu.pc, emu.i_reg, emu.sp, emu.stack, emu.registers)
+    }
+
+    // Everything else instructions can change.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn rest(emu: &CHIP8) -> (bool, bool, u8, Option<MemAccess>, Option<MemAccess>) {
+        (emu.hires, emu.exited, emu.planes, emu.last_read, emu.last_write)
+    }
+
+    // Every opcode does the same thing compiled as interpreted, in every
+    // mode, starting from a machine with some state worth changing.
+    #[test]
+    #[cfg(not(target_arch = "wasm32"))]
+    fn test_matches_execute() {
+        let mut base = CHIP8::new();
+        for (idx, reg) in base.registers.iter_mut().take(16).enumerate() {
+            *reg = (idx as u8).wrapping_mul(37);
+        }
+        base.registers[3] = base.registers[7];
+        base.i_reg = 0x300;
+        for (idx, byte) in base.memory[0x300..0x340].iter_mut().enumerate() {
+            *byte = (idx as u8).wrapping_mul(91);
+        }
+        base.pc = 0x400;
+        base.sp = 2;
+        base.stack[2] = 0x222;
+        // A long instruction to skip over in XO-CHIP mode.
+        base.memory[0x402] = 0xF0;
+
+        for &mode in [Mode::Chip8, Mode::SuperChip, Mode::XoChip].iter() {
+            for &quirks in [Quirks::cosmac_vip(), Quirks::modern()].iter() {
+                let mut start = base.clone();
+                start.set_mode(mode);
+                start.set_quirks(quirks);
+
+                for opcode in 0x0000..=0xFFFF {
+                    let mut expected = start.clone();
+                    expected.pc += 2;
+                    let expected_result = expected.execute(opcode);
+                    let mut actual = start.clone();
+                    let actual_result = compile(decode(opcode), opcode)(&mut actual);
+
+                    assert_eq!(actual_result, expected_result, "{:04X} in {:?}", opcode, mode);
+                    assert_eq!(cpu(&actual), cpu(&expected), "{:04X} in {:?}", opcode, mode);
+                    assert_eq!(rest(&actual), rest(&expected), "{:04X} in {:?}", opcode, mode);
+                    assert!(actual.memory[..] == expected.memory[..], "{:04X} in {:?}", opcode, mode);
+                    assert!(actual.display[..] == expected.display[..], "{:04X} in {:?}", opcode, mode);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_matches_tick() {
+        let emu = program("
+                LD V0, 0
+                LD V1, 10
+                LD I, 0x300
+            loop:
+                ADD V0, 3
+                LD V2, V0
+                SUB V2, V1
+                ADD V2, V0
+                CALL draw
+                LD B, V0
+                SNE V0, 93
+                JP done
+                SE V1, V1
+                CLS
+                JP loop
+            draw:
+                LD F, V2
+                DRW V0, V1, 5
+                RET
+            done:
+                JP 0xFFF
+        ");
+
+        let mut expected = emu.clone();
+        let expected_result = (0..2000).map(|_| expected.tick()).find(Result::is_err).unwrap_or(Ok(()));
+
+        let mut actual = emu.clone();
+        let mut jit = Jit::new();
+        let mut result = Ok(());
+        while actual.cycles() < 2000 && result.is_ok() {
+            result = jit.run(&mut actual, 5);
+        }
+
+        assert!(!jit.is_empty());
+        assert!(expected_result.is_err());
+        assert_eq!(result, expected_result);
+        assert!(actual.save_state() == expected.save_state());
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        // Counts V5 up in a loop that rewrites its own ADD to add one more
+        // every time around.
+        let mut emu = program("
+                LD V0, 0x75
+                LD V1, 0
+            loop:
+                LD I, patch
+                ADD V1, 1
+                LD [I], V1
+            patch:
+                ADD V5, 0
+                SE V1, 40
+                JP loop
+            halt:
+                JP halt
+        ");
+        let mut expected = emu.clone();
+        for _ in 0..400 {
+            expected.tick().unwrap();
+        }
+
+        let mut jit = Jit::new();
+        jit.run(&mut emu, 400).unwrap();
+        assert_eq!(emu.registers[5], (1..=40u32).sum::<u32>() as u8);
+        assert!(emu.save_state() == expected.save_state());
+        // The patched ADD is interpreted, the loop around it still compiles.
+        assert!(jit.modified[0x20A]);
+        assert!(!jit.is_empty());
+        assert_eq!(jit.starts[0x20A], 0);
+    }
+
+    #[test]
+    fn test_invalidation() {
+        let mut emu = program("LD V0, 1\nhalt: JP halt");
+        let mut jit = Jit::new();
+        jit.run(&mut emu, 40).unwrap();
+        assert_eq!(emu.registers[0], 1);
+        assert_eq!(jit.len(), 1);
+
+        // Reloading forgets the compiled code and how hot it was.
+        emu.load_rom(Some(assemble("LD V0, 2\nhalt: JP halt").unwrap().into_boxed_slice()));
+        jit.run(&mut emu, 2).unwrap();
+        assert_eq!(emu.registers[0], 2);
+        assert!(jit.is_empty());
+    }
+}
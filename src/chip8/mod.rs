@@ -4,25 +4,110 @@
 // have some interesting/neat implementation details.
 //
 // Check out the `research` section of the README to learn more.
-#[cfg(target_arch = "wasm32")]
-use js_sys::{ Math };
-#[cfg(not(target_arch = "wasm32"))]
-use rand::{ thread_rng, Rng };
-
 use std::fmt;
+use std::str::FromStr;
 use wasm_bindgen::prelude::*;
 use utils;
 
+// Exports the `tick` & `run_for` of an `Observer`, JS can't call trait
+// methods. Defined up here so the tool modules below can use it.
+macro_rules! export_observer {
+    ($observer:ident) => {
+        #[wasm_bindgen]
+        impl $observer {
+            // Runs a single instruction through `record`.
+            pub fn tick(&mut self, emu: &mut CHIP8) -> Result<(), Error> {
+                self.record(emu, CHIP8::tick)
+            }
+
+            // `CHIP8::run_for` with every instruction going through
+            // `record`.
+            pub fn run_for(&mut self, emu: &mut CHIP8, elapsed_ms: f64) -> Result<(), Error> {
+                emu.run_for_with(elapsed_ms, |emu| self.tick(emu))
+            }
+        }
+    };
+}
+
+mod asm;
+mod backend;
+mod cache;
+mod clock;
+// Only the wasm build runs generated code, tests check what it generates.
+#[cfg(any(target_arch = "wasm32", test))]
+mod codegen;
+mod coverage;
+mod debugger;
+mod diff;
+mod dirty;
+mod disasm;
+mod error;
 mod font;
-use self::font::{ FONT };
+// Only for tests & cargo-fuzz, which builds with `--cfg fuzzing`.
+#[cfg(any(test, fuzzing))]
+mod fuzz;
+// Needs real sockets, only the native builds have those.
+#[cfg(not(target_arch = "wasm32"))]
+mod gdb;
+mod image;
+mod jit;
+mod movie;
+mod profile;
+mod quirks;
+mod rewind;
+mod rng;
+mod state;
+mod trace;
+pub use self::asm::{ assemble, Assembler, AsmError };
+pub use self::backend::{ Backend };
+pub use self::cache::{ DecodeCache };
+pub use self::coverage::{ Coverage, CoverageSummary };
+pub use self::debugger::{ Comparison, Debugger, StopReason, WatchKind };
+pub use self::diff::{ compare_trace, lockstep, Divergence, TraceDivergence };
+pub use self::dirty::{ Rect };
+pub use self::disasm::{ disassemble, listing, Instruction };
+pub use self::error::{ Error };
+#[cfg(any(test, fuzzing))]
+pub use self::fuzz::{ fuzz };
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::gdb::{ GdbStub };
+pub use self::image::{ GifRecorder, Palette };
+pub use self::jit::{ Jit };
+pub use self::movie::{ Movie, MovieError };
+pub use self::profile::{ Profiler, Routine };
+pub use self::quirks::{ Quirks };
+pub use self::rewind::{ Rewind };
+pub use self::state::{ StateError };
+pub use self::trace::{ Observer, TraceSink, Tracer };
+use self::backend::{ Engine };
+use self::dirty::{ Dirty };
+use self::font::{ BIG_FONT, FONT };
+use self::rng::{ Rng, DEFAULT_SEED };
 
 // Various dimensions used in this emulator implementation.
 const NUM_REGISTERS: usize = 18;
-const MEM_SIZE: usize = 4096;
+// XO-CHIP programs can address a full 64KB of memory, everything else
+// only gets the first 4KB.
+const MEM_SIZE: usize = 0x10000;
+const CHIP8_MEM_SIZE: usize = 0x1000;
 const STACK_SIZE: usize = 16;
 const DISPLAY_WIDTH: usize = 64;
 const DISPLAY_HEIGHT: usize = 32;
-const DISPLAY_SIZE: usize = DISPLAY_HEIGHT * DISPLAY_WIDTH;
+// SUPER-CHIP high resolution mode doubles the display in both directions.
+const HIRES_WIDTH: usize = 128;
+const HIRES_HEIGHT: usize = 64;
+// The display buffer is always big enough for high resolution mode.
+const DISPLAY_SIZE: usize = HIRES_HEIGHT * HIRES_WIDTH;
+// Where the large SUPER-CHIP font lives, right after the regular font.
+const BIG_FONT_ADDR: usize = 0x50;
+// Number of RPL user flags SUPER-CHIP can save registers to.
+const NUM_RPL_FLAGS: usize = 16;
+// Size of the XO-CHIP audio pattern buffer, 128 1-bit samples.
+const AUDIO_PATTERN_SIZE: usize = 16;
+// Pitch at which the audio pattern plays back at 4000 samples per second.
+const DEFAULT_PITCH: u8 = 64;
+// Instructions executed per second, roughly what most ROMs expect.
+pub const DEFAULT_CLOCK_RATE: u32 = 700;
 
 #[wasm_bindgen]
 extern {
@@ -36,31 +121,62 @@ macro_rules! log {
 }
 
 #[cfg(not(target_arch = "wasm32"))]
+#[allow(unused_macros)]
 macro_rules! log {
     ($($t:tt)*) => (println!($($t)*))
 }
 
-#[cfg(target_arch = "wasm32")]
-fn random_byte() -> u8 {
-    (Math::random() * 255.0) as u8
-}
-
-#[cfg(not(target_arch = "wasm32"))]
-fn random_byte() -> u8 {
-    // thread_rng is often the most convenient source of randomness:
-    let mut rng = thread_rng();
-    (rng.gen::<f32>() * 255.0) as u8
-}
-
 // Mapping of CHIP8 keys
 #[wasm_bindgen]
 #[repr(u8)]
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Key {
     K1, K2, K3, K4, K5, K6, K7, K8, K9, K0,
     KA, KB, KC, KD, KE, KF
 }
 
+// Keys in the order of their index in the keypad.
+const KEYS: [Key; 16] = [
+    Key::K1, Key::K2, Key::K3, Key::K4, Key::K5, Key::K6, Key::K7, Key::K8,
+    Key::K9, Key::K0, Key::KA, Key::KB, Key::KC, Key::KD, Key::KE, Key::KF,
+];
+
+impl Key {
+    // Key with the given keypad index (0x0 through 0xF).
+    pub fn from_index(idx: u8) -> Option<Key> {
+        KEYS.get(idx as usize).cloned()
+    }
+}
+
+// Instruction sets the emulator understands.
+#[wasm_bindgen]
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    // The original CHIP-8 instruction set.
+    Chip8,
+    // SUPER-CHIP 1.1, which adds scrolling, a 128x64 high resolution mode,
+    // 16x16 sprites, a large font and the RPL user flags.
+    SuperChip,
+    // XO-CHIP, a superset of SUPER-CHIP which adds 64KB of memory, a second
+    // display plane and programmable audio.
+    XoChip,
+}
+
+// Names used on the command line.
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Mode, String> {
+        match name {
+            "chip8" => Ok(Mode::Chip8),
+            "schip" => Ok(Mode::SuperChip),
+            "xochip" => Ok(Mode::XoChip),
+            _ => Err(format!("unknown mode \"{}\"", name)),
+        }
+    }
+}
+
 // Mapping of register names to the register bank
 #[wasm_bindgen]
 #[repr(u8)]
@@ -76,8 +192,26 @@ pub enum Register {
     ST,
 }
 
+// A range of memory read or written through the I register.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemAccess {
+    pub addr: u16,
+    pub len: u16,
+}
+
+impl MemAccess {
+    // Whether this access touches any of the `len` bytes starting at `addr`.
+    pub fn overlaps(&self, addr: u16, len: u16) -> bool {
+        let start = usize::from(self.addr);
+        let end = start + usize::from(self.len);
+        let other_start = usize::from(addr);
+        let other_end = other_start + usize::from(len);
+        start < other_end && other_start < end
+    }
+}
+
 #[wasm_bindgen]
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct CHIP8 {
     // 16-bit register called "I". This register is generally used to store
     // memory addresses.
@@ -89,7 +223,7 @@ pub struct CHIP8 {
     // The CHIP-8 has 16 general purpose 8-bit registers, usually referred to as Vx
     // where x is a hexadecimal digit (0 through F).
     registers: [u8; NUM_REGISTERS],
-    // The language is capable of accessing up to 4KB of RAM.
+    // The language is capable of accessing up to 4KB of RAM (64KB for XO-CHIP).
     // The first 512 bytes (0x200) are where the original interpreter was located
     // thus most CHIP8 programs start at location 0x200.
     memory: [u8; MEM_SIZE],
@@ -97,12 +231,57 @@ pub struct CHIP8 {
     // interpreter should return to when finished. Thus only really allowing up
     // to 16 levels of nested function calls.
     stack: [u16; STACK_SIZE],
-    // Display
-    display: [u8; DISPLAY_HEIGHT * DISPLAY_WIDTH],
+    // Display, one byte per pixel. Rows are laid out using the width of
+    // the active resolution.
+    //
+    // XO-CHIP has two display planes, each pixel stores the first plane in
+    // bit 0 and the second plane in bit 1 for 4 possible colors.
+    display: [u8; DISPLAY_SIZE],
+    // Parts of the display changed since the frontend last drew it.
+    dirty: Dirty,
+    // Bitmask of the planes XO-CHIP drawing instructions operate on.
+    planes: u8,
+    // Whether the SUPER-CHIP high resolution mode (128x64) is active.
+    hires: bool,
+    // SUPER-CHIP RPL user flags. On the HP-48 these survived between runs.
+    rpl: [u8; NUM_RPL_FLAGS],
+    // XO-CHIP audio pattern, played back while the sound timer is active.
+    audio_pattern: [u8; AUDIO_PATTERN_SIZE],
+    // XO-CHIP audio pitch, controls the pattern playback rate.
+    pitch: u8,
     // Current key press
     current_key: Option<Key>,
     // Whether key[x] is pressed or not.
     keys: [bool; 16],
+    // Which instruction set to execute.
+    mode: Mode,
+    // Which interpreter to mimic for instructions with ambiguous behavior.
+    quirks: Quirks,
+    // Set once a SUPER-CHIP program exits through 00FD.
+    exited: bool,
+    // Memory read & written through the I register by the last instruction.
+    last_read: Option<MemAccess>,
+    last_write: Option<MemAccess>,
+    // The fault that halted execution, if any. Once set, the emulator
+    // refuses to tick until it is reset or a new rom is loaded.
+    fault: Option<Error>,
+    // Instructions executed per second.
+    clock_rate: u32,
+    // Progress towards the next 60Hz timer decrement, every instruction
+    // adds 60 and the timers decrement each time it reaches `clock_rate`.
+    timer_cycles: u32,
+    // Fraction of an instruction `run_for` still owes from earlier calls.
+    pending_cycles: f64,
+    // Instructions executed since the last reset.
+    cycles: u64,
+    // Bumped whenever memory changes other than through I (a new rom, a
+    // save state, ...), so caches of decoded code know to start over.
+    memory_epoch: u32,
+    // Random numbers for RND, restarted from `seed` on reset.
+    seed: u64,
+    rng: Rng,
+    // Runs the instructions, see `backend.rs`.
+    engine: Engine,
 }
 
 impl Default for CHIP8 {
@@ -123,26 +302,70 @@ impl CHIP8 {
             registers: [0; NUM_REGISTERS],
             memory: [0; MEM_SIZE],
             stack: [0; STACK_SIZE],
-            display: [0; DISPLAY_HEIGHT * DISPLAY_WIDTH],
+            display: [0; DISPLAY_SIZE],
+            dirty: Dirty::new(),
+            planes: 1,
+            hires: false,
+            rpl: [0; NUM_RPL_FLAGS],
+            audio_pattern: [0; AUDIO_PATTERN_SIZE],
+            pitch: DEFAULT_PITCH,
             current_key: None,
-            keys: [false; 16]
+            keys: [false; 16],
+            mode: Mode::Chip8,
+            quirks: Quirks::default(),
+            exited: false,
+            last_read: None,
+            last_write: None,
+            fault: None,
+            clock_rate: DEFAULT_CLOCK_RATE,
+            timer_cycles: 0,
+            pending_cycles: 0.0,
+            cycles: 0,
+            memory_epoch: 0,
+            seed: DEFAULT_SEED,
+            rng: Rng::new(DEFAULT_SEED),
+            engine: Engine::new(Backend::Interpreter),
         };
-        // Load fonts into memory.
+        chip8.load_fonts();
+        chip8.mark_display();
+
+        chip8
+    }
+
+    // Load fonts into the interpreter area of memory.
+    fn load_fonts(&mut self) {
         let mut idx = 0;
         for sprite in FONT.iter() {
             for &byte in sprite.iter() {
-                chip8.memory[idx] = byte;
+                self.memory[idx] = byte;
                 idx += 1;
             }
         }
 
-        chip8
+        let mut idx = BIG_FONT_ADDR;
+        for sprite in BIG_FONT.iter() {
+            for &byte in sprite.iter() {
+                self.memory[idx] = byte;
+                idx += 1;
+            }
+        }
+    }
+
+    // Dimensions of the active resolution.
+    pub fn display_height(&self) -> usize {
+        if self.hires { HIRES_HEIGHT } else { DISPLAY_HEIGHT }
+    }
+
+    pub fn display_width(&self) -> usize {
+        if self.hires { HIRES_WIDTH } else { DISPLAY_WIDTH }
+    }
+
+    // Amount of memory addressable in the current mode.
+    pub fn mem_size(&self) -> usize {
+        if self.mode == Mode::XoChip { MEM_SIZE } else { CHIP8_MEM_SIZE }
     }
 
     // Handy access to emu constants
-    pub fn display_height() -> usize { DISPLAY_HEIGHT }
-    pub fn display_width() -> usize { DISPLAY_WIDTH }
-    pub fn mem_size() -> usize { MEM_SIZE }
     pub fn num_registers() -> usize { NUM_REGISTERS }
     pub fn stack_size() -> usize { STACK_SIZE }
     // Whether we need to beep this tick.
@@ -150,287 +373,598 @@ impl CHIP8 {
         self.registers[Register::ST as usize] > 0
     }
 
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+        // Changes how much memory is addressable.
+        self.memory_changed();
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    // Whether execution was halted by a fault or the program exited.
+    pub fn is_halted(&self) -> bool {
+        self.exited || self.fault.is_some()
+    }
+
+    // Description of the fault that halted execution, if any.
+    pub fn fault(&self) -> Option<String> {
+        self.fault.map(|fault| fault.to_string())
+    }
+
     // Retrieves the current opcode pointed to by the program counter.
     // All instrs are 2 bytes long and are stored most-sig byte first.
-    fn fetch(&mut self) -> u16 {
-        // Shift first byte to upper 8 bits and OR second byte into lower 8 bits
+    fn fetch(&mut self) -> Result<u16, Error> {
         let pc = self.pc as usize;
+        if pc + 1 >= self.mem_size() {
+            return Err(Error::PcOutOfBounds { pc: self.pc, opcode: 0 });
+        }
+
+        // Shift first byte to upper 8 bits and OR second byte into lower 8 bits
         let opcode = u16::from(self.memory[pc]) << 8 | u16::from(self.memory[pc + 1]);
         // Increment program counter
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
+
+        Ok(opcode)
+    }
+
+    // Same as `i_range`, but also remembers the access for debuggers and
+    // other tools.
+    fn i_read(&mut self, len: usize, opcode: u16) -> Result<usize, Error> {
+        let start = self.i_range(len, opcode)?;
+        if len > 0 {
+            self.last_read = Some(MemAccess { addr: start as u16, len: len as u16 });
+        }
+
+        Ok(start)
+    }
+
+    fn i_write(&mut self, len: usize, opcode: u16) -> Result<usize, Error> {
+        let start = self.i_range(len, opcode)?;
+        if len > 0 {
+            self.last_write = Some(MemAccess { addr: start as u16, len: len as u16 });
+        }
+
+        Ok(start)
+    }
+
+    // Skips over the next instruction. On XO-CHIP this has to take the
+    // 4 byte long F000 NNNN instruction into account.
+    fn skip(&mut self) {
+        let pc = self.pc as usize;
+        let long = self.mode == Mode::XoChip
+            && pc + 1 < MEM_SIZE
+            && self.memory[pc] == 0xF0
+            && self.memory[pc + 1] == 0x00;
+        self.pc = self.pc.wrapping_add(if long { 4 } else { 2 });
+    }
+
+    // Makes sure `len` bytes starting at the I register fit in memory and
+    // returns the starting address.
+    fn i_range(&self, len: usize, opcode: u16) -> Result<usize, Error> {
+        let start = self.i_reg as usize;
+        if start + len > self.mem_size() {
+            return Err(Error::IOutOfBounds {
+                pc: self.pc.wrapping_sub(2),
+                opcode,
+                i_reg: self.i_reg,
+            });
+        }
+
+        Ok(start)
+    }
+
+    // The COSMAC VIP clobbers VF on logical operations.
+    fn vf_reset(&mut self) {
+        if self.quirks.vf_reset {
+            self.registers[Register::VF as usize] = 0;
+        }
+    }
+
+    // The COSMAC VIP moves I past the registers read/written by FX55 & FX65.
+    fn memory_increment(&mut self, vx: usize) {
+        if self.quirks.memory_increment {
+            self.i_reg = self.i_reg.wrapping_add(vx as u16 + 1);
+        }
+    }
+
+    // XORs a sprite starting at memory location I onto the display at (x, y)
+    // and returns 1 if any pixels were erased. `width` is either 8 or 16
+    // pixels, one or two bytes per row.
+    //
+    // When XO-CHIP has both planes selected, the sprite for the second plane
+    // follows right after the sprite for the first plane.
+    fn draw_sprite(&mut self, x: u8, y: u8, width: usize, rows: usize, opcode: u16) -> Result<u8, Error> {
+        let sprite_len = rows * width / 8;
+        let num_planes = self.planes.count_ones() as usize;
+        let mut start = self.i_read(sprite_len * num_planes, opcode)?;
+
+        let mut collision = 0;
+        for plane in 0..2 {
+            let mask = 1 << plane;
+            if self.planes & mask != 0 {
+                collision |= self.draw_plane(mask, start, x, y, width, rows);
+                start += sprite_len;
+            }
+        }
+        self.mark_sprite(x, y, width, rows);
+
+        Ok(collision)
+    }
+
+    // Draws a single plane of a sprite starting at memory location `start`.
+    //
+    // The starting position always wraps if x/y is greater than the display
+    // width/height. Parts of the sprite that go past the edge are either
+    // wrapped to the other side or clipped depending on the quirks.
+    fn draw_plane(&mut self, mask: u8, start: usize, x: u8, y: u8, width: usize, rows: usize) -> u8 {
+        let bytes_per_row = width / 8;
+        let display_width = self.display_width();
+        let display_height = self.display_height();
+        let x0 = x as usize % display_width;
+        let y0 = y as usize % display_height;
+
+        let mut collision = 0;
+        // Loop each row of the sprite.
+        for row in 0..rows {
+            let mut py = y0 + row;
+            if py >= display_height {
+                if self.quirks.clipping {
+                    break;
+                }
+                py %= display_height;
+            }
 
-        opcode
+            // Loop through each bit.
+            for col in 0..width {
+                let mut px = x0 + col;
+                if px >= display_width {
+                    if self.quirks.clipping {
+                        break;
+                    }
+                    px %= display_width;
+                }
+
+                let byte = self.memory[start + row * bytes_per_row + col / 8];
+                if (byte >> (7 - col % 8)) & 1 == 0 {
+                    continue;
+                }
+
+                let display_idx = py * display_width + px;
+                // Collision if we erase a pixel.
+                if self.display[display_idx] & mask != 0 {
+                    collision = 1;
+                }
+                // Remember that you need to XOR the value instead of
+                // just setting the display at this index.
+                self.display[display_idx] ^= mask;
+            }
+        }
+
+        collision
+    }
+
+    // Clears the selected planes.
+    fn clear_display(&mut self) {
+        for idx in 0..DISPLAY_SIZE {
+            self.display[idx] &= !self.planes;
+        }
+        self.mark_display();
+    }
+
+    // Switching resolutions clears every plane.
+    fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        for idx in 0..DISPLAY_SIZE {
+            self.display[idx] = 0;
+        }
+        self.mark_display();
+    }
+
+    // Moves the selected planes of the display by (dx, dy) pixels. Pixels
+    // scrolled in from the edges are blank.
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let width = self.display_width() as isize;
+        let height = self.display_height() as isize;
+        let previous = self.display;
+        for py in 0..height {
+            for px in 0..width {
+                let (sx, sy) = (px - dx, py - dy);
+                let pixel = if sx >= 0 && sx < width && sy >= 0 && sy < height {
+                    previous[(sy * width + sx) as usize]
+                } else {
+                    0
+                };
+
+                let idx = (py * width + px) as usize;
+                self.display[idx] = (self.display[idx] & !self.planes) | (pixel & self.planes);
+            }
+        }
+        self.mark_display();
     }
 
     // Executes an opcode.
-    fn execute(&mut self, opcode: u16) {
-        let instr = opcode & 0xF000;
-        let subinstr  = opcode & 0x000F;
-        let addr  = opcode & 0x0FFF;
-        let lower = (opcode & 0x00FF) as u8;
-        // Register positions
-        let vx = ((opcode & 0x0F00) >> 8) as usize;
-        let vy = ((opcode & 0x00F0) >> 4) as usize;
+    fn execute(&mut self, opcode: u16) -> Result<(), Error> {
+        self.execute_instruction(decode(opcode), opcode)
+    }
+
+    // Executes an already decoded opcode, `opcode` is only used to report
+    // faults.
+    fn execute_instruction(&mut self, instr: Instruction, opcode: u16) -> Result<(), Error> {
+        // Address of this instruction, the program counter has already been
+        // moved past it by the time we get here.
+        let pc = self.pc.wrapping_sub(2);
+        let unknown = Err(Error::UnknownOpcode { pc, opcode });
+        let chip8 = self.mode == Mode::Chip8;
+        let xo_chip = self.mode == Mode::XoChip;
 
         match instr {
-            0x0000 => {
-                match lower {
-                    // Clear display.
-                    0xE0 => {
-                        for idx in 0..DISPLAY_SIZE {
-                            self.display[idx] = 0;
-                        }
-                    },
-                    // Return from subroutine.
-                    0xEE => {
-                        // Sets the program counter to the address at the top
-                        // of the stack.
-                        self.pc = self.stack[self.sp as usize];
-                        // Subtract 1 from the stack pointer.
-                        self.sp -= 1;
-                    },
-                    _ => log!("Unknown opcode {:#X}", opcode)
+            // Clear display.
+            Instruction::Cls => self.clear_display(),
+            // Return from subroutine.
+            Instruction::Ret => {
+                if self.sp == 0 {
+                    return Err(Error::StackUnderflow { pc, opcode });
                 }
+                // Sets the program counter to the address at the top
+                // of the stack.
+                self.pc = self.stack[self.sp as usize];
+                // Subtract 1 from the stack pointer.
+                self.sp -= 1;
             },
+            // SCD nibble
+            // SUPER-CHIP: Scroll the display down by nibble pixels.
+            Instruction::Scd(n) if !chip8 => self.scroll(0, n as isize),
+            // SCU nibble
+            // XO-CHIP: Scroll the display up by nibble pixels.
+            Instruction::Scu(n) if xo_chip => self.scroll(0, -(n as isize)),
+            // SCR
+            // SUPER-CHIP: Scroll the display right by 4 pixels.
+            Instruction::Scr if !chip8 => self.scroll(4, 0),
+            // SCL
+            // SUPER-CHIP: Scroll the display left by 4 pixels.
+            Instruction::Scl if !chip8 => self.scroll(-4, 0),
+            // EXIT
+            // SUPER-CHIP: Stop the interpreter. The program counter stays on
+            // this instruction.
+            Instruction::Exit if !chip8 => {
+                self.exited = true;
+                self.pc = pc;
+            },
+            // LOW
+            // SUPER-CHIP: Switch to the regular 64x32 resolution.
+            Instruction::Low if !chip8 => self.set_hires(false),
+            // HIGH
+            // SUPER-CHIP: Switch to the 128x64 high resolution mode.
+            Instruction::High if !chip8 => self.set_hires(true),
             // JP <addr>: Jump to <addr>
-            0x1000 => self.pc = addr as u16,
+            Instruction::Jp(addr) => self.pc = addr,
             // CALL <addr>: call subroutine at <addr>
-            0x2000 => {
+            Instruction::Call(addr) => {
+                // The bottom slot of the stack is never used since the stack
+                // pointer is incremented before storing the return address.
+                if self.sp as usize + 1 >= STACK_SIZE {
+                    return Err(Error::StackOverflow { pc, opcode });
+                }
                 // Increments the stack pointer and adds the current program counter
                 // to the top of the stack.
                 self.sp += 1;
                 self.stack[self.sp as usize] = self.pc;
                 // Program counter set to <addr>.
-                self.pc = addr as u16;
+                self.pc = addr;
             },
             // SE vx, byte
             // Skips next instruction if Vx = lower byte.
-            0x3000 => {
-                if self.registers[vx] == lower {
-                    self.pc += 2;
+            Instruction::SeByte(vx, byte) => {
+                if self.registers[vx as usize] == byte {
+                    self.skip();
                 }
             },
             // SNE vx, byte
             // Skips next instruction if vx != lower byte.
-            0x4000 => {
-                if self.registers[vx] != lower {
-                    self.pc += 2;
+            Instruction::SneByte(vx, byte) => {
+                if self.registers[vx as usize] != byte {
+                    self.skip();
                 }
             },
             // SE vx, vy
             // Skip next instruction if vx == vy
-            0x5000 => {
-                if self.registers[vx] == self.registers[vy] {
-                    self.pc += 2
+            Instruction::SeReg(vx, vy) => {
+                if self.registers[vx as usize] == self.registers[vy as usize] {
+                    self.skip();
+                }
+            },
+            // SAVE vx - vy
+            // XO-CHIP: Store registers vx through vy in memory starting
+            // at I, in reverse order if vx > vy. I is left untouched.
+            Instruction::Save(vx, vy) if xo_chip => {
+                let (vx, vy) = (vx as usize, vy as usize);
+                let len = vx.abs_diff(vy) + 1;
+                let start = self.i_write(len, opcode)?;
+                for idx in 0..len {
+                    let reg = if vx > vy { vx - idx } else { vx + idx };
+                    self.memory[start + idx] = self.registers[reg];
+                }
+            },
+            // LOAD vx - vy
+            // XO-CHIP: Load registers vx through vy from memory starting
+            // at I, in reverse order if vx > vy. I is left untouched.
+            Instruction::Load(vx, vy) if xo_chip => {
+                let (vx, vy) = (vx as usize, vy as usize);
+                let len = vx.abs_diff(vy) + 1;
+                let start = self.i_read(len, opcode)?;
+                for idx in 0..len {
+                    let reg = if vx > vy { vx - idx } else { vx + idx };
+                    self.registers[reg] = self.memory[start + idx];
                 }
             },
             // LD vx, byte (vx = byte)
             // Puts the value of the lower byte into the register vx.
-            0x6000 => self.registers[vx] = lower,
+            Instruction::LdByte(vx, byte) => self.registers[vx as usize] = byte,
             // ADD vx, byte (vx = vx + byte)
             // Adds the value of lower to the value in vx, storing the result in vx.
-            0x7000 => self.registers[vx] += lower,
-            0x8000 => {
-                match subinstr {
-                    // LD vx, vy
-                    0 => self.registers[vx] = self.registers[vy],
-                    // OR vx, vy
-                    1 => self.registers[vx] |= self.registers[vy],
-                    // AND vx, vy
-                    2 => self.registers[vx] &= self.registers[vy],
-                    // XOR vx, vy
-                    3 => self.registers[vx] ^= self.registers[vy],
-                    // ADD vx, vy
-                    4 => self.registers[vx] += self.registers[vy],
-                    // SUB vx, vy
-                    5 => self.registers[vx] -= self.registers[vy],
-                    // SHR vx {, vy} (bit shift right)
-                    // vx = vx shr 1.
-                    // If the least significant bit of vx is 1, then vf is set to 1, otherwise 0.
-                    // Then vx is divided by 2.
-                    6 => {
-                        let lsb = self.registers[vx] & 1;
-                        // Set VF to least-significant bit before shift
-                        self.registers[Register::VF as usize] = lsb;
-                        self.registers[vx] >>= 1;
-                    },
-                    // SUBN vx, vy
-                    // vx = vy - vx, set vf = 1 if vy > vx
-                    7 => {
-                        if self.registers[vy] > self.registers[vx] {
-                            self.registers[Register::VF as usize] = 1;
-                        } else {
-                            self.registers[Register::VF as usize] = 0;
-                        }
-                        self.registers[vx] = self.registers[vy] - self.registers[vx];
-                    },
-                    0xE => {
-                        let msb = (self.registers[vx] & 0b1000_0000) >> 7;
-                        // Set VF to most-significant bit before shift
-                        self.registers[Register::VF as usize] = msb;
-                        self.registers[vx] <<= 1;
-                    },
-                    _ => log!("Unknown opcode {:#X}", opcode),
+            // The carry flag is not changed and the result wraps around.
+            Instruction::AddByte(vx, byte) => {
+                self.registers[vx as usize] = self.registers[vx as usize].wrapping_add(byte);
+            },
+            // LD vx, vy
+            Instruction::LdReg(vx, vy) => self.registers[vx as usize] = self.registers[vy as usize],
+            // OR vx, vy
+            Instruction::Or(vx, vy) => {
+                self.registers[vx as usize] |= self.registers[vy as usize];
+                self.vf_reset();
+            },
+            // AND vx, vy
+            Instruction::And(vx, vy) => {
+                self.registers[vx as usize] &= self.registers[vy as usize];
+                self.vf_reset();
+            },
+            // XOR vx, vy
+            Instruction::Xor(vx, vy) => {
+                self.registers[vx as usize] ^= self.registers[vy as usize];
+                self.vf_reset();
+            },
+            // ADD vx, vy
+            // vx = vx + vy, set vf = 1 if the result overflowed.
+            Instruction::AddReg(vx, vy) => {
+                let (sum, carry) = self.registers[vx as usize].overflowing_add(self.registers[vy as usize]);
+                self.registers[vx as usize] = sum;
+                self.registers[Register::VF as usize] = carry as u8;
+            },
+            // SUB vx, vy
+            // vx = vx - vy, set vf = 1 if there was no borrow.
+            Instruction::Sub(vx, vy) => {
+                let (diff, borrow) = self.registers[vx as usize].overflowing_sub(self.registers[vy as usize]);
+                self.registers[vx as usize] = diff;
+                self.registers[Register::VF as usize] = !borrow as u8;
+            },
+            // SHR vx {, vy} (bit shift right)
+            // vx = vx shr 1.
+            // If the least significant bit of vx is 1, then vf is set to 1, otherwise 0.
+            // Then vx is divided by 2.
+            //
+            // The COSMAC VIP shifts vy and stores the result in vx.
+            Instruction::Shr(vx, vy) => {
+                let (vx, vy) = (vx as usize, vy as usize);
+                if !self.quirks.shift_in_place {
+                    self.registers[vx] = self.registers[vy];
                 }
+                // VF is set last so the flag wins when vx is VF.
+                let lsb = self.registers[vx] & 1;
+                self.registers[vx] >>= 1;
+                self.registers[Register::VF as usize] = lsb;
+            },
+            // SUBN vx, vy
+            // vx = vy - vx, set vf = 1 if there was no borrow.
+            Instruction::Subn(vx, vy) => {
+                let (diff, borrow) = self.registers[vy as usize].overflowing_sub(self.registers[vx as usize]);
+                self.registers[vx as usize] = diff;
+                self.registers[Register::VF as usize] = !borrow as u8;
+            },
+            // SHL vx {, vy} (bit shift left)
+            // Same as SHR, but the most significant bit ends up in vf.
+            Instruction::Shl(vx, vy) => {
+                let (vx, vy) = (vx as usize, vy as usize);
+                if !self.quirks.shift_in_place {
+                    self.registers[vx] = self.registers[vy];
+                }
+                let msb = (self.registers[vx] & 0b1000_0000) >> 7;
+                self.registers[vx] <<= 1;
+                self.registers[Register::VF as usize] = msb;
             },
             // SNE vx, vy
             // Skip next instruction if vx != vy
-            0x9000 => {
-                if self.registers[vx] != self.registers[vy] {
-                    self.pc += 2;
+            Instruction::SneReg(vx, vy) => {
+                if self.registers[vx as usize] != self.registers[vy as usize] {
+                    self.skip();
                 }
             },
             // LD i, <addr>
-            0xA000 => self.i_reg = addr,
+            Instruction::LdI(addr) => self.i_reg = addr,
             // JP V0, <addr>
             // Jump to location v0 + <addr>
-            0xB000 => self.pc = u16::from(self.registers[Register::V0 as usize]) + addr,
+            //
+            // CHIP-48 & SUPER-CHIP read this as JP vx, <addr> (with x being
+            // the highest nibble of the address) instead.
+            Instruction::JpV0(addr) => {
+                let offset = if self.quirks.jump_vx { (addr >> 8) as usize } else { Register::V0 as usize };
+                self.pc = u16::from(self.registers[offset]) + addr;
+            },
             // RND vx, byte
             // vx = random byte AND kk
             // Generates a random number from 0 to 255 which is then ANDed with the
             // lower byte and stored in VX.
-            0xC000 => {
-                self.registers[vx] = random_byte() & lower;
+            Instruction::Rnd(vx, byte) => {
+                self.registers[vx as usize] = self.rng.next_u8() & byte;
             },
             // DRW vx, vy, nibble
             // Display n-byte sprite starting at memory location I at (vx, vy) and set
             // VF = collision.
             // A collision occurs if during sprite xor-ing any pixels are erased.
-            // The sprite should wrap the screen if vx/vy is greater than the
-            // display width/height.
-            0xD000 => {
-                self.registers[Register::VF as usize] = 0;
-                // Starting point for the sprite.
-                let mut px = self.registers[vx];
-                let mut py = self.registers[vy];
-                // Loop each row of the sprite.
-                for idx in 0..subinstr {
-                    let byte = self.memory[(self.i_reg + idx) as usize];
-                    // Loop through each bit.
-                    for bit_idx in 0..8 {
-                        let value = (byte & (0b1000_0000 >> bit_idx)) >> (7 - bit_idx);
-                        // Handle horizontal wrapping.
-                        let mut wx = px;
-                        if px >= (DISPLAY_WIDTH as u8) {
-                            wx = px % DISPLAY_WIDTH as u8;
-                        }
-
-                        // Handle vertical wrapping.
-                        let mut wy = py;
-                        if py >= (DISPLAY_HEIGHT as u8) {
-                            wy = py % DISPLAY_HEIGHT as u8;
-                        }
-
-                        let display_idx = (wy as usize) * DISPLAY_WIDTH + (wx as usize);
-                        if display_idx > (DISPLAY_HEIGHT * DISPLAY_WIDTH) {
-                            log!("wx: {}, wy: {}, px: {}, py: {}", wx, wy, px, py);
-                        }
-
-                        // Set VF register if we erase a pixel.
-                        if self.registers[Register::VF as usize] == 0
-                            && value == 1
-                            && self.display[display_idx] == 1 {
-                            self.registers[Register::VF as usize] = 1;
-                        }
-
-                        // Remember that you need to XOR the value instead of
-                        // just setting the display at this index.
-                        self.display[display_idx] ^= value;
-                        px += 1;
-                    }
-                    px = self.registers[vx];
-                    py += 1;
+            //
+            // SUPER-CHIP draws a 16x16 sprite (2 bytes per row) when nibble is 0.
+            Instruction::Drw(vx, vy, n) => {
+                let (width, rows) = if n == 0 && !chip8 {
+                    (16, 16)
+                } else {
+                    (8, n as usize)
+                };
+
+                let x = self.registers[vx as usize];
+                let y = self.registers[vy as usize];
+                let collision = self.draw_sprite(x, y, width, rows, opcode)?;
+                self.registers[Register::VF as usize] = collision;
+            },
+            // SKP Vx
+            // Skip next instruction if key with the value of Vx is pressed.
+            //
+            // Checks the keyboard, and if the key corresponding to the value of Vx
+            // is currently in the down position, PC is increased by 2.
+            Instruction::Skp(vx) => {
+                let key = self.registers[vx as usize] & 0xF;
+                if self.keys[key as usize] {
+                    self.skip();
                 }
             },
-            0xE000 => {
-                match lower {
-                    // SKP Vx
-                    // Skip next instruction if key with the value of Vx is pressed.
-                    //
-                    // Checks the keyboard, and if the key corresponding to the value of Vx
-                    // is currently in the down position, PC is increased by 2.
-                    0x9E => {
-                        let key = self.registers[vx as usize];
-                        if self.keys[key as usize] {
-                            self.pc += 2;
-                        }
-                    },
-                    // SKNP Vx
-                    // Skip next instruction if key with the value of Vx is not pressed.
-                    //
-                    // Checks the keyboard, and if the key corresponding to
-                    // the value of Vx is currently in the up position, PC
-                    // is increased by 2.
-                    0xA1 => {
-                        let key = self.registers[vx as usize];
-                        if !self.keys[key as usize] {
-                            self.pc += 2;
-                        }
-                    },
-                    _ => log!("Unknown opcode {:#X}", opcode)
+            // SKNP Vx
+            // Skip next instruction if key with the value of Vx is not pressed.
+            //
+            // Checks the keyboard, and if the key corresponding to
+            // the value of Vx is currently in the up position, PC
+            // is increased by 2.
+            Instruction::Sknp(vx) => {
+                let key = self.registers[vx as usize] & 0xF;
+                if !self.keys[key as usize] {
+                    self.skip();
                 }
             },
-            0xF000 => {
-                match lower {
-                    // LD vx, DT
-                    0x07 => self.registers[vx] = self.registers[Register::DT as usize],
-                    // LD vx, k
-                    0x0A => {
-                        match self.current_key {
-                            Some(key) => {
-                                self.registers[vx] = key as u8;
-                            },
-                            // NOTE: This blocks all execution until a key press. This is
-                            // simulated by not advancing the PC forward until the key
-                            // press is detected.
-                            None => self.pc -= 2,
-                        }
-                    },
-                    // LD dt, vx
-                    0x15 => self.registers[Register::DT as usize] = self.registers[vx],
-                    // LD st, vx
-                    0x18 => self.registers[Register::ST as usize] = self.registers[vx],
-                    // ADD I, vx
-                    0x1E => self.i_reg += u16::from(self.registers[vx]),
-                    // LD f, vx
-                    // The value of I is set to the location for the hexadecimal
-                    // sprite corresponding to the value of vx.
-                    // Index via simple multiply since each sprite is 5 bytes long.
-                    0x29 => self.i_reg = (vx * 5) as u16,
-                    // LD b, vx
-                    // Store the BCD representation of vx in memory locations I, I+1, I+2
-                    0x33 => {
-                        let mut num = self.registers[vx];
-                        for idx in (0..3).rev() {
-                            self.memory[(self.i_reg + idx) as usize] = (num % 10) as u8;
-                            num = num / 10;
-                        }
-                    },
-                    // LD [I], vx
-                    // Copies the value of registers v0 through vx into memory.
-                    0x55 => {
-                        for idx in 0..vx {
-                            self.memory[self.i_reg as usize + idx] = self.registers[idx];
-                        }
-                    },
-                    // LD vx, [i]
-                    0x65 => {
-                        for idx in 0..vx {
-                            self.registers[idx] = self.memory[self.i_reg as usize + idx];
-                        }
+            // LD i, long <addr>
+            // XO-CHIP: Load the 16-bit address stored in the next two
+            // bytes into I and skip past it.
+            Instruction::LdILong if xo_chip => {
+                let addr = self.fetch()?;
+                self.i_reg = addr;
+            },
+            // PLANE n
+            // XO-CHIP: Select the display planes drawing instructions
+            // operate on.
+            Instruction::Plane(n) if xo_chip => self.planes = n & 0b11,
+            // AUDIO
+            // XO-CHIP: Load the 16 byte audio pattern starting at I.
+            Instruction::Audio if xo_chip => {
+                let start = self.i_read(AUDIO_PATTERN_SIZE, opcode)?;
+                self.audio_pattern.copy_from_slice(&self.memory[start..start + AUDIO_PATTERN_SIZE]);
+            },
+            // PITCH vx
+            // XO-CHIP: Set the audio pattern playback pitch.
+            Instruction::Pitch(vx) if xo_chip => self.pitch = self.registers[vx as usize],
+            // LD vx, DT
+            Instruction::LdVxDt(vx) => self.registers[vx as usize] = self.registers[Register::DT as usize],
+            // LD vx, k
+            Instruction::LdVxK(vx) => {
+                match self.current_key {
+                    Some(key) => {
+                        self.registers[vx as usize] = key as u8;
                     },
-                    _ => log!("Unknown opcode {:#X}", opcode)
+                    // NOTE: This blocks all execution until a key press. This is
+                    // simulated by not advancing the PC forward until the key
+                    // press is detected.
+                    None => self.pc -= 2,
                 }
             },
-            _ => log!("Unknown opcode {:#X}", opcode)
+            // LD dt, vx
+            Instruction::LdDtVx(vx) => self.registers[Register::DT as usize] = self.registers[vx as usize],
+            // LD st, vx
+            Instruction::LdStVx(vx) => self.registers[Register::ST as usize] = self.registers[vx as usize],
+            // ADD I, vx
+            Instruction::AddI(vx) => self.i_reg = self.i_reg.wrapping_add(u16::from(self.registers[vx as usize])),
+            // LD f, vx
+            // The value of I is set to the location for the hexadecimal
+            // sprite corresponding to the value of vx.
+            // Index via simple multiply since each sprite is 5 bytes long.
+            Instruction::LdF(vx) => self.i_reg = u16::from(self.registers[vx as usize] & 0xF) * 5,
+            // LD hf, vx
+            // Same as above but for the large SUPER-CHIP font, each
+            // sprite is 10 bytes long.
+            Instruction::LdHf(vx) if !chip8 => {
+                self.i_reg = (BIG_FONT_ADDR + usize::from(self.registers[vx as usize] & 0xF) * 10) as u16;
+            },
+            // LD b, vx
+            // Store the BCD representation of vx in memory locations I, I+1, I+2
+            Instruction::LdB(vx) => {
+                let start = self.i_write(3, opcode)?;
+                let mut num = self.registers[vx as usize];
+                for idx in (0..3).rev() {
+                    self.memory[start + idx] = num % 10;
+                    num /= 10;
+                }
+            },
+            // LD [I], vx
+            // Copies the value of registers v0 through vx into memory.
+            Instruction::LdIVx(vx) => {
+                let vx = vx as usize;
+                let start = self.i_write(vx + 1, opcode)?;
+                for idx in 0..=vx {
+                    self.memory[start + idx] = self.registers[idx];
+                }
+                self.memory_increment(vx);
+            },
+            // LD vx, [i]
+            // Reads registers v0 through vx from memory.
+            Instruction::LdVxI(vx) => {
+                let vx = vx as usize;
+                let start = self.i_read(vx + 1, opcode)?;
+                for idx in 0..=vx {
+                    self.registers[idx] = self.memory[start + idx];
+                }
+                self.memory_increment(vx);
+            },
+            // LD r, vx
+            // SUPER-CHIP: Stores registers v0 through vx in the RPL user flags.
+            Instruction::LdRVx(vx) if !chip8 => {
+                let vx = vx as usize;
+                self.rpl[..=vx].copy_from_slice(&self.registers[..=vx]);
+            },
+            // LD vx, r
+            // SUPER-CHIP: Reads registers v0 through vx from the RPL user flags.
+            Instruction::LdVxR(vx) if !chip8 => {
+                let vx = vx as usize;
+                self.registers[..=vx].copy_from_slice(&self.rpl[..=vx]);
+            },
+            // Machine code routines (0NNN), instructions from another mode
+            // and anything else that doesn't decode.
+            _ => return unknown,
         }
+
+        Ok(())
     }
 
-    // Retrieves a pointer to the display memory.
+    // Retrieves a pointer to the display memory. Each pixel holds a bitmask
+    // of the XO-CHIP planes it is set in, so 0 or 1 for everything else.
     pub fn display(&self) -> *const u8 {
         self.display.as_ptr()
     }
 
+    // Retrieves a pointer to the XO-CHIP audio pattern buffer.
+    pub fn audio_pattern(&self) -> *const u8 {
+        self.audio_pattern.as_ptr()
+    }
+
+    // Playback rate of the XO-CHIP audio pattern in samples (bits) per
+    // second. A pitch of 64 plays back at 4000Hz.
+    pub fn audio_rate(&self) -> f64 {
+        4000.0 * 2f64.powf((f64::from(self.pitch) - 64.0) / 48.0)
+    }
+
     // Retrieves a pointer to the register bank.
     pub fn registers(&self) -> *const u8 {
         self.registers.as_ptr()
@@ -446,9 +980,10 @@ impl CHIP8 {
         self.stack.as_ptr()
     }
 
-    // Utility functions to get program counter & stack pointer.
+    // Utility functions to get program counter, stack pointer & I.
     pub fn pc(&self) -> u16 { self.pc }
     pub fn sp(&self) -> u8 { self.sp }
+    pub fn i_reg(&self) -> u16 { self.i_reg }
 
     pub fn key_press(&mut self, key: Key) {
         self.current_key = Some(key);
@@ -476,7 +1011,8 @@ impl CHIP8 {
         let mut start = 0;
         self.pc = 0x200;
         if let Some(data) = rom {
-            for byte in data.iter() {
+            // Anything that doesn't fit in memory is dropped.
+            for byte in data.iter().take(MEM_SIZE - self.pc as usize) {
                 self.memory[self.pc as usize + start] = *byte;
                 start += 1;
             }
@@ -505,35 +1041,124 @@ impl CHIP8 {
         }
         self.sp = 0;
         self.pc = 0;
+        self.exited = false;
+        self.fault = None;
+        self.timer_cycles = 0;
+        self.pending_cycles = 0.0;
+        self.cycles = 0;
+        self.rng = Rng::new(self.seed);
+        self.memory_changed();
+
+        // Release the keys, otherwise a key held while loading a rom would
+        // change how a movie replays.
+        self.keys = [false; 16];
+        self.current_key = None;
+
+        // Reset XO-CHIP audio & plane selection.
+        self.audio_pattern = [0; AUDIO_PATTERN_SIZE];
+        self.pitch = DEFAULT_PITCH;
+        self.planes = 1;
+
+        // Clear display and drop back to the regular resolution.
+        self.set_hires(false);
+        // Fonts live in memory, so they need to be put back.
+        self.load_fonts();
+    }
 
-        // Clear display
-        for i in 0..(DISPLAY_HEIGHT * DISPLAY_WIDTH) {
-            self.display[i] = 0;
-        }
+    // Runs a single instruction on the machine's backend. If the
+    // instruction faults the emulator is halted and every following tick
+    // returns the same fault.
+    pub fn tick(&mut self) -> Result<(), Error> {
+        self.run(1)
+    }
+}
+
+impl CHIP8 {
+    // `tick` on the interpreter, whatever the backend.
+    pub(super) fn step(&mut self) -> Result<(), Error> {
+        self.run_instruction(|emu| emu.fetch().and_then(|opcode| emu.execute(opcode)))
     }
 
-    pub fn tick(&mut self) {
-        // Handle delay & sound timers
-        if self.registers[Register::DT as usize] > 0 {
-            self.registers[Register::DT as usize] -= 1;
+    // Runs the instruction at the program counter. `execute` does the
+    // actual work, fetching the instruction or using one decoded earlier,
+    // and has to move the program counter past it.
+    pub(super) fn run_instruction<F>(&mut self, execute: F) -> Result<(), Error>
+        where F: FnOnce(&mut CHIP8) -> Result<(), Error>
+    {
+        if let Some(fault) = self.fault {
+            return Err(fault);
+        }
+
+        if self.exited {
+            return Ok(());
         }
 
-        if self.registers[Register::ST as usize] > 0 {
-            self.registers[Register::ST as usize] -= 1;
+        self.last_read = None;
+        self.last_write = None;
+
+        // The delay & sound timers run at 60Hz, independent of the
+        // instruction rate.
+        self.advance_timers();
+        self.cycles += 1;
+
+        let result = execute(self);
+        if let Err(fault) = result {
+            self.fault = Some(fault);
         }
 
-        // Fetch opcode
-        let opcode = self.fetch();
-        // Execute opcode
-        self.execute(opcode);
+        result
+    }
+
+    // Call after changing memory behind the emulator's back.
+    pub(super) fn memory_changed(&mut self) {
+        self.memory_epoch = self.memory_epoch.wrapping_add(1);
+    }
+}
+
+// Introspection for debuggers & other tools that doesn't need to be
+// exposed to JS.
+impl CHIP8 {
+    // The fault that halted the emulator, `fault` without the formatting.
+    pub fn faulted(&self) -> Option<Error> {
+        self.fault
+    }
+
+    // Memory read through the I register by the last instruction.
+    pub fn last_read(&self) -> Option<MemAccess> {
+        self.last_read
+    }
+
+    // Memory written through the I register by the last instruction.
+    pub fn last_write(&self) -> Option<MemAccess> {
+        self.last_write
+    }
+
+    // Native counterparts of the pointer getters above.
+    pub fn register_bank(&self) -> &[u8] {
+        &self.registers
+    }
+
+    // Only the part of memory the current mode can address.
+    pub fn ram(&self) -> &[u8] {
+        &self.memory[..self.mem_size()]
+    }
+
+    pub fn call_stack(&self) -> &[u16] {
+        &self.stack
+    }
+
+    // Pixels of the active resolution, row by row.
+    pub fn pixels(&self) -> &[u8] {
+        &self.display[..self.display_width() * self.display_height()]
     }
 }
 
 impl fmt::Display for CHIP8 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for py in 0..DISPLAY_HEIGHT {
-            for px in 0..DISPLAY_WIDTH {
-                let pixel = self.display[py * DISPLAY_WIDTH + px];
+        let width = self.display_width();
+        for py in 0..self.display_height() {
+            for px in 0..width {
+                let pixel = self.display[py * width + px];
                 let symbol = if pixel == 0 { '◻' } else { '◼' };
                 write!(f, "{}", symbol)?
             }
@@ -545,10 +1170,50 @@ impl fmt::Display for CHIP8 {
     }
 }
 
+// Decodes an opcode the way the interpreter runs it, which is looser than
+// the disassembler: 0NNN instructions only look at their low byte (so 01E0
+// clears the screen) and 9XYN ignores N.
+fn decode(opcode: u16) -> Instruction {
+    match disassemble(opcode) {
+        Instruction::Sys(_) => disassemble(opcode & 0x00FF),
+        Instruction::Unknown(_) if opcode & 0xF000 == 0x9000 => disassemble(opcode & 0xFFF0),
+        instr => instr,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    // A new machine on each backend.
+    fn machines() -> Vec<CHIP8> {
+        Backend::ALL.iter().map(|&backend| {
+            let mut emu = CHIP8::new();
+            emu.set_backend(backend);
+            emu
+        }).collect()
+    }
+
+    // Runs `opcode` on the machine's backend the way `execute` does, as the
+    // instruction right before the program counter. The JIT compiles it
+    // straight away instead of waiting for it to get hot, and faults don't
+    // stick so the next opcode still runs.
+    fn exec(emu: &mut CHIP8, opcode: u16) -> Result<(), Error> {
+        emu.pc = emu.pc.wrapping_sub(2);
+        let pc = emu.pc as usize;
+        emu.memory[pc] = (opcode >> 8) as u8;
+        emu.memory[pc + 1] = opcode as u8;
+        emu.memory_changed();
+        emu.fault = None;
+
+        if emu.backend() == Backend::Jit {
+            let mut jit = Jit::new();
+            jit.set_hot_runs(1);
+            return jit.run(emu, 1);
+        }
+        emu.tick()
+    }
+
     #[test]
     fn test_initialization() {
         let emu = CHIP8::new();
@@ -562,7 +1227,7 @@ mod tests {
         emu.memory[0x200] = 0xAB;
         emu.memory[0x201] = 0xCD;
         // Op-code should be read with most-sig byte first.
-        let opcode = emu.fetch();
+        let opcode = emu.fetch().unwrap();
         assert_eq!(opcode, 0xABCD);
     }
 
@@ -581,245 +1246,608 @@ mod tests {
 
     #[test]
     fn test_execute_0x1000() {
-        let mut emu = CHIP8::new();
-        // Test basic jump
-        emu.execute(0x1FED);
-        assert_eq!(emu.pc, 0x0FED);
+        for mut emu in machines() {
+            // Test basic jump
+            exec(&mut emu, 0x1FED).unwrap();
+            assert_eq!(emu.pc, 0x0FED);
+        }
     }
 
     #[test]
     fn test_execute_0x2000() {
-        let mut emu = CHIP8::new();
-        // Test function call
-        emu.pc = 0xDEAD;
-        emu.execute(0x2FED);
-        assert_eq!(emu.pc, 0x0FED);
-        assert_eq!(emu.stack[emu.sp as usize], 0xDEAD);
+        for mut emu in machines() {
+            // Test function call
+            emu.pc = 0x0EAD;
+            exec(&mut emu, 0x2FED).unwrap();
+            assert_eq!(emu.pc, 0x0FED);
+            assert_eq!(emu.stack[emu.sp as usize], 0x0EAD);
+        }
     }
 
     #[test]
     fn test_execute_0x3000() {
-        let mut emu = CHIP8::new();
-        // Test skip instruction
-        emu.pc = 0;
-        emu.registers[0] = 0xAD;
-        emu.execute(0x30AD);
-        assert_eq!(emu.pc, 2);
-
-        emu.pc = 0;
-        emu.registers[0] = 0;
-        emu.execute(0x30AD);
-        assert_eq!(emu.pc, 0);
+        for mut emu in machines() {
+            // Test skip instruction
+            emu.pc = 0x300;
+            emu.registers[0] = 0xAD;
+            exec(&mut emu, 0x30AD).unwrap();
+            assert_eq!(emu.pc, 0x302);
+
+            emu.pc = 0x300;
+            emu.registers[0] = 0;
+            exec(&mut emu, 0x30AD).unwrap();
+            assert_eq!(emu.pc, 0x300);
+        }
     }
 
     #[test]
     fn test_execute_0x4000() {
-        let mut emu = CHIP8::new();
-        // Test ne skip instruction
-        emu.pc = 0;
-        emu.registers[0] = 0xAD;
-        emu.execute(0x40AD);
-        assert_eq!(emu.pc, 0);
-
-        emu.pc = 0;
-        emu.registers[0] = 0;
-        emu.execute(0x40AD);
-        assert_eq!(emu.pc, 2);
+        for mut emu in machines() {
+            // Test ne skip instruction
+            emu.pc = 0x300;
+            emu.registers[0] = 0xAD;
+            exec(&mut emu, 0x40AD).unwrap();
+            assert_eq!(emu.pc, 0x300);
+
+            emu.pc = 0x300;
+            emu.registers[0] = 0;
+            exec(&mut emu, 0x40AD).unwrap();
+            assert_eq!(emu.pc, 0x302);
+        }
     }
 
     #[test]
     fn test_execute_0x5000() {
-        let mut emu = CHIP8::new();
-        emu.pc = 0;
-        emu.registers[0x0] = 0xAB;
-        emu.registers[0x1] = 0xAB;
-        emu.execute(0x5010);
-        assert_eq!(emu.pc, 2);
-
-        emu.pc = 0;
-        emu.registers[0x0] = 0xAB;
-        emu.registers[0x1] = 0xCD;
-        emu.execute(0x5010);
-        assert_eq!(emu.pc, 0);
+        for mut emu in machines() {
+            emu.pc = 0x300;
+            emu.registers[0x0] = 0xAB;
+            emu.registers[0x1] = 0xAB;
+            exec(&mut emu, 0x5010).unwrap();
+            assert_eq!(emu.pc, 0x302);
+
+            emu.pc = 0x300;
+            emu.registers[0x0] = 0xAB;
+            emu.registers[0x1] = 0xCD;
+            exec(&mut emu, 0x5010).unwrap();
+            assert_eq!(emu.pc, 0x300);
+        }
     }
 
     #[test]
     fn test_execute_0x6000() {
-        let mut emu = CHIP8::new();
-        emu.execute(0x60AB);
-        assert_eq!(emu.registers[0], 0xAB);
+        for mut emu in machines() {
+            exec(&mut emu, 0x60AB).unwrap();
+            assert_eq!(emu.registers[0], 0xAB);
+        }
     }
 
     #[test]
     fn test_execute_0x7000() {
-        let mut emu = CHIP8::new();
-        emu.registers[0] = 2;
-        emu.execute(0x7002);
-        assert_eq!(emu.registers[0], 4);
+        for mut emu in machines() {
+            emu.registers[0] = 2;
+            exec(&mut emu, 0x7002).unwrap();
+            assert_eq!(emu.registers[0], 4);
+        }
     }
 
     #[test]
     fn test_execute_0x8000() {
-        let mut emu = CHIP8::new();
-        // LD
-        emu.registers[1] = 0xAD;
-        emu.execute(0x8010);
-        assert_eq!(emu.registers[0], 0xAD);
-        // OR
-        emu.registers[0] = 0xF0;
-        emu.registers[1] = 0x0F;
-        emu.execute(0x8011);
-        assert_eq!(emu.registers[0], 0xFF);
-        // AND
-        emu.registers[0] = 0xF0;
-        emu.registers[1] = 0x0F;
-        emu.execute(0x8012);
-        assert_eq!(emu.registers[0], 0x00);
-        // XOR
-        emu.registers[0] = 0xF0;
-        emu.registers[1] = 0x0F;
-        emu.execute(0x8013);
-        assert_eq!(emu.registers[0], 0xFF);
-        // ADD
-        emu.registers[0] = 0x02;
-        emu.registers[1] = 0x02;
-        emu.execute(0x8014);
-        assert_eq!(emu.registers[0], 4);
-        // SUB
-        emu.registers[0] = 0x02;
-        emu.registers[1] = 0x02;
-        emu.execute(0x8015);
-        assert_eq!(emu.registers[0], 0);
-        // SHR
-        emu.registers[0] = 0x01;
-        emu.execute(0x8006);
-        // Right shifting 1 should result in VF = 1, V1 = 0
-        assert_eq!(emu.registers[Register::VF as usize], 1);
-        assert_eq!(emu.registers[0], 0);
-        // Right shifting 2 should result in VF = 0, V1 = 1
-        emu.registers[0] = 0b0010;
-        emu.execute(0x8006);
-        assert_eq!(emu.registers[Register::VF as usize], 0);
-        assert_eq!(emu.registers[0], 0b0001);
-        // SUBN vx, vy
-        emu.registers[0] = 0x02;
-        emu.registers[1] = 0x04;
-        emu.execute(0x8017);
-        assert_eq!(emu.registers[Register::VF as usize], 1);
-        assert_eq!(emu.registers[0], 2);
-        // SHL vx
-        emu.registers[0] = 0b1000_0000;
-        emu.execute(0x800E);
-        assert_eq!(emu.registers[Register::VF as usize], 1);
-        assert_eq!(emu.registers[0], 0);
-
-        emu.registers[0] = 0b0000_0001;
-        emu.execute(0x800E);
-        assert_eq!(emu.registers[Register::VF as usize], 0);
-        assert_eq!(emu.registers[0], 0b0010);
+        for mut emu in machines() {
+            // LD
+            emu.registers[1] = 0xAD;
+            exec(&mut emu, 0x8010).unwrap();
+            assert_eq!(emu.registers[0], 0xAD);
+            // OR
+            emu.registers[0] = 0xF0;
+            emu.registers[1] = 0x0F;
+            exec(&mut emu, 0x8011).unwrap();
+            assert_eq!(emu.registers[0], 0xFF);
+            // AND
+            emu.registers[0] = 0xF0;
+            emu.registers[1] = 0x0F;
+            exec(&mut emu, 0x8012).unwrap();
+            assert_eq!(emu.registers[0], 0x00);
+            // XOR
+            emu.registers[0] = 0xF0;
+            emu.registers[1] = 0x0F;
+            exec(&mut emu, 0x8013).unwrap();
+            assert_eq!(emu.registers[0], 0xFF);
+            // ADD
+            emu.registers[0] = 0x02;
+            emu.registers[1] = 0x02;
+            exec(&mut emu, 0x8014).unwrap();
+            assert_eq!(emu.registers[0], 4);
+            // SUB
+            emu.registers[0] = 0x02;
+            emu.registers[1] = 0x02;
+            exec(&mut emu, 0x8015).unwrap();
+            assert_eq!(emu.registers[0], 0);
+            // SHR
+            emu.registers[0] = 0x01;
+            exec(&mut emu, 0x8006).unwrap();
+            // Right shifting 1 should result in VF = 1, V1 = 0
+            assert_eq!(emu.registers[Register::VF as usize], 1);
+            assert_eq!(emu.registers[0], 0);
+            // Right shifting 2 should result in VF = 0, V1 = 1
+            emu.registers[0] = 0b0010;
+            exec(&mut emu, 0x8006).unwrap();
+            assert_eq!(emu.registers[Register::VF as usize], 0);
+            assert_eq!(emu.registers[0], 0b0001);
+            // SUBN vx, vy
+            emu.registers[0] = 0x02;
+            emu.registers[1] = 0x04;
+            exec(&mut emu, 0x8017).unwrap();
+            assert_eq!(emu.registers[Register::VF as usize], 1);
+            assert_eq!(emu.registers[0], 2);
+            // Equal registers don't borrow, like SUB.
+            emu.registers[0] = 0x04;
+            emu.registers[1] = 0x04;
+            exec(&mut emu, 0x8017).unwrap();
+            assert_eq!(emu.registers[Register::VF as usize], 1);
+            assert_eq!(emu.registers[0], 0);
+            emu.registers[0] = 0x05;
+            emu.registers[1] = 0x04;
+            exec(&mut emu, 0x8017).unwrap();
+            assert_eq!(emu.registers[Register::VF as usize], 0);
+            assert_eq!(emu.registers[0], 0xFF);
+            // The flag wins when vx is VF.
+            emu.registers[0xF] = 0x02;
+            emu.registers[1] = 0x01;
+            exec(&mut emu, 0x8F17).unwrap();
+            assert_eq!(emu.registers[Register::VF as usize], 0);
+            // SHL vx
+            emu.registers[0] = 0b1000_0000;
+            exec(&mut emu, 0x800E).unwrap();
+            assert_eq!(emu.registers[Register::VF as usize], 1);
+            assert_eq!(emu.registers[0], 0);
+
+            emu.registers[0] = 0b0000_0001;
+            exec(&mut emu, 0x800E).unwrap();
+            assert_eq!(emu.registers[Register::VF as usize], 0);
+            assert_eq!(emu.registers[0], 0b0010);
+            // Shifting VF itself leaves the flag.
+            emu.registers[0xF] = 0b1000_0011;
+            exec(&mut emu, 0x8F06).unwrap();
+            assert_eq!(emu.registers[Register::VF as usize], 1);
+            emu.registers[0xF] = 0b0100_0000;
+            exec(&mut emu, 0x8F0E).unwrap();
+            assert_eq!(emu.registers[Register::VF as usize], 0);
+        }
     }
 
     #[test]
     fn test_execute_0x9000() {
-        let mut emu = CHIP8::new();
-        emu.pc = 0;
-        emu.registers[0] = 0xAB;
-        emu.registers[1] = 0xCD;
-        emu.execute(0x9010);
-        assert_eq!(emu.pc, 2);
+        for mut emu in machines() {
+            emu.pc = 0x300;
+            emu.registers[0] = 0xAB;
+            emu.registers[1] = 0xCD;
+            exec(&mut emu, 0x9010).unwrap();
+            assert_eq!(emu.pc, 0x302);
+            // The low nibble is ignored.
+            exec(&mut emu, 0x9015).unwrap();
+            assert_eq!(emu.pc, 0x304);
+        }
     }
 
     #[test]
     fn test_execute_0xa000() {
-        let mut emu = CHIP8::new();
-        emu.execute(0xABCD);
-        assert_eq!(emu.i_reg, 0xBCD);
+        for mut emu in machines() {
+            exec(&mut emu, 0xABCD).unwrap();
+            assert_eq!(emu.i_reg, 0xBCD);
+        }
     }
 
     #[test]
     fn test_execute_0xb000() {
-        let mut emu = CHIP8::new();
-        emu.registers[0] = 0xF;
-        emu.execute(0xBCD0);
-        assert_eq!(emu.pc, 0xCDF);
+        for mut emu in machines() {
+            emu.registers[0] = 0xF;
+            exec(&mut emu, 0xBCD0).unwrap();
+            assert_eq!(emu.pc, 0xCDF);
+        }
     }
 
     #[test]
     fn test_execute_0xc000() {
-        let mut emu = CHIP8::new();
-        emu.execute(0xC0AD);
-        assert_ne!(emu.registers[0], 0);
+        for mut emu in machines() {
+            exec(&mut emu, 0xC0FF).unwrap();
+            let value = emu.registers[0];
+
+            // The same seed always produces the same byte.
+            emu.set_seed(emu.seed());
+            exec(&mut emu, 0xC0AD).unwrap();
+            assert_eq!(emu.registers[0], value & 0xAD);
+        }
     }
 
     #[test]
     fn test_execute_0xd000() {
-        let mut emu = CHIP8::new();
-        // Fake sprite.
-        emu.memory[0] = 0xFF;
-        emu.execute(0xD001);
-        // VF register should be set to 0
-        assert_eq!(emu.registers[Register::VF as usize], 0);
-        // Check that the sprite was written to the display memory
-        for idx in 0..8 {
-            assert_eq!(emu.display[idx], 1);
-        }
-        // Writing to the same location on the display again with an
-        // the same sprite should set the VF register and erase the sprite.
-        emu.memory[0] = 0xFF;
-        emu.execute(0xD001);
-        assert_eq!(emu.registers[Register::VF as usize], 1);
-        // Check that the sprite was written to the display memory
-        for idx in 0..8 {
-            assert_eq!(emu.display[idx], 0);
+        for mut emu in machines() {
+            // Fake sprite.
+            emu.memory[0] = 0xFF;
+            exec(&mut emu, 0xD001).unwrap();
+            // VF register should be set to 0
+            assert_eq!(emu.registers[Register::VF as usize], 0);
+            // Check that the sprite was written to the display memory
+            for idx in 0..8 {
+                assert_eq!(emu.display[idx], 1);
+            }
+            // Writing to the same location on the display again with an
+            // the same sprite should set the VF register and erase the sprite.
+            emu.memory[0] = 0xFF;
+            exec(&mut emu, 0xD001).unwrap();
+            assert_eq!(emu.registers[Register::VF as usize], 1);
+            // Check that the sprite was written to the display memory
+            for idx in 0..8 {
+                assert_eq!(emu.display[idx], 0);
+            }
+
+            // Testing horizontal wrapping
+            emu.memory[0] = 0xFF;
+            emu.memory[1] = 0xFF;
+            emu.registers[0] = (DISPLAY_WIDTH - 1) as u8;
+            emu.registers[1] = 0;
+            exec(&mut emu, 0xD011).unwrap();
+            // Should start on the far right and then wrap over to the left again.
+            assert_eq!(emu.display[DISPLAY_WIDTH - 1], 1);
+            emu.display[DISPLAY_WIDTH - 1] = 0;
+            for idx in 0..7 {
+                assert_eq!(emu.display[idx], 1);
+                // Set back to zero for next test
+                emu.display[idx] = 0;
+            }
+
+            emu.registers[0] = (DISPLAY_WIDTH - 1) as u8;
+            emu.registers[1] = (DISPLAY_HEIGHT - 1) as u8;
+            exec(&mut emu, 0xD012).unwrap();
+            // Top right & bottom right pixels are set
+            assert_eq!(emu.display[DISPLAY_WIDTH - 1], 1);
+            assert_eq!(emu.display[(DISPLAY_HEIGHT - 1) * DISPLAY_WIDTH + (DISPLAY_WIDTH - 1)], 1);
+            // Top left 7 pixels and bottom left 7 pixels
+            for idx in 0..7 {
+                assert_eq!(emu.display[idx], 1);
+                assert_eq!(emu.display[(DISPLAY_HEIGHT - 1) * DISPLAY_WIDTH + idx], 1);
+            }
         }
+    }
+
+    #[test]
+    fn test_execute_0xf000() {
+        for mut emu in machines() {
+            emu.registers[0] = 123;
+            exec(&mut emu, 0xF033).unwrap();
+            // Should have the digits, 1, 2 & 3 in each individual memory
+            // location
+            for idx in 0..3 {
+                assert_eq!(emu.memory[idx], (idx + 1) as u8);
+            }
 
-        // Testing horizontal wrapping
-        emu.memory[0] = 0xFF;
-        emu.memory[1] = 0xFF;
-        emu.registers[0] = (DISPLAY_WIDTH - 1) as u8;
-        emu.registers[1] = 0;
-        emu.execute(0xD011);
-        // Should start on the far right and then wrap over to the left again.
-        assert_eq!(emu.display[DISPLAY_WIDTH - 1], 1);
-        emu.display[DISPLAY_WIDTH - 1] = 0;
-        for idx in 0..7 {
-            assert_eq!(emu.display[idx], 1);
-            // Set back to zero for next test
-            emu.display[idx] = 0;
+            // Simulate key press
+            let old_pc = emu.pc;
+            exec(&mut emu, 0xF00A).unwrap();
+            // PC should be decremented by 2 to simulate waiting for
+            // key press.
+            assert_eq!(old_pc - 2, emu.pc);
+            emu.current_key = Some(Key::KA);
+            exec(&mut emu, 0xF00A).unwrap();
+            assert_eq!(emu.registers[0], 0x0A);
         }
+    }
 
-        emu.registers[0] = (DISPLAY_WIDTH - 1) as u8;
-        emu.registers[1] = (DISPLAY_HEIGHT - 1) as u8;
-        emu.execute(0xD012);
-        // Top right & bottom right pixels are set
-        assert_eq!(emu.display[DISPLAY_WIDTH - 1], 1);
-        assert_eq!(emu.display[(DISPLAY_HEIGHT - 1) * DISPLAY_WIDTH + (DISPLAY_WIDTH - 1)], 1);
-        // Top left 7 pixels and bottom left 7 pixels
-        for idx in 0..7 {
-            assert_eq!(emu.display[idx], 1);
-            assert_eq!(emu.display[(DISPLAY_HEIGHT - 1) * DISPLAY_WIDTH + idx], 1);
+    #[test]
+    fn test_unknown_opcode() {
+        for mut emu in machines() {
+            emu.memory[0x200] = 0xE0;
+            emu.memory[0x201] = 0x00;
+            assert_eq!(emu.tick(), Err(Error::UnknownOpcode { pc: 0x200, opcode: 0xE000 }));
+            // The emulator is now halted and won't execute anything else.
+            assert!(emu.is_halted());
+            assert_eq!(emu.tick(), Err(Error::UnknownOpcode { pc: 0x200, opcode: 0xE000 }));
+            assert_eq!(emu.pc, 0x202);
+            // Resetting clears the fault.
+            emu.reset();
+            assert!(!emu.is_halted());
+            assert_eq!(emu.fault(), None);
         }
     }
 
     #[test]
-    fn test_execute_0xf000() {
-        let mut emu = CHIP8::new();
-        emu.registers[0] = 123;
-        emu.execute(0xF033);
-        // Should have the digits, 1, 2 & 3 in each individual memory
-        // location
-        for idx in 0..3 {
-            assert_eq!(emu.memory[idx], (idx + 1) as u8);
-        }
-
-        // Simulate key press
-        let old_pc = emu.pc;
-        emu.execute(0xF00A);
-        // PC should be decremented by 2 to simulate waiting for
-        // key press.
-        assert_eq!(old_pc - 2, emu.pc);
-        emu.current_key = Some(Key::KA);
-        emu.execute(0xF00A);
-        assert_eq!(emu.registers[0], 0x0A);
-    }
-}
\ No newline at end of file
+    fn test_loose_0nnn_decoding() {
+        // 0NNN instructions only look at their low byte, so these run as
+        // CLS, RET & EXIT.
+        for mut emu in machines() {
+            emu.set_mode(Mode::SuperChip);
+            emu.display[0] = 1;
+            exec(&mut emu, 0x01E0).unwrap();
+            assert_eq!(emu.display[0], 0);
+            exec(&mut emu, 0x2300).unwrap();
+            exec(&mut emu, 0x0AEE).unwrap();
+            assert_eq!(emu.sp, 0);
+            exec(&mut emu, 0x03FD).unwrap();
+            assert!(emu.is_halted());
+        }
+
+        // Anything else still faults, and reports the whole opcode.
+        for mut emu in machines() {
+            emu.memory[0x200] = 0x01;
+            emu.memory[0x201] = 0x23;
+            assert_eq!(emu.tick(), Err(Error::UnknownOpcode { pc: 0x200, opcode: 0x0123 }));
+        }
+    }
+
+    #[test]
+    fn test_stack_faults() {
+        for mut emu in machines() {
+            emu.pc = 0x202;
+            assert_eq!(exec(&mut emu, 0x00EE), Err(Error::StackUnderflow { pc: 0x200, opcode: 0x00EE }));
+
+            // Fill up the stack.
+            for _ in 0..(STACK_SIZE - 1) {
+                exec(&mut emu, 0x2200).unwrap();
+            }
+            assert_eq!(exec(&mut emu, 0x2200), Err(Error::StackOverflow { pc: 0x1FE, opcode: 0x2200 }));
+            assert_eq!(emu.sp as usize, STACK_SIZE - 1);
+        }
+    }
+
+    #[test]
+    fn test_pc_out_of_bounds() {
+        for mut emu in machines() {
+            emu.pc = (emu.mem_size() - 1) as u16;
+            assert_eq!(emu.tick(), Err(Error::PcOutOfBounds { pc: 0xFFF, opcode: 0 }));
+            assert!(emu.fault().is_some());
+        }
+    }
+
+    #[test]
+    fn test_i_out_of_bounds() {
+        for mut emu in machines() {
+            emu.pc = 0x202;
+            emu.i_reg = (emu.mem_size() - 2) as u16;
+            let fault = Err(Error::IOutOfBounds { pc: 0x200, opcode: 0xF033, i_reg: 0xFFE });
+            assert_eq!(exec(&mut emu, 0xF033), fault);
+            assert!(exec(&mut emu, 0xF155).is_ok());
+            assert!(exec(&mut emu, 0xF265).is_err());
+            assert!(exec(&mut emu, 0xD003).is_err());
+        }
+    }
+
+    #[test]
+    fn test_quirks_shift() {
+        for mut emu in machines() {
+            emu.registers[0] = 0b0000_0100;
+            emu.registers[1] = 0b0000_0011;
+            exec(&mut emu, 0x8016).unwrap();
+            assert_eq!(emu.registers[0], 0b0000_0010);
+            assert_eq!(emu.registers[Register::VF as usize], 0);
+
+            emu.set_quirks(Quirks::cosmac_vip());
+            emu.registers[0] = 0b0000_0100;
+            exec(&mut emu, 0x8016).unwrap();
+            assert_eq!(emu.registers[0], 0b0000_0001);
+            assert_eq!(emu.registers[Register::VF as usize], 1);
+            exec(&mut emu, 0x801E).unwrap();
+            assert_eq!(emu.registers[0], 0b0000_0110);
+            assert_eq!(emu.registers[Register::VF as usize], 0);
+        }
+    }
+
+    #[test]
+    fn test_quirks_memory_and_vf_reset() {
+        for mut emu in machines() {
+            emu.i_reg = 0x300;
+            emu.registers[0] = 1;
+            emu.registers[1] = 2;
+            exec(&mut emu, 0xF155).unwrap();
+            assert_eq!(emu.i_reg, 0x300);
+            assert_eq!(&emu.memory[0x300..0x302], &[1, 2]);
+
+            emu.set_quirks(Quirks::cosmac_vip());
+            exec(&mut emu, 0xF165).unwrap();
+            assert_eq!(emu.i_reg, 0x302);
+
+            emu.registers[Register::VF as usize] = 1;
+            exec(&mut emu, 0x8011).unwrap();
+            assert_eq!(emu.registers[Register::VF as usize], 0);
+        }
+    }
+
+    #[test]
+    fn test_quirks_jump() {
+        for mut emu in machines() {
+            emu.set_quirks(Quirks::super_chip());
+            emu.registers[0] = 0x1;
+            emu.registers[2] = 0x4;
+            exec(&mut emu, 0xB220).unwrap();
+            assert_eq!(emu.pc, 0x224);
+        }
+    }
+
+    #[test]
+    fn test_quirks_clipping() {
+        for mut emu in machines() {
+            emu.set_quirks(Quirks::cosmac_vip());
+            emu.memory[0] = 0xFF;
+            emu.memory[1] = 0xFF;
+            emu.registers[0] = (DISPLAY_WIDTH - 1) as u8;
+            emu.registers[1] = (DISPLAY_HEIGHT - 1) as u8;
+            exec(&mut emu, 0xD012).unwrap();
+            // Only the bottom right pixel is drawn, everything else is clipped.
+            assert_eq!(emu.display.iter().filter(|&&pixel| pixel == 1).count(), 1);
+            assert_eq!(emu.display[DISPLAY_HEIGHT * DISPLAY_WIDTH - 1], 1);
+
+            // The starting position still wraps.
+            emu.registers[0] = DISPLAY_WIDTH as u8;
+            emu.registers[1] = DISPLAY_HEIGHT as u8;
+            exec(&mut emu, 0xD011).unwrap();
+            for idx in 0..8 {
+                assert_eq!(emu.display[idx], 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_super_chip_opcodes_need_mode() {
+        for mut emu in machines() {
+            emu.pc = 0x202;
+            assert_eq!(exec(&mut emu, 0x00FF), Err(Error::UnknownOpcode { pc: 0x200, opcode: 0x00FF }));
+            assert_eq!(exec(&mut emu, 0xF030), Err(Error::UnknownOpcode { pc: 0x200, opcode: 0xF030 }));
+            emu.set_mode(Mode::SuperChip);
+            exec(&mut emu, 0x00FF).unwrap();
+            exec(&mut emu, 0xF030).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_super_chip_resolution() {
+        for mut emu in machines() {
+            emu.set_mode(Mode::SuperChip);
+            assert_eq!((emu.display_width(), emu.display_height()), (64, 32));
+            exec(&mut emu, 0x00FF).unwrap();
+            assert_eq!((emu.display_width(), emu.display_height()), (128, 64));
+
+            // Draw a 16x16 sprite in the bottom right corner.
+            for idx in 0..32 {
+                emu.memory[0x300 + idx] = 0xFF;
+            }
+            emu.i_reg = 0x300;
+            emu.registers[0] = (HIRES_WIDTH - 16) as u8;
+            emu.registers[1] = (HIRES_HEIGHT - 16) as u8;
+            exec(&mut emu, 0xD010).unwrap();
+            assert_eq!(emu.display.iter().filter(|&&pixel| pixel == 1).count(), 256);
+            assert_eq!(emu.display[DISPLAY_SIZE - 1], 1);
+            assert_eq!(emu.registers[Register::VF as usize], 0);
+
+            // Switching resolutions clears the display.
+            exec(&mut emu, 0x00FE).unwrap();
+            assert_eq!((emu.display_width(), emu.display_height()), (64, 32));
+            assert!(emu.display.iter().all(|&pixel| pixel == 0));
+        }
+    }
+
+    #[test]
+    fn test_super_chip_scroll() {
+        for mut emu in machines() {
+            emu.set_mode(Mode::SuperChip);
+            emu.display[0] = 1;
+            exec(&mut emu, 0x00C2).unwrap();
+            assert_eq!(emu.display[0], 0);
+            assert_eq!(emu.display[2 * DISPLAY_WIDTH], 1);
+
+            exec(&mut emu, 0x00FB).unwrap();
+            assert_eq!(emu.display[2 * DISPLAY_WIDTH + 4], 1);
+            exec(&mut emu, 0x00FC).unwrap();
+            exec(&mut emu, 0x00FC).unwrap();
+            // Scrolled off the left edge.
+            assert!(emu.display.iter().all(|&pixel| pixel == 0));
+        }
+    }
+
+    #[test]
+    fn test_super_chip_misc() {
+        for mut emu in machines() {
+            emu.set_mode(Mode::SuperChip);
+            // Large font
+            emu.registers[0] = 3;
+            exec(&mut emu, 0xF030).unwrap();
+            assert_eq!(emu.i_reg as usize, BIG_FONT_ADDR + 30);
+            assert_eq!(emu.memory[emu.i_reg as usize], BIG_FONT[3][0]);
+
+            // RPL flags
+            emu.registers[0] = 1;
+            emu.registers[1] = 2;
+            exec(&mut emu, 0xF175).unwrap();
+            emu.registers[0] = 0;
+            emu.registers[1] = 0;
+            exec(&mut emu, 0xF185).unwrap();
+            assert_eq!(&emu.registers[..2], &[1, 2]);
+
+            // Exit
+            emu.memory[0x200] = 0x00;
+            emu.memory[0x201] = 0xFD;
+            emu.tick().unwrap();
+            assert!(emu.is_halted());
+            emu.tick().unwrap();
+            assert_eq!(emu.pc, 0x200);
+        }
+    }
+
+    #[test]
+    fn test_xo_chip_memory() {
+        for mut emu in machines() {
+            emu.set_mode(Mode::XoChip);
+            assert_eq!(emu.mem_size(), 0x10000);
+            // Long I load
+            emu.memory[0x200..0x204].copy_from_slice(&[0xF0, 0x00, 0xBE, 0xEF]);
+            emu.tick().unwrap();
+            assert_eq!(emu.i_reg, 0xBEEF);
+            assert_eq!(emu.pc, 0x204);
+
+            // Skips jump over the whole long load.
+            emu.memory[0x204..0x20A].copy_from_slice(&[0x30, 0x00, 0xF0, 0x00, 0x12, 0x34]);
+            emu.tick().unwrap();
+            assert_eq!(emu.pc, 0x20A);
+        }
+    }
+
+    #[test]
+    fn test_xo_chip_register_ranges() {
+        for mut emu in machines() {
+            emu.set_mode(Mode::XoChip);
+            emu.i_reg = 0x400;
+            emu.registers[2] = 2;
+            emu.registers[3] = 3;
+            emu.registers[4] = 4;
+            exec(&mut emu, 0x5242).unwrap();
+            assert_eq!(&emu.memory[0x400..0x403], &[2, 3, 4]);
+            // Reverse order
+            exec(&mut emu, 0x5422).unwrap();
+            assert_eq!(&emu.memory[0x400..0x403], &[4, 3, 2]);
+            exec(&mut emu, 0x5133).unwrap();
+            assert_eq!(&emu.registers[1..4], &[4, 3, 2]);
+            assert_eq!(emu.i_reg, 0x400);
+        }
+    }
+
+    #[test]
+    fn test_xo_chip_planes() {
+        for mut emu in machines() {
+            emu.set_mode(Mode::XoChip);
+            emu.i_reg = 0x300;
+            emu.memory[0x300] = 0b1100_0000;
+            emu.memory[0x301] = 0b1010_0000;
+            // Draw on both planes, the second plane's sprite follows the first.
+            exec(&mut emu, 0xF301).unwrap();
+            exec(&mut emu, 0xD011).unwrap();
+            assert_eq!(&emu.display[0..3], &[0b11, 0b01, 0b10]);
+
+            // Clearing only touches the selected plane.
+            exec(&mut emu, 0xF101).unwrap();
+            exec(&mut emu, 0x00E0).unwrap();
+            assert_eq!(&emu.display[0..3], &[0b10, 0b00, 0b10]);
+
+            // Scrolling too.
+            exec(&mut emu, 0xF201).unwrap();
+            exec(&mut emu, 0x00C1).unwrap();
+            exec(&mut emu, 0x00D1).unwrap();
+            exec(&mut emu, 0x00D1).unwrap();
+            assert!(emu.display.iter().all(|&pixel| pixel == 0));
+        }
+    }
+
+    #[test]
+    fn test_xo_chip_audio() {
+        for mut emu in machines() {
+            emu.set_mode(Mode::XoChip);
+            assert_eq!(emu.audio_rate(), 4000.0);
+            emu.i_reg = 0x300;
+            for idx in 0..AUDIO_PATTERN_SIZE {
+                emu.memory[0x300 + idx] = idx as u8;
+            }
+            exec(&mut emu, 0xF002).unwrap();
+            assert_eq!(emu.audio_pattern[15], 15);
+            emu.registers[0] = 112;
+            exec(&mut emu, 0xF03A).unwrap();
+            assert_eq!(emu.audio_rate(), 8000.0);
+        }
+    }
+}
@@ -0,0 +1,361 @@
+// Movies
+// ------
+// Records every key press & release together with the cycle it happened
+// on, so a session can be played back bit-for-bit later. Everything else
+// that influences a run (the rom, RNG seed, mode, quirks and clock rate) is
+// stored alongside, which makes a movie a reproducible bug report or a
+// regression test for a full game session.
+//
+// Recording always starts from a freshly loaded rom with no keys held.
+// Inputs are applied right before the instruction with the recorded cycle
+// number executes.
+//
+// File layout, all multi-byte values are little endian:
+//
+//   magic      4 bytes  "C8MV"
+//   version    1 byte
+//   rom hash   4 bytes  CRC-32 of the rom
+//   seed       8 bytes
+//   mode       1 byte
+//   quirks     5 bytes
+//   clock      4 bytes  instructions per second
+//   end        8 bytes  cycle the recording stopped at
+//   count      4 bytes  number of inputs
+//   inputs     count * <cycle: 8 bytes><key: 1 byte><pressed: 1 byte>
+//   checksum   4 bytes  CRC-32 of everything before it
+use std::error;
+use std::fmt;
+use wasm_bindgen::prelude::*;
+
+use super::state::{ crc32, Reader, Writer };
+use super::{ Error, Key, Mode, Quirks, StateError, CHIP8 };
+
+const MAGIC: &[u8; 4] = b"C8MV";
+const VERSION: u8 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MovieError {
+    // The movie file couldn't be read.
+    BadFile(StateError),
+    // The rom doesn't match the one the movie was recorded with.
+    RomMismatch,
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MovieError::BadFile(err) => write!(f, "Bad movie file: {}", err),
+            MovieError::RomMismatch => write!(f, "Movie was recorded with a different rom"),
+        }
+    }
+}
+
+impl error::Error for MovieError {}
+
+impl From<StateError> for MovieError {
+    fn from(err: StateError) -> MovieError {
+        MovieError::BadFile(err)
+    }
+}
+
+impl From<MovieError> for JsValue {
+    fn from(err: MovieError) -> JsValue {
+        JsValue::from_str(&err.to_string())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Input {
+    cycle: u64,
+    key: u8,
+    pressed: bool,
+}
+
+#[wasm_bindgen]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Movie {
+    rom_hash: u32,
+    seed: u64,
+    mode: Mode,
+    quirks: Quirks,
+    clock_rate: u32,
+    inputs: Vec<Input>,
+    end: u64,
+    // Next input to apply during playback.
+    cursor: usize,
+}
+
+#[wasm_bindgen]
+impl Movie {
+    // Loads `rom` and starts recording. Key presses need to go through
+    // `key_press` & `key_up` from here on to end up in the movie.
+    pub fn record(emu: &mut CHIP8, rom: &[u8]) -> Movie {
+        emu.load_rom(Some(rom.to_vec().into_boxed_slice()));
+        Movie {
+            rom_hash: crc32(rom),
+            seed: emu.seed,
+            mode: emu.mode,
+            quirks: emu.quirks,
+            clock_rate: emu.clock_rate,
+            inputs: Vec::new(),
+            end: 0,
+            cursor: 0,
+        }
+    }
+
+    pub fn key_press(&mut self, emu: &mut CHIP8, key: Key) {
+        self.push(emu, key, true);
+        emu.key_press(key);
+    }
+
+    pub fn key_up(&mut self, emu: &mut CHIP8, key: Key) {
+        self.push(emu, key, false);
+        emu.key_up(key);
+    }
+
+    // Marks where the recording ends.
+    pub fn stop(&mut self, emu: &CHIP8) {
+        self.end = emu.cycles;
+    }
+
+    // Number of recorded inputs.
+    pub fn len(&self) -> usize { self.inputs.len() }
+    pub fn is_empty(&self) -> bool { self.inputs.is_empty() }
+    // Cycle the recording stopped at.
+    pub fn end_cycle(&self) -> u64 { self.end }
+
+    // Sets the machine up the way it was when recording started and loads
+    // `rom`, which has to be the rom the movie was recorded with.
+    pub fn play(&mut self, emu: &mut CHIP8, rom: &[u8]) -> Result<(), MovieError> {
+        if crc32(rom) != self.rom_hash {
+            return Err(MovieError::RomMismatch);
+        }
+
+        emu.set_mode(self.mode);
+        emu.set_quirks(self.quirks);
+        emu.set_clock_rate(self.clock_rate);
+        emu.set_seed(self.seed);
+        emu.load_rom(Some(rom.to_vec().into_boxed_slice()));
+        self.cursor = 0;
+
+        Ok(())
+    }
+
+    // Applies the inputs due before the next instruction and runs it.
+    pub fn tick(&mut self, emu: &mut CHIP8) -> Result<(), Error> {
+        self.apply_inputs(emu);
+        emu.tick()
+    }
+
+    // Playback counterpart of `CHIP8::run_for`.
+    pub fn run_for(&mut self, emu: &mut CHIP8, elapsed_ms: f64) -> Result<(), Error> {
+        for _ in 0..emu.owed_cycles(elapsed_ms) {
+            self.tick(emu)?;
+        }
+
+        Ok(())
+    }
+
+    // Whether playback reached the end of the recording.
+    pub fn is_finished(&self, emu: &CHIP8) -> bool {
+        emu.cycles >= self.end || emu.is_halted()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = Writer { buf: Vec::new() };
+        w.bytes(MAGIC);
+        w.u8(VERSION);
+        w.u32(self.rom_hash);
+        w.u64(self.seed);
+        w.mode(self.mode);
+        w.quirks(self.quirks);
+        w.u32(self.clock_rate);
+        w.u64(self.end);
+        w.u32(self.inputs.len() as u32);
+        for input in self.inputs.iter() {
+            w.u64(input.cycle);
+            w.u8(input.key);
+            w.bool(input.pressed);
+        }
+        let checksum = crc32(&w.buf);
+        w.u32(checksum);
+
+        w.buf
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Movie, MovieError> {
+        if data.len() < MAGIC.len() + 1 + 4 {
+            return Err(StateError::Corrupt.into());
+        }
+        if &data[..MAGIC.len()] != MAGIC {
+            return Err(StateError::BadMagic.into());
+        }
+        let version = data[MAGIC.len()];
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version).into());
+        }
+
+        let (contents, checksum) = data.split_at(data.len() - 4);
+        let mut r = Reader { buf: checksum };
+        if r.u32()? != crc32(contents) {
+            return Err(StateError::BadChecksum.into());
+        }
+
+        let mut r = Reader { buf: &contents[MAGIC.len() + 1..] };
+        let mut movie = Movie {
+            rom_hash: r.u32()?,
+            seed: r.u64()?,
+            mode: r.mode()?,
+            quirks: r.quirks()?,
+            clock_rate: r.u32()?,
+            end: r.u64()?,
+            inputs: Vec::new(),
+            cursor: 0,
+        };
+        let count = r.u32()?;
+        for _ in 0..count {
+            let input = Input { cycle: r.u64()?, key: r.u8()?, pressed: r.bool()? };
+            let ordered = movie.inputs.last().map_or(true, |last| last.cycle <= input.cycle);
+            if Key::from_index(input.key).is_none() || !ordered {
+                return Err(StateError::Corrupt.into());
+            }
+            movie.inputs.push(input);
+        }
+        if movie.clock_rate == 0 || !r.buf.is_empty() {
+            return Err(StateError::Corrupt.into());
+        }
+
+        Ok(movie)
+    }
+}
+
+impl Movie {
+    // Applies the inputs due before the next instruction without running
+    // it, for playing a movie on something other than `CHIP8::tick`.
+    pub fn apply_inputs(&mut self, emu: &mut CHIP8) {
+        while let Some(input) = self.inputs.get(self.cursor) {
+            if input.cycle > emu.cycles {
+                break;
+            }
+
+            if let Some(key) = Key::from_index(input.key) {
+                if input.pressed {
+                    emu.key_press(key);
+                } else {
+                    emu.key_up(key);
+                }
+            }
+            self.cursor += 1;
+        }
+    }
+
+    fn push(&mut self, emu: &CHIP8, key: Key, pressed: bool) {
+        self.inputs.push(Input { cycle: emu.cycles, key: key as u8, pressed });
+        self.end = emu.cycles;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{ assemble };
+
+    fn game() -> Vec<u8> {
+        assemble("
+            loop:
+                LD V0, K
+                RND V1, 0xFF
+                ADD V2, V1
+                ADD V3, V0
+                LD I, 0x300
+                LD [I], V3
+                JP loop
+        ").unwrap()
+    }
+
+    // Records a session, returning the movie and the final state.
+    fn record() -> (Movie, Vec<u8>) {
+        let mut emu = CHIP8::new();
+        emu.set_seed(99);
+        emu.set_mode(Mode::SuperChip);
+        let mut movie = Movie::record(&mut emu, &game());
+        for (step, &key) in [Key::K5, Key::KA, Key::K1, Key::K5].iter().enumerate() {
+            for _ in 0..10 + step * 7 {
+                emu.tick().unwrap();
+            }
+            movie.key_press(&mut emu, key);
+            emu.run_for(20.0).unwrap();
+            movie.key_up(&mut emu, key);
+        }
+        emu.run_for(50.0).unwrap();
+        movie.stop(&emu);
+
+        (movie, emu.save_state())
+    }
+
+    #[test]
+    fn test_replay() {
+        let (mut movie, state) = record();
+        assert_eq!(movie.len(), 8);
+
+        let mut emu = CHIP8::new();
+        movie.play(&mut emu, &game()).unwrap();
+        assert_eq!(emu.mode(), Mode::SuperChip);
+        while !movie.is_finished(&emu) {
+            movie.tick(&mut emu).unwrap();
+        }
+        assert_eq!(emu.save_state(), state);
+        assert_ne!(emu.memory[0x300], 0);
+
+        // Playing again starts over. Running in real time overshoots the
+        // end, but the rom is back to waiting for a key by then.
+        let expected = emu.clone();
+        movie.play(&mut emu, &game()).unwrap();
+        while !movie.is_finished(&emu) {
+            movie.run_for(&mut emu, 10.0).unwrap();
+        }
+        assert_eq!(emu.registers, expected.registers);
+        assert_eq!(emu.memory[0x300], expected.memory[0x300]);
+
+        assert_eq!(movie.play(&mut emu, &[0x12, 0x00]), Err(MovieError::RomMismatch));
+    }
+
+    // A key held before playing doesn't count, the recording started with
+    // none held.
+    #[test]
+    fn test_replay_with_key_held() {
+        let rom = assemble("
+            loop:
+                SKNP V0
+                ADD V1, 1
+                JP loop
+        ").unwrap();
+        let mut emu = CHIP8::new();
+        let mut movie = Movie::record(&mut emu, &rom);
+        for _ in 0..30 {
+            emu.tick().unwrap();
+        }
+        movie.stop(&emu);
+        let state = emu.save_state();
+
+        let mut emu = CHIP8::new();
+        emu.key_press(Key::K0);
+        movie.play(&mut emu, &rom).unwrap();
+        while !movie.is_finished(&emu) {
+            movie.tick(&mut emu).unwrap();
+        }
+        assert_eq!(emu.save_state(), state);
+    }
+
+    #[test]
+    fn test_file() {
+        let (movie, _) = record();
+        let data = movie.to_bytes();
+        assert_eq!(Movie::from_bytes(&data), Ok(movie));
+
+        let mut bad = data.clone();
+        bad[20] ^= 1;
+        assert_eq!(Movie::from_bytes(&bad), Err(MovieError::BadFile(StateError::BadChecksum)));
+        assert_eq!(Movie::from_bytes(b"C8SS\x01nope"), Err(MovieError::BadFile(StateError::BadMagic)));
+    }
+}
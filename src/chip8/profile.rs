@@ -0,0 +1,295 @@
+// Profiler
+// --------
+// Counts how often every address executes, which kinds of instructions run
+// the most and how many instructions are spent inside each subroutine, to
+// find the loops a rom spends its time in.
+//
+// Subroutine cycles are inclusive: everything from the first instruction of
+// the routine up to and including its RET, nested calls included. It only
+// counts what runs through it, see `Observer` in trace.rs.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use wasm_bindgen::prelude::*;
+
+use super::{ disassemble, Error, Instruction, Observer, CHIP8, MEM_SIZE };
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Routine {
+    // Number of times it was called.
+    pub calls: u64,
+    // Instructions executed inside it.
+    pub cycles: u64,
+}
+
+#[wasm_bindgen]
+pub struct Profiler {
+    // Executions per address.
+    counts: Vec<u64>,
+    // Executions per opcode class, e.g. "DXYN".
+    classes: BTreeMap<&'static str, u64>,
+    routines: BTreeMap<u16, Routine>,
+    // Mirrors the call stack, holding the routine each level called. Levels
+    // that were already on the stack when profiling started are unknown.
+    frames: Vec<Option<u16>>,
+    total: u64,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[wasm_bindgen]
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            counts: vec![0; MEM_SIZE],
+            classes: BTreeMap::new(),
+            routines: BTreeMap::new(),
+            frames: Vec::new(),
+            total: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Profiler::new();
+    }
+
+    // Instructions profiled so far.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    // Times the instruction at `addr` executed.
+    pub fn count(&self, addr: u16) -> u64 {
+        self.counts[addr as usize]
+    }
+
+    // How hot each of the first `len` bytes of memory is, from 0 (never
+    // executed) to 255 (the hottest address). Counts are scaled
+    // logarithmically so loops don't drown out everything else, and both
+    // bytes of an instruction get the same heat.
+    pub fn heatmap(&self, len: usize) -> Vec<u8> {
+        let len = len.min(MEM_SIZE);
+        let max = self.counts.iter().cloned().max().unwrap_or(0);
+        let mut heat = vec![0; len];
+        if max == 0 {
+            return heat;
+        }
+
+        let scale = 255.0 / (max as f64).ln_1p();
+        for (addr, &count) in self.counts.iter().enumerate().take(len).filter(|&(_, &count)| count > 0) {
+            // Anything that ran at all shows up.
+            let value = ((count as f64).ln_1p() * scale).round().max(1.0) as u8;
+            for byte in heat.iter_mut().skip(addr).take(2) {
+                *byte = (*byte).max(value);
+            }
+        }
+
+        heat
+    }
+
+    // Hot addresses, opcode classes & subroutines, sorted hottest first.
+    // Shows at most `limit` entries per section.
+    pub fn report(&self, emu: &CHIP8, limit: usize) -> String {
+        let percent = |count: u64| 100.0 * count as f64 / self.total.max(1) as f64;
+        let mut out = String::new();
+
+        let _ = writeln!(out, "Hot addresses ({} instructions)", self.total);
+        let _ = writeln!(out, "  ADDR      COUNT       %  INSTRUCTION");
+        for (addr, count) in self.hotspots(limit) {
+            let opcode = emu.memory.get(addr as usize..addr as usize + 2)
+                .map_or(0, |word| u16::from(word[0]) << 8 | u16::from(word[1]));
+            let _ = writeln!(out, "  {:04X} {:>10} {:>6.2}%  {}", addr, count, percent(count), disassemble(opcode));
+        }
+
+        let _ = writeln!(out, "\nOpcode classes");
+        let _ = writeln!(out, "  CLASS     COUNT       %");
+        for (class, count) in sorted(self.classes.iter().map(|(&class, &count)| (class, count)), limit) {
+            let _ = writeln!(out, "  {} {:>10} {:>6.2}%", class, count, percent(count));
+        }
+
+        let _ = writeln!(out, "\nSubroutines");
+        let _ = writeln!(out, "  ADDR      CALLS     CYCLES       %    PER CALL");
+        let routines = self.routines.iter().map(|(&addr, routine)| ((addr, routine.calls), routine.cycles));
+        for ((addr, calls), cycles) in sorted(routines, limit) {
+            let per_call = cycles as f64 / calls.max(1) as f64;
+            let _ = writeln!(out, "  {:04X} {:>10} {:>10} {:>6.2}% {:>11.1}", addr, calls, cycles, percent(cycles), per_call);
+        }
+
+        out
+    }
+}
+
+impl Profiler {
+    // The `limit` most executed addresses with their counts.
+    pub fn hotspots(&self, limit: usize) -> Vec<(u16, u64)> {
+        let counts = self.counts.iter()
+            .enumerate()
+            .filter(|&(_, &count)| count > 0)
+            .map(|(addr, &count)| (addr as u16, count));
+        sorted(counts, limit)
+    }
+
+    // Times instructions of an opcode class (e.g. "DXYN") executed.
+    pub fn class_count(&self, class: &str) -> u64 {
+        self.classes.get(class).cloned().unwrap_or(0)
+    }
+
+    pub fn routine(&self, addr: u16) -> Option<Routine> {
+        self.routines.get(&addr).cloned()
+    }
+}
+
+export_observer!(Profiler);
+
+impl Observer for Profiler {
+    // Where the instruction is & its opcode, if it's in memory at all.
+    type Before = (u16, Option<u16>);
+
+    fn before(&mut self, emu: &CHIP8) -> (u16, Option<u16>) {
+        // Levels we didn't see being called.
+        while self.frames.len() < emu.sp as usize {
+            self.frames.push(None);
+        }
+
+        let pc = emu.pc;
+        let opcode = emu.memory.get(pc as usize..pc as usize + 2)
+            .map(|word| u16::from(word[0]) << 8 | u16::from(word[1]));
+        (pc, opcode)
+    }
+
+    fn after(&mut self, emu: &CHIP8, (pc, opcode): (u16, Option<u16>), result: &Result<(), Error>) {
+        let instr = match opcode {
+            Some(opcode) if result.is_ok() => disassemble(opcode),
+            _ => return,
+        };
+        self.total += 1;
+        self.counts[pc as usize] += 1;
+        *self.classes.entry(instr.pattern()).or_insert(0) += 1;
+
+        // The instruction counts towards every routine that was running,
+        // recursive routines only once.
+        for (level, frame) in self.frames.iter().enumerate() {
+            if let Some(addr) = *frame {
+                if !self.frames[..level].contains(&Some(addr)) {
+                    self.routines.entry(addr).or_default().cycles += 1;
+                }
+            }
+        }
+
+        let depth = emu.sp as usize;
+        self.frames.truncate(depth);
+        while self.frames.len() < depth {
+            let called = match instr {
+                Instruction::Call(addr) if self.frames.len() + 1 == depth => Some(addr),
+                _ => None,
+            };
+            if let Some(addr) = called {
+                self.routines.entry(addr).or_default().calls += 1;
+            }
+            self.frames.push(called);
+        }
+    }
+}
+
+// Highest counts first, ties in key order so reports are stable.
+fn sorted<K: Ord, I: Iterator<Item = (K, u64)>>(items: I, limit: usize) -> Vec<(K, u64)> {
+    let mut items: Vec<(K, u64)> = items.collect();
+    items.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    items.truncate(limit);
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{ assemble };
+
+    fn program(source: &str) -> CHIP8 {
+        let mut emu = CHIP8::new();
+        emu.load_rom(Some(assemble(source).unwrap().into_boxed_slice()));
+        emu
+    }
+
+    #[test]
+    fn test_counts() {
+        // Calls `count` 3 times, which loops 4 times and calls `inner`.
+        let mut emu = program("
+            main:
+                CALL count
+                ADD V1, 1
+                SE V1, 3
+                JP main
+            done:
+                JP done
+            count:
+                LD V0, 0
+            loop:
+                ADD V0, 1
+                SE V0, 4
+                JP loop
+                CALL inner
+                RET
+            inner:
+                RET
+        ");
+        let mut profiler = Profiler::new();
+        for _ in 0..200 {
+            profiler.tick(&mut emu).unwrap();
+        }
+
+        assert_eq!(profiler.total(), 200);
+        assert_eq!(profiler.count(0x200), 3);
+        // `done` spins for whatever is left.
+        assert_eq!(profiler.hotspots(1), vec![(0x208, 200 - 19 - 19 - 18)]);
+        assert_eq!(profiler.class_count("2NNN"), 6);
+        assert_eq!(profiler.class_count("00EE"), 6);
+
+        // LD, 4 * (ADD, SE) + 3 * JP, CALL, RET, inner's RET.
+        assert_eq!(profiler.routine(0x20A), Some(Routine { calls: 3, cycles: 3 * 15 }));
+        assert_eq!(profiler.routine(0x216), Some(Routine { calls: 3, cycles: 3 }));
+
+        let report = profiler.report(&emu, 3);
+        assert!(report.contains("  0208        144  72.00%  JP 0x208\n"), "{}", report);
+        assert!(report.contains("  1NNN        155  77.50%\n"), "{}", report);
+        assert!(report.contains("  020A          3         45  22.50%        15.0\n"), "{}", report);
+
+        let heat = profiler.heatmap(0x220);
+        assert_eq!((heat[0x208], heat[0x209]), (255, 255));
+        assert!(heat[0x200] > 0 && heat[0x200] < heat[0x20E]);
+        assert_eq!(heat[0x218], 0);
+
+        profiler.clear();
+        assert_eq!(profiler.total(), 0);
+        assert_eq!(profiler.heatmap(16), vec![0; 16]);
+    }
+
+    #[test]
+    fn test_unknown_callers() {
+        // Starts profiling inside a routine, its RET shouldn't confuse the
+        // call tracking.
+        let mut emu = program("
+                CALL outer
+            halt:
+                JP halt
+            outer:
+                CALL inner
+                RET
+            inner:
+                RET
+        ");
+        for _ in 0..2 {
+            emu.tick().unwrap();
+        }
+
+        let mut profiler = Profiler::new();
+        for _ in 0..4 {
+            profiler.tick(&mut emu).unwrap();
+        }
+        assert_eq!(profiler.routine(0x204), None);
+        assert_eq!(profiler.routine(0x208), None);
+        assert_eq!(profiler.hotspots(4), vec![(0x202, 2), (0x206, 1), (0x208, 1)]);
+    }
+}
@@ -0,0 +1,112 @@
+// CHIP-8 quirks
+// -------------
+// The original COSMAC VIP interpreter and the interpreters that followed it
+// (CHIP-48, SUPER-CHIP, ...) don't agree on how a handful of instructions
+// behave. ROMs are usually written against one of them, so the emulator
+// needs to know which behavior to mimic.
+//
+// See: https://github.com/Timendus/chip8-test-suite#quirks-test
+use std::str::FromStr;
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    // 8XY1, 8XY2 & 8XY3 reset VF to 0 after the logical operation.
+    pub vf_reset: bool,
+    // FX55 & FX65 leave I pointing past the last register stored/loaded
+    // instead of leaving it untouched.
+    pub memory_increment: bool,
+    // 8XY6 & 8XYE shift vx in place instead of storing the shifted vy
+    // in vx.
+    pub shift_in_place: bool,
+    // BNNN jumps to XNN + vx instead of NNN + v0.
+    pub jump_vx: bool,
+    // DXYN clips sprites at the edges of the screen instead of wrapping
+    // them around to the other side.
+    pub clipping: bool,
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Self::modern()
+    }
+}
+
+// Names of the presets used on the command line.
+impl FromStr for Quirks {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Quirks, String> {
+        match name {
+            "vip" => Ok(Quirks::cosmac_vip()),
+            "chip48" => Ok(Quirks::chip48()),
+            "schip" => Ok(Quirks::super_chip()),
+            "xochip" => Ok(Quirks::xo_chip()),
+            "modern" => Ok(Quirks::modern()),
+            _ => Err(format!("unknown quirks \"{}\"", name)),
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl Quirks {
+    // The original interpreter on the COSMAC VIP.
+    pub fn cosmac_vip() -> Quirks {
+        Quirks {
+            vf_reset: true,
+            memory_increment: true,
+            shift_in_place: false,
+            jump_vx: false,
+            clipping: true,
+        }
+    }
+
+    // CHIP-48 on the HP-48 calculators.
+    //
+    // NOTE: CHIP-48 actually increments I by X (one short) on FX55/FX65.
+    // Hardly any ROMs depend on that, so we treat it like SUPER-CHIP.
+    pub fn chip48() -> Quirks {
+        Quirks {
+            vf_reset: false,
+            memory_increment: false,
+            shift_in_place: true,
+            jump_vx: true,
+            clipping: true,
+        }
+    }
+
+    // SUPER-CHIP 1.1, also on the HP-48 calculators.
+    pub fn super_chip() -> Quirks {
+        Quirks {
+            vf_reset: false,
+            memory_increment: false,
+            shift_in_place: true,
+            jump_vx: true,
+            clipping: true,
+        }
+    }
+
+    // XO-CHIP as implemented by Octo.
+    pub fn xo_chip() -> Quirks {
+        Quirks {
+            vf_reset: false,
+            memory_increment: true,
+            shift_in_place: false,
+            jump_vx: false,
+            clipping: false,
+        }
+    }
+
+    // What most emulators written against Cowgod's reference do: shifts in
+    // place, FX55/FX65 leave I alone, BNNN uses V0 and sprites wrap.
+    pub fn modern() -> Quirks {
+        Quirks {
+            vf_reset: false,
+            memory_increment: false,
+            shift_in_place: true,
+            jump_vx: false,
+            clipping: false,
+        }
+    }
+}
@@ -0,0 +1,319 @@
+// Rewind
+// ------
+// Keeps a history of recent machine states so the frontend can step the
+// emulator backwards in time.
+//
+// Recording every frame in full would be expensive, so only every
+// `KEYFRAME_INTERVAL`th frame is a full snapshot (a keyframe). The frames
+// in between only store the bytes that changed since the previous frame.
+// To go back to a frame we start from the closest keyframe before it and
+// replay the deltas on top.
+//
+// A "frame" is whatever the caller decides to record, once per rendered
+// frame for holding a rewind key, or once per instruction for stepping
+// backwards in a debugger.
+use std::collections::VecDeque;
+use wasm_bindgen::prelude::*;
+
+use super::{ CHIP8 };
+
+// Number of frames between full snapshots.
+const KEYFRAME_INTERVAL: usize = 60;
+// Changed bytes closer together than this are stored as a single run, it's
+// cheaper than the header of a new run.
+const MIN_RUN_GAP: usize = 8;
+
+enum Frame {
+    // Full snapshot of the machine.
+    Key(Vec<u8>),
+    // Runs of bytes that changed since the previous frame, each run is
+    // stored as <offset: u32><len: u32><bytes>.
+    Delta(Vec<u8>),
+}
+
+impl Frame {
+    fn size(&self) -> usize {
+        match *self {
+            Frame::Key(ref data) | Frame::Delta(ref data) => data.len(),
+        }
+    }
+}
+
+// Encodes the differences between two snapshots of the same size.
+fn diff(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    let mut idx = 0;
+    while idx < new.len() {
+        if old[idx] == new[idx] {
+            idx += 1;
+            continue;
+        }
+
+        // Extend the run until we find enough unchanged bytes in a row.
+        let start = idx;
+        let mut end = idx + 1;
+        let mut probe = end;
+        while probe < new.len() && probe - end < MIN_RUN_GAP {
+            if old[probe] != new[probe] {
+                end = probe + 1;
+            }
+            probe += 1;
+        }
+
+        delta.extend_from_slice(&(start as u32).to_le_bytes());
+        delta.extend_from_slice(&((end - start) as u32).to_le_bytes());
+        delta.extend_from_slice(&new[start..end]);
+        idx = end;
+    }
+
+    delta
+}
+
+fn apply(snapshot: &mut [u8], delta: &[u8]) {
+    let mut idx = 0;
+    while idx < delta.len() {
+        let mut word = [0; 4];
+        word.copy_from_slice(&delta[idx..idx + 4]);
+        let start = u32::from_le_bytes(word) as usize;
+        word.copy_from_slice(&delta[idx + 4..idx + 8]);
+        let len = u32::from_le_bytes(word) as usize;
+        idx += 8;
+
+        snapshot[start..start + len].copy_from_slice(&delta[idx..idx + len]);
+        idx += len;
+    }
+}
+
+#[wasm_bindgen]
+pub struct Rewind {
+    // Recorded frames, oldest first. The oldest frame is always a keyframe.
+    frames: VecDeque<Frame>,
+    // Snapshot of the most recently recorded frame, what new deltas are
+    // computed against.
+    last: Vec<u8>,
+    // Frames recorded since the last keyframe.
+    since_keyframe: usize,
+    // Maximum number of frames to keep around.
+    depth: usize,
+    // Maximum number of bytes the recorded frames may use.
+    budget: usize,
+    // Bytes currently used by the recorded frames.
+    used: usize,
+}
+
+#[wasm_bindgen]
+impl Rewind {
+    // Keeps at most `depth` frames using at most `budget` bytes, whichever
+    // runs out first. The oldest frames are dropped to make room.
+    pub fn new(depth: usize, budget: usize) -> Rewind {
+        Rewind {
+            frames: VecDeque::new(),
+            last: Vec::new(),
+            since_keyframe: 0,
+            depth,
+            budget,
+            used: 0,
+        }
+    }
+
+    pub fn depth(&self) -> usize { self.depth }
+    pub fn budget(&self) -> usize { self.budget }
+    // Number of frames we can currently go back.
+    pub fn len(&self) -> usize { self.frames.len().saturating_sub(1) }
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+    // Bytes currently used by the recorded frames.
+    pub fn memory_usage(&self) -> usize { self.used }
+
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+        self.evict();
+    }
+
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+        self.evict();
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.last.clear();
+        self.since_keyframe = 0;
+        self.used = 0;
+    }
+
+    // Records the current state of the machine as the newest frame.
+    pub fn record(&mut self, emu: &CHIP8) {
+        let snapshot = emu.snapshot();
+        // The snapshot size changes with the amount of addressable memory,
+        // deltas only work between snapshots of the same size.
+        let frame = if self.frames.is_empty()
+            || self.since_keyframe + 1 >= KEYFRAME_INTERVAL
+            || snapshot.len() != self.last.len() {
+            self.since_keyframe = 0;
+            Frame::Key(snapshot.clone())
+        } else {
+            self.since_keyframe += 1;
+            Frame::Delta(diff(&self.last, &snapshot))
+        };
+
+        self.used += frame.size();
+        self.frames.push_back(frame);
+        self.last = snapshot;
+        self.evict();
+    }
+
+    // Restores the machine to the state it was in `frames` recorded frames
+    // ago and forgets everything recorded after that. Returns how many
+    // frames we actually went back, which is less than asked for if the
+    // history doesn't go back that far.
+    pub fn rewind(&mut self, emu: &mut CHIP8, frames: usize) -> usize {
+        let frames = frames.min(self.len());
+        if self.frames.is_empty() {
+            return 0;
+        }
+
+        let target = self.frames.len() - 1 - frames;
+        let keyframe = (0..=target)
+            .rev()
+            .find(|&idx| matches!(self.frames[idx], Frame::Key(_)))
+            .unwrap_or(0);
+
+        let mut snapshot = match self.frames[keyframe] {
+            Frame::Key(ref data) => data.clone(),
+            Frame::Delta(_) => unreachable!("the oldest frame is always a keyframe"),
+        };
+        for idx in keyframe + 1..=target {
+            if let Frame::Delta(ref delta) = self.frames[idx] {
+                apply(&mut snapshot, delta);
+            }
+        }
+
+        for frame in self.frames.drain(target + 1..) {
+            self.used -= frame.size();
+        }
+        self.since_keyframe = target - keyframe;
+
+        emu.restore_snapshot(&snapshot).expect("rewind snapshots are always valid");
+        self.last = snapshot;
+
+        frames
+    }
+
+    // Drops the oldest frames until we're within the depth & memory budget.
+    // The newest frame is always kept.
+    fn evict(&mut self) {
+        while self.frames.len() > 1 && (self.frames.len() > self.depth + 1 || self.used > self.budget) {
+            let oldest = match self.frames.pop_front() {
+                Some(Frame::Key(data)) => data,
+                _ => unreachable!("the oldest frame is always a keyframe"),
+            };
+            self.used -= oldest.len();
+
+            // Promote the next frame to a keyframe so the chain of deltas
+            // still has somewhere to start from.
+            let promoted = match self.frames.front() {
+                Some(Frame::Delta(delta)) => {
+                    let mut snapshot = oldest;
+                    apply(&mut snapshot, delta);
+                    Some(snapshot)
+                },
+                _ => None,
+            };
+            if let Some(snapshot) = promoted {
+                let frame = Frame::Key(snapshot);
+                self.used -= self.frames[0].size();
+                self.used += frame.size();
+                self.frames[0] = frame;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counter_rom() -> Option<Box<[u8]>> {
+        // Loop forever, adding 1 to v0 and storing it at 0x300.
+        Some(Box::new([0xA3, 0x00, 0x70, 0x01, 0xF0, 0x55, 0x12, 0x02]))
+    }
+
+    #[test]
+    fn test_diff_apply() {
+        let old = vec![0; 64];
+        let mut new = old.clone();
+        new[3] = 1;
+        new[5] = 2;
+        new[40] = 3;
+        let delta = diff(&old, &new);
+        // Two runs: 3..6 and 40..41.
+        assert_eq!(delta.len(), 8 + 3 + 8 + 1);
+
+        let mut restored = old.clone();
+        apply(&mut restored, &delta);
+        assert_eq!(restored, new);
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut emu = CHIP8::new();
+        emu.load_rom(counter_rom());
+        let mut rewind = Rewind::new(1000, 1 << 20);
+
+        let mut history = Vec::new();
+        for _ in 0..200 {
+            rewind.record(&emu);
+            history.push(emu.save_state());
+            for _ in 0..3 {
+                emu.tick().unwrap();
+            }
+        }
+        rewind.record(&emu);
+        history.push(emu.save_state());
+
+        assert_eq!(rewind.rewind(&mut emu, 1), 1);
+        assert_eq!(emu.save_state(), history[199]);
+        // Rewinding past a keyframe.
+        assert_eq!(rewind.rewind(&mut emu, 75), 75);
+        assert_eq!(emu.save_state(), history[124]);
+        assert_eq!(rewind.len(), 124);
+
+        // Recording again after a rewind continues from there.
+        emu.tick().unwrap();
+        rewind.record(&emu);
+        rewind.rewind(&mut emu, 1);
+        assert_eq!(emu.save_state(), history[124]);
+
+        // Can't go back further than the history.
+        assert_eq!(rewind.rewind(&mut emu, 1000), 124);
+        assert_eq!(emu.save_state(), history[0]);
+        assert_eq!(rewind.rewind(&mut emu, 1), 0);
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut emu = CHIP8::new();
+        emu.load_rom(counter_rom());
+        let mut rewind = Rewind::new(10, 1 << 20);
+        let mut history = Vec::new();
+        for _ in 0..100 {
+            rewind.record(&emu);
+            history.push(emu.save_state());
+            emu.tick().unwrap();
+        }
+        assert_eq!(rewind.len(), 10);
+        assert_eq!(rewind.rewind(&mut emu, 100), 10);
+        assert_eq!(emu.save_state(), history[89]);
+
+        // A budget that only fits a couple of keyframes.
+        let keyframe = emu.snapshot().len();
+        rewind.set_depth(1000);
+        rewind.set_budget(keyframe * 2);
+        for _ in 0..100 {
+            rewind.record(&emu);
+            emu.tick().unwrap();
+        }
+        assert!(rewind.memory_usage() <= keyframe * 2);
+        assert!(!rewind.is_empty());
+    }
+}
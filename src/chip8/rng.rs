@@ -0,0 +1,111 @@
+// Random numbers
+// --------------
+// RND (CXNN) draws from a small PRNG owned by the emulator instead of the
+// platform's random source. Seeding it with the same value produces the
+// same numbers on every run and platform, which is what makes replays, save
+// states and tests reproducible. Frontends that want variety seed it with
+// something random when loading a rom.
+use wasm_bindgen::prelude::*;
+
+use super::{ CHIP8 };
+
+// Seed used until `set_seed` is called.
+pub(super) const DEFAULT_SEED: u64 = 0x0C8C_8C8C_8C8C_8C8C;
+
+// xorshift64*, plenty random for games and only a single word of state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub(super) fn new(seed: u64) -> Rng {
+        // Run the seed through splitmix64 so similar seeds give unrelated
+        // sequences, xorshift also gets stuck on an all zero state.
+        let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+
+        Rng { state: if z == 0 { 1 } else { z } }
+    }
+
+    // Restores the state saved with `state`.
+    pub(super) fn from_state(state: u64) -> Option<Rng> {
+        if state == 0 { None } else { Some(Rng { state }) }
+    }
+
+    pub(super) fn state(&self) -> u64 {
+        self.state
+    }
+
+    pub(super) fn next_u8(&mut self) -> u8 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        // The high bits are the most random.
+        (self.state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+    }
+}
+
+#[wasm_bindgen]
+impl CHIP8 {
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // Restarts the random sequence from `seed`. Resetting or loading a rom
+    // restarts it from the same seed again.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = Rng::new(seed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequence() {
+        let mut a = Rng::new(42);
+        let mut b = Rng::new(42);
+        let mut c = Rng::new(43);
+        let a: Vec<u8> = (0..32).map(|_| a.next_u8()).collect();
+        let b: Vec<u8> = (0..32).map(|_| b.next_u8()).collect();
+        let c: Vec<u8> = (0..32).map(|_| c.next_u8()).collect();
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+
+        // Zero is a perfectly fine seed.
+        let mut rng = Rng::new(0);
+        assert!((0..32).any(|_| rng.next_u8() != 0));
+    }
+
+    #[test]
+    fn test_seeded_rnd() {
+        // Fill v0..v7 with random bytes.
+        let rom: Vec<u8> = (0..8).flat_map(|x| vec![0xC0 | x, 0xFF]).collect();
+        let run = |seed: u64| {
+            let mut emu = CHIP8::new();
+            emu.set_seed(seed);
+            emu.load_rom(Some(rom.clone().into_boxed_slice()));
+            for _ in 0..8 {
+                emu.tick().unwrap();
+            }
+            emu.registers
+        };
+
+        assert_eq!(run(1234), run(1234));
+        assert_ne!(run(1234), run(4321));
+
+        // Reloading the rom restarts the sequence.
+        let mut emu = CHIP8::new();
+        emu.load_rom(Some(rom.clone().into_boxed_slice()));
+        emu.tick().unwrap();
+        let first = emu.registers[0];
+        emu.load_rom(Some(rom.into_boxed_slice()));
+        emu.tick().unwrap();
+        assert_eq!(emu.registers[0], first);
+    }
+}
@@ -0,0 +1,397 @@
+// Save states
+// -----------
+// Serializes the whole machine into a small binary blob so it can be
+// stashed away (e.g. in localStorage) and restored later.
+//
+// Layout, all multi-byte values are little endian:
+//
+//   magic      4 bytes  "C8SS"
+//   version    1 byte
+//   payload    N bytes  see `write_payload`
+//   checksum   4 bytes  CRC-32 of everything before it
+//
+// Any change to the payload layout needs a new version number.
+use std::error;
+use std::fmt;
+use wasm_bindgen::prelude::*;
+
+use super::{
+    CHIP8, Key, Mode, Quirks, Rng,
+    AUDIO_PATTERN_SIZE, DISPLAY_SIZE, MEM_SIZE, NUM_REGISTERS, NUM_RPL_FLAGS, STACK_SIZE,
+};
+
+const MAGIC: &[u8; 4] = b"C8SS";
+const VERSION: u8 = 4;
+// Stored in place of the current key when no key is pressed.
+const NO_KEY: u8 = 0xFF;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StateError {
+    // Doesn't start with the save state magic bytes.
+    BadMagic,
+    // Saved by a version of the emulator we don't know how to read.
+    UnsupportedVersion(u8),
+    // The checksum doesn't match the contents.
+    BadChecksum,
+    // Ran out of bytes or found a value that makes no sense.
+    Corrupt,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::BadMagic => write!(f, "Not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported save state version {}", version)
+            },
+            StateError::BadChecksum => write!(f, "Save state checksum mismatch"),
+            StateError::Corrupt => write!(f, "Save state is corrupt"),
+        }
+    }
+}
+
+impl error::Error for StateError {}
+
+impl From<StateError> for JsValue {
+    fn from(err: StateError) -> JsValue {
+        JsValue::from_str(&err.to_string())
+    }
+}
+
+// Bitwise CRC-32 (IEEE). Save states are small enough that a lookup table
+// isn't worth the extra code size.
+pub(super) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+
+    !crc
+}
+
+pub(super) struct Writer {
+    pub(super) buf: Vec<u8>,
+}
+
+impl Writer {
+    pub(super) fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub(super) fn u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(super) fn u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(super) fn u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(super) fn bool(&mut self, value: bool) {
+        self.buf.push(value as u8);
+    }
+
+    pub(super) fn bytes(&mut self, value: &[u8]) {
+        self.buf.extend_from_slice(value);
+    }
+
+    pub(super) fn mode(&mut self, mode: Mode) {
+        self.u8(mode as u8);
+    }
+
+    pub(super) fn quirks(&mut self, quirks: Quirks) {
+        self.bool(quirks.vf_reset);
+        self.bool(quirks.memory_increment);
+        self.bool(quirks.shift_in_place);
+        self.bool(quirks.jump_vx);
+        self.bool(quirks.clipping);
+    }
+}
+
+pub(super) struct Reader<'a> {
+    pub(super) buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(super) fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if len > self.buf.len() {
+            return Err(StateError::Corrupt);
+        }
+
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    pub(super) fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub(super) fn u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub(super) fn u32(&mut self) -> Result<u32, StateError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub(super) fn u64(&mut self) -> Result<u64, StateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub(super) fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Corrupt),
+        }
+    }
+
+    pub(super) fn mode(&mut self) -> Result<Mode, StateError> {
+        match self.u8()? {
+            0 => Ok(Mode::Chip8),
+            1 => Ok(Mode::SuperChip),
+            2 => Ok(Mode::XoChip),
+            _ => Err(StateError::Corrupt),
+        }
+    }
+
+    pub(super) fn quirks(&mut self) -> Result<Quirks, StateError> {
+        Ok(Quirks {
+            vf_reset: self.bool()?,
+            memory_increment: self.bool()?,
+            shift_in_place: self.bool()?,
+            jump_vx: self.bool()?,
+            clipping: self.bool()?,
+        })
+    }
+}
+
+impl CHIP8 {
+    fn write_payload(&self, w: &mut Writer) {
+        w.u16(self.i_reg);
+        w.u16(self.pc);
+        w.u8(self.sp);
+        w.bytes(&self.registers);
+        for &addr in self.stack.iter() {
+            w.u16(addr);
+        }
+
+        // Only the addressable part of memory is saved.
+        let mem_size = self.mem_size();
+        w.u32(mem_size as u32);
+        w.bytes(&self.memory[..mem_size]);
+
+        w.bytes(&self.display);
+        w.u8(self.planes);
+        w.bool(self.hires);
+        w.bytes(&self.rpl);
+        w.bytes(&self.audio_pattern);
+        w.u8(self.pitch);
+
+        w.u8(self.current_key.map_or(NO_KEY, |key| key as u8));
+        for &pressed in self.keys.iter() {
+            w.bool(pressed);
+        }
+
+        w.mode(self.mode);
+        w.quirks(self.quirks);
+        w.bool(self.exited);
+
+        w.u32(self.clock_rate);
+        w.u32(self.timer_cycles);
+        w.u64(self.cycles);
+
+        w.u64(self.seed);
+        w.u64(self.rng.state());
+    }
+
+    // Reads the payload into a copy of the machine so a bad save state never
+    // leaves us half restored.
+    fn read_payload(&self, r: &mut Reader) -> Result<CHIP8, StateError> {
+        let mut emu = self.clone();
+        emu.i_reg = r.u16()?;
+        emu.pc = r.u16()?;
+        emu.sp = r.u8()?;
+        if emu.sp as usize >= STACK_SIZE {
+            return Err(StateError::Corrupt);
+        }
+        emu.registers.copy_from_slice(r.bytes(NUM_REGISTERS)?);
+        for idx in 0..STACK_SIZE {
+            emu.stack[idx] = r.u16()?;
+        }
+
+        let mem_size = r.u32()? as usize;
+        if mem_size > MEM_SIZE {
+            return Err(StateError::Corrupt);
+        }
+        emu.memory = [0; MEM_SIZE];
+        emu.memory[..mem_size].copy_from_slice(r.bytes(mem_size)?);
+
+        emu.display.copy_from_slice(r.bytes(DISPLAY_SIZE)?);
+        emu.planes = r.u8()?;
+        emu.hires = r.bool()?;
+        emu.rpl.copy_from_slice(r.bytes(NUM_RPL_FLAGS)?);
+        emu.audio_pattern.copy_from_slice(r.bytes(AUDIO_PATTERN_SIZE)?);
+        emu.pitch = r.u8()?;
+
+        emu.current_key = match r.u8()? {
+            NO_KEY => None,
+            key => Some(Key::from_index(key).ok_or(StateError::Corrupt)?),
+        };
+        for idx in 0..emu.keys.len() {
+            emu.keys[idx] = r.bool()?;
+        }
+
+        emu.mode = r.mode()?;
+        emu.quirks = r.quirks()?;
+        emu.exited = r.bool()?;
+
+        emu.clock_rate = r.u32()?;
+        emu.timer_cycles = r.u32()?;
+        emu.cycles = r.u64()?;
+        if emu.clock_rate == 0 || emu.timer_cycles >= emu.clock_rate {
+            return Err(StateError::Corrupt);
+        }
+        // Time that hasn't been run yet isn't part of the machine state.
+        emu.pending_cycles = 0.0;
+
+        emu.seed = r.u64()?;
+        emu.rng = Rng::from_state(r.u64()?).ok_or(StateError::Corrupt)?;
+        // Faults aren't saved, restoring always resumes execution.
+        emu.fault = None;
+        emu.last_read = None;
+        emu.last_write = None;
+        // Frontends have to redraw everything, caches start over.
+        emu.mark_display();
+        emu.memory_changed();
+
+        if !r.buf.is_empty() {
+            return Err(StateError::Corrupt);
+        }
+
+        Ok(emu)
+    }
+
+    // Raw payload without the header or checksum, used for in-memory
+    // snapshots that never leave the emulator (e.g. rewinding).
+    pub(super) fn snapshot(&self) -> Vec<u8> {
+        let mut w = Writer { buf: Vec::new() };
+        self.write_payload(&mut w);
+        w.buf
+    }
+
+    pub(super) fn restore_snapshot(&mut self, snapshot: &[u8]) -> Result<(), StateError> {
+        let mut r = Reader { buf: snapshot };
+        *self = self.read_payload(&mut r)?;
+        Ok(())
+    }
+}
+
+#[wasm_bindgen]
+impl CHIP8 {
+    // Serializes the whole machine into a versioned, checksummed blob.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = Writer { buf: Vec::new() };
+        w.bytes(MAGIC);
+        w.u8(VERSION);
+        self.write_payload(&mut w);
+        let checksum = crc32(&w.buf);
+        w.u32(checksum);
+
+        w.buf
+    }
+
+    // Restores a blob created by `save_state`. The machine is left untouched
+    // if the blob can't be read.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        if state.len() < MAGIC.len() + 1 + 4 {
+            return Err(StateError::Corrupt);
+        }
+        if &state[..MAGIC.len()] != MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        let version = state[MAGIC.len()];
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        let (contents, checksum) = state.split_at(state.len() - 4);
+        let mut r = Reader { buf: checksum };
+        if r.u32()? != crc32(contents) {
+            return Err(StateError::BadChecksum);
+        }
+
+        let mut r = Reader { buf: &contents[MAGIC.len() + 1..] };
+        *self = self.read_payload(&mut r)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut emu = CHIP8::new();
+        emu.set_mode(Mode::SuperChip);
+        emu.set_quirks(Quirks::cosmac_vip());
+        emu.load_rom(Some(Box::new([0x00, 0xFF, 0x60, 0x12, 0x22, 0x00])));
+        emu.tick().unwrap();
+        emu.tick().unwrap();
+        emu.tick().unwrap();
+        emu.key_press(Key::KA);
+        emu.display[42] = 1;
+
+        let state = emu.save_state();
+        let mut restored = CHIP8::new();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.save_state(), state);
+        assert_eq!(restored.pc, 0x200);
+        assert_eq!(restored.sp, 1);
+        assert_eq!(restored.registers[0], 0x12);
+        assert_eq!(restored.current_key, Some(Key::KA));
+        assert_eq!(restored.mode, Mode::SuperChip);
+        assert_eq!(restored.quirks, Quirks::cosmac_vip());
+        assert!(restored.hires);
+        assert_eq!(restored.display[42], 1);
+        assert_eq!(&restored.memory[..], &emu.memory[..]);
+    }
+
+    #[test]
+    fn test_bad_states() {
+        let mut emu = CHIP8::new();
+        let mut state = emu.save_state();
+
+        assert_eq!(emu.load_state(&state[..3]), Err(StateError::Corrupt));
+        assert_eq!(emu.load_state(b"nope, not a state"), Err(StateError::BadMagic));
+
+        state[4] = VERSION + 1;
+        assert_eq!(emu.load_state(&state), Err(StateError::UnsupportedVersion(VERSION + 1)));
+        state[4] = VERSION;
+
+        // Flip a bit in memory.
+        state[100] ^= 1;
+        assert_eq!(emu.load_state(&state), Err(StateError::BadChecksum));
+        state[100] ^= 1;
+        assert!(emu.load_state(&state).is_ok());
+    }
+}
@@ -0,0 +1,327 @@
+// Instruction trace
+// -----------------
+// Logs every executed instruction together with what it changed, which
+// makes it easy to diff a run against another build or a reference
+// interpreter when chasing a bug in `execute`.
+//
+// One line per instruction, the format is meant to stay stable:
+//
+//   <cycle> <pc>: <opcode>  <mnemonic>  <changes>
+//   00000002 0204: A300  LD I, 0x300            I=0300
+//   00000003 0206: F155  LD [I], V1             [0300]=0A0B
+//
+// The cycle is the number of instructions executed before this one, all
+// numbers are hex except for the cycle. Changes are listed in a fixed order:
+// V0-VF, I, SP, DT, ST, then memory written through I as the address
+// followed by the new bytes. An instruction that faults ends with
+// `! <fault>`.
+//
+// Lines go to a `TraceSink`. The default keeps the most recent lines in
+// memory, natively anything implementing `io::Write` (a file, stdout) works
+// as well.
+use std::collections::VecDeque;
+use std::fmt::Write as FmtWrite;
+use std::io;
+use wasm_bindgen::prelude::*;
+
+use super::{ disassemble, Error, CHIP8, NUM_REGISTERS };
+
+// Names of the registers in the register bank, in trace order.
+pub(super) const REGISTER_NAMES: [&str; NUM_REGISTERS] = [
+    "V0", "V1", "V2", "V3", "V4", "V5", "V6", "V7",
+    "V8", "V9", "VA", "VB", "VC", "VD", "VE", "VF",
+    "DT", "ST",
+];
+// Column the changes start at, keeps the lines lined up.
+const MNEMONIC_WIDTH: usize = 22;
+
+pub trait TraceSink {
+    fn write(&mut self, line: &str) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+impl<W: io::Write> TraceSink for W {
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        writeln!(self, "{}", line)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::Write::flush(self)
+    }
+}
+
+// Tools watching a machine run: the tracer, the profiler & coverage. They
+// don't own the machine, so they only see the instructions run through them,
+// with `record` or the `tick` & `run_for` every tool exports. Nothing runs on
+// a halted machine, the hooks aren't called for it.
+pub trait Observer {
+    // What `before` notes down for `after`.
+    type Before;
+
+    // Called right before an instruction runs.
+    fn before(&mut self, emu: &CHIP8) -> Self::Before;
+
+    // Called right after, `result` is an error if fetching or executing the
+    // instruction failed, in which case it never ran.
+    fn after(&mut self, emu: &CHIP8, before: Self::Before, result: &Result<(), Error>);
+
+    // Watches whatever `step` does to the machine, which should be
+    // executing a single instruction. Handy to trace e.g. movie playback:
+    //
+    //   tracer.record(&mut emu, |emu| movie.tick(emu))
+    fn record<F>(&mut self, emu: &mut CHIP8, step: F) -> Result<(), Error>
+        where F: FnOnce(&mut CHIP8) -> Result<(), Error>, Self: Sized
+    {
+        if emu.is_halted() {
+            return step(emu);
+        }
+
+        let before = self.before(emu);
+        let result = step(emu);
+        self.after(emu, before, &result);
+
+        result
+    }
+}
+
+// The line so far & what an instruction could change, taken right before
+// it runs.
+pub struct Before {
+    line: String,
+    registers: [u8; NUM_REGISTERS],
+    i_reg: u16,
+    sp: u8,
+}
+
+#[wasm_bindgen]
+pub struct Tracer {
+    // Most recent lines, used when there's no sink.
+    lines: VecDeque<String>,
+    capacity: usize,
+    sink: Option<Box<dyn TraceSink>>,
+    // First error the sink ran into. Tracing stops after it.
+    error: Option<io::Error>,
+}
+
+#[wasm_bindgen]
+impl Tracer {
+    // Keeps the last `capacity` lines in memory.
+    pub fn new(capacity: usize) -> Tracer {
+        Tracer { lines: VecDeque::new(), capacity, sink: None, error: None }
+    }
+
+    // The lines kept in memory, oldest first.
+    pub fn lines(&self) -> String {
+        let mut out = String::new();
+        for line in self.lines.iter() {
+            out.push_str(line);
+            out.push('\n');
+        }
+
+        out
+    }
+
+    pub fn clear(&mut self) {
+        self.lines.clear();
+    }
+}
+
+impl Tracer {
+    // Hands every line to `sink` instead of keeping them in memory.
+    pub fn with_sink(sink: Box<dyn TraceSink>) -> Tracer {
+        Tracer { lines: VecDeque::new(), capacity: 0, sink: Some(sink), error: None }
+    }
+
+    // Flushes the sink, returning the first error it ran into.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+
+        match self.sink {
+            Some(ref mut sink) => sink.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn push(&mut self, line: &str) {
+        if self.error.is_some() {
+            return;
+        }
+
+        match self.sink {
+            Some(ref mut sink) => {
+                if let Err(err) = sink.write(line) {
+                    self.error = Some(err);
+                }
+            },
+            None => {
+                if self.lines.len() == self.capacity {
+                    self.lines.pop_front();
+                }
+                if self.capacity > 0 {
+                    self.lines.push_back(line.to_string());
+                }
+            },
+        }
+    }
+}
+
+export_observer!(Tracer);
+
+impl Observer for Tracer {
+    type Before = Before;
+
+    fn before(&mut self, emu: &CHIP8) -> Before {
+        let (opcode, mnemonic) = decode(emu, emu.pc);
+        Before {
+            line: format!("{:08} {:04X}: {:04X}  {:<width$}", emu.cycles, emu.pc, opcode, mnemonic, width = MNEMONIC_WIDTH),
+            registers: emu.registers,
+            i_reg: emu.i_reg,
+            sp: emu.sp,
+        }
+    }
+
+    fn after(&mut self, emu: &CHIP8, before: Before, result: &Result<(), Error>) {
+        let mut line = before.line;
+        for (idx, name) in REGISTER_NAMES.iter().enumerate().take(16) {
+            if emu.registers[idx] != before.registers[idx] {
+                let _ = write!(line, " {}={:02X}", name, emu.registers[idx]);
+            }
+        }
+        if emu.i_reg != before.i_reg {
+            let _ = write!(line, " I={:04X}", emu.i_reg);
+        }
+        if emu.sp != before.sp {
+            let _ = write!(line, " SP={:02X}", emu.sp);
+        }
+        for (idx, name) in REGISTER_NAMES.iter().enumerate().skip(16) {
+            if emu.registers[idx] != before.registers[idx] {
+                let _ = write!(line, " {}={:02X}", name, emu.registers[idx]);
+            }
+        }
+        if let Some(access) = emu.last_write {
+            let start = access.addr as usize;
+            let _ = write!(line, " [{:04X}]=", start);
+            for byte in emu.memory[start..start + access.len as usize].iter() {
+                let _ = write!(line, "{:02X}", byte);
+            }
+        }
+        if let Err(ref fault) = *result {
+            let _ = write!(line, " ! {}", fault);
+        }
+
+        self.push(line.trim_end());
+    }
+}
+
+// The instruction at `pc` and how it disassembles.
+fn decode(emu: &CHIP8, pc: u16) -> (u16, String) {
+    let word = |addr: usize| {
+        if addr + 1 < emu.mem_size() {
+            Some(u16::from(emu.memory[addr]) << 8 | u16::from(emu.memory[addr + 1]))
+        } else {
+            None
+        }
+    };
+
+    let opcode = match word(pc as usize) {
+        Some(opcode) => opcode,
+        None => return (0, "??".to_string()),
+    };
+    let instr = disassemble(opcode);
+    let mnemonic = match word(pc as usize + 2) {
+        Some(long) if instr.size() == 4 => format!("LD I, LONG {:#06X}", long),
+        _ => instr.to_string(),
+    };
+
+    (opcode, mnemonic)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use super::super::{ assemble, Key, Movie };
+
+    fn program(source: &str) -> CHIP8 {
+        let mut emu = CHIP8::new();
+        emu.load_rom(Some(assemble(source).unwrap().into_boxed_slice()));
+        emu
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut emu = program("
+            LD V0, 0x0A
+            LD V1, 0x0B
+            LD I, 0x300
+            LD [I], V1
+            CALL func
+            func:
+            JP 0xFFF
+        ");
+        let mut tracer = Tracer::new(16);
+        for _ in 0..6 {
+            tracer.tick(&mut emu).unwrap();
+        }
+        assert!(tracer.tick(&mut emu).is_err());
+        // Halted machines don't add anything.
+        assert!(tracer.tick(&mut emu).is_err());
+
+        assert_eq!(tracer.lines(), "\
+00000000 0200: 600A  LD V0, 0x0A            V0=0A
+00000001 0202: 610B  LD V1, 0x0B            V1=0B
+00000002 0204: A300  LD I, 0x300            I=0300
+00000003 0206: F155  LD [I], V1             [0300]=0A0B
+00000004 0208: 220A  CALL 0x20A             SP=01
+00000005 020A: 1FFF  JP 0xFFF
+00000006 0FFF: 0000  ??                     ! Program counter out of bounds at 0x0FFF
+");
+    }
+
+    #[test]
+    fn test_sinks() {
+        let mut emu = program("loop: ADD V0, 1\nJP loop");
+
+        // The ring buffer only keeps the most recent lines.
+        let mut tracer = Tracer::new(2);
+        for _ in 0..5 {
+            tracer.tick(&mut emu).unwrap();
+        }
+        assert_eq!(tracer.lines().lines().count(), 2);
+        assert!(tracer.lines().starts_with("00000003 0202: 1200  JP 0x200"));
+        tracer.clear();
+        assert_eq!(tracer.lines(), "");
+
+        // Anything that's io::Write works as a sink.
+        #[derive(Clone, Default)]
+        struct Shared(Rc<RefCell<Vec<u8>>>);
+        impl io::Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.borrow_mut().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> { Ok(()) }
+        }
+
+        let out = Shared::default();
+        let mut tracer = Tracer::with_sink(Box::new(out.clone()));
+        let rom = assemble("LD V0, K\nLD V1, V0").unwrap();
+        let mut emu = CHIP8::new();
+        let mut movie = Movie::record(&mut emu, &rom);
+        movie.key_press(&mut emu, Key::KA);
+        movie.stop(&emu);
+        movie.play(&mut emu, &rom).unwrap();
+        for _ in 0..2 {
+            tracer.record(&mut emu, |emu| movie.tick(emu)).unwrap();
+        }
+        tracer.flush().unwrap();
+        assert_eq!(String::from_utf8(out.0.borrow().clone()).unwrap(), "\
+00000000 0200: F00A  LD V0, K               V0=0A
+00000001 0202: 8100  LD V1, V0              V1=0A
+");
+        assert_eq!(tracer.lines(), "");
+    }
+}
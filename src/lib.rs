@@ -1,7 +1,11 @@
 extern crate cfg_if;
+#[cfg(target_arch = "wasm32")]
 extern crate js_sys;
-extern crate rand;
 extern crate wasm_bindgen;
+#[cfg(all(test, not(target_arch = "wasm32")))]
+extern crate proptest;
+#[cfg(all(test, not(target_arch = "wasm32")))]
+extern crate wasmi;
 
 pub mod chip8;
 mod utils;
\ No newline at end of file
@@ -0,0 +1,375 @@
+// Golden image tests
+// ------------------
+// Runs every rom in `docs/chip8/roms` for a fixed number of cycles with
+// scripted input and compares the display against the golden images in
+// `tests/golden`, so interpreter changes can't silently break games. Every
+// rom runs on each backend (interpreter, decode cache and JIT) and all of
+// them have to match. When one doesn't, the first instruction where it
+// disagrees with the interpreter is printed as well.
+//
+// Golden images are the text `fmt::Display` produces. After a change that
+// is supposed to alter the output, regenerate them with:
+//
+//   UPDATE_GOLDEN=1 cargo test --test roms
+//
+// and review the changes in the diff. On a mismatch a PNG of what the rom
+// actually drew is saved next to the other test output.
+//
+// Besides games, `docs/chip8/roms/tests` has test roms that draw a tick or
+// a cross for every check (opcodes & flags) or show which quirks the
+// interpreter has (quirks, once per preset). Their assembly source sits
+// next to them and has to assemble to exactly the bundled rom.
+//
+// Each case also has to exercise a minimum share of its rom, so a change
+// that makes a game stop early (or input that stops doing anything) shows
+// up. `chip8 --coverage` shows what a run missed.
+extern crate chip8_emulator;
+
+use std::env;
+use std::fs;
+use std::path::{ Path, PathBuf };
+
+use chip8_emulator::chip8::{
+    assemble, lockstep, Backend, Coverage, CoverageSummary, Error, Key, Mode, Palette, Quirks, CHIP8,
+};
+
+struct Case {
+    // Name of the golden image.
+    name: &'static str,
+    // Path of the rom, relative to docs/chip8/roms.
+    rom: &'static str,
+    mode: Mode,
+    quirks: fn() -> Quirks,
+    cycles: u64,
+    // Smallest share of the rom the run has to execute or read.
+    coverage: f64,
+    // (cycle, key, pressed), sorted by cycle.
+    inputs: &'static [(u64, u8, bool)],
+}
+
+const CASES: &[Case] = &[
+    Case {
+        name: "breakout",
+        rom: "chip8/Breakout [Carmelo Cortez, 1979].ch8",
+        mode: Mode::Chip8,
+        quirks: Quirks::cosmac_vip,
+        cycles: 4000,
+        coverage: 0.6,
+        inputs: &[(500, 0x6, true), (1500, 0x6, false)],
+    },
+    Case {
+        name: "brix",
+        rom: "chip8/Brix [Andreas Gustafsson, 1990].ch8",
+        mode: Mode::Chip8,
+        quirks: Quirks::cosmac_vip,
+        cycles: 4000,
+        coverage: 0.8,
+        inputs: &[(500, 0x4, true), (1200, 0x4, false)],
+    },
+    Case {
+        name: "logo",
+        rom: "chip8/Chip8 emulator Logo [Garstyciuks].ch8",
+        mode: Mode::Chip8,
+        quirks: Quirks::modern,
+        cycles: 1000,
+        coverage: 0.95,
+        inputs: &[],
+    },
+    Case {
+        name: "maze",
+        rom: "chip8/Maze [David Winter, 199x].ch8",
+        mode: Mode::Chip8,
+        quirks: Quirks::modern,
+        cycles: 3000,
+        coverage: 0.95,
+        inputs: &[],
+    },
+    Case {
+        name: "particle_demo",
+        rom: "chip8/Particle Demo [zeroZshadow, 2008].ch8",
+        mode: Mode::Chip8,
+        quirks: Quirks::modern,
+        cycles: 3000,
+        coverage: 0.75,
+        inputs: &[],
+    },
+    Case {
+        name: "pong",
+        rom: "chip8/Pong (alt).ch8",
+        mode: Mode::Chip8,
+        quirks: Quirks::modern,
+        cycles: 4000,
+        coverage: 0.7,
+        inputs: &[(400, 0x1, true), (900, 0x1, false), (2000, 0xC, true), (2600, 0xC, false)],
+    },
+    Case {
+        name: "sierpinski",
+        rom: "chip8/Sierpinski [Sergey Naydenov, 2010].ch8",
+        mode: Mode::Chip8,
+        quirks: Quirks::modern,
+        cycles: 5000,
+        coverage: 0.65,
+        inputs: &[],
+    },
+    Case {
+        name: "space_invaders",
+        rom: "chip8/Space Invaders [David Winter].ch8",
+        mode: Mode::Chip8,
+        quirks: Quirks::modern,
+        cycles: 6000,
+        coverage: 0.4,
+        inputs: &[(300, 0x5, true), (500, 0x5, false), (2000, 0x6, true), (2800, 0x6, false)],
+    },
+    Case {
+        name: "tetris",
+        rom: "chip8/Tetris [Fran Dachille, 1991].ch8",
+        mode: Mode::Chip8,
+        quirks: Quirks::modern,
+        cycles: 5000,
+        coverage: 0.3,
+        inputs: &[(1000, 0x6, true), (1200, 0x6, false), (1500, 0x4, true), (1600, 0x4, false)],
+    },
+    Case {
+        name: "zero_demo",
+        rom: "chip8/Zero Demo [zeroZshadow, 2007].ch8",
+        mode: Mode::Chip8,
+        quirks: Quirks::modern,
+        cycles: 3000,
+        coverage: 0.95,
+        inputs: &[],
+    },
+    Case {
+        name: "ufo",
+        rom: "chip8/ufo.ch8",
+        mode: Mode::Chip8,
+        quirks: Quirks::modern,
+        cycles: 4000,
+        coverage: 0.9,
+        inputs: &[(800, 0x5, true), (900, 0x5, false)],
+    },
+    Case {
+        name: "test_opcodes",
+        rom: "tests/opcodes.ch8",
+        mode: Mode::Chip8,
+        quirks: Quirks::modern,
+        cycles: 1000,
+        coverage: 0.9,
+        inputs: &[],
+    },
+    Case {
+        name: "test_flags",
+        rom: "tests/flags.ch8",
+        mode: Mode::Chip8,
+        quirks: Quirks::modern,
+        cycles: 1000,
+        coverage: 0.95,
+        inputs: &[],
+    },
+    Case {
+        name: "test_quirks_vip",
+        rom: "tests/quirks.ch8",
+        mode: Mode::Chip8,
+        quirks: Quirks::cosmac_vip,
+        cycles: 500,
+        coverage: 0.85,
+        inputs: &[],
+    },
+    Case {
+        name: "test_quirks_schip",
+        rom: "tests/quirks.ch8",
+        mode: Mode::SuperChip,
+        quirks: Quirks::super_chip,
+        cycles: 500,
+        coverage: 0.85,
+        inputs: &[],
+    },
+    Case {
+        name: "test_quirks_xochip",
+        rom: "tests/quirks.ch8",
+        mode: Mode::XoChip,
+        quirks: Quirks::xo_chip,
+        cycles: 500,
+        coverage: 0.85,
+        inputs: &[],
+    },
+    Case {
+        name: "test_quirks_modern",
+        rom: "tests/quirks.ch8",
+        mode: Mode::Chip8,
+        quirks: Quirks::modern,
+        cycles: 500,
+        coverage: 0.85,
+        inputs: &[],
+    },
+];
+
+fn root() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+}
+
+// Where roms are loaded.
+const ROM_START: u16 = 0x200;
+
+fn load(case: &Case) -> (CHIP8, usize) {
+    let rom = fs::read(root().join("docs/chip8/roms").join(case.rom))
+        .unwrap_or_else(|err| panic!("couldn't read {}: {}", case.rom, err));
+
+    let mut emu = CHIP8::new();
+    emu.set_mode(case.mode);
+    emu.set_quirks((case.quirks)());
+    let rom_len = emu.load_rom(Some(rom.into_boxed_slice()));
+    (emu, rom_len)
+}
+
+// Applies the case's inputs due before the next instruction.
+fn press_keys(case: &Case, emu: &mut CHIP8) {
+    let cycle = emu.cycles();
+    for &(_, key, pressed) in case.inputs.iter().filter(|&&(at, _, _)| at == cycle) {
+        let key = Key::from_index(key).expect("keys go from 0x0 to 0xF");
+        if pressed { emu.key_press(key) } else { emu.key_up(key) }
+    }
+}
+
+// Runs the case on the interpreter, keeping track of coverage.
+fn run(case: &Case) -> (CHIP8, CoverageSummary) {
+    let (mut emu, rom_len) = load(case);
+    let mut coverage = Coverage::new();
+    for _ in 0..case.cycles {
+        press_keys(case, &mut emu);
+        if let Err(fault) = coverage.tick(&mut emu) {
+            panic!("{} faulted: {}", case.name, fault);
+        }
+    }
+
+    (emu, coverage.summary(ROM_START, rom_len))
+}
+
+// Runs the case on another backend, which should draw the same thing. It
+// runs as far as it can between inputs so blocks don't get cut short.
+fn run_on(case: &Case, backend: Backend) -> (CHIP8, Result<(), Error>) {
+    let (mut emu, _) = load(case);
+    emu.set_backend(backend);
+    let stops = case.inputs.iter().map(|&(at, _, _)| at).filter(|&at| at < case.cycles);
+    for stop in stops.chain(Some(case.cycles)) {
+        press_keys(case, &mut emu);
+        let cycles = stop.saturating_sub(emu.cycles());
+        if let Err(fault) = emu.run(cycles) {
+            return (emu, Err(fault));
+        }
+    }
+
+    (emu, Ok(()))
+}
+
+// Where the backend first disagrees with the interpreter, if it does when
+// run an instruction at a time.
+fn divergence(case: &Case, backend: Backend) -> String {
+    let (start, _) = load(case);
+    let mut other = start.clone();
+    other.set_backend(backend);
+    let step = |emu: &mut CHIP8| { press_keys(case, emu); emu.tick() };
+    match lockstep(&start, &other, case.cycles, step, step) {
+        Ok(()) => String::new(),
+        Err(divergence) => divergence.report("Interpreter", &format!("{:?}", backend)),
+    }
+}
+
+// Shows the rows that differ, one above the other with the differing
+// pixels marked underneath.
+fn diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<Vec<char>> = expected.lines().map(|line| line.chars().collect()).collect();
+    let actual: Vec<Vec<char>> = actual.lines().map(|line| line.chars().collect()).collect();
+    let size = |image: &Vec<Vec<char>>| (image.first().map_or(0, |row| row.len()), image.len());
+    if size(&expected) != size(&actual) {
+        return format!("expected a {:?} display, got {:?}\n", size(&expected), size(&actual));
+    }
+
+    let mut out = String::new();
+    let mut pixels = 0;
+    for (row, (want, got)) in expected.iter().zip(actual.iter()).enumerate() {
+        if want == got {
+            continue;
+        }
+
+        let marks: String = want.iter().zip(got.iter()).map(|(a, b)| if a == b { ' ' } else { '^' }).collect();
+        pixels += marks.chars().filter(|&c| c == '^').count();
+        out.push_str(&format!("row {:2} expected {}\n", row, want.iter().collect::<String>()));
+        out.push_str(&format!("       actual   {}\n", got.iter().collect::<String>()));
+        out.push_str(&format!("                {}\n", marks.trim_end()));
+    }
+
+    format!("{} pixels differ\n{}", pixels, out)
+}
+
+#[test]
+fn test_roms_match_source() {
+    let dir = root().join("docs/chip8/roms/tests");
+    for name in ["opcodes", "flags", "quirks"].iter() {
+        let source = fs::read_to_string(dir.join(format!("{}.asm", name))).unwrap();
+        let rom = fs::read(dir.join(format!("{}.ch8", name))).unwrap();
+        match assemble(&source) {
+            Ok(assembled) => assert!(assembled == rom, "{}.ch8 is out of date with {}.asm", name, name),
+            Err(err) => panic!("{}.asm: {}", name, err),
+        }
+    }
+}
+
+#[test]
+fn test_golden_images() {
+    let golden_dir = root().join("tests/golden");
+    let update = env::var_os("UPDATE_GOLDEN").is_some();
+
+    let mut failures = Vec::new();
+    for case in CASES {
+        let (emu, coverage) = run(case);
+        if coverage.exercised() < case.coverage {
+            failures.push(format!(
+                "{}: only {:.1}% of the rom exercised, expected at least {:.1}%",
+                case.name, coverage.exercised() * 100.0, case.coverage * 100.0,
+            ));
+        }
+        let path = golden_dir.join(format!("{}.txt", case.name));
+        if update {
+            fs::write(&path, emu.to_string()).unwrap();
+            continue;
+        }
+
+        let expected = match fs::read_to_string(&path) {
+            Ok(expected) => expected,
+            Err(err) => {
+                failures.push(format!("{}: couldn't read {}: {}", case.name, display(&path), err));
+                continue;
+            },
+        };
+
+        // Every backend has to match, not just the interpreter.
+        let others = Backend::ALL.iter().filter(|&&backend| backend != Backend::Interpreter);
+        let runs = Some((Backend::Interpreter, (emu, Ok(())))).into_iter()
+            .chain(others.map(|&backend| (backend, run_on(case, backend))));
+        for (backend, (emu, result)) in runs {
+            let actual = emu.to_string();
+            let mismatch = match result {
+                Err(fault) => format!("faulted: {}\n", fault),
+                Ok(()) if actual == expected => continue,
+                Ok(()) => {
+                    let name = format!("{}_{:?}", case.name, backend).to_lowercase();
+                    let png = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("{}.png", name));
+                    fs::write(&png, emu.to_png(8, &Palette::default())).unwrap();
+                    format!("{}(saved as {})\n", diff(&expected, &actual), png.display())
+                },
+            };
+
+            // Point out the instruction where the backend went wrong.
+            let divergence = if backend == Backend::Interpreter { String::new() } else { divergence(case, backend) };
+            failures.push(format!("{} ({:?}): {}{}", case.name, backend, mismatch, divergence));
+        }
+    }
+
+    if !failures.is_empty() {
+        panic!("{} runs of {} roms don't match their golden image\n\n{}", failures.len(), CASES.len(), failures.join("\n"));
+    }
+}
+
+fn display(path: &Path) -> String {
+    path.strip_prefix(root()).unwrap_or(path).display().to_string()
+}
@@ -35,7 +35,17 @@ romList.addEventListener('change', event => {
     })
     .then((buffer) => {
       let array = new Uint8Array(buffer);
+      // RND is deterministic for a given seed, pick a new one every time a
+      // rom is loaded so games play out differently.
+      engine.engine.set_seed(BigInt(Math.floor(Math.random() * Number.MAX_SAFE_INTEGER)));
       engine.engine.load_rom(array);
+      engine.rewind.clear();
+      if (engine.profiler) {
+        engine.profiler.clear();
+      }
+      if (engine.coverage) {
+        engine.coverage.clear();
+      }
     });
 });
 
@@ -65,5 +75,31 @@ playPauseButton.addEventListener('click', event => {
   }
 });
 
+// Save states are kept in localStorage as base64.
+const SAVE_STATE_KEY = 'chip8-save-state';
+
+document.getElementById('save-state').addEventListener('click', () => {
+  const state = engine.engine.save_state();
+  // XO-CHIP states are too big to spread into String.fromCharCode.
+  let binary = '';
+  state.forEach(byte => binary += String.fromCharCode(byte));
+  localStorage.setItem(SAVE_STATE_KEY, btoa(binary));
+});
+
+document.getElementById('load-state').addEventListener('click', () => {
+  const saved = localStorage.getItem(SAVE_STATE_KEY);
+  if (!saved) {
+    return;
+  }
+
+  const state = Uint8Array.from(atob(saved), c => c.charCodeAt(0));
+  try {
+    engine.engine.load_state(state);
+  } catch (err) {
+    console.error(`Unable to load save state: ${err}`);
+  }
+  engine.render();
+});
+
 // Render UI but don't start engine yet.
 engine.render();
\ No newline at end of file
@@ -1,4 +1,4 @@
-import { CHIP8, Key } from 'chip8-emulator';
+import { Backend, CHIP8, Coverage, Key, Profiler, Rewind } from 'chip8-emulator';
 
 import { Display } from './ui/display';
 import { FPS } from './ui/fps';
@@ -10,7 +10,19 @@ export interface WasmMemory {
 
 const BEEP = new Audio("data:audio/wav;base64,//uQRAAAAWMSLwUIYAAsYkXgoQwAEaYLWfkWgAI0wWs/ItAAAGDgYtAgAyN+QWaAAihwMWm4G8QQRDiMcCBcH3Cc+CDv/7xA4Tvh9Rz/y8QADBwMWgQAZG/ILNAARQ4GLTcDeIIIhxGOBAuD7hOfBB3/94gcJ3w+o5/5eIAIAAAVwWgQAVQ2ORaIQwEMAJiDg95G4nQL7mQVWI6GwRcfsZAcsKkJvxgxEjzFUgfHoSQ9Qq7KNwqHwuB13MA4a1q/DmBrHgPcmjiGoh//EwC5nGPEmS4RcfkVKOhJf+WOgoxJclFz3kgn//dBA+ya1GhurNn8zb//9NNutNuhz31f////9vt///z+IdAEAAAK4LQIAKobHItEIYCGAExBwe8jcToF9zIKrEdDYIuP2MgOWFSE34wYiR5iqQPj0JIeoVdlG4VD4XA67mAcNa1fhzA1jwHuTRxDUQ//iYBczjHiTJcIuPyKlHQkv/LHQUYkuSi57yQT//uggfZNajQ3Vmz+Zt//+mm3Wm3Q576v////+32///5/EOgAAADVghQAAAAA//uQZAUAB1WI0PZugAAAAAoQwAAAEk3nRd2qAAAAACiDgAAAAAAABCqEEQRLCgwpBGMlJkIz8jKhGvj4k6jzRnqasNKIeoh5gI7BJaC1A1AoNBjJgbyApVS4IDlZgDU5WUAxEKDNmmALHzZp0Fkz1FMTmGFl1FMEyodIavcCAUHDWrKAIA4aa2oCgILEBupZgHvAhEBcZ6joQBxS76AgccrFlczBvKLC0QI2cBoCFvfTDAo7eoOQInqDPBtvrDEZBNYN5xwNwxQRfw8ZQ5wQVLvO8OYU+mHvFLlDh05Mdg7BT6YrRPpCBznMB2r//xKJjyyOh+cImr2/4doscwD6neZjuZR4AgAABYAAAABy1xcdQtxYBYYZdifkUDgzzXaXn98Z0oi9ILU5mBjFANmRwlVJ3/6jYDAmxaiDG3/6xjQQCCKkRb/6kg/wW+kSJ5//rLobkLSiKmqP/0ikJuDaSaSf/6JiLYLEYnW/+kXg1WRVJL/9EmQ1YZIsv/6Qzwy5qk7/+tEU0nkls3/zIUMPKNX/6yZLf+kFgAfgGyLFAUwY//uQZAUABcd5UiNPVXAAAApAAAAAE0VZQKw9ISAAACgAAAAAVQIygIElVrFkBS+Jhi+EAuu+lKAkYUEIsmEAEoMeDmCETMvfSHTGkF5RWH7kz/ESHWPAq/kcCRhqBtMdokPdM7vil7RG98A2sc7zO6ZvTdM7pmOUAZTnJW+NXxqmd41dqJ6mLTXxrPpnV8avaIf5SvL7pndPvPpndJR9Kuu8fePvuiuhorgWjp7Mf/PRjxcFCPDkW31srioCExivv9lcwKEaHsf/7ow2Fl1T/9RkXgEhYElAoCLFtMArxwivDJJ+bR1HTKJdlEoTELCIqgEwVGSQ+hIm0NbK8WXcTEI0UPoa2NbG4y2K00JEWbZavJXkYaqo9CRHS55FcZTjKEk3NKoCYUnSQ0rWxrZbFKbKIhOKPZe1cJKzZSaQrIyULHDZmV5K4xySsDRKWOruanGtjLJXFEmwaIbDLX0hIPBUQPVFVkQkDoUNfSoDgQGKPekoxeGzA4DUvnn4bxzcZrtJyipKfPNy5w+9lnXwgqsiyHNeSVpemw4bWb9psYeq//uQZBoABQt4yMVxYAIAAAkQoAAAHvYpL5m6AAgAACXDAAAAD59jblTirQe9upFsmZbpMudy7Lz1X1DYsxOOSWpfPqNX2WqktK0DMvuGwlbNj44TleLPQ+Gsfb+GOWOKJoIrWb3cIMeeON6lz2umTqMXV8Mj30yWPpjoSa9ujK8SyeJP5y5mOW1D6hvLepeveEAEDo0mgCRClOEgANv3B9a6fikgUSu/DmAMATrGx7nng5p5iimPNZsfQLYB2sDLIkzRKZOHGAaUyDcpFBSLG9MCQALgAIgQs2YunOszLSAyQYPVC2YdGGeHD2dTdJk1pAHGAWDjnkcLKFymS3RQZTInzySoBwMG0QueC3gMsCEYxUqlrcxK6k1LQQcsmyYeQPdC2YfuGPASCBkcVMQQqpVJshui1tkXQJQV0OXGAZMXSOEEBRirXbVRQW7ugq7IM7rPWSZyDlM3IuNEkxzCOJ0ny2ThNkyRai1b6ev//3dzNGzNb//4uAvHT5sURcZCFcuKLhOFs8mLAAEAt4UWAAIABAAAAAB4qbHo0tIjVkUU//uQZAwABfSFz3ZqQAAAAAngwAAAE1HjMp2qAAAAACZDgAAAD5UkTE1UgZEUExqYynN1qZvqIOREEFmBcJQkwdxiFtw0qEOkGYfRDifBui9MQg4QAHAqWtAWHoCxu1Yf4VfWLPIM2mHDFsbQEVGwyqQoQcwnfHeIkNt9YnkiaS1oizycqJrx4KOQjahZxWbcZgztj2c49nKmkId44S71j0c8eV9yDK6uPRzx5X18eDvjvQ6yKo9ZSS6l//8elePK/Lf//IInrOF/FvDoADYAGBMGb7FtErm5MXMlmPAJQVgWta7Zx2go+8xJ0UiCb8LHHdftWyLJE0QIAIsI+UbXu67dZMjmgDGCGl1H+vpF4NSDckSIkk7Vd+sxEhBQMRU8j/12UIRhzSaUdQ+rQU5kGeFxm+hb1oh6pWWmv3uvmReDl0UnvtapVaIzo1jZbf/pD6ElLqSX+rUmOQNpJFa/r+sa4e/pBlAABoAAAAA3CUgShLdGIxsY7AUABPRrgCABdDuQ5GC7DqPQCgbbJUAoRSUj+NIEig0YfyWUho1VBBBA//uQZB4ABZx5zfMakeAAAAmwAAAAF5F3P0w9GtAAACfAAAAAwLhMDmAYWMgVEG1U0FIGCBgXBXAtfMH10000EEEEEECUBYln03TTTdNBDZopopYvrTTdNa325mImNg3TTPV9q3pmY0xoO6bv3r00y+IDGid/9aaaZTGMuj9mpu9Mpio1dXrr5HERTZSmqU36A3CumzN/9Robv/Xx4v9ijkSRSNLQhAWumap82WRSBUqXStV/YcS+XVLnSS+WLDroqArFkMEsAS+eWmrUzrO0oEmE40RlMZ5+ODIkAyKAGUwZ3mVKmcamcJnMW26MRPgUw6j+LkhyHGVGYjSUUKNpuJUQoOIAyDvEyG8S5yfK6dhZc0Tx1KI/gviKL6qvvFs1+bWtaz58uUNnryq6kt5RzOCkPWlVqVX2a/EEBUdU1KrXLf40GoiiFXK///qpoiDXrOgqDR38JB0bw7SoL+ZB9o1RCkQjQ2CBYZKd/+VJxZRRZlqSkKiws0WFxUyCwsKiMy7hUVFhIaCrNQsKkTIsLivwKKigsj8XYlwt/WKi2N4d//uQRCSAAjURNIHpMZBGYiaQPSYyAAABLAAAAAAAACWAAAAApUF/Mg+0aohSIRobBAsMlO//Kk4soosy1JSFRYWaLC4qZBYWFRGZdwqKiwkNBVmoWFSJkWFxX4FFRQWR+LsS4W/rFRb/////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////VEFHAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAU291bmRib3kuZGUAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAMjAwNGh0dHA6Ly93d3cuc291bmRib3kuZGUAAAAAAAAAACU=");
 
-const SPEED_UP = 4;
+// Hold backspace to rewind.
+const REWIND_KEY = 8;
+// Keep 10 seconds of history at 60fps, in no more than 8MB.
+const REWIND_DEPTH = 600;
+const REWIND_BUDGET = 8 * 1024 * 1024;
+// Backends selectable with ?backend=...
+const BACKENDS: { [name: string]: Backend } = {
+    interpreter: Backend.Interpreter,
+    cached: Backend.Cached,
+    jit: Backend.Jit,
+};
+// Entries per section of the profile report.
+const PROFILE_ENTRIES = 10;
 // Maps keycode -> CHIP-8 key.
 const KEY_MAP: { [key: number]: Key } = {
     49: 0x1,  // 1
@@ -37,8 +49,16 @@ export default class Engine {
     engine: CHIP8 = CHIP8.new();
     memory: WasmMemory;
 
-    width: number = CHIP8.display_width();
-    height: number = CHIP8.display_height();
+    rewind: Rewind = Rewind.new(REWIND_DEPTH, REWIND_BUDGET);
+    isRewinding: boolean = false;
+    // Only created with ?profile=true, profiling slows the emulator down.
+    profiler: Profiler = null;
+    // Tracked along with the memory display to tell code from data. Only
+    // one tool can run the emulator at a time, so not while profiling.
+    coverage: Coverage = null;
+    // Timestamp of the previous animation frame, used to run the emulator
+    // for however much time actually passed.
+    lastFrame: number = null;
 
     fps: FPS;
     display: Display;
@@ -48,12 +68,13 @@ export default class Engine {
 
     constructor(memory: WasmMemory) {
         this.memory = memory;
+        this._parseURLParams();
         this.display = new Display(
             'engine-display',
             this.memory,
             this.engine.display(),
-            this.width,
-            this.height
+            this.engine.display_width(),
+            this.engine.display_height()
         );
 
         this.memDisplay = new MemoryDisplay(
@@ -61,7 +82,7 @@ export default class Engine {
             this.engine.registers(),
             CHIP8.num_registers(),
             this.engine.memory(),
-            CHIP8.mem_size(),
+            this.engine.mem_size(),
             this.engine.stack(),
             CHIP8.stack_size(),
             this.engine.pc(),
@@ -92,16 +113,29 @@ export default class Engine {
     private _parseURLParams() {
         let urlParams = new URLSearchParams(window.location.search);
         this.showMemDisplay = urlParams.has('memDisplay') ? urlParams.get('memDisplay') === 'true' : false;
+        if (urlParams.get('profile') === 'true') {
+            this.profiler = Profiler.new();
+        } else if (this.showMemDisplay) {
+            this.coverage = Coverage.new();
+        }
+        // The emulator and every tool running it use this backend. It
+        // notices new roms & rewinds on its own, nothing needs to clear it.
+        let backend = BACKENDS[urlParams.get('backend')];
+        this.engine.set_backend(backend === undefined ? Backend.Cached : backend);
     }
 
     public handleKeyPress(ev: KeyboardEvent) {
-        if (ev.keyCode in KEY_MAP) {
+        if (ev.keyCode === REWIND_KEY) {
+            this.isRewinding = true;
+        } else if (ev.keyCode in KEY_MAP) {
             this.engine.key_press(KEY_MAP[ev.keyCode]);
         }
     }
 
     public handleKeyUp(ev: KeyboardEvent) {
-        if (ev.keyCode in KEY_MAP) {
+        if (ev.keyCode === REWIND_KEY) {
+            this.isRewinding = false;
+        } else if (ev.keyCode in KEY_MAP) {
             this.engine.key_up(KEY_MAP[ev.keyCode]);
         }
     }
@@ -110,26 +144,67 @@ export default class Engine {
         return this.animationId === null;
     }
 
-    public tick() {
+    public tick(timestamp?: number) {
         this.fps.render();
+        const elapsed = this.lastFrame === null || timestamp === undefined ? 0 : timestamp - this.lastFrame;
+        this.lastFrame = timestamp === undefined ? null : timestamp;
 
         this.render();
-        for (let i = 0; i < SPEED_UP; i++) {
-            this.engine.tick();
-            if (this.engine.has_beep()) {
-                BEEP.play();
+        if (this.isRewinding) {
+            this.rewind.rewind(this.engine, 1);
+            this.animationId = requestAnimationFrame(this.tick);
+            return;
+        }
+
+        this.rewind.record(this.engine);
+        try {
+            if (this.profiler) {
+                this.profiler.run_for(this.engine, elapsed);
+            } else if (this.coverage) {
+                this.coverage.run_for(this.engine, elapsed);
+            } else {
+                this.engine.run_for(elapsed);
             }
+        } catch (fault) {
+            // The emulator halts itself on a fault, stop the render loop
+            // and leave the last frame on screen.
+            console.error(`CHIP8 halted: ${this.engine.fault()}`);
+            this.animationId = null;
+            this.lastFrame = null;
+            this.render();
+            return;
+        }
+
+        if (this.engine.has_beep()) {
+            BEEP.play();
         }
 
         this.animationId = requestAnimationFrame(this.tick);
     }
 
     public render() {
-        this.display.drawPixels();
+        // SUPER-CHIP programs can switch resolutions at any time.
+        this.display.resize(this.engine.display_width(), this.engine.display_height());
+        // Only repaint what changed since the last frame, if anything.
+        if (this.engine.is_dirty()) {
+            this.display.drawRegions(this.engine.dirty_rects());
+            this.engine.clear_dirty();
+        }
 
         this.memDisplay.drawRegisters();
         if (this.showMemDisplay) {
-            this.memDisplay.drawMemory(this.isPaused());
+            const pc = this.engine.pc();
+            const start = Math.max(pc - 8, 0);
+            const listing = this.coverage
+                ? this.coverage.listing(this.engine, start, 32)
+                : this.engine.disassemble(start, 16);
+            this.memDisplay.drawInstructions(this.isPaused(), listing, pc);
+            const heatmap = this.profiler ? this.profiler.heatmap(this.engine.mem_size()) : null;
+            const coverage = this.coverage ? this.coverage.map(this.engine.mem_size()) : null;
+            this.memDisplay.drawMemory(this.isPaused(), heatmap, coverage);
+        }
+        if (this.profiler) {
+            this.memDisplay.drawProfile(this.isPaused(), this.profiler.report(this.engine, PROFILE_ENTRIES));
         }
     }
 }
\ No newline at end of file
@@ -4,6 +4,10 @@ import './display.scss';
 const CELL_SIZE = 5; // px
 const OFF_COLOR = '#FFFFFF';
 const ON_COLOR = '#000000';
+// Colors for XO-CHIP pixels set in only the second plane or both planes.
+const PLANE2_COLOR = '#AAAAAA';
+const BOTH_COLOR = '#555555';
+const COLORS = [OFF_COLOR, ON_COLOR, PLANE2_COLOR, BOTH_COLOR];
 
 export class Display {
     canvas: HTMLCanvasElement;
@@ -16,6 +20,7 @@ export class Display {
 
     width: number;
     height: number;
+    cellSize: number;
 
     constructor(elementId: string, memory: WasmMemory, displayPtr: number, width: number, height: number) {
         this.canvas = <HTMLCanvasElement>document.getElementById(elementId);
@@ -24,16 +29,26 @@ export class Display {
         this.memory = memory;
         this.displayPtr = displayPtr;
 
-        // Give the canvas room for all of the cells and a 1px border
-        // around each of them.
+        this.resize(width, height);
+
+        this.drawPixels = this.drawPixels.bind(this);
+        this.drawRegions = this.drawRegions.bind(this);
+        this.resize = this.resize.bind(this);
+    }
+
+    public resize(width: number, height: number) {
+        if (width === this.width && height === this.height) { return; }
+
+        // Give the canvas room for all of the cells, keeping the canvas the
+        // same size on screen in high resolution mode.
+        const cellSize = CELL_SIZE * 64 / width;
         this.width = width;
         this.height = height;
-        this.canvas.height = CELL_SIZE * this.height;
-        this.canvas.width = CELL_SIZE * this.width;
+        this.canvas.height = cellSize * this.height;
+        this.canvas.width = cellSize * this.width;
+        this.cellSize = cellSize;
 
         this.pixels = new Uint8Array(this.memory.buffer, this.displayPtr, this.width * this.height);
-
-        this.drawPixels = this.drawPixels.bind(this);
     }
 
     private getIndex(row: number, col: number) {
@@ -41,36 +56,37 @@ export class Display {
     }
 
     public drawPixels() {
-        this.ctx.beginPath();
+        this.drawRegion(0, 0, this.width, this.height);
+    }
 
-        this.ctx.fillStyle = ON_COLOR;
-        for (let row = 0; row < this.height; row++) {
-            for (let col = 0; col < this.width; col++) {
-                const idx = this.getIndex(row, col);
-                if (this.pixels[idx] === 0) { continue; }
-                this.ctx.fillRect(
-                    col * CELL_SIZE,
-                    row * CELL_SIZE,
-                    CELL_SIZE,
-                    CELL_SIZE
-                );
-            }
+    // Repaints the rectangles in a flat list of x, y, width, height.
+    public drawRegions(regions: Uint8Array) {
+        for (let idx = 0; idx < regions.length; idx += 4) {
+            this.drawRegion(regions[idx], regions[idx + 1], regions[idx + 2], regions[idx + 3]);
         }
+    }
+
+    private drawRegion(x: number, y: number, width: number, height: number) {
+        this.ctx.beginPath();
 
-        this.ctx.fillStyle = OFF_COLOR;
-        for (let row = 0; row < this.height; row++) {
-            for (let col = 0; col < this.width; col++) {
-                const idx = this.getIndex(row, col);
-                if (this.pixels[idx] === 1) { continue; }
-                this.ctx.fillRect(
-                    col * CELL_SIZE,
-                    row * CELL_SIZE,
-                    CELL_SIZE,
-                    CELL_SIZE
-                );
+        // Each pixel is a bitmask of the XO-CHIP planes it's set in, draw
+        // all pixels of one color at a time.
+        for (let color = 0; color < COLORS.length; color++) {
+            this.ctx.fillStyle = COLORS[color];
+            for (let row = y; row < y + height; row++) {
+                for (let col = x; col < x + width; col++) {
+                    const idx = this.getIndex(row, col);
+                    if (this.pixels[idx] !== color) { continue; }
+                    this.ctx.fillRect(
+                        col * this.cellSize,
+                        row * this.cellSize,
+                        this.cellSize,
+                        this.cellSize
+                    );
+                }
             }
         }
 
         this.ctx.stroke();
     }
-}
\ No newline at end of file
+}
@@ -1,6 +1,14 @@
 import { WasmMemory } from '../engine';
 
 const MEM_PER_ROW = 32;
+// Flags in `Coverage.map`, a byte can have several.
+const EXECUTED = 1;
+const READ = 2;
+const WRITTEN = 4;
+// Text colors for code, data and variables in the coverage map.
+const CODE_COLOR = '#0000CC';
+const DATA_COLOR = '#008800';
+const WRITTEN_COLOR = '#CC6600';
 
 export class MemoryDisplay {
     memory: WasmMemory;
@@ -39,6 +47,8 @@ export class MemoryDisplay {
         this._toHex = this._toHex.bind(this);
         this.drawMemory = this.drawMemory.bind(this);
         this.drawRegisters = this.drawRegisters.bind(this);
+        this.drawInstructions = this.drawInstructions.bind(this);
+        this.drawProfile = this.drawProfile.bind(this);
     }
 
     private _toHex(number: number, len: number = 2) {
@@ -55,7 +65,33 @@ export class MemoryDisplay {
         document.getElementById('registers').innerHTML = disp;
     }
 
-    public drawMemory(isPaused: boolean) {
+    // `listing` is the output of `CHIP8.disassemble` or `Coverage.listing`,
+    // one instruction per line prefixed with its address.
+    public drawInstructions(isPaused: boolean, listing: string, pc: number) {
+        const element = document.getElementById('instructions');
+        if (!isPaused) {
+            element.innerHTML = '';
+            return;
+        }
+
+        let disp = '';
+        for (const line of listing.split('\n')) {
+            if (line.length === 0) {
+                continue;
+            }
+
+            const addr = parseInt(line.slice(0, line.indexOf(':')), 16);
+            const marker = addr === pc ? '&gt;' : '&nbsp;';
+            disp += `<div>${marker} ${line}</div>`;
+        }
+
+        element.innerHTML = `<code>${disp}</code>`;
+    }
+
+    // `heatmap` is the output of `Profiler.heatmap`, shading each byte by
+    // how often it executed. `coverage` is the output of `Coverage.map`,
+    // coloring code, data and variables differently.
+    public drawMemory(isPaused: boolean, heatmap: Uint8Array = null, coverage: Uint8Array = null) {
         const element = document.getElementById('memory');
         if (!isPaused) {
             element.innerHTML = '<code>Memory only shown on pause.</code>';
@@ -71,11 +107,40 @@ export class MemoryDisplay {
             disp += `<div>${this._toHex(rowStart, 3)}: `;
             for (let col = 0; col < MEM_PER_ROW; col++) {
                 let idx = (row * MEM_PER_ROW) + col;
-                disp += `<span>${this._toHex(memory[idx])}</span>`
+                const heat = heatmap ? heatmap[idx] : 0;
+                const color = coverage ? this._coverageColor(coverage[idx]) : null;
+                let style = '';
+                if (heat > 0) {
+                    style += `background: rgba(255, 0, 0, ${(heat / 255).toFixed(2)});`;
+                }
+                if (color) {
+                    style += `color: ${color};`;
+                }
+                disp += style ? `<span style="${style}">` : '<span>';
+                disp += `${this._toHex(memory[idx])}</span>`
             }
             disp += '</div>';
         }
 
         element.innerHTML = `<code>${disp}</code>`;
     }
+
+    // Code wins over data, self-modifying code is still code.
+    private _coverageColor(flags: number) {
+        if (flags & EXECUTED) { return CODE_COLOR; }
+        if (flags & READ) { return DATA_COLOR; }
+        if (flags & WRITTEN) { return WRITTEN_COLOR; }
+        return null;
+    }
+
+    // `report` is the output of `Profiler.report`.
+    public drawProfile(isPaused: boolean, report: string) {
+        const element = document.getElementById('profile');
+        if (!isPaused) {
+            element.innerHTML = '<code>Profile only shown on pause.</code>';
+            return;
+        }
+
+        element.innerHTML = `<pre>${report}</pre>`;
+    }
 }